@@ -17,15 +17,36 @@
 //!
 //! See [this blog post](https://ticki.github.io/blog/an-atomic-hash-table/)
 //! for details.
+//!
+//! # Snapshots
+//!
+//! There is no `snapshot()` operation (yet). Capturing the root is not enough
+//! to freeze the map: The root table is stored inline, and the tables below
+//! it are updated in place (by CAS on their buckets) rather than by path
+//! copying, so a captured table keeps changing under the reader.
+//!
+//! Cheap, consistent snapshots require the Ctrie approach: Every table gets a
+//! generation, and the buckets are updated through a generation-checked CAS
+//! (GCAS), such that taking a snapshot merely swaps the root for a copy with a
+//! new generation, and both the snapshot and the live map lazily copy the
+//! tables of the old generation on their way down. Until then, consistent
+//! iteration requires pausing the writers externally, as `for_each` alone is
+//! not consistent.
 
 #![feature(box_patterns)]
 
 extern crate conc;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+#[cfg(feature = "serde")]
+mod serde_impls;
 mod sponge;
 mod table;
 
+use std::collections;
 use std::hash::Hash;
+use std::iter::FromIterator;
 use sponge::Sponge;
 
 /// A lock-free, concurrent hash map.
@@ -35,7 +56,20 @@ pub struct HashMap<K, V> {
     table: table::Table<K, V>,
 }
 
+impl<K, V> Default for HashMap<K, V> {
+    fn default() -> HashMap<K, V> {
+        HashMap {
+            table: table::Table::default(),
+        }
+    }
+}
+
 impl<K: Hash + Eq + 'static + Clone, V: Clone> HashMap<K, V> {
+    /// Create a new, empty map.
+    pub fn new() -> HashMap<K, V> {
+        HashMap::default()
+    }
+
     /// Get a value from the map.
     pub fn get(&self, key: &K) -> Option<conc::Guard<V>> {
         self.table.get(key, Sponge::new(&key))
@@ -74,3 +108,22 @@ impl<K: Hash + Eq + 'static + Clone, V: Clone> HashMap<K, V> {
         self.take_each(|_, _| ());
     }
 }
+
+impl<K: Hash + Eq + 'static + Clone, V: Clone> FromIterator<(K, V)> for HashMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> HashMap<K, V> {
+        // Deduplicate the keys (the last value wins), as the table must be built from distinct
+        // keys.
+        let entries: collections::HashMap<K, V> = iter.into_iter().collect();
+
+        // Build the table in one go.
+        HashMap {
+            table: table::Table::from_pairs(entries.into_iter().map(|(key, val)| {
+                let sponge = Sponge::new(&key);
+                (table::Pair {
+                    key: key,
+                    val: val,
+                }, sponge)
+            }).collect()),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Serialization and deserialization through `serde`.
+//!
+//! The map is serialized as a map of its entries. Since it can be modified concurrently, the
+//! serialized entries are not necessarily a snapshot: Entries inserted or removed during
+//! serialization might or might not be included.
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use HashMap;
+
+impl<K, V> Serialize for HashMap<K, V>
+where K: Serialize + Hash + Eq + 'static + Clone, V: Serialize + Clone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The length can change under our feet, so we don't give it in advance.
+        let map = RefCell::new(serializer.serialize_map(None)?);
+        // The first error, if any. `for_each` cannot be interrupted, so we skip the remaining
+        // entries instead.
+        let err = RefCell::new(None);
+
+        self.for_each(|key, val| {
+            if err.borrow().is_none() {
+                if let Err(e) = map.borrow_mut().serialize_entry(key, val) {
+                    *err.borrow_mut() = Some(e);
+                }
+            }
+        });
+
+        match err.into_inner() {
+            Some(e) => Err(e),
+            None => map.into_inner().end(),
+        }
+    }
+}
+
+/// A visitor deserializing a map.
+struct Visitor<K, V> {
+    _phantom: PhantomData<HashMap<K, V>>,
+}
+
+impl<'de, K, V> de::Visitor<'de> for Visitor<K, V>
+where K: Deserialize<'de> + Hash + Eq + 'static + Clone, V: Deserialize<'de> + Clone {
+    type Value = HashMap<K, V>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<HashMap<K, V>, A::Error> {
+        // Gather the entries, such that the table can be built bottom-up (see `FromIterator`).
+        let mut entries = Vec::with_capacity(access.size_hint().unwrap_or(0));
+        while let Some(entry) = access.next_entry()? {
+            entries.push(entry);
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for HashMap<K, V>
+where K: Deserialize<'de> + Hash + Eq + 'static + Clone, V: Deserialize<'de> + Clone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<K, V>, D::Error> {
+        deserializer.deserialize_map(Visitor {
+            _phantom: PhantomData,
+        })
+    }
+}
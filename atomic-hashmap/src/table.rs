@@ -65,6 +65,36 @@ impl<K: Hash + Eq + 'static + Clone, V: Clone> Table<K, V> {
         table
     }
 
+    /// Build a table from a number of key-value pairs, given their sponges.
+    ///
+    /// This builds the tree bottom-up, which is a lot faster than inserting the pairs one by one,
+    /// as no CAS is needed, and every table is only allocated once. The keys must be distinct.
+    pub fn from_pairs(pairs: Vec<(Pair<K, V>, Sponge)>) -> Table<K, V> {
+        // Distribute the pairs over the buckets, by squeezing their sponges.
+        let mut groups: Vec<Vec<(Pair<K, V>, Sponge)>> = (0..256).map(|_| Vec::new()).collect();
+        for (pair, mut sponge) in pairs {
+            let pos = sponge.squeeze();
+            groups[pos as usize].push((pair, sponge));
+        }
+
+        // Start with an empty table.
+        let mut table = Table::default();
+
+        for (pos, mut group) in groups.into_iter().enumerate() {
+            table.buckets[pos] = match group.len() {
+                // The bucket is empty.
+                0 => continue,
+                // A single pair ends up in the bucket, so it becomes a leaf.
+                1 => conc::Atomic::new(Some(Box::new(Node::Leaf(group.pop().unwrap().0)))),
+                // Several pairs collide in the bucket, so they are placed in a branch. The sponges
+                // are already squeezed to the next level of the tree.
+                _ => conc::Atomic::new(Some(Box::new(Node::Branch(Table::from_pairs(group))))),
+            };
+        }
+
+        table
+    }
+
     /// Get the value associated with some key, given its sponge.
     pub fn get(&self, key: &K, mut sponge: Sponge) -> Option<conc::Guard<V>> {
         // Load the bucket and handle the respective cases.
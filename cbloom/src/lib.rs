@@ -5,14 +5,25 @@
 //!
 //! This implementation is fairly standard, except that it uses atomic integers to work
 //! concurrently.
+//!
+//! Under heavy concurrent insertion, the atomic operations on the shared words can limit the
+//! throughput. For such cases, inserts can be buffered thread-locally through `InsertBuffer`, and
+//! flushed to the filter in batches.
+//!
+//! For membership which expires (e.g. "seen recently"), `RotatingFilter` keeps a number of
+//! generations of filters, and forgets the oldest one at a time.
 
 #![feature(integer_atomics)]
 
 use std::cmp;
-use std::sync::atomic::{self, AtomicU64};
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::time::{Duration, Instant};
 
 /// The atomic ordering used throughout the crate.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The default number of words held by an insert buffer.
+const DEFAULT_BUFFER_WORDS: usize = 16;
 
 /// Hash an integer.
 ///
@@ -50,10 +61,16 @@ pub struct Filter {
 }
 
 impl Filter {
+    /// Get the index of the chunk of a particular hash.
+    #[inline]
+    fn index(&self, hash: u64) -> usize {
+        (hash as usize / 64) % self.bits.len()
+    }
+
     /// Get the chunk of a particular hash.
     #[inline]
     fn get(&self, hash: u64) -> &AtomicU64 {
-        &self.bits[(hash as usize / 64) % self.bits.len()]
+        &self.bits[self.index(hash)]
     }
 
     /// Create a new Bloom filter with the optimal number of hash functions.
@@ -139,6 +156,255 @@ impl Filter {
         // Every bit was set, so the element might be in the filter.
         true
     }
+
+    /// Create an insert buffer for this filter.
+    ///
+    /// See `InsertBuffer` for details.
+    pub fn buffered<'a>(&'a self) -> InsertBuffer<'a> {
+        InsertBuffer::with_capacity(self, DEFAULT_BUFFER_WORDS)
+    }
+}
+
+/// A buffer of inserts into some Bloom filter.
+///
+/// Rather than setting the bits of the filter directly (one atomic operation per hasher), this
+/// accumulates the bits of the inserted elements locally, and sets them in the filter in a batch,
+/// with only one atomic operation per word. It is intended to be owned by a single thread, which
+/// inserts many elements.
+///
+/// The buffer is flushed when it is full, when `flush()` is called, and when it is dropped. Until
+/// then, the inserted elements are invisible to other users of the filter.
+pub struct InsertBuffer<'a> {
+    /// The filter we insert into.
+    filter: &'a Filter,
+    /// The buffered words.
+    ///
+    /// This holds the indexes of the words with pending bits, along with the pending bits.
+    words: Vec<(usize, u64)>,
+    /// The maximal number of buffered words.
+    capacity: usize,
+}
+
+impl<'a> InsertBuffer<'a> {
+    /// Create an insert buffer holding at most `capacity` words.
+    ///
+    /// If `capacity` is 0, it will be rounded to 1.
+    pub fn with_capacity(filter: &'a Filter, capacity: usize) -> InsertBuffer<'a> {
+        let capacity = cmp::max(capacity, 1);
+
+        InsertBuffer {
+            filter: filter,
+            words: Vec::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    /// Insert an element into the buffer.
+    pub fn insert(&mut self, x: u64) {
+        // Start at `x`.
+        let mut h = x;
+        // Run over the hashers.
+        for _ in 0..self.filter.hashers {
+            // The hashes are generated as in `Filter::insert()`.
+            h = hash(h);
+            let index = self.filter.index(h);
+            let mask = 1 << (h % 8);
+
+            // If the word is already buffered, we OR the mask into it.
+            if let Some(word) = self.words.iter_mut().find(|&&mut (i, _)| i == index) {
+                word.1 |= mask;
+                continue;
+            }
+
+            // The word is not buffered, so we must add it, flushing first if we're out of room.
+            if self.words.len() == self.capacity {
+                self.flush();
+            }
+            self.words.push((index, mask));
+        }
+    }
+
+    /// Check if the filter (including the buffered elements) potentially contains an element.
+    ///
+    /// This acts like `Filter::maybe_contains()`, but takes the buffered elements into account.
+    pub fn maybe_contains(&self, x: u64) -> bool {
+        // Start at `x`.
+        let mut h = x;
+
+        // Go over the hashers.
+        for _ in 0..self.filter.hashers {
+            h = hash(h);
+            let index = self.filter.index(h);
+            let mask = 1 << (h % 8);
+
+            // Check the buffered bits as well as the filter's.
+            let buffered = self.words.iter().find(|&&(i, _)| i == index).map_or(0, |&(_, x)| x);
+            if (self.filter.bits[index].load(ORDERING) | buffered) & mask == 0 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Flush the buffer.
+    ///
+    /// This sets the bits of the buffered elements in the filter, making them visible to other
+    /// users of the filter.
+    pub fn flush(&mut self) {
+        for (index, mask) in self.words.drain(..) {
+            self.filter.bits[index].fetch_or(mask, ORDERING);
+        }
+    }
+}
+
+impl<'a> Drop for InsertBuffer<'a> {
+    fn drop(&mut self) {
+        // Make the buffered elements visible.
+        self.flush();
+    }
+}
+
+/// The policy of rotating the generations of a `RotatingFilter`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    /// Rotate after some number of insertions.
+    Count(u64),
+    /// Rotate after some amount of time.
+    ///
+    /// The rotation happens on the first insertion after the interval elapsed, so a filter with no
+    /// insertions never rotates.
+    Interval(Duration),
+    /// Only rotate when `RotatingFilter::rotate()` is called.
+    Manual,
+}
+
+/// A Bloom filter whose elements expire.
+///
+/// This consists of a number of generations, each being a `Filter`. Elements are inserted into the
+/// current generation, and membership is tested against all of them. When rotating (as dictated
+/// by the `Rotation` policy), the oldest generation is cleared and becomes the current one.
+///
+/// Hence, an element is forgotten after exactly `generations` rotations (counting from its
+/// insertion), when the generation it was inserted into is cleared.
+pub struct RotatingFilter {
+    /// The generations.
+    generations: Vec<Filter>,
+    /// The index of the current generation.
+    current: AtomicUsize,
+    /// The rotation policy.
+    policy: Rotation,
+    /// The number of insertions since the last rotation.
+    inserted: AtomicU64,
+    /// The time of creation of the filter.
+    created: Instant,
+    /// The time of the last rotation, in nanoseconds since `created`.
+    last_rotation: AtomicU64,
+    /// The lock held while rotating.
+    ///
+    /// This ensures that concurrent insertions don't rotate more than once.
+    rotating: Mutex<()>,
+}
+
+/// Convert a duration to nanoseconds.
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+impl RotatingFilter {
+    /// Create a new rotating filter.
+    ///
+    /// This creates a filter of `generations` generations, each of which has `bytes` bytes and is
+    /// optimized for `expected_elements` elements (see `Filter::new()`), rotating according to
+    /// `policy`.
+    ///
+    /// If `generations` is less than 2, it will be rounded to 2, as a single generation would
+    /// forget everything on rotation.
+    pub fn new(generations: usize, bytes: usize, expected_elements: usize, policy: Rotation)
+        -> RotatingFilter {
+        RotatingFilter {
+            generations: (0..cmp::max(generations, 2))
+                .map(|_| Filter::new(bytes, expected_elements))
+                .collect(),
+            current: AtomicUsize::new(0),
+            policy: policy,
+            inserted: AtomicU64::new(0),
+            created: Instant::now(),
+            last_rotation: AtomicU64::new(0),
+            rotating: Mutex::new(()),
+        }
+    }
+
+    /// Is a rotation due according to the policy?
+    fn rotation_due(&self) -> bool {
+        match self.policy {
+            Rotation::Count(count) => self.inserted.load(ORDERING) >= count,
+            Rotation::Interval(interval) => {
+                // Another thread might rotate between the two reads, making the last rotation
+                // newer than the time we read, so we saturate.
+                nanos(self.created.elapsed()).saturating_sub(self.last_rotation.load(ORDERING))
+                    >= nanos(interval)
+            },
+            Rotation::Manual => false,
+        }
+    }
+
+    /// Rotate the generations, given the rotation lock.
+    fn rotate_locked(&self) {
+        // The oldest generation is the one after the current.
+        let next = (self.current.load(ORDERING) + 1) % self.generations.len();
+
+        // Forget the oldest generation before making it current. Insertions racing with this go
+        // to the previous generation, so they aren't lost.
+        self.generations[next].clear();
+        self.current.store(next, ORDERING);
+
+        self.inserted.store(0, ORDERING);
+        self.last_rotation.store(nanos(self.created.elapsed()), ORDERING);
+    }
+
+    /// Rotate the generations.
+    ///
+    /// This forgets the oldest generation and makes it the current one, regardless of the policy.
+    pub fn rotate(&self) {
+        let _lock = self.rotating.lock().unwrap();
+        self.rotate_locked();
+    }
+
+    /// Insert an element into the filter.
+    ///
+    /// This rotates the generations first, if the policy says so.
+    pub fn insert(&self, x: u64) {
+        if self.rotation_due() {
+            // If another thread is rotating, we leave it to it.
+            if let Ok(_lock) = self.rotating.try_lock() {
+                // Another thread might have rotated in the meantime, so we check again.
+                if self.rotation_due() {
+                    self.rotate_locked();
+                }
+            }
+        }
+
+        self.generations[self.current.load(ORDERING)].insert(x);
+        self.inserted.fetch_add(1, ORDERING);
+    }
+
+    /// Check if the filter potentially contains an element.
+    ///
+    /// This acts like `Filter::maybe_contains()` over all the generations.
+    pub fn maybe_contains(&self, x: u64) -> bool {
+        self.generations.iter().any(|filter| filter.maybe_contains(x))
+    }
+
+    /// Clear the filter.
+    ///
+    /// This removes every element from every generation. As with `Filter::clear()`, it is not
+    /// atomic.
+    pub fn clear(&self) {
+        for filter in &self.generations {
+            filter.clear();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -211,4 +477,145 @@ mod tests {
             assert!(!filter.maybe_contains(i));
         }
     }
+
+    #[test]
+    fn buffered() {
+        let filter = Filter::with_size_and_hashers(400, 4);
+        {
+            let mut buf = filter.buffered();
+            buf.insert(3);
+            buf.insert(5);
+
+            // The elements are only visible through the buffer until it is flushed.
+            assert!(buf.maybe_contains(3));
+            assert!(buf.maybe_contains(5));
+            assert!(!filter.maybe_contains(3));
+
+            buf.flush();
+            assert!(filter.maybe_contains(3));
+            assert!(filter.maybe_contains(5));
+
+            buf.insert(7);
+            buf.insert(13);
+        }
+
+        // Dropping the buffer flushes it.
+        assert!(filter.maybe_contains(7));
+        assert!(filter.maybe_contains(13));
+        assert!(!filter.maybe_contains(0));
+        assert!(!filter.maybe_contains(1));
+    }
+
+    #[test]
+    fn buffered_overflow() {
+        let filter = Filter::new(4000, 1000);
+        let mut buf = InsertBuffer::with_capacity(&filter, 2);
+        for i in 0..1000 {
+            buf.insert(i);
+        }
+        drop(buf);
+
+        for i in 0..1000 {
+            assert!(filter.maybe_contains(i));
+        }
+    }
+
+    #[test]
+    fn buffered_spam() {
+        let filter = Arc::new(Filter::new(2000, 100));
+        let mut joins = Vec::new();
+
+        for _ in 0..16 {
+            let filter = filter.clone();
+            joins.push(thread::spawn(move || {
+                let mut buf = filter.buffered();
+                for i in 0..100 {
+                    buf.insert(i)
+                }
+            }));
+        }
+
+        for i in joins {
+            i.join().unwrap();
+        }
+
+        for i in 0..100 {
+            assert!(filter.maybe_contains(i));
+        }
+    }
+
+    #[test]
+    fn rotating_count() {
+        let filter = RotatingFilter::new(3, 400, 4, Rotation::Count(2));
+        filter.insert(1);
+        filter.insert(2);
+        // Rotates.
+        filter.insert(3);
+        filter.insert(4);
+        // Rotates.
+        filter.insert(5);
+
+        assert!(filter.maybe_contains(1));
+        assert!(filter.maybe_contains(5));
+
+        // Rotates, forgetting the first generation.
+        filter.insert(6);
+        filter.insert(7);
+
+        assert!(!filter.maybe_contains(1));
+        assert!(!filter.maybe_contains(2));
+        assert!(filter.maybe_contains(3));
+        assert!(filter.maybe_contains(7));
+    }
+
+    #[test]
+    fn rotating_manual() {
+        let filter = RotatingFilter::new(0, 400, 4, Rotation::Manual);
+        for i in 0..100 {
+            filter.insert(i);
+        }
+        assert!(filter.maybe_contains(3));
+
+        // There are at least two generations, so a single rotation doesn't forget.
+        filter.rotate();
+        assert!(filter.maybe_contains(3));
+        filter.rotate();
+        assert!(!filter.maybe_contains(3));
+    }
+
+    #[test]
+    fn rotating_interval() {
+        let filter = RotatingFilter::new(2, 400, 4, Rotation::Interval(Duration::from_millis(20)));
+        filter.insert(1);
+        thread::sleep(Duration::from_millis(30));
+        filter.insert(2);
+        assert!(filter.maybe_contains(1));
+        thread::sleep(Duration::from_millis(30));
+        filter.insert(3);
+
+        assert!(!filter.maybe_contains(1));
+        assert!(filter.maybe_contains(2));
+        assert!(filter.maybe_contains(3));
+    }
+
+    #[test]
+    fn rotating_interval_concurrent() {
+        let filter = RotatingFilter::new(2, 400, 4, Rotation::Interval(Duration::from_millis(20)));
+        // Emulate another thread rotating after we read the time.
+        filter.last_rotation.store(u64::max_value(), ORDERING);
+
+        assert!(!filter.rotation_due());
+    }
+
+    #[test]
+    fn rotating_clear() {
+        let filter = RotatingFilter::new(2, 400, 4, Rotation::Manual);
+        filter.insert(1);
+        filter.rotate();
+        filter.insert(2);
+        filter.clear();
+
+        assert!(!filter.maybe_contains(1));
+        assert!(!filter.maybe_contains(2));
+    }
 }
@@ -64,6 +64,93 @@ const MAX_LOAD_FACTOR_DENOM: usize = 100;
 const DEFAULT_INITIAL_CAPACITY: usize = 64;
 /// The lowest capacity a table can have.
 const MINIMUM_CAPACITY: usize = 8;
+/// The number of shards of the length counter.
+const COUNTER_SHARDS: usize = 16;
+/// The number of insertions into a shard of the length counter between checks of the load factor.
+///
+/// This must be a power of two, such that the checks stay in step when the shard wraps around.
+const LOAD_CHECK_INTERVAL: usize = 16;
+
+/// A shard of the length counter.
+///
+/// This is aligned to the cache line size, such that threads updating different shards won't
+/// compete for the same cache line.
+#[repr(align(64))]
+#[derive(Default)]
+struct CounterShard(AtomicUsize);
+
+/// The source of shard indexes for new threads.
+static NEXT_COUNTER_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The index of the length counter shard updated by this thread.
+    ///
+    /// This is assigned in a round-robin fashion, such that threads are spread out over the shards.
+    static COUNTER_SHARD: usize = NEXT_COUNTER_SHARD.fetch_add(1, ORDERING) % COUNTER_SHARDS;
+}
+
+/// A sharded counter.
+///
+/// Having every writer update the same atomic integer makes its cache line bounce back and forth
+/// between the cores, which serializes the writers. Instead, we split the counter into shards,
+/// each updated by a subset of the threads, and sum them when reading.
+///
+/// The shards wrap around, as a thread might decrement a shard that another thread incremented,
+/// but the sum doesn't, as long as the counter itself doesn't underflow.
+struct Counter {
+    /// The shards of the counter.
+    shards: [CounterShard; COUNTER_SHARDS],
+}
+
+impl Counter {
+    /// Create a new counter with some initial value.
+    fn new(init: usize) -> Counter {
+        let counter = Counter {
+            shards: Default::default(),
+        };
+        counter.shards[0].0.store(init, ORDERING);
+
+        counter
+    }
+
+    /// Get the shard updated by the current thread.
+    fn shard(&self) -> &AtomicUsize {
+        &self.shards[COUNTER_SHARD.with(|&x| x)].0
+    }
+
+    /// Increment the counter by `n`.
+    ///
+    /// This returns the new value of the shard updated by the current thread.
+    fn add(&self, n: usize) -> usize {
+        self.shard().fetch_add(n, ORDERING).wrapping_add(n)
+    }
+
+    /// Decrement the counter by `n`.
+    fn sub(&self, n: usize) {
+        self.shard().fetch_sub(n, ORDERING);
+    }
+
+    /// Get the value of the counter.
+    ///
+    /// This reads the shards one by one without any synchronization, so it is only exact if no
+    /// other thread updates the counter in the meantime.
+    fn get(&self) -> usize {
+        let sum = self.shards.iter().fold(0usize, |sum, x| sum.wrapping_add(x.0.load(ORDERING)));
+
+        // If a decrement was read, but not the increment preceding it, the sum can be
+        // (temporarily) negative. The counter is never actually negative, so we clamp it.
+        if (sum as isize) < 0 { 0 } else { sum }
+    }
+
+    /// Reset the counter to zero and return its old value.
+    ///
+    /// Like `get()`, this is only exact if no other thread updates the counter in the meantime.
+    fn take(&self) -> usize {
+        let sum = self.shards.iter().fold(0usize, |sum, x| sum.wrapping_add(x.0.swap(0, ORDERING)));
+
+        if (sum as isize) < 0 { 0 } else { sum }
+    }
+}
 
 /// A bucket state.
 ///
@@ -177,7 +264,7 @@ struct Table<K, V> {
     /// The bucket array.
     ///
     /// This vector stores the buckets. The order in which they're stored is far from arbitrary: A
-    /// KV pair `(key, val)`'s first priority location is at `self.hash(&key) % len`. If not
+    /// KV pair `(key, val)`'s first priority location is at `self.home(self.hash(&key))`. If not
     /// possible, the next bucket is used, and this process repeats until the bucket is free (or
     /// the end is reached, in which we simply wrap around).
     buckets: Vec<RwLock<Bucket<K, V>>>,
@@ -205,18 +292,40 @@ impl<K, V> Table<K, V> {
     fn with_capacity(cap: usize) -> Table<K, V> {
         Table::new(cmp::max(MINIMUM_CAPACITY, cap * LENGTH_MULTIPLIER))
     }
+
+    /// Get the first priority bucket of some hash.
+    ///
+    /// The hashes are mapped to the buckets in order (the lowest hashes to the first bucket and so
+    /// on), so, aside from the displacement by collisions, the entries are ordered by their hash.
+    /// Scan cursors rely on this (see `CHashMap::scan()`).
+    fn home(&self, hash: u64) -> usize {
+        // Scale the upper half of the hash to the number of buckets. Tables never get anywhere near
+        // 2^32 buckets, so this doesn't overflow.
+        (((hash >> 32) * self.buckets.len() as u64) >> 32) as usize
+    }
+
+    /// Get the lowest hash whose first priority bucket is some bucket or later.
+    ///
+    /// This is the inverse of `home()`. `None` is returned if `index` is past the last bucket.
+    fn first_hash(&self, index: usize) -> Option<u64> {
+        let len = self.buckets.len() as u64;
+        if index as u64 >= len {
+            return None;
+        }
+
+        // Round up, as `home()` rounds down.
+        Some(((((index as u64) << 32) + len - 1) / len) << 32)
+    }
 }
 
 impl<K: PartialEq + Hash, V> Table<K, V> {
     /// Hash some key through the internal hash function.
-    fn hash(&self, key: &K) -> usize {
+    fn hash(&self, key: &K) -> u64 {
         // Build the initial hash function state.
         let mut hasher = self.hash_builder.build_hasher();
         // Hash the key.
         key.hash(&mut hasher);
-        // Cast to `usize`. Since the hash function returns `u64`, this cast won't ever cause
-        // entropy less than the ouput space.
-        hasher.finish() as usize
+        hasher.finish()
     }
 
     /// Scan from the first priority of a key until a match is found.
@@ -227,14 +336,14 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
     /// The read guard from the RW-lock of the bucket is returned.
     fn scan<F>(&self, key: &K, matches: F) -> RwLockReadGuard<Bucket<K, V>>
     where F: Fn(&Bucket<K, V>) -> bool {
-        // Hash the key.
-        let hash = self.hash(key);
+        // Hash the key, and find its first priority bucket.
+        let home = self.home(self.hash(key));
 
         // Start at the first priority bucket, and then move upwards, searching for the matching
         // bucket.
         for i in 0..self.buckets.len() {
             // Get the lock of the `i`'th bucket after the first priority bucket (wrap on end).
-            let lock = self.buckets[(hash + i) % self.buckets.len()].read();
+            let lock = self.buckets[(home + i) % self.buckets.len()].read();
 
             // Check if it is a match.
             if matches(&lock) {
@@ -251,14 +360,14 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
     /// returned.
     fn scan_mut<F>(&self, key: &K, matches: F) -> RwLockWriteGuard<Bucket<K, V>>
     where F: Fn(&Bucket<K, V>) -> bool {
-        // Hash the key.
-        let hash = self.hash(key);
+        // Hash the key, and find its first priority bucket.
+        let home = self.home(self.hash(key));
 
         // Start at the first priority bucket, and then move upwards, searching for the matching
         // bucket.
         for i in 0..self.buckets.len() {
             // Get the lock of the `i`'th bucket after the first priority bucket (wrap on end).
-            let lock = self.buckets[(hash + i) % self.buckets.len()].write();
+            let lock = self.buckets[(home + i) % self.buckets.len()].write();
 
             // Check if it is a match.
             if matches(&lock) {
@@ -275,8 +384,8 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
     /// aliasing invariants of `&mut`.
     fn scan_mut_no_lock<F>(&mut self, key: &K, matches: F) -> &mut Bucket<K, V>
     where F: Fn(&Bucket<K, V>) -> bool {
-        // Hash the key.
-        let hash = self.hash(key);
+        // Hash the key, and find its first priority bucket.
+        let home = self.home(self.hash(key));
         // TODO: To tame the borrowchecker, we fetch this in advance.
         let len = self.buckets.len();
 
@@ -284,7 +393,7 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
         // bucket.
         for i in 0..self.buckets.len() {
             // TODO: hacky hacky
-            let idx = (hash + i) % len;
+            let idx = (home + i) % len;
 
             // Get the lock of the `i`'th bucket after the first priority bucket (wrap on end).
 
@@ -305,8 +414,8 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
     /// This scans for buckets with key `key`. If one is found, it will be returned. If none are
     /// found, it will return a free bucket in the same cluster.
     fn lookup_or_free(&self, key: &K) -> RwLockWriteGuard<Bucket<K, V>> {
-        // Hash the key.
-        let hash = self.hash(key);
+        // Hash the key, and find its first priority bucket.
+        let home = self.home(self.hash(key));
         // The encountered free bucket.
         let mut free = None;
 
@@ -314,7 +423,7 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
         // bucket.
         for i in 0..self.buckets.len() {
             // Get the lock of the `i`'th bucket after the first priority bucket (wrap on end).
-            let lock = self.buckets[(hash + i) % self.buckets.len()].write();
+            let lock = self.buckets[(home + i) % self.buckets.len()].write();
 
             if lock.key_matches(key) {
                 // We found a match.
@@ -384,12 +493,16 @@ impl<K: PartialEq + Hash, V> Table<K, V> {
 
     /// Fill the table with data from another table.
     ///
-    /// This is used to efficiently copy the data of `table` into `self`.
+    /// This is used to efficiently copy the data of `table` into `self`. The hash function of
+    /// `table` is kept, such that the entries keep their order (see `home()`), and scan cursors
+    /// obtained from `table` stay valid.
     ///
     /// # Important
     ///
     /// The table should be empty for this to work correctly/logically.
     fn fill(&mut self, table: Table<K, V>) {
+        self.hash_builder = table.hash_builder;
+
         // Run over all the buckets.
         for i in table.buckets {
             // We'll only transfer the bucket if it is a KV pair.
@@ -465,6 +578,41 @@ impl<K, V> Iterator for IntoIter<K, V> {
     }
 }
 
+/// A weakly-consistent iterator over the entries of some map.
+///
+/// See `CHashMap::iter_weak()`.
+pub struct IterWeak<'a, K: 'a, V: 'a> {
+    /// The map we're iterating over.
+    map: &'a CHashMap<K, V>,
+    /// The index of the next bucket to visit.
+    index: usize,
+}
+
+impl<'a, K: Clone, V: Clone> Iterator for IterWeak<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        // Acquire the read lock to the table. This is only held until we've found the next entry,
+        // so it won't block resizes for long.
+        let table = self.map.table.read();
+
+        // Go over the buckets from where we left off. If the table was resized in the meantime,
+        // the index refers to another bucket, which is fine, as we're only weakly consistent.
+        while let Some(bucket) = table.buckets.get(self.index) {
+            self.index += 1;
+
+            // Briefly lock the bucket for reading.
+            if let Bucket::Contains(ref key, ref val) = *bucket.read() {
+                // We clone the entry, so no locks are held when we hand it back.
+                return Some((key.clone(), val.clone()));
+            }
+        }
+
+        // We've exhausted all the buckets.
+        None
+    }
+}
+
 impl<K, V> IntoIterator for Table<K, V> {
     type Item = (K, V);
     type IntoIter = IntoIter<K, V>;
@@ -557,8 +705,41 @@ pub struct CHashMap<K, V> {
     table: RwLock<Table<K, V>>,
     /// The total number of KV pairs in the table.
     ///
-    /// This is used to calculate the load factor.
-    len: AtomicUsize,
+    /// This is used to calculate the load factor. It is only updated while holding (at least) the
+    /// read lock of the table, hence it is exact whenever the write lock is held.
+    len: Counter,
+}
+
+/// A cursor of a paginated scan.
+///
+/// See `CHashMap::scan()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cursor {
+    /// The lowest hash of the entries left to visit.
+    ///
+    /// The entries are visited in the order of their hash, so this is independent of the table,
+    /// and survives resizes. This is `None` if the scan is complete.
+    hash: Option<u64>,
+}
+
+impl Cursor {
+    /// Get the cursor starting a scan.
+    pub fn start() -> Cursor {
+        Cursor {
+            hash: Some(0),
+        }
+    }
+
+    /// Is the scan complete?
+    pub fn is_end(&self) -> bool {
+        self.hash.is_none()
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Cursor {
+        Cursor::start()
+    }
 }
 
 impl<K, V> CHashMap<K, V> {
@@ -569,7 +750,7 @@ impl<K, V> CHashMap<K, V> {
     pub fn with_capacity(cap: usize) -> CHashMap<K, V> {
         CHashMap {
             // Start at 0 KV pairs.
-            len: AtomicUsize::new(0),
+            len: Counter::new(0),
             // Make a new empty table. We will make sure that it is at least one.
             table: RwLock::new(Table::with_capacity(cap)),
         }
@@ -584,11 +765,13 @@ impl<K, V> CHashMap<K, V> {
 
     /// Get the number of entries in the hash table.
     ///
-    /// This is entirely atomic, and will not acquire any locks.
-    ///
-    /// This is guaranteed to reflect the number of entries _at this particular moment.
+    /// This will not acquire any locks, and thus never blocks or is blocked by writers. The
+    /// counter is sharded to avoid contention between the writers, and hence the result is
+    /// approximate under concurrency: If entries are inserted or removed while this runs, some of
+    /// the changes might be reflected, and others not. When there are no concurrent writers, the
+    /// result is exact.
     pub fn len(&self) -> usize {
-        self.len.load(ORDERING)
+        self.len.get()
     }
 
     /// Get the capacity of the hash table.
@@ -608,6 +791,8 @@ impl<K, V> CHashMap<K, V> {
     }
 
     /// Is the hash table empty?
+    ///
+    /// Like `len()`, this doesn't acquire any locks, and is approximate under concurrency.
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -623,8 +808,9 @@ impl<K, V> CHashMap<K, V> {
         CHashMap {
             // Replace the old table with an empty initial table.
             table: RwLock::new(mem::replace(&mut *lock, Table::new(DEFAULT_INITIAL_CAPACITY))),
-            // Replace the length with 0 and use the old length.
-            len: AtomicUsize::new(self.len.swap(0, ORDERING)),
+            // Replace the length with 0 and use the old length. This is exact, as we hold the
+            // write lock.
+            len: Counter::new(self.len.take()),
         }
     }
 
@@ -665,7 +851,7 @@ impl<K, V> CHashMap<K, V> {
                 // TODO: Can we somehow bundle these up to reduce the overhead of atomic
                 //       operations? Storing in a local variable and then subtracting causes
                 //       issues with consistency.
-                self.len.fetch_sub(1, ORDERING);
+                self.len.sub(1);
             }
         }
     }
@@ -692,6 +878,22 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
         }
     }
 
+    /// Apply a closure to the value of some key.
+    ///
+    /// This looks up `key` and returns `f` applied to its value, or `None` if it doesn't exist.
+    /// Unlike `get`, no guard is handed out: The lock is released before this returns, so it is
+    /// safe to access the map again afterwards, without risking a deadlock with a guard still
+    /// held. `f` itself must not access the map.
+    pub fn get_with<F, R>(&self, key: &K, f: F) -> Option<R>
+    where F: FnOnce(&V) -> R {
+        // Acquire the read lock and lookup in the table.
+        let lock = self.table.read();
+        let bucket = lock.lookup(key);
+
+        // Copy the needed data out, while the bucket is locked.
+        bucket.value_ref().ok().map(f)
+    }
+
     /// Get the (mutable) value of some key.
     ///
     /// This will lookup the entry of some key `key`, and acquire the writable lock. This means
@@ -795,6 +997,37 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
         ret
     }
 
+    /// Insert an entry, constructing the value lazily.
+    ///
+    /// If `key` doesn't exist, the result of closure `f` is inserted, and `true` is returned. If
+    /// it exists, the entry is left untouched, `f` is never called, and `false` is returned.
+    ///
+    /// `f` runs while the bucket is locked, so no other party can insert the key in the meantime
+    /// (i.e. `f` is called at most once per inserted entry). Consequently, `f` must not access the
+    /// map.
+    pub fn insert_with<F>(&self, key: K, f: F) -> bool
+    where F: FnOnce() -> V {
+        // Expand and lock the table. We need to expand to ensure the bounds on the load factor.
+        let lock = self.table.read();
+        {
+            // Lookup the key or a free bucket in the inner table.
+            let mut bucket = lock.lookup_or_free(&key);
+
+            if !bucket.is_free() {
+                // The entry exists already, so we leave it be.
+                return false;
+            }
+
+            // The bucket was free, so we construct the value and insert it.
+            *bucket = Bucket::Contains(key, f());
+        }
+
+        // A new entry was inserted, so naturally, we expand the table.
+        self.expand(lock);
+
+        true
+    }
+
     /// Insert or update.
     ///
     /// This looks up `key`. If it exists, the reference to its value is passed through closure
@@ -851,7 +1084,7 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
                     return;
                 } else {
                     // The old entry was removed, so we decrement the length of the map.
-                    self.len.fetch_sub(1, ORDERING);
+                    self.len.sub(1);
                     // TODO: We return as a hack to avoid the borrowchecker from thinking we moved a
                     //       referenced object. Namely, under this match arm the expansion after the match
                     //       statement won't ever be reached.
@@ -886,7 +1119,7 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
             //       madness, we do weird weird stuff.
             bucket => {
                 // Decrement the length of the map.
-                self.len.fetch_sub(1, ORDERING);
+                self.len.sub(1);
 
                 // Set the bucket to "removed" and return its value.
                 mem::replace(bucket, Bucket::Removed).value()
@@ -935,7 +1168,20 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
     /// This returns the read lock, such that the caller won't have to acquire it twice.
     fn expand(&self, lock: RwLockReadGuard<Table<K, V>>) {
         // Increment the length to take the new element into account.
-        let len = self.len.fetch_add(1, ORDERING) + 1;
+        let shard = self.len.add(1);
+
+        // Summing the shards of the length counter on every insertion would make them bounce
+        // between the cores anyway, so on large tables we only check the load factor every
+        // `LOAD_CHECK_INTERVAL` insertions into a shard. Every shard can then be less than
+        // `LOAD_CHECK_INTERVAL` insertions ahead of the last check, which the spare buckets of the
+        // table must be able to hold.
+        if shard & (LOAD_CHECK_INTERVAL - 1) != 0
+            && lock.buckets.len() * (MAX_LOAD_FACTOR_DENOM - MAX_LOAD_FACTOR_NUM)
+                >= COUNTER_SHARDS * LOAD_CHECK_INTERVAL * MAX_LOAD_FACTOR_DENOM {
+            return;
+        }
+
+        let len = self.len.get();
 
         // Extend if necessary. We multiply by some constant to adjust our load factor.
         if len * MAX_LOAD_FACTOR_DENOM > lock.buckets.len() * MAX_LOAD_FACTOR_NUM {
@@ -947,6 +1193,116 @@ impl<K: PartialEq + Hash, V> CHashMap<K, V> {
     }
 }
 
+impl<K: PartialEq + Hash, V: Clone> CHashMap<K, V> {
+    /// Get a clone of the value of some key.
+    ///
+    /// This acts like `get`, but clones the value and releases the lock before returning (see
+    /// `get_with`).
+    pub fn get_cloned(&self, key: &K) -> Option<V> {
+        self.get_with(key, V::clone)
+    }
+}
+
+impl<K: Clone, V: Clone> CHashMap<K, V> {
+    /// Iterate over the entries of the map, without blocking writers.
+    ///
+    /// This returns an iterator over (clones of) the entries of the map. No locks are held in
+    /// between the calls to `next()`, and every bucket is only locked briefly, so it is safe to
+    /// access (and modify) the map while iterating.
+    ///
+    /// The iteration is weakly consistent: Entries inserted or removed during the iteration might
+    /// or might not be visited, and if the table is resized during the iteration, some entries
+    /// might be missed or visited twice. An entry present during the whole iteration, while the
+    /// map isn't resized, is visited exactly once. This makes it suited for monitoring and
+    /// eviction scans, but not for anything requiring a consistent snapshot.
+    pub fn iter_weak<'a>(&'a self) -> IterWeak<'a, K, V> {
+        IterWeak {
+            map: self,
+            index: 0,
+        }
+    }
+}
+
+impl<K: PartialEq + Hash + Clone, V: Clone> CHashMap<K, V> {
+    /// Scan a page of the entries of the map.
+    ///
+    /// This returns (clones of) up to `limit` entries following `cursor`, along with the cursor to
+    /// continue from. Starting at `Cursor::start()`, and passing the returned cursor to the next
+    /// call until it `is_end()`, visits every entry of the map, so large maps can be scanned
+    /// incrementally (e.g. a page per scheduling quantum). Entries whose hashes collide are never
+    /// split across pages, so a page can exceed `limit` in the (unlikely) case of a collision.
+    ///
+    /// The entries are visited in the order of their hash, and only one bucket is locked at a
+    /// time. Like `iter_weak()`, the scan is weakly consistent, but the cursor refers to a hash
+    /// rather than a bucket, so it survives resizes of the table. Hence, every entry present
+    /// during the whole scan is visited exactly once, even if the map is resized in between.
+    pub fn scan(&self, cursor: Cursor, limit: usize) -> (Vec<(K, V)>, Cursor) {
+        let hash = match cursor.hash {
+            Some(hash) if limit > 0 => hash,
+            _ => return (Vec::new(), cursor),
+        };
+
+        // Acquire the read lock to the table. This prevents resizes during the call.
+        let table = self.table.read();
+        let len = table.buckets.len();
+        let first = table.home(hash);
+
+        // The entries following the cursor, along with their hashes.
+        let mut found = Vec::new();
+        // The entries displaced past the end of the table, which wrapped around to its start.
+        // These follow every other entry in hash order, so they only count once we wrap around.
+        let mut wrapped = Vec::new();
+        // The lowest hash of which the entries weren't necessarily found.
+        let mut bound = None;
+        // Collect the entries from the first priority bucket of the cursor and upwards. Entries
+        // with lower hashes were visited by earlier pages, and entries are only ever displaced
+        // upwards (wrapping on end), so every entry following the cursor is found this way.
+        for i in 0..len {
+            let index = (first + i) % len;
+
+            // Briefly lock the bucket for reading.
+            match *table.buckets[index].read() {
+                Bucket::Contains(ref key, ref val) => {
+                    let key_hash = table.hash(key);
+                    if key_hash < hash {
+                        // Visited by an earlier page.
+                    } else if table.home(key_hash) > index {
+                        wrapped.push((key_hash, key.clone(), val.clone()));
+                    } else {
+                        found.push((key_hash, key.clone(), val.clone()));
+                    }
+                },
+                // An empty bucket ends the cluster, so the entries with first priority buckets up
+                // to this one are found. We stop here if that makes a page, unless we wrapped
+                // around, in which case we're done anyway.
+                Bucket::Empty if first + i < len && found.len() >= limit => {
+                    bound = table.first_hash(index + 1);
+                    break;
+                },
+                Bucket::Empty if first + i >= len => break,
+                _ => (),
+            }
+        }
+        if bound.is_none() {
+            // We got to the end of the table.
+            found.extend(wrapped);
+        }
+
+        // Take the page from the lowest hashes, never cutting between colliding entries.
+        found.sort_by_key(|&(key_hash, _, _)| key_hash);
+        let mut end = cmp::min(limit, found.len());
+        while end > 0 && end < found.len() && found[end].0 == found[end - 1].0 {
+            end += 1;
+        }
+        let next = found.get(end).map(|&(key_hash, _, _)| key_hash).or(bound);
+        found.truncate(end);
+
+        (found.into_iter().map(|(_, key, val)| (key, val)).collect(), Cursor {
+            hash: next,
+        })
+    }
+}
+
 impl<K, V> Default for CHashMap<K, V> {
     fn default() -> CHashMap<K, V> {
         // Forward the call to `new`.
@@ -958,7 +1314,7 @@ impl<K: Clone, V: Clone> Clone for CHashMap<K, V> {
     fn clone(&self) -> CHashMap<K, V> {
         CHashMap {
             table: RwLock::new(self.table.read().clone()),
-            len: AtomicUsize::new(self.len.load(ORDERING)),
+            len: Counter::new(self.len.get()),
         }
     }
 }
@@ -996,7 +1352,7 @@ impl<K: PartialEq + Hash, V> iter::FromIterator<(K, V)> for CHashMap<K, V> {
 
         CHashMap {
             table: RwLock::new(table),
-            len: AtomicUsize::new(len),
+            len: Counter::new(len),
         }
     }
 }
@@ -11,7 +11,8 @@
 use std::thread;
 use std::cell::RefCell;
 use std::sync::Arc;
-use CHashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use {CHashMap, Cursor};
 
 #[test]
 fn spam_insert() {
@@ -198,6 +199,72 @@ fn simultanous_reserve() {
     assert_eq!(*m.get(&8).unwrap(), 16);
 }
 
+#[test]
+fn spam_len() {
+    let m = Arc::new(CHashMap::new());
+    let mut joins = Vec::new();
+
+    for t in 0..10 {
+        let m = m.clone();
+        joins.push(thread::spawn(move || {
+            for i in t * 1000..(t + 1) * 1000 {
+                m.insert(i, i);
+                // Other threads are writing concurrently, so this is only approximate, but never
+                // more than the number of entries ever inserted.
+                assert!(m.len() <= 10000);
+            }
+            for i in t * 1000..t * 1000 + 500 {
+                m.remove(&i);
+            }
+        }));
+    }
+
+    for j in joins.drain(..) {
+        j.join().unwrap();
+    }
+
+    // There are no concurrent writers anymore, so the length is exact.
+    assert_eq!(m.len(), 5000);
+    assert!(!m.is_empty());
+    m.clear();
+    assert_eq!(m.len(), 0);
+    assert!(m.is_empty());
+}
+
+#[test]
+fn iter_weak() {
+    let m = CHashMap::new();
+    for i in 0..100 {
+        m.insert(i, i * 2);
+    }
+
+    let mut entries: Vec<_> = m.iter_weak().collect();
+    entries.sort();
+    assert_eq!(entries, (0..100).map(|i| (i, i * 2)).collect::<Vec<_>>());
+}
+
+#[test]
+fn iter_weak_modify() {
+    let m = CHashMap::new();
+    for i in 0..100 {
+        m.insert(i, i);
+    }
+
+    // Modifying the map while iterating must not deadlock.
+    for (key, val) in m.iter_weak() {
+        if key % 2 == 0 {
+            m.remove(&key);
+        } else {
+            *m.get_mut(&key).unwrap() = val + 1;
+        }
+    }
+
+    assert_eq!(m.len(), 50);
+    for i in (1..100).step_by(2) {
+        assert_eq!(*m.get(&i).unwrap(), i + 1);
+    }
+}
+
 #[test]
 fn create_capacity_zero() {
     let m = CHashMap::with_capacity(0);
@@ -702,3 +769,172 @@ fn insert_into_map_full_of_free_buckets() {
         m.remove(&i);
     }
 }
+
+#[test]
+fn get_with() {
+    let m = CHashMap::new();
+    m.insert(1, vec![1, 2, 3]);
+
+    assert_eq!(m.get_with(&1, |v| v.len()), Some(3));
+    assert_eq!(m.get_with(&2, |v| v.len()), None);
+    assert_eq!(m.get_cloned(&1), Some(vec![1, 2, 3]));
+    assert_eq!(m.get_cloned(&2), None);
+}
+
+#[test]
+fn get_with_releases_lock() {
+    let m = CHashMap::new();
+    m.insert(1, 1);
+
+    // Writing to the entry right after the access would deadlock if the lock was still held.
+    let x = m.get_with(&1, |&v| v + 1).unwrap();
+    *m.get_mut(&1).unwrap() = x;
+    let y = m.get_cloned(&1).unwrap();
+    m.insert(1, y + 1);
+
+    assert_eq!(*m.get(&1).unwrap(), 3);
+}
+
+#[test]
+fn insert_with() {
+    let m = CHashMap::new();
+
+    assert!(m.insert_with(1, || 1));
+    assert!(!m.insert_with(1, || panic!("constructed a value for an existing key")));
+    assert_eq!(*m.get(&1).unwrap(), 1);
+    assert_eq!(m.len(), 1);
+
+    m.remove(&1);
+    assert!(m.insert_with(1, || 2));
+    assert_eq!(*m.get(&1).unwrap(), 2);
+}
+
+#[test]
+fn spam_insert_with() {
+    let m = Arc::new(CHashMap::new());
+    let constructed = Arc::new(AtomicUsize::new(0));
+    let mut joins = Vec::new();
+
+    for _ in 0..10 {
+        let m = m.clone();
+        let constructed = constructed.clone();
+        joins.push(thread::spawn(move || {
+            for i in 0..100 {
+                m.insert_with(i, || {
+                    constructed.fetch_add(1, Ordering::SeqCst);
+                    i
+                });
+            }
+        }));
+    }
+
+    for j in joins.drain(..) {
+        j.join().unwrap();
+    }
+
+    // Every key was constructed exactly once.
+    assert_eq!(constructed.load(Ordering::SeqCst), 100);
+    assert_eq!(m.len(), 100);
+}
+
+#[test]
+fn scan() {
+    let m = CHashMap::new();
+    for i in 0..1000 {
+        m.insert(i, i * 2);
+    }
+
+    let mut entries = Vec::new();
+    let mut cursor = Cursor::start();
+    while !cursor.is_end() {
+        let (page, next) = m.scan(cursor, 64);
+        assert!(page.len() <= 64);
+        entries.extend(page);
+        cursor = next;
+    }
+
+    entries.sort();
+    assert_eq!(entries, (0..1000).map(|i| (i, i * 2)).collect::<Vec<_>>());
+
+    // Scanning past the end gives nothing.
+    assert!(m.scan(cursor, 64).0.is_empty());
+}
+
+#[test]
+fn scan_resize() {
+    let m = CHashMap::new();
+    for i in 0..100 {
+        m.insert(i, i);
+    }
+
+    let (mut entries, mut cursor) = m.scan(Cursor::start(), 10);
+    // Resize the table in the middle of the scan, both up and down.
+    m.reserve(10000);
+    let (page, next) = m.scan(cursor, 10);
+    entries.extend(page);
+    cursor = next;
+    m.shrink_to_fit();
+    while !cursor.is_end() {
+        let (page, next) = m.scan(cursor, 10);
+        entries.extend(page);
+        cursor = next;
+    }
+
+    // Every entry is visited exactly once.
+    entries.sort();
+    assert_eq!(entries, (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+}
+
+#[test]
+fn scan_concurrent_resize() {
+    let m = Arc::new(CHashMap::new());
+    for i in 0..1000 {
+        m.insert(i, i);
+    }
+
+    let m2 = m.clone();
+    let j = thread::spawn(move || {
+        for i in 1000..5000 {
+            m2.insert(i, i);
+        }
+    });
+
+    let mut entries = Vec::new();
+    let mut cursor = Cursor::start();
+    while !cursor.is_end() {
+        let (page, next) = m.scan(cursor, 16);
+        entries.extend(page.into_iter().map(|(key, _)| key).filter(|&key| key < 1000));
+        cursor = next;
+    }
+    j.join().unwrap();
+
+    // The entries present during the whole scan are all visited exactly once.
+    entries.sort();
+    assert_eq!(entries, (0..1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn concurrent_insert_load_factor() {
+    let m = Arc::new(CHashMap::with_capacity(4096));
+    let mut j = Vec::new();
+
+    for t in 0..8 {
+        let m = m.clone();
+        j.push(thread::spawn(move || {
+            for i in 0..10000 {
+                m.insert(t * 10000 + i, i);
+            }
+        }));
+    }
+    for i in j {
+        i.join().unwrap();
+    }
+
+    // The load factor isn't checked on every insertion, but the table never fills up.
+    assert_eq!(m.len(), 80000);
+    assert!(m.buckets() > m.len());
+    for i in 0..80000 {
+        assert_eq!(*m.get(&i).unwrap(), i % 10000);
+    }
+}
+
@@ -0,0 +1,157 @@
+//! Custom allocators.
+//!
+//! The hazards are allocated on the heap and handed out as raw pointers, which are scanned and
+//! eventually deallocated by the garbage collector. By default, they live on the global
+//! allocator. An embedder can place them elsewhere (e.g. in an arena or a pinned memory region)
+//! by implementing `Allocator`, and either install it for the default domain (see
+//! `set_default()`) or give it to a particular domain or registry (see `Domain::with_allocator()`
+//! and `Registry::with_allocator()`).
+//!
+//! Every hazard remembers the allocator it was allocated with, and is deallocated through it, so
+//! the allocator must outlive the hazards (hence the `'static`).
+//!
+//! The garbage is destroyed by its destructor, so garbage allocated elsewhere should be added with
+//! a destructor returning it to its allocator (e.g. through `add_garbage()`). The buffers queuing
+//! the garbage are always allocated on the global allocator.
+
+#[cfg(not(feature = "std"))]
+use alloc::alloc as heap;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use std::alloc as heap;
+use std::alloc::Layout;
+use std::ptr;
+
+use atomics::{self as atomic, AtomicPtr};
+
+/// An allocator of the objects of the reclamation system.
+///
+/// # Safety
+///
+/// `alloc()` must return either null or a pointer to a block of memory fitting `layout`, which
+/// stays valid until it is passed to `dealloc()`.
+pub unsafe trait Allocator: Send + Sync {
+    /// Allocate a block of memory fitting `layout`.
+    ///
+    /// `layout` never has size zero. Null is returned if the allocation failed.
+    fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// Deallocate a block of memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator with the same `layout`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// The global allocator.
+///
+/// This is the allocator used, unless another one is installed (see `set_default()`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { heap::alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        heap::dealloc(ptr, layout)
+    }
+}
+
+/// The installed default allocator.
+///
+/// This is null if no allocator was installed. Otherwise, it points to a leaked reference to the
+/// allocator (as trait objects cannot be stored in an atomic).
+static DEFAULT: AtomicPtr<&'static Allocator> = AtomicPtr::new(ptr::null_mut());
+
+/// Install the default allocator.
+///
+/// The default allocator is used by the default domain (and hence the thread-local caches), and
+/// by domains and registries not given an allocator of their own. It can only be installed once,
+/// and this returns `Err(())` if some allocator is already installed. The objects allocated before
+/// it is installed stay on the allocator they were allocated with.
+pub fn set_default(allocator: &'static Allocator) -> Result<(), ()> {
+    let new = Box::into_raw(Box::new(allocator));
+
+    if DEFAULT.compare_and_swap(ptr::null_mut(), new, atomic::Ordering::AcqRel).is_null() {
+        Ok(())
+    } else {
+        // Another allocator was installed, so we must free our reference.
+        drop(unsafe { Box::from_raw(new) });
+        Err(())
+    }
+}
+
+/// Get the default allocator.
+///
+/// This is the installed allocator (see `set_default()`), or the global allocator if none is.
+pub fn default() -> &'static Allocator {
+    let allocator = DEFAULT.load(atomic::Ordering::Acquire);
+    if allocator.is_null() {
+        &Global
+    } else {
+        // The reference is never freed once installed.
+        unsafe { *allocator }
+    }
+}
+
+/// Move a value onto memory from an allocator.
+///
+/// This aborts through `handle_alloc_error()` if the allocation fails.
+pub fn new<T>(allocator: &Allocator, val: T) -> *mut T {
+    let layout = Layout::new::<T>();
+    debug_assert!(layout.size() != 0, "Allocating a zero-sized value.");
+
+    let ptr = allocator.alloc(layout) as *mut T;
+    if ptr.is_null() {
+        heap::handle_alloc_error(layout);
+    }
+
+    unsafe { ptr::write(ptr, val); }
+    ptr
+}
+
+/// Drop and deallocate a value allocated by `new()`.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `new()` with the same allocator, and must not be used
+/// afterwards.
+pub unsafe fn free<T>(allocator: &Allocator, ptr: *mut T) {
+    ptr::drop_in_place(ptr);
+    allocator.dealloc(ptr as *mut u8, Layout::new::<T>());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// An allocator counting the live allocations.
+    struct Counting(AtomicUsize);
+
+    unsafe impl Allocator for Counting {
+        fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            Global.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn new_free() {
+        let a = Counting(AtomicUsize::new(0));
+        let ptr = new(&a, 42u64);
+        assert_eq!(unsafe { *ptr }, 42);
+        assert_eq!(a.0.load(atomic::Ordering::Relaxed), 1);
+        unsafe { free(&a, ptr); }
+        assert_eq!(a.0.load(atomic::Ordering::Relaxed), 0);
+    }
+}
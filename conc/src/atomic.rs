@@ -1,11 +1,25 @@
 //! Concurrent, atomic options.
-
-use std::{mem, ptr};
-use std::sync::atomic::{self, AtomicPtr};
+//!
+//! # Tagged pointers
+//!
+//! Since the pointee is aligned, the lowest bits of the pointer are always zero, and can be used
+//! to store a small tag alongside the pointer (e.g. to mark nodes as deleted in a Harris list).
+//! The number of bits available is given by the alignment of `T` (see `Atomic::tag_mask()`).
+//!
+//! The tag is stripped before the pointer is protected or queued as garbage, such that hazards
+//! (and the garbage collector) only ever see the real address of the object, which can't collide
+//! with the sentinel values of the hazards. The tag is read and written through the `_tagged` and
+//! `_tag` methods, while the other methods treat the pointer as having tag zero: Loads ignore
+//! the tag, and compare-and-swaps fail if the tag is set.
+
+use std::{fmt, mem, ptr};
+use atomics::{self as atomic, AtomicPtr};
 use std::marker::PhantomData;
 
 use add_garbage_box;
-use guard::Guard;
+use domain::{Domain, Global, Select};
+use guard::{Guard, SendGuard};
+use provenance;
 
 /// A concurrently accessible and updatable optional pointer.
 ///
@@ -16,7 +30,10 @@ use guard::Guard;
 /// or any variant thereof.
 ///
 /// It conveniently wraps this crate's API in a seamless manner.
-pub struct Atomic<T> {
+///
+/// `D` is the domain the values are protected and reclaimed in (see `domain::Select`). The default
+/// is the global state, which takes no space.
+pub struct Atomic<T, D: Select = Global> {
     /// The inner atomic pointer.
     inner: AtomicPtr<T>,
     /// Make the `Sync` and `Send` (and other OIBITs) transitive.
@@ -30,6 +47,8 @@ pub struct Atomic<T> {
     ///
     /// `Send` is transitive for future-proofing.
     _marker: PhantomData<T>,
+    /// The domain the values are protected and reclaimed in.
+    domain: D,
 }
 
 impl<T> Atomic<T> {
@@ -39,6 +58,62 @@ impl<T> Atomic<T> {
             // Convert the box to a raw pointer.
             inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
             _marker: PhantomData,
+            domain: Global,
+        }
+    }
+}
+
+impl<T> Atomic<T, &'static Domain> {
+    /// Create a new `Atomic<T>` bound to some domain.
+    ///
+    /// The guards returned by this `Atomic<T>` are of domain `domain`, and its old values are
+    /// queued as garbage in `domain` (see `conc::domain`).
+    pub fn new_in(init: Option<Box<T>>, domain: &'static Domain) -> Atomic<T, &'static Domain> {
+        Atomic {
+            inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
+            _marker: PhantomData,
+            domain: domain,
+        }
+    }
+}
+
+impl<T, D: Select> Atomic<T, D> {
+    /// Create a guard in the domain of this `Atomic<T>`.
+    fn guard<F>(&self, ptr: F) -> Option<Guard<T>>
+    where F: FnOnce() -> Option<&'static T> {
+        match self.domain.domain() {
+            Some(domain) => Guard::maybe_new_in(domain, ptr),
+            None => Guard::maybe_new(ptr),
+        }
+    }
+
+    /// Protect the current value through the publish/validate protocol.
+    ///
+    /// See `Guard::protect_with()`.
+    fn protect(&self, ordering: atomic::Ordering) -> Option<Guard<T>> {
+        let load = || untag(self.load_raw(ordering)) as *const T;
+
+        // The values are only ever destroyed through the garbage of the domain of `self`.
+        unsafe {
+            match self.domain.domain() {
+                Some(domain) => Guard::protect_with_in(domain, load),
+                None => Guard::protect_with(load),
+            }
+        }
+    }
+
+    /// Queue the deletion of some pointer in the domain of this `Atomic<T>`.
+    ///
+    /// The tag of `ptr` (if any) is stripped.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `add_garbage_box`.
+    unsafe fn retire(&self, ptr: *const T) {
+        let ptr = untag(ptr as *mut T) as *const T;
+        match self.domain.domain() {
+            Some(domain) => domain.add_garbage_box(ptr),
+            None => add_garbage_box(ptr),
         }
     }
 
@@ -53,8 +128,8 @@ impl<T> Atomic<T> {
     /// This is unsafe as you can easily invalidate the invariants. When using, you must ensure
     /// that, if you drop, there are no existing readers/hazards of the `Atomic` and that, if you
     /// mutate, the value, you change to is valid.
-    pub unsafe fn get_inner(&self) -> &AtomicPtr<T> {
-        &self.inner
+    pub unsafe fn get_inner(&self) -> &::std::sync::atomic::AtomicPtr<T> {
+        atomic::as_std(&self.inner)
     }
 
     /// Get an immutable reference to the underlying `std::sync::AtomicPtr`
@@ -68,8 +143,53 @@ impl<T> Atomic<T> {
     /// This is unsafe as you can easily invalidate the invariants. When using, you must ensure
     /// that, if you drop, there are no existing readers/hazards of the `Atomic` and that, if you
     /// mutate, the value, you change to is valid.
-    pub unsafe fn get_inner_mut(&mut self) -> &mut AtomicPtr<T> {
-        &mut self.inner
+    pub unsafe fn get_inner_mut(&mut self) -> &mut ::std::sync::atomic::AtomicPtr<T> {
+        atomic::as_std_mut(&mut self.inner)
+    }
+
+    /// Get the mask of the bits available for tags.
+    ///
+    /// Tags are stored in the lower bits of the pointer, which are zero due to the alignment of
+    /// `T`, so this is the alignment of `T` minus one. For example, this is `0b111` for
+    /// `Atomic<u64>`, and zero for `Atomic<u8>`, which can't be tagged.
+    pub fn tag_mask() -> usize {
+        tag_mask::<T>()
+    }
+
+    /// Get a mutable reference to the current value.
+    ///
+    /// This bypasses the guards and atomic operations entirely, as the mutable reference to `self`
+    /// guarantees that no other thread can access the `Atomic<T>` meanwhile. `None` corresponds to
+    /// the null pointer.
+    ///
+    /// # Safety
+    ///
+    /// Exclusive access to the `Atomic<T>` does not imply exclusive access to its value, as guards
+    /// of it (e.g. obtained through `load()` earlier) outlive the borrow of the `Atomic<T>`. The
+    /// caller must ensure that no guards of the current value exist.
+    pub unsafe fn get_mut(&mut self) -> Option<&mut T> {
+        untag(*self.inner.get_mut()).as_mut()
+    }
+
+    /// Take the current value out of the `Atomic<T>`.
+    ///
+    /// This consumes `self` and returns its value as a box (or `None` if it is null), without
+    /// queuing it as garbage: The value is handed over to the caller, rather than destroyed
+    /// eventually (as it would be, if `self` were dropped).
+    ///
+    /// # Safety
+    ///
+    /// As with `get_mut()`, the caller must ensure that no guards of the current value exist, as
+    /// the box can be dropped right away.
+    pub unsafe fn into_inner(mut self) -> Option<Box<T>> {
+        // Replace the pointer by null, such that the destructor of `self` won't queue it.
+        let ptr = untag(mem::replace(self.inner.get_mut(), ptr::null_mut()));
+
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Box::from_raw(ptr))
+        }
     }
 
     /// Load the container's current pointer.
@@ -103,11 +223,78 @@ impl<T> Atomic<T> {
     /// documentation for more information.
     pub fn load(&self, ordering: atomic::Ordering) -> Option<Guard<T>> {
         // Load the inner and wrap it in a guard.
-        Guard::maybe_new(|| unsafe {
-            self.load_raw(ordering).as_ref()
+        self.guard(|| unsafe {
+            untag(self.load_raw(ordering)).as_ref()
         })
     }
 
+    /// Get a reference to the current content of the option along with its tag.
+    ///
+    /// This acts like `load`, but also returns the tag of the pointer (see the module
+    /// documentation).
+    pub fn load_tagged(&self, ordering: atomic::Ordering) -> (Option<Guard<T>>, usize) {
+        let mut tag = 0;
+        let guard = self.guard(|| unsafe {
+            let ptr = self.load_raw(ordering);
+            tag = tag_of(ptr);
+            untag(ptr).as_ref()
+        });
+
+        (guard, tag)
+    }
+
+    /// Get a reference to the current content of the option, which can be held across awaits.
+    ///
+    /// This acts like `load`, but returns a `SendGuard<T>`, so a future holding it can be migrated
+    /// between the worker threads of an executor. The value is loaded and protected synchronously,
+    /// so no blocked hazard is ever held across a suspension point, and it is always protected by
+    /// a hazard, as a pin held while the task is suspended would hold up the destruction of all the
+    /// garbage (see `SendGuard::maybe_new()`).
+    pub fn load_async(&self, ordering: atomic::Ordering) -> Option<SendGuard<T>>
+    where T: Sync {
+        let ptr = || unsafe {
+            untag(self.load_raw(ordering)).as_ref()
+        };
+
+        match self.domain.domain() {
+            Some(domain) => SendGuard::maybe_new_in(domain, ptr),
+            None => SendGuard::maybe_new(ptr),
+        }
+    }
+
+    /// Get a weak reference to the current content of the option.
+    ///
+    /// This only records the pointer without protecting it, so it doesn't hold up the destruction
+    /// of the value. It can be upgraded to a guard later, as long as the value is still current
+    /// (see `WeakGuard::upgrade()`). `None` is returned for the null pointer.
+    pub fn load_weak(&self, ordering: atomic::Ordering) -> Option<WeakGuard<T, D>> {
+        let ptr = untag(self.load_raw(ordering));
+        if ptr.is_null() {
+            None
+        } else {
+            Some(WeakGuard {
+                atomic: self,
+                ptr: ptr,
+            })
+        }
+    }
+
+    /// Store a new value with some tag in the option.
+    ///
+    /// This acts like `store`, but tags the new pointer with `tag`.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `tag` has bits outside of `tag_mask()`.
+    pub fn store_tagged(&self, new: Option<Box<T>>, tag: usize, ordering: atomic::Ordering) {
+        let new = with_tag(new.map_or(ptr::null_mut(), Box::into_raw), tag);
+        let ptr = self.inner.swap(new, ordering);
+        if !untag(ptr).is_null() {
+            // Queue the deletion of the content.
+            unsafe { self.retire(ptr); }
+        }
+    }
+
     /// Store a new value in the option.
     ///
     /// The old value of `self` will eventually be dropped, at some point after all the guarding
@@ -121,9 +308,9 @@ impl<T> Atomic<T> {
         let new = new.map_or(ptr::null_mut(), Box::into_raw);
         // Swap the contents with the new value.
         let ptr = self.inner.swap(new, ordering);
-        if !ptr.is_null() {
+        if !untag(ptr).is_null() {
             // Queue the deletion of the content.
-            unsafe { add_garbage_box(ptr); }
+            unsafe { self.retire(ptr); }
         }
     }
 
@@ -144,15 +331,19 @@ impl<T> Atomic<T> {
         // TODO: Use coercions.
         let new_ptr = new.map_or(ptr::null_mut(), Box::into_raw);
 
+        // The old pointer. This is retired rather than the pointer of the guard, as the latter is
+        // derived from a shared reference, which must not be used to deallocate.
+        let mut old = ptr::null_mut();
         // Create the guard. It is very important that this is done before the garbage is added,
         // otherwise we might introduce premature frees.
-        Guard::maybe_new(|| unsafe {
+        self.guard(|| unsafe {
             // Swap the atomic pointer with the new one.
-            self.inner.swap(new_ptr, ordering).as_ref()
+            old = self.inner.swap(new_ptr, ordering);
+            untag(old).as_ref()
         }).map(|guard| {
             // Since the pointer is now unreachable from the option, it can safely be queued for
             // deletion.
-            unsafe { add_garbage_box(&*guard); }
+            unsafe { self.retire(old); }
 
             guard
         })
@@ -180,12 +371,14 @@ impl<T> Atomic<T> {
     -> Result<(), ()> {
 
         // Compare-and-swap the value and check if it was successful.
-        if self.inner.compare_and_swap(old as *mut T, new, ordering) as *const T == old {
+        let actual = self.inner.compare_and_swap(old as *mut T, new, ordering);
+        if actual as *const T == old {
             // It was. `self` is now `new`.
 
-            // Queue the deletion of now-unreachable `old` (unless it's `None`).
+            // Queue the deletion of now-unreachable `old` (unless it's `None`). The pointer read
+            // from `self` is retired, as `old` might be derived from a shared reference.
             if !old.is_null() {
-                add_garbage_box(old);
+                self.retire(actual);
             }
 
             Ok(())
@@ -204,25 +397,24 @@ impl<T> Atomic<T> {
     /// documentation for more information.
     pub fn compare_and_store(&self, old: Option<*const T>, new: Option<Box<T>>, ordering: atomic::Ordering)
     -> Result<(), Option<Box<T>>> {
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+
         // Run the CAS.
         if unsafe {
             self.compare_and_store_raw(
                 // Convert the input to raw pointers.
                 old.unwrap_or(ptr::null()),
-                // TODO: Does this break NOALIAS if it is enabled on `Box` in the future in `rustc`
-                //       (see also `compare_and_swap`)?
-                new.as_ref().map_or(ptr::null_mut(), |x| &**x as *const T as *mut T),
+                new,
                 ordering,
             )
         }.is_ok() {
-            // `new` is now in `self`. We must thus ensure that the destructor isn't called, as
-            // that might cause use-after-free.
-            mem::forget(new);
-
+            // `new` is now in `self`.
             Ok(())
         } else {
             // Hand back the box.
-            Err(new)
+            Err(unsafe { into_box(new) })
         }
     }
 
@@ -251,23 +443,22 @@ impl<T> Atomic<T> {
         new: *mut T,
         ordering: atomic::Ordering
     ) -> Result<Option<Guard<T>>, Option<Guard<T>>> {
+        // The actual value, including its tag.
+        let mut actual = ptr::null_mut();
         // Create the guard beforehand to avoid premature frees.
-        let guard = Guard::maybe_new(|| {
+        let guard = self.guard(|| {
             // The guard is active, so we can do the CAS now.
-            self.inner.compare_and_swap(old as *mut T, new, ordering).as_ref()
+            actual = self.inner.compare_and_swap(old as *mut T, new, ordering);
+            untag(actual).as_ref()
         });
 
-        // Convert the guard to a raw pointer.
-        // TODO: Use coercions.
-        let guard_ptr = guard.as_ref().map_or(ptr::null_mut(), |x| &**x as *const T as *mut T);
-
-        // Check if the CAS was successful.
-        if guard_ptr as *const T == old {
+        // Check if the CAS was successful. The tag is compared too, as it is part of the value.
+        if actual as *const T == old {
             // It was. `self` is now `new`.
 
             // Queue the deletion of now-unreachable `old` (unless it's `None`).
             if !old.is_null() {
-                add_garbage_box(old);
+                self.retire(actual);
             }
 
             Ok(guard)
@@ -292,26 +483,534 @@ impl<T> Atomic<T> {
     /// `compare_and_store`.
     pub fn compare_and_swap(&self, old: Option<*const T>, new: Option<Box<T>>, ordering: atomic::Ordering)
     -> Result<Option<Guard<T>>, (Option<Guard<T>>, Option<Box<T>>)> {
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+
         // Run the CAS.
         match unsafe {
             self.compare_and_swap_raw(
                 // Convert the input to raw pointers.
                 old.unwrap_or(ptr::null()),
-                new.as_ref().map_or(ptr::null_mut(), |x| &**x as *const T as *mut T),
+                new,
                 ordering,
             )
         } {
-            Ok(guard) => {
-                // `new` is now in `self`. We must thus ensure that the destructor isn't called, as
-                // that might cause use-after-free.
-                mem::forget(new);
+            // `new` is now in `self`.
+            Ok(guard) => Ok(guard),
+            // Hand back the box too.
+            Err(guard) => Err((guard, unsafe { into_box(new) })),
+        }
+    }
+
+    /// Store a (raw) pointer if the current matches the specified pointer, allowing spurious
+    /// failures.
+    ///
+    /// This acts like `compare_and_store_raw`, except that it might fail (return `Err(())`) even
+    /// if `self` matches `old`. In return, it compiles to a single LL/SC pair on platforms like
+    /// ARM, making it the better choice inside retry loops.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `compare_and_store_raw`.
+    ///
+    /// # Memory leak
+    ///
+    /// If it fails (returns `Err`), this function won't drop `new` at any point. The handling of
+    /// its destructor lies solely on the caller of the function.
+    pub unsafe fn compare_and_store_weak_raw(&self, old: *const T, new: *mut T, ordering: atomic::Ordering)
+    -> Result<(), ()> {
+        // Compare-and-swap the value and check if it was successful.
+        if let Ok(actual) = self.inner.compare_exchange_weak(old as *mut T, new, ordering,
+                                                             failure_ordering(ordering)) {
+            // It was. `self` is now `new`.
+
+            // Queue the deletion of now-unreachable `old` (unless it's `None`).
+            if !old.is_null() {
+                self.retire(actual);
+            }
+
+            Ok(())
+        } else {
+            // It failed (possibly spuriously).
+            Err(())
+        }
+    }
+
+    /// Store a pointer if the current matches the specified pointer, allowing spurious failures.
+    ///
+    /// This acts like `compare_and_store`, except that it might fail (return `Err(new)`) even if
+    /// `self` matches `old`. See `compare_and_store_weak_raw` for details.
+    pub fn compare_and_store_weak(&self, old: Option<*const T>, new: Option<Box<T>>, ordering: atomic::Ordering)
+    -> Result<(), Option<Box<T>>> {
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+
+        // Run the CAS.
+        if unsafe {
+            self.compare_and_store_weak_raw(
+                // Convert the input to raw pointers.
+                old.unwrap_or(ptr::null()),
+                new,
+                ordering,
+            )
+        }.is_ok() {
+            // `new` is now in `self`.
+            Ok(())
+        } else {
+            // Hand back the box.
+            Err(unsafe { into_box(new) })
+        }
+    }
+
+    /// Swap a (raw) pointer if it matches the specified pointer, allowing spurious failures.
+    ///
+    /// This acts like `compare_and_swap_raw`, except that it might fail even if `self` matches
+    /// `old`, in which case the guard wrapped in `Err` might protect `old` itself. See
+    /// `compare_and_store_weak_raw` for details.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `compare_and_swap_raw`.
+    ///
+    /// # Memory leak
+    ///
+    /// If it fails (returns `Err`), this function won't drop `new` at any point. The handling of
+    /// its destructor lies solely on the caller of the function.
+    pub unsafe fn compare_and_swap_weak_raw(
+        &self,
+        old: *const T,
+        new: *mut T,
+        ordering: atomic::Ordering
+    ) -> Result<Option<Guard<T>>, Option<Guard<T>>> {
+        // As the CAS can fail spuriously, we cannot compare the pointers to figure out if it
+        // succeeded, so we store the result.
+        let mut success = false;
+        // The actual value.
+        let mut actual = ptr::null_mut();
+        // Create the guard beforehand to avoid premature frees.
+        let guard = self.guard(|| {
+            // The guard is active, so we can do the CAS now.
+            let res = self.inner.compare_exchange_weak(old as *mut T, new, ordering,
+                                                       failure_ordering(ordering));
+            success = res.is_ok();
+
+            match res {
+                Ok(ptr) | Err(ptr) => {
+                    actual = ptr;
+                    untag(ptr).as_ref()
+                },
+            }
+        });
+
+        if success {
+            // It was. `self` is now `new`.
+
+            // Queue the deletion of now-unreachable `old` (unless it's `None`).
+            if !old.is_null() {
+                self.retire(actual);
+            }
+
+            Ok(guard)
+        } else {
+            Err(guard)
+        }
+    }
+
+    /// Swap a pointer if it matches the specified pointer, allowing spurious failures.
+    ///
+    /// This acts like `compare_and_swap`, except that it might fail even if `self` matches `old`.
+    /// See `compare_and_store_weak_raw` for details.
+    pub fn compare_and_swap_weak(&self, old: Option<*const T>, new: Option<Box<T>>, ordering: atomic::Ordering)
+    -> Result<Option<Guard<T>>, (Option<Guard<T>>, Option<Box<T>>)> {
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+
+        // Run the CAS.
+        match unsafe {
+            self.compare_and_swap_weak_raw(
+                // Convert the input to raw pointers.
+                old.unwrap_or(ptr::null()),
+                new,
+                ordering,
+            )
+        } {
+            // `new` is now in `self`.
+            Ok(guard) => Ok(guard),
+            // Hand back the box too.
+            Err(guard) => Err((guard, unsafe { into_box(new) })),
+        }
+    }
+
+    /// Swap a (raw) pointer if it matches the specified pointer, with separate orderings.
+    ///
+    /// This acts like `compare_and_swap_raw`, but like `AtomicPtr::compare_exchange`, it takes an
+    /// ordering for the success case (`success`) and one for the failure case (`failure`), which
+    /// cannot be `Release` or `AcqRel`, nor stronger than `success`.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `compare_and_swap_raw`.
+    ///
+    /// # Memory leak
+    ///
+    /// If it fails (returns `Err`), this function won't drop `new` at any point. The handling of
+    /// its destructor lies solely on the caller of the function.
+    pub unsafe fn compare_exchange_raw(
+        &self,
+        old: *const T,
+        new: *mut T,
+        success: atomic::Ordering,
+        failure: atomic::Ordering,
+    ) -> Result<Option<Guard<T>>, Option<Guard<T>>> {
+        let mut succeeded = false;
+        // The actual value.
+        let mut actual = ptr::null_mut();
+        // Create the guard beforehand to avoid premature frees.
+        let guard = self.guard(|| {
+            // The guard is active, so we can do the CAS now.
+            let res = self.inner.compare_exchange(old as *mut T, new, success, failure);
+            succeeded = res.is_ok();
+
+            match res {
+                Ok(ptr) | Err(ptr) => {
+                    actual = ptr;
+                    untag(ptr).as_ref()
+                },
+            }
+        });
+
+        if succeeded {
+            // It was. `self` is now `new`.
+
+            // Queue the deletion of now-unreachable `old` (unless it's `None`).
+            if !old.is_null() {
+                self.retire(actual);
+            }
+
+            Ok(guard)
+        } else {
+            Err(guard)
+        }
+    }
+
+    /// Swap a pointer if it matches the specified pointer, with separate orderings.
+    ///
+    /// This acts like `compare_and_swap`, but takes an ordering for the success and for the
+    /// failure case. See `compare_exchange_raw`.
+    pub fn compare_exchange(
+        &self,
+        old: Option<*const T>,
+        new: Option<Box<T>>,
+        success: atomic::Ordering,
+        failure: atomic::Ordering,
+    ) -> Result<Option<Guard<T>>, (Option<Guard<T>>, Option<Box<T>>)> {
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+
+        // Run the CAS.
+        match unsafe {
+            self.compare_exchange_raw(
+                // Convert the input to raw pointers.
+                old.unwrap_or(ptr::null()),
+                new,
+                success,
+                failure,
+            )
+        } {
+            // `new` is now in `self`.
+            Ok(guard) => Ok(guard),
+            // Hand back the box too.
+            Err(guard) => Err((guard, unsafe { into_box(new) })),
+        }
+    }
+
+    /// Update the value through a closure, retrying until it succeeds.
+    ///
+    /// This loads the current value, computes its replacement through `f`, and stores it, unless
+    /// the value was replaced meanwhile, in which case `f` is called again with the new value.
+    /// When the update succeeds, the old value is queued for destruction and its guard is
+    /// returned. The tag of the pointer (if any) is kept.
+    ///
+    /// If `f` returns `None`, the update is aborted, and the guard of the current value is returned
+    /// in `Err`. If `self` is `None`, `Err(None)` is returned without calling `f`.
+    ///
+    /// `f` might be called several times, so it should be free of side effects. Like
+    /// `AtomicPtr::fetch_update`, this takes an ordering for the store (`set_order`) and one for
+    /// the loads (`fetch_order`).
+    pub fn fetch_update<F>(&self, set_order: atomic::Ordering, fetch_order: atomic::Ordering, mut f: F)
+    -> Result<Guard<T>, Option<Guard<T>>>
+    where F: FnMut(&T) -> Option<T> {
+        // The box of the last rejected value, which is reused rather than reallocated.
+        let mut spare: Option<Box<T>> = None;
+
+        loop {
+            let (old, tag) = self.load_tagged(fetch_order);
+            let old = match old {
+                Some(old) => old,
+                None => return Err(None),
+            };
+
+            let new = match f(&old) {
+                Some(new) => new,
+                None => return Err(Some(old)),
+            };
+            let new = match spare.take() {
+                Some(mut spare) => {
+                    *spare = new;
+                    spare
+                },
+                None => Box::new(new),
+            };
+            let new = with_tag(Box::into_raw(new), tag);
+
+            // `old` protects the old value already, so no guard is needed for the CAS.
+            match self.inner.compare_exchange_weak(with_tag(old.as_ptr() as *mut T, tag), new,
+                                                   set_order, fetch_order) {
+                Ok(actual) => {
+                    // The old value is now unreachable, so it can be queued for deletion.
+                    unsafe { self.retire(actual); }
+
+                    return Ok(old);
+                },
+                // Reuse the box in the next try.
+                Err(_) => spare = unsafe { into_box(untag(new)) },
+            }
+        }
+    }
+}
 
-                Ok(guard)
-            },
+impl<T, D: Select> Atomic<T, D> {
+    /// Swap a tagged pointer if it matches the specified tagged pointer.
+    ///
+    /// This acts like `compare_and_swap`, but `self` is compared to `old` tagged with `old_tag`,
+    /// and set to `new` tagged with `new_tag`. If it fails, the actual tag is returned along with
+    /// the guard to the actual value and the box of `new`.
+    ///
+    /// If the pointer of `old` and `new` is the same (i.e. both are `None`), only the tag changes,
+    /// and nothing is queued for deletion. To change the tag of a non-null pointer, use
+    /// `compare_and_set_tag`.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if either tag has bits outside of `tag_mask()`.
+    pub fn compare_and_set_with_tag(
+        &self,
+        old: Option<*const T>,
+        old_tag: usize,
+        new: Option<Box<T>>,
+        new_tag: usize,
+        ordering: atomic::Ordering,
+    ) -> Result<Option<Guard<T>>, (Option<Guard<T>>, usize, Option<Box<T>>)> {
+        let old = with_tag(old.unwrap_or(ptr::null()) as *mut T, old_tag);
+        // Convert the box into a raw pointer up front, as a pointer derived from a reference to it
+        // must not end up in `self`.
+        let new = new.map_or(ptr::null_mut(), Box::into_raw);
+        let new_ptr = with_tag(new, new_tag);
+
+        // The actual value, including its tag.
+        let mut actual = ptr::null_mut();
+        // Create the guard beforehand to avoid premature frees.
+        let guard = self.guard(|| unsafe {
+            actual = self.inner.compare_and_swap(old, new_ptr, ordering);
+            untag(actual).as_ref()
+        });
+
+        if actual == old {
+            // `new` is now in `self`.
+
+            // Queue the deletion of now-unreachable `old`, unless only the tag changed.
+            if !untag(old).is_null() && untag(old) != untag(new_ptr) {
+                unsafe { self.retire(actual); }
+            }
+
+            Ok(guard)
+        } else {
             // Hand back the box too.
-            Err(guard) => Err((guard, new))
+            Err((guard, tag_of(actual), unsafe { into_box(new) }))
         }
     }
+
+    /// Change the tag of the pointer, if it matches the specified tagged pointer.
+    ///
+    /// This compares `self` to `ptr` tagged with `old_tag`. If they match, the tag is set to
+    /// `new_tag`, keeping the pointer, and `Ok(())` is returned. Otherwise, the actual tag is
+    /// returned in `Err` (the pointer might differ as well).
+    ///
+    /// This is the way to mark a node in place, as nothing is queued for deletion.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if either tag has bits outside of `tag_mask()`.
+    pub fn compare_and_set_tag(
+        &self,
+        ptr: Option<*const T>,
+        old_tag: usize,
+        new_tag: usize,
+        ordering: atomic::Ordering,
+    ) -> Result<(), usize> {
+        let old = with_tag(ptr.unwrap_or(ptr::null()) as *mut T, old_tag);
+        // Check the new tag before the CAS.
+        with_tag(ptr::null_mut::<T>(), new_tag);
+
+        loop {
+            let actual = self.inner.load(failure_ordering(ordering));
+            if actual != old {
+                return Err(tag_of(actual));
+            }
+
+            // The new value is derived from the actual one rather than `ptr`, which might be
+            // derived from a shared reference, and thus must not end up in `self`.
+            if self.inner.compare_exchange_weak(actual, with_tag(untag(actual), new_tag), ordering,
+                                                failure_ordering(ordering)).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A weak reference to the value of an `Atomic<T>`.
+///
+/// This is obtained through `Atomic::load_weak()`. It records the pointer of the value without
+/// protecting it, so the value might be destroyed meanwhile, but it can be upgraded to a guard as
+/// long as the value is still current. This gives e.g. caches a fast path, without holding up the
+/// destruction of the cached values indefinitely.
+pub struct WeakGuard<'a, T: 'a, D: 'a + Select = Global> {
+    /// The atomic the pointer was loaded from.
+    atomic: &'a Atomic<T, D>,
+    /// The untagged pointer.
+    ///
+    /// This is never dereferenced, as the value might have been destroyed.
+    ptr: *const T,
+}
+
+impl<'a, T, D: Select> WeakGuard<'a, T, D> {
+    /// Upgrade the weak reference to a guard.
+    ///
+    /// This protects the current value of the atomic, if it is (still) the recorded value.
+    /// Otherwise, `None` is returned.
+    ///
+    /// Note that the value might have been replaced and destroyed, and a new value allocated at
+    /// the same address and stored meanwhile (the ABA problem), in which case the guard refers to
+    /// the new value.
+    pub fn upgrade(&self, ordering: atomic::Ordering) -> Option<Guard<T>> {
+        // No garbage is destroyed while the guard is created, so the value is still alive if it
+        // is current.
+        self.atomic.guard(|| unsafe {
+            let ptr = untag(self.atomic.load_raw(ordering));
+            if ptr as *const T == self.ptr {
+                ptr.as_ref()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the recorded pointer.
+    ///
+    /// It must not be dereferenced, as the value might have been destroyed.
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr
+    }
+}
+
+// The pointer is never dereferenced, so the weak guard is as thread-safe as a reference to the
+// atomic.
+unsafe impl<'a, T: Sync, D: Select + Sync> Send for WeakGuard<'a, T, D> {}
+unsafe impl<'a, T: Sync, D: Select + Sync> Sync for WeakGuard<'a, T, D> {}
+
+// TODO: Use derive when https://github.com/rust-lang/rust/issues/26925 is fixed.
+impl<'a, T, D: Select> Clone for WeakGuard<'a, T, D> {
+    fn clone(&self) -> WeakGuard<'a, T, D> {
+        *self
+    }
+}
+
+impl<'a, T, D: Select> Copy for WeakGuard<'a, T, D> {}
+
+impl<'a, T, D: Select> fmt::Debug for WeakGuard<'a, T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WeakGuard").field(&self.ptr).finish()
+    }
+}
+
+/// Protect the current values of several `Atomic<T>`s consistently.
+///
+/// This loads every atomic in `atomics`, protects the values, and then validates all the loads
+/// again, retrying until no value was replaced in the meantime. The guards are returned in the
+/// order of `atomics` (`None` for null values).
+///
+/// When it returns, every value was still current after all of them were protected, so e.g. a
+/// node and its successor can be protected without one of them being unlinked (and destroyed)
+/// while the other is protected. Note that the validating loads are not a single atomic snapshot.
+/// Tags are ignored, as in `Atomic::load()`.
+pub fn protect_all<T, D: Select>(atomics: &[&Atomic<T, D>], ordering: atomic::Ordering)
+    -> Vec<Option<Guard<T>>> {
+    loop {
+        // Protect each value on its own.
+        let guards = atomics.iter().map(|atomic| atomic.protect(ordering)).collect::<Vec<_>>();
+
+        // Validate all of the values, now that they are all protected.
+        if atomics.iter().zip(&guards).all(|(atomic, guard)| {
+            untag(atomic.load_raw(ordering)) as *const T
+                == guard.as_ref().map_or(ptr::null(), |guard| guard.as_ptr())
+        }) {
+            return guards;
+        }
+    }
+}
+
+/// Get the mask of the tag bits of pointers to `T`.
+fn tag_mask<T>() -> usize {
+    mem::align_of::<T>() - 1
+}
+
+/// Strip the tag of some pointer.
+pub fn untag<T>(ptr: *mut T) -> *mut T {
+    provenance::with_addr(ptr, ptr as usize & !tag_mask::<T>())
+}
+
+/// Convert a raw pointer back into the (optional) box it was obtained from.
+///
+/// # Safety
+///
+/// `ptr` must either be null or obtained through `Box::into_raw()`, and not be used afterwards.
+unsafe fn into_box<T>(ptr: *mut T) -> Option<Box<T>> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(Box::from_raw(ptr))
+    }
+}
+
+/// Get the tag of some pointer.
+pub fn tag_of<T>(ptr: *mut T) -> usize {
+    ptr as usize & tag_mask::<T>()
+}
+
+/// Tag some (untagged) pointer.
+///
+/// # Panics
+///
+/// This will panic if `tag` has bits outside of the tag mask of `T`.
+pub fn with_tag<T>(ptr: *mut T, tag: usize) -> *mut T {
+    assert_eq!(tag & !tag_mask::<T>(), 0, "Tag {:x} exceeds the alignment of the pointer.", tag);
+
+    provenance::with_addr(ptr, ptr as usize | tag)
+}
+
+/// Get the strongest ordering allowed for the failure case of a CAS with some ordering.
+///
+/// The failure case of a CAS is a load, so it cannot have release semantics.
+fn failure_ordering(ordering: atomic::Ordering) -> atomic::Ordering {
+    match ordering {
+        atomic::Ordering::Release | atomic::Ordering::Relaxed => atomic::Ordering::Relaxed,
+        atomic::Ordering::AcqRel | atomic::Ordering::Acquire => atomic::Ordering::Acquire,
+        _ => atomic::Ordering::SeqCst,
+    }
 }
 
 // TODO: Use derive when https://github.com/rust-lang/rust/issues/26925 is fixed.
@@ -321,14 +1020,14 @@ impl<T> Default for Atomic<T> {
     }
 }
 
-impl<T> Drop for Atomic<T> {
+impl<T, D: Select> Drop for Atomic<T, D> {
     fn drop(&mut self) {
         // We use the neat `get_mut` to get around the overhead of atomics.
-        let ptr = self.inner.get_mut();
+        let ptr = *self.inner.get_mut();
 
-        if !ptr.is_null() {
+        if !untag(ptr).is_null() {
             // As the read pointer was not null, we can safely call its destructor.
-            unsafe { add_garbage_box(*ptr); }
+            unsafe { self.retire(ptr); }
         }
     }
 }
@@ -385,6 +1084,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn basic_properties_parallel() {
         let mut j = Vec::new();
 
@@ -406,6 +1106,107 @@ mod tests {
         }
     }
 
+    #[test]
+    fn load_async() {
+        let drops = Arc::new(AtomicUsize::default());
+        let a = Atomic::new(Some(Box::new(42)));
+        let b = Atomic::new(Some(Box::new(Dropper {
+            d: drops.clone(),
+        })));
+        let g = a.load_async(atomic::Ordering::Acquire).unwrap();
+        assert!(Atomic::<u8>::default().load_async(atomic::Ordering::Relaxed).is_none());
+
+        // The guard can be held by a migrating task.
+        let g = thread::spawn(move || {
+            a.store(None, atomic::Ordering::Release);
+            ::gc();
+            assert_eq!(*g, 42);
+            g
+        }).join().unwrap();
+
+        // The guard only protects its own value.
+        b.store(None, atomic::Ordering::Release);
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(atomic::Ordering::Relaxed) == 1 {
+                break;
+            }
+        }
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(*g, 42);
+    }
+
+    #[test]
+    fn fetch_update() {
+        let a = Atomic::new(Some(Box::new(0)));
+
+        // The tag is kept.
+        a.store_tagged(Some(Box::new(1)), 1, atomic::Ordering::Relaxed);
+        let old = a.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |&x| {
+            Some(x * 2)
+        }).unwrap();
+        assert_eq!(*old, 1);
+        let (new, tag) = a.load_tagged(atomic::Ordering::Relaxed);
+        assert_eq!((*new.unwrap(), tag), (2, 1));
+
+        // Aborting the update.
+        let cur = a.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |_| None);
+        assert_eq!(*cur.unwrap_err().unwrap(), 2);
+        a.store(None, atomic::Ordering::Relaxed);
+        assert!(a.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |&x| {
+            Some(x)
+        }).unwrap_err().is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fetch_update_concurrent() {
+        let opt = Arc::new(Atomic::new(Some(Box::new(0))));
+
+        let mut j = Vec::new();
+        for _ in 0..16 {
+            let opt = opt.clone();
+            j.push(thread::spawn(move || for _ in 0..1000 {
+                let old = opt.fetch_update(atomic::Ordering::AcqRel,
+                                           atomic::Ordering::Acquire,
+                                           |&x| Some(x + 1)).unwrap();
+                assert!(*old < 16000);
+            }));
+        }
+
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert_eq!(*opt.load(atomic::Ordering::Relaxed).unwrap(), 16000);
+    }
+
+    #[test]
+    fn load_weak() {
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::new(Some(Box::new(Dropper {
+            d: drops.clone(),
+        })));
+        assert!(Atomic::<u8>::default().load_weak(atomic::Ordering::Relaxed).is_none());
+
+        let weak = opt.load_weak(atomic::Ordering::Acquire).unwrap();
+        assert_eq!(weak.upgrade(atomic::Ordering::Acquire).unwrap().as_ptr(), weak.as_ptr());
+
+        // The weak reference doesn't hold up the destruction.
+        opt.store(None, atomic::Ordering::Release);
+        assert!(weak.upgrade(atomic::Ordering::Acquire).is_none());
+        ::local::free_cached_hazards();
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(atomic::Ordering::Relaxed) == 1 {
+                break;
+            }
+        }
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+    }
+
     #[test]
     fn cas() {
         let bx1 = Box::new(1);
@@ -461,6 +1262,146 @@ mod tests {
     }
 
     #[test]
+    fn cas_weak() {
+        let bx1 = Box::new(1);
+        let ptr1 = &*bx1 as *const usize;
+        let bx2 = Box::new(1);
+        let ptr2 = &*bx2 as *const usize;
+
+        let opt = Atomic::new(Some(bx1));
+        // Mismatching CASes never succeed.
+        assert_eq!(ptr1, &*opt.compare_and_swap_weak(Some(ptr2), None, atomic::Ordering::Relaxed).unwrap_err().0.unwrap());
+        opt.compare_and_store_weak(None, None, atomic::Ordering::Relaxed).unwrap_err();
+        assert_eq!(ptr1, &*opt.load(atomic::Ordering::Relaxed).unwrap());
+
+        // Matching CASes might fail spuriously, so we retry.
+        let mut new = Some(bx2);
+        loop {
+            match opt.compare_and_swap_weak(Some(ptr1), new, atomic::Ordering::AcqRel) {
+                Ok(old) => {
+                    assert_eq!(ptr1, &*old.unwrap());
+                    break;
+                },
+                Err((_, bx)) => new = bx,
+            }
+        }
+        assert_eq!(ptr2, &*opt.load(atomic::Ordering::Relaxed).unwrap());
+
+        while opt.compare_and_store_weak(Some(ptr2), None, atomic::Ordering::Release).is_err() {}
+        assert!(opt.load(atomic::Ordering::Relaxed).is_none());
+    }
+
+    #[test]
+    fn compare_exchange() {
+        let bx1 = Box::new(1);
+        let ptr1 = &*bx1 as *const usize;
+        let bx2 = Box::new(2);
+        let ptr2 = &*bx2 as *const usize;
+
+        let opt = Atomic::new(Some(bx1));
+        let (guard, new) = opt.compare_exchange(None, Some(bx2), atomic::Ordering::AcqRel,
+                                                atomic::Ordering::Acquire).unwrap_err();
+        assert_eq!(ptr1, &*guard.unwrap());
+
+        let old = opt.compare_exchange(Some(ptr1), new, atomic::Ordering::AcqRel,
+                                       atomic::Ordering::Relaxed).unwrap();
+        assert_eq!(ptr1, &*old.unwrap());
+        assert_eq!(ptr2, &*opt.load(atomic::Ordering::Relaxed).unwrap());
+        assert_eq!(*opt.load(atomic::Ordering::Relaxed).unwrap(), 2);
+    }
+
+    #[test]
+    fn size() {
+        // The global domain takes no space.
+        assert_eq!(mem::size_of::<Atomic<u8>>(), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn tagged() {
+        assert_eq!(Atomic::<u64>::tag_mask(), mem::align_of::<u64>() - 1);
+
+        let d = Arc::new(AtomicUsize::new(0));
+        let bx = Box::new(Dropper { d: d.clone() });
+        let ptr = &*bx as *const Dropper;
+
+        let opt = Atomic::default();
+        opt.store_tagged(Some(bx), 1, atomic::Ordering::Relaxed);
+        let (guard, tag) = opt.load_tagged(atomic::Ordering::Relaxed);
+        assert_eq!(ptr, &*guard.unwrap());
+        assert_eq!(tag, 1);
+        // The tag is ignored by plain loads.
+        assert_eq!(ptr, &*opt.load(atomic::Ordering::Relaxed).unwrap());
+
+        // Plain CASes fail, as the tag is set.
+        assert!(opt.compare_and_store(Some(ptr), None, atomic::Ordering::Relaxed).is_err());
+
+        // Mark the pointer.
+        assert_eq!(opt.compare_and_set_tag(Some(ptr), 0, 3, atomic::Ordering::Relaxed), Err(1));
+        opt.compare_and_set_tag(Some(ptr), 1, 3, atomic::Ordering::Relaxed).unwrap();
+        assert_eq!(opt.load_tagged(atomic::Ordering::Relaxed).1, 3);
+        ::gc();
+        assert_eq!(d.load(atomic::Ordering::Relaxed), 0);
+
+        // Unlink it.
+        let (_, tag, _) = opt.compare_and_set_with_tag(Some(ptr), 1, None, 0,
+                                                       atomic::Ordering::Relaxed).unwrap_err();
+        assert_eq!(tag, 3);
+        let old = opt.compare_and_set_with_tag(Some(ptr), 3, None, 2, atomic::Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(ptr, &*old.unwrap());
+        let (guard, tag) = opt.load_tagged(atomic::Ordering::Relaxed);
+        assert!(guard.is_none());
+        assert_eq!(tag, 2);
+
+        ::gc();
+        assert_eq!(d.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn protect_all() {
+        let a = Atomic::new(Some(Box::new(1)));
+        let b = Atomic::new(None);
+        let c = Atomic::new(Some(Box::new(3)));
+
+        let guards = super::protect_all(&[&a, &b, &c], atomic::Ordering::Acquire);
+        a.store(None, atomic::Ordering::Relaxed);
+        c.store(None, atomic::Ordering::Relaxed);
+        ::gc();
+
+        assert_eq!(guards.len(), 3);
+        assert_eq!(**guards[0].as_ref().unwrap(), 1);
+        assert!(guards[1].is_none());
+        assert_eq!(**guards[2].as_ref().unwrap(), 3);
+    }
+
+    #[test]
+    fn protect_all_concurrent() {
+        let a = Arc::new(Atomic::new(Some(Box::new(0))));
+        let b = Arc::new(Atomic::new(Some(Box::new(0))));
+
+        let (a2, b2) = (a.clone(), b.clone());
+        let writer = thread::spawn(move || for i in 1..1000 {
+            a2.store(Some(Box::new(i)), atomic::Ordering::Release);
+            b2.store(Some(Box::new(i)), atomic::Ordering::Release);
+        });
+
+        for _ in 0..1000 {
+            let guards = super::protect_all(&[&*a, &*b], atomic::Ordering::Acquire);
+            // `a` is stored first, so it is never behind `b`.
+            assert!(**guards[0].as_ref().unwrap() >= **guards[1].as_ref().unwrap());
+        }
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn tag_overflow() {
+        Atomic::<u16>::default().store_tagged(None, 2, atomic::Ordering::Relaxed);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn spam() {
         let opt = Arc::new(Atomic::default());
 
@@ -486,6 +1427,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn drop1() {
         let drops = Arc::new(AtomicUsize::default());
         let opt = Arc::new(Atomic::new(None));
@@ -519,6 +1461,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn drop2() {
         let drops = Arc::new(AtomicUsize::default());
 
@@ -548,6 +1491,8 @@ mod tests {
     }
 
     #[test]
+    // The cached hazards keep protecting the last object, unlike pins.
+    #[cfg_attr(feature = "epoch", ignore)]
     fn drop3() {
         let drops = Arc::new(AtomicUsize::default());
 
@@ -601,6 +1546,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn drop4() {
         for i in 0..256 {
             let opt = Arc::new(Atomic::new(Some(Box::new(i))));
@@ -619,6 +1565,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn get_mut() {
+        let mut opt = Atomic::new(Some(Box::new(1)));
+        unsafe {
+            *opt.get_mut().unwrap() += 1;
+        }
+        assert_eq!(*opt.load(atomic::Ordering::Relaxed).unwrap(), 2);
+
+        opt.store(None, atomic::Ordering::Relaxed);
+        assert!(unsafe { opt.get_mut() }.is_none());
+    }
+
+    #[test]
+    fn into_inner() {
+        let drops = Arc::new(AtomicUsize::default());
+
+        let opt = Atomic::new(Some(Box::new(Dropper {
+            d: drops.clone(),
+        })));
+        let b = unsafe { opt.into_inner() }.unwrap();
+
+        // The value was not queued as garbage.
+        ::gc();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+
+        drop(b);
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+
+        assert!(unsafe { Atomic::<u8>::new(None).into_inner() }.is_none());
+    }
+
     #[test]
     fn tls() {
         thread::spawn(|| BASIC.with(|_| {})).join().unwrap();
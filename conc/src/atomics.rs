@@ -0,0 +1,186 @@
+//! The atomic types used throughout the crate.
+//!
+//! Without the `paranoid` feature, these are simply those of `std::sync::atomic`. With it, they
+//! are wrappers ignoring the given orderings: every operation is done with `SeqCst`, and
+//! surrounded by `SeqCst` fences. This is meant for debugging, as it rules out the memory
+//! orderings as the cause of a bug (if it persists with `paranoid`, the orderings aren't to blame).
+//!
+//! The primitives of the hazard protocol (see `primitives`) build on these outside of `cfg(loom)`.
+//! Under `cfg(loom)`, they are replaced by those of `loom`, which aren't affected by `paranoid`.
+
+#[cfg(not(feature = "paranoid"))]
+pub use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering, fence};
+#[cfg(feature = "paranoid")]
+pub use std::sync::atomic::Ordering;
+#[cfg(feature = "paranoid")]
+pub use self::paranoid::{AtomicBool, AtomicPtr, AtomicUsize, fence};
+
+/// Get the `std` atomic underlying an atomic pointer.
+///
+/// Operations through the returned reference are not strengthened by `paranoid`.
+pub fn as_std<T>(atomic: &AtomicPtr<T>) -> &::std::sync::atomic::AtomicPtr<T> {
+    #[cfg(not(feature = "paranoid"))]
+    return atomic;
+    #[cfg(feature = "paranoid")]
+    return &atomic.inner;
+}
+
+/// Get the `std` atomic underlying an atomic pointer mutably.
+///
+/// Operations through the returned reference are not strengthened by `paranoid`.
+pub fn as_std_mut<T>(atomic: &mut AtomicPtr<T>) -> &mut ::std::sync::atomic::AtomicPtr<T> {
+    #[cfg(not(feature = "paranoid"))]
+    return atomic;
+    #[cfg(feature = "paranoid")]
+    return &mut atomic.inner;
+}
+
+#[cfg(feature = "paranoid")]
+mod paranoid {
+    use std::fmt;
+    use std::sync::atomic::{self, Ordering};
+
+    /// Issue a sequentially consistent fence, regardless of the given ordering.
+    pub fn fence(_: Ordering) {
+        atomic::fence(Ordering::SeqCst);
+    }
+
+    /// Run an atomic operation between two sequentially consistent fences.
+    fn fenced<T, F: FnOnce() -> T>(op: F) -> T {
+        atomic::fence(Ordering::SeqCst);
+        let ret = op();
+        atomic::fence(Ordering::SeqCst);
+        ret
+    }
+
+    /// Define a wrapper of an atomic type of `std`, doing every operation sequentially consistent.
+    macro_rules! wrapper {
+        ($(#[$attr:meta])* struct $name:ident $(<$t:ident>)*($val:ty);
+         $($extra:tt)*) => {
+            $(#[$attr])*
+            pub struct $name$(<$t>)* {
+                /// The underlying atomic.
+                pub inner: atomic::$name$(<$t>)*,
+            }
+
+            impl$(<$t>)* $name$(<$t>)* {
+                /// Create a new atomic.
+                pub const fn new(val: $val) -> $name$(<$t>)* {
+                    $name {
+                        inner: atomic::$name::new(val),
+                    }
+                }
+
+                /// Load the value.
+                pub fn load(&self, _: Ordering) -> $val {
+                    fenced(|| self.inner.load(Ordering::SeqCst))
+                }
+
+                /// Store a value.
+                pub fn store(&self, val: $val, _: Ordering) {
+                    fenced(|| self.inner.store(val, Ordering::SeqCst))
+                }
+
+                /// Store a value, returning the previous one.
+                pub fn swap(&self, val: $val, _: Ordering) -> $val {
+                    fenced(|| self.inner.swap(val, Ordering::SeqCst))
+                }
+
+                /// Store `new` if the value is `current`, returning the previous value.
+                pub fn compare_and_swap(&self, current: $val, new: $val, _: Ordering) -> $val {
+                    match self.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst) {
+                        Ok(x) | Err(x) => x,
+                    }
+                }
+
+                /// Store `new` if the value is `current`.
+                pub fn compare_exchange(&self, current: $val, new: $val, _: Ordering, _: Ordering)
+                    -> Result<$val, $val> {
+                    fenced(|| self.inner.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst))
+                }
+
+                /// Store `new` if the value is `current`, possibly failing spuriously.
+                pub fn compare_exchange_weak(&self, current: $val, new: $val, _: Ordering, _: Ordering)
+                    -> Result<$val, $val> {
+                    fenced(|| {
+                        self.inner.compare_exchange_weak(current, new, Ordering::SeqCst, Ordering::SeqCst)
+                    })
+                }
+
+                /// Get a mutable reference to the value.
+                pub fn get_mut(&mut self) -> &mut $val {
+                    self.inner.get_mut()
+                }
+
+                /// Get the value, consuming the atomic.
+                pub fn into_inner(self) -> $val {
+                    self.inner.into_inner()
+                }
+
+                $($extra)*
+            }
+
+            impl$(<$t>)* Default for $name$(<$t>)* {
+                fn default() -> $name$(<$t>)* {
+                    $name {
+                        inner: atomic::$name::default(),
+                    }
+                }
+            }
+
+            impl$(<$t>)* fmt::Debug for $name$(<$t>)* {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    self.inner.fmt(f)
+                }
+            }
+        };
+    }
+
+    wrapper! {
+        /// A sequentially consistent `AtomicBool`.
+        struct AtomicBool(bool);
+    }
+
+    wrapper! {
+        /// A sequentially consistent `AtomicUsize`.
+        struct AtomicUsize(usize);
+
+        /// Add to the value, returning the previous value.
+        pub fn fetch_add(&self, val: usize, _: Ordering) -> usize {
+            fenced(|| self.inner.fetch_add(val, Ordering::SeqCst))
+        }
+
+        /// Subtract from the value, returning the previous value.
+        pub fn fetch_sub(&self, val: usize, _: Ordering) -> usize {
+            fenced(|| self.inner.fetch_sub(val, Ordering::SeqCst))
+        }
+    }
+
+    wrapper! {
+        /// A sequentially consistent `AtomicPtr`.
+        struct AtomicPtr<T>(*mut T);
+    }
+}
+
+#[cfg(all(test, feature = "paranoid"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations() {
+        let x = AtomicUsize::new(1);
+        assert_eq!(x.fetch_add(2, Ordering::Relaxed), 1);
+        assert_eq!(x.fetch_sub(1, Ordering::Relaxed), 3);
+        assert_eq!(x.swap(5, Ordering::Relaxed), 2);
+        assert_eq!(x.compare_and_swap(4, 6, Ordering::Relaxed), 5);
+        assert_eq!(x.compare_exchange(5, 6, Ordering::Release, Ordering::Relaxed), Ok(5));
+        assert_eq!(x.load(Ordering::Relaxed), 6);
+
+        let mut b = Box::new(0u8);
+        let p = AtomicPtr::default();
+        assert!(p.load(Ordering::Relaxed).is_null());
+        p.store(&mut *b, Ordering::Relaxed);
+        assert_eq!(as_std(&p).load(Ordering::Relaxed), &mut *b as *mut u8);
+        assert_eq!(p.into_inner(), &mut *b as *mut u8);
+    }
+}
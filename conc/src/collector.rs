@@ -0,0 +1,220 @@
+//! Background garbage collection.
+//!
+//! Normally, garbage is collected inline by the threads adding garbage (see `Settings`), meaning
+//! that any application thread might pay for a GC pause. Alternatively, a dedicated collector can
+//! periodically collect the garbage in the background, such that the application threads never
+//! collect garbage inline.
+//!
+//! The collector either runs in a thread of its own (see `start()`), or in a thread driven by the
+//! application (see `drive()`). While it runs, ticking never triggers a collection.
+//!
+//! The collector only collects the garbage of the global state. Garbage cached thread-locally is
+//! still exported as usual, and `Domain`s still collect their own garbage.
+
+use parking_lot::{Condvar, Mutex};
+use std::thread;
+use atomics::{self as atomic, AtomicBool};
+use std::time::Duration;
+use {debug, global};
+
+lazy_static! {
+    /// The state of the collector.
+    static ref COLLECTOR: Collector = Collector {
+        state: Mutex::new(State {
+            interval: Duration::from_millis(100),
+            stop: false,
+            thread: None,
+        }),
+        wake: Condvar::new(),
+    };
+}
+
+/// Is the collector running?
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The collector.
+struct Collector {
+    /// The state of the collector.
+    state: Mutex<State>,
+    /// The condition variable, which the collector waits on between collections.
+    ///
+    /// It is notified when the collector is stopped, woken, or the interval changes.
+    wake: Condvar,
+}
+
+/// The state of the collector.
+struct State {
+    /// The interval between collections.
+    interval: Duration,
+    /// Shall the collector stop?
+    stop: bool,
+    /// The thread of the collector, if it was spawned by `start()`.
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Is the collector running?
+///
+/// While it is, ticking never triggers a garbage collection.
+pub fn is_running() -> bool {
+    RUNNING.load(atomic::Ordering::Relaxed)
+}
+
+/// Start the collector in a thread of its own.
+///
+/// The collector collects the garbage every `interval` from now on, until it is stopped. If the
+/// collector is already running, `Err(())` is returned.
+pub fn start(interval: Duration) -> Result<(), ()> {
+    let mut state = COLLECTOR.state.lock();
+    if RUNNING.swap(true, atomic::Ordering::Relaxed) {
+        // The collector is already running.
+        return Err(());
+    }
+
+    state.interval = interval;
+    state.stop = false;
+    state.thread = Some(thread::Builder::new()
+        .name("conc-collector".to_owned())
+        .spawn(run)
+        .expect("Failed to spawn the collector thread."));
+
+    Ok(())
+}
+
+/// Run the collector in the current thread.
+///
+/// This collects the garbage periodically (see `set_interval()`) until the collector is stopped,
+/// allowing the application to drive the collector in a thread it controls. If the collector is
+/// already running, `Err(())` is returned immediately.
+pub fn drive() -> Result<(), ()> {
+    {
+        let mut state = COLLECTOR.state.lock();
+        if RUNNING.swap(true, atomic::Ordering::Relaxed) {
+            // The collector is already running.
+            return Err(());
+        }
+
+        state.stop = false;
+    }
+
+    run();
+
+    Ok(())
+}
+
+/// Stop the collector.
+///
+/// If the collector runs in its own thread, this waits for the thread to exit. If it is driven by
+/// the application, `drive()` returns after the current collection.
+///
+/// This does nothing if the collector isn't running.
+pub fn stop() {
+    let thread = {
+        let mut state = COLLECTOR.state.lock();
+        state.stop = true;
+        COLLECTOR.wake.notify_all();
+        state.thread.take()
+    };
+
+    if let Some(thread) = thread {
+        thread.join().expect("The collector thread panicked.");
+    }
+}
+
+/// Set the interval between collections.
+///
+/// This takes effect immediately, even if the collector is currently waiting.
+pub fn set_interval(interval: Duration) {
+    COLLECTOR.state.lock().interval = interval;
+    COLLECTOR.wake.notify_all();
+}
+
+/// Wake the collector, making it collect now.
+///
+/// This does nothing if the collector isn't running.
+pub fn wake() {
+    // Take the lock, such that the wake-up isn't lost, if the collector is about to wait.
+    let _state = COLLECTOR.state.lock();
+    COLLECTOR.wake.notify_all();
+}
+
+/// Run the collector loop until it is stopped.
+fn run() {
+    loop {
+        // Print message in debug mode.
+        debug::exec(|| println!("Background collection."));
+
+        // The collector thread has no garbage of its own, so we needn't export anything.
+        // Destructors panicking are caught by the collection.
+        while global::try_gc().is_err() {}
+
+        let mut state = COLLECTOR.state.lock();
+        if !state.stop {
+            let interval = state.interval;
+            COLLECTOR.wake.wait_for(&mut state, interval);
+        }
+
+        if state.stop {
+            RUNNING.store(false, atomic::Ordering::Relaxed);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+    use local;
+
+    /// Add garbage, and wait for the collector to collect it.
+    fn wait_for_collection() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(x: &'static AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let before = X.load(atomic::Ordering::Relaxed);
+        ::add_garbage(&X, dtor);
+        local::export_garbage();
+
+        // The garbage is collected without any collection in this thread.
+        let begin = Instant::now();
+        while X.load(atomic::Ordering::Relaxed) == before {
+            assert!(begin.elapsed() < Duration::from_secs(10), "The collector didn't collect.");
+            wake();
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    // `parking_lot` casts integers to pointers when waiting on the condition variable, which Miri
+    // rejects under strict provenance.
+    #[cfg_attr(miri, ignore)]
+    fn start_stop_drive() {
+        // These are tested together, as there is only one collector.
+        start(Duration::from_millis(1)).unwrap();
+        assert!(is_running());
+        assert!(start(Duration::from_millis(1)).is_err());
+        assert!(drive().is_err());
+
+        wait_for_collection();
+
+        stop();
+        assert!(!is_running());
+
+        // A long interval, so the collection is triggered by waking.
+        set_interval(Duration::from_secs(1000));
+        let driver = thread::spawn(drive);
+        while !is_running() {
+            thread::yield_now();
+        }
+
+        wait_for_collection();
+
+        stop();
+        assert_eq!(driver.join().unwrap(), Ok(()));
+        assert!(!is_running());
+    }
+}
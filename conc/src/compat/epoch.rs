@@ -0,0 +1,707 @@
+//! An adapter mimicking the API of `crossbeam-epoch`.
+//!
+//! Code written against `crossbeam-epoch` can be ported to `conc` (e.g. to benchmark the two
+//! against each other) by replacing `crossbeam_epoch` with `conc::compat::epoch`. The names and
+//! signatures follow those of `crossbeam-epoch`, but they are mapped onto the hazards of the
+//! default domain:
+//!
+//! - `pin()` returns a `Guard`, which protects nothing by itself.
+//! - Every pointer handed out by the guard (e.g. by `Atomic::load()`) is protected by a
+//!   `conc::Guard` held by the guard, until the guard is dropped or repinned.
+//! - `Guard::defer_destroy()` adds the pointer as garbage of the default domain.
+//!
+//! A guard hence costs a hazard per pointer loaded through it, so long traversals under a single
+//! guard should repin it now and then (see `Guard::repin()`).
+//!
+//! # Differences
+//!
+//! - The guards protect pointers rather than a span of time, so the closures deferred by
+//!   `Guard::defer()` only wait for the next garbage collection. They must not destroy anything
+//!   other threads might have loaded (use `Guard::defer_destroy()` for that). With feature
+//!   `epoch`, the garbage waits for the pinned threads as well, so this is as in
+//!   `crossbeam-epoch`.
+//! - `unprotected()`, `Collector`, `LocalHandle` and `Guard::defer_unchecked()` are not provided.
+//! - Tags exceeding the alignment of the pointee panic rather than being truncated.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::compat::epoch::{self, Atomic, Owned};
+//! use std::sync::atomic::Ordering;
+//!
+//! let a = Atomic::new(1);
+//!
+//! let guard = epoch::pin();
+//! let old = a.swap(Owned::new(2), Ordering::AcqRel, &guard);
+//! assert_eq!(unsafe { *old.deref() }, 1);
+//! unsafe { guard.defer_destroy(old); }
+//! assert_eq!(unsafe { *a.load(Ordering::Acquire, &guard).deref() }, 2);
+//! # drop(guard);
+//! # drop(unsafe { a.into_owned() });
+//! ```
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::{fmt, mem, ops, ptr};
+
+use atomic::{tag_of, untag, with_tag};
+use atomics::{AtomicPtr, Ordering};
+use garbage::Garbage;
+use {add_garbage_box, local};
+
+/// Pin the current thread.
+///
+/// The returned guard protects the pointers handed out through it (see the module documentation).
+pub fn pin() -> Guard {
+    Guard {
+        guards: RefCell::new(Vec::new()),
+    }
+}
+
+/// A guard protecting the pointers handed out through it.
+///
+/// This corresponds to `crossbeam_epoch::Guard`.
+pub struct Guard {
+    /// The guards protecting the pointers handed out.
+    guards: RefCell<Vec<::Guard<u8>>>,
+}
+
+impl Guard {
+    /// Protect the pointer obtained by `f`.
+    ///
+    /// `f` is evaluated like the closure of `conc::Guard::new()`, so the pointer it returns is
+    /// protected, even if it is unlinked right after being read. Hence, it must not cause a garbage
+    /// collection. The pointer is returned.
+    fn protect<T, F>(&self, f: F) -> *mut T
+    where F: FnOnce() -> *mut T {
+        let mut ptr = ptr::null_mut();
+        let guard = ::Guard::maybe_new(|| unsafe {
+            ptr = f();
+            (untag(ptr) as *const u8).as_ref()
+        });
+        if let Some(guard) = guard {
+            self.guards.borrow_mut().push(guard);
+        }
+
+        ptr
+    }
+
+    /// Defer the call of a closure.
+    ///
+    /// The closure is called by a later garbage collection of the default domain. Unlike in
+    /// `crossbeam-epoch`, it doesn't wait for the guards alive (see the module documentation).
+    pub fn defer<F, R>(&self, f: F)
+    where F: FnOnce() -> R + Send + 'static {
+        // The closure is queued as garbage pointing to its own box.
+        let ptr = Box::into_raw(Box::new(f));
+        local::add_garbage(Garbage::new_with(ptr as *const u8, |ptr| unsafe {
+            Box::from_raw(ptr as *mut F)();
+        }));
+    }
+
+    /// Destroy the object of a pointer once it is no longer protected.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be obtained from an `Owned`, and be unlinked, such that no thread can load
+    /// it anymore. It must not be destroyed otherwise.
+    pub unsafe fn defer_destroy<T>(&self, ptr: Shared<T>) {
+        add_garbage_box(ptr.as_raw());
+    }
+
+    /// Export the garbage of the current thread.
+    ///
+    /// See `conc::export_garbage()`.
+    pub fn flush(&self) {
+        ::export_garbage();
+    }
+
+    /// Release the protection of the pointers handed out so far.
+    pub fn repin(&mut self) {
+        self.guards.get_mut().clear();
+    }
+}
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Guard { .. }")
+    }
+}
+
+/// A pointer which can be stored in an `Atomic`.
+///
+/// This corresponds to `crossbeam_epoch::Pointer`.
+pub trait Pointer<T> {
+    /// Convert the pointer into a raw, possibly tagged pointer.
+    fn into_ptr(self) -> *mut T;
+
+    /// Convert a raw pointer back.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be obtained by `into_ptr()` of the same type.
+    unsafe fn from_ptr(ptr: *mut T) -> Self;
+}
+
+/// An owned, heap-allocated object.
+///
+/// This corresponds to `crossbeam_epoch::Owned`. It acts like a `Box<T>` with a tag.
+pub struct Owned<T> {
+    /// The tagged pointer to the object.
+    ptr: *mut T,
+    /// Act like a box.
+    _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send> Send for Owned<T> {}
+unsafe impl<T: Sync> Sync for Owned<T> {}
+
+impl<T> Owned<T> {
+    /// Allocate a new object.
+    pub fn new(val: T) -> Owned<T> {
+        Owned::from(Box::new(val))
+    }
+
+    /// Convert the object into a box, dropping the tag.
+    pub fn into_box(self) -> Box<T> {
+        unsafe { Box::from_raw(untag(self.into_ptr())) }
+    }
+
+    /// Convert the object into a pointer protected by `guard`.
+    pub fn into_shared<'g>(self, guard: &'g Guard) -> Shared<'g, T> {
+        // The object is owned, so it can't be destroyed before it is protected.
+        let ptr = self.into_ptr();
+        unsafe { Shared::from_ptr(guard.protect(|| ptr)) }
+    }
+
+    /// Get the tag.
+    pub fn tag(&self) -> usize {
+        tag_of(self.ptr)
+    }
+
+    /// Set the tag.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the tag exceeds the alignment of `T`.
+    pub fn with_tag(self, tag: usize) -> Owned<T> {
+        let ptr = self.into_ptr();
+        unsafe { Owned::from_ptr(with_tag(untag(ptr), tag)) }
+    }
+}
+
+impl<T> Pointer<T> for Owned<T> {
+    fn into_ptr(self) -> *mut T {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
+
+    unsafe fn from_ptr(ptr: *mut T) -> Owned<T> {
+        debug_assert!(!untag(ptr).is_null(), "Creating an `Owned` from the null pointer.");
+
+        Owned {
+            ptr: ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> From<Box<T>> for Owned<T> {
+    fn from(b: Box<T>) -> Owned<T> {
+        unsafe { Owned::from_ptr(Box::into_raw(b)) }
+    }
+}
+
+impl<T> ops::Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*untag(self.ptr) }
+    }
+}
+
+impl<T> ops::DerefMut for Owned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *untag(self.ptr) }
+    }
+}
+
+impl<T> Drop for Owned<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(untag(self.ptr)) });
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Owned")
+            .field("value", &**self)
+            .field("tag", &self.tag())
+            .finish()
+    }
+}
+
+/// A pointer protected by a guard.
+///
+/// This corresponds to `crossbeam_epoch::Shared`. The object is protected as long as the guard
+/// `'g` is alive.
+pub struct Shared<'g, T: 'g> {
+    /// The tagged pointer.
+    ptr: *mut T,
+    /// Borrow the guard.
+    _marker: PhantomData<(&'g (), *const T)>,
+}
+
+impl<'g, T> Shared<'g, T> {
+    /// Get the null pointer.
+    pub fn null() -> Shared<'g, T> {
+        unsafe { Shared::from_ptr(ptr::null_mut()) }
+    }
+
+    /// Is the pointer null?
+    ///
+    /// The tag is ignored.
+    pub fn is_null(&self) -> bool {
+        untag(self.ptr).is_null()
+    }
+
+    /// Get the raw pointer without the tag.
+    pub fn as_raw(&self) -> *const T {
+        untag(self.ptr)
+    }
+
+    /// Dereference the pointer.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null.
+    pub unsafe fn deref(&self) -> &'g T {
+        &*untag(self.ptr)
+    }
+
+    /// Dereference the pointer, if it isn't null.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a valid object, if it isn't null.
+    pub unsafe fn as_ref(&self) -> Option<&'g T> {
+        untag(self.ptr).as_ref()
+    }
+
+    /// Take ownership of the object.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be obtained from an `Owned`, and no other thread may access the object
+    /// anymore.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_ptr(self.ptr)
+    }
+
+    /// Get the tag.
+    pub fn tag(&self) -> usize {
+        tag_of(self.ptr)
+    }
+
+    /// Set the tag.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the tag exceeds the alignment of `T`.
+    pub fn with_tag(&self, tag: usize) -> Shared<'g, T> {
+        unsafe { Shared::from_ptr(with_tag(untag(self.ptr), tag)) }
+    }
+}
+
+impl<'g, T> Pointer<T> for Shared<'g, T> {
+    fn into_ptr(self) -> *mut T {
+        self.ptr
+    }
+
+    unsafe fn from_ptr(ptr: *mut T) -> Shared<'g, T> {
+        Shared {
+            ptr: ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+// TODO: Use derive when https://github.com/rust-lang/rust/issues/26925 is fixed.
+impl<'g, T> Clone for Shared<'g, T> {
+    fn clone(&self) -> Shared<'g, T> {
+        *self
+    }
+}
+
+impl<'g, T> Copy for Shared<'g, T> {}
+
+impl<'g, T> PartialEq for Shared<'g, T> {
+    fn eq(&self, other: &Shared<'g, T>) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<'g, T> Eq for Shared<'g, T> {}
+
+impl<'g, T> fmt::Debug for Shared<'g, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("raw", &self.as_raw())
+            .field("tag", &self.tag())
+            .finish()
+    }
+}
+
+/// The error of a failed compare-and-exchange.
+///
+/// This corresponds to `crossbeam_epoch::CompareExchangeError`.
+pub struct CompareExchangeError<'g, T: 'g, P: Pointer<T>> {
+    /// The current value of the atomic, protected by the guard.
+    pub current: Shared<'g, T>,
+    /// The new value, which was not stored.
+    pub new: P,
+}
+
+impl<'g, T, P: Pointer<T> + fmt::Debug> fmt::Debug for CompareExchangeError<'g, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompareExchangeError")
+            .field("current", &self.current)
+            .field("new", &self.new)
+            .finish()
+    }
+}
+
+/// An atomic pointer.
+///
+/// This corresponds to `crossbeam_epoch::Atomic`. Like it, it doesn't destroy its object when
+/// dropped.
+pub struct Atomic<T> {
+    /// The tagged pointer.
+    inner: AtomicPtr<T>,
+    /// Act like a box.
+    _marker: PhantomData<Box<T>>,
+}
+
+// The object is shared between threads, and can be taken out (and dropped) by any of them.
+unsafe impl<T: Send + Sync> Send for Atomic<T> {}
+unsafe impl<T: Send + Sync> Sync for Atomic<T> {}
+
+impl<T> Atomic<T> {
+    /// Allocate a new object, and create an atomic pointer to it.
+    pub fn new(val: T) -> Atomic<T> {
+        Atomic::from(Owned::new(val))
+    }
+
+    /// Create a null atomic pointer.
+    pub fn null() -> Atomic<T> {
+        Atomic {
+            inner: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load the pointer.
+    ///
+    /// The object is protected by `guard`.
+    pub fn load<'g>(&self, ordering: Ordering, guard: &'g Guard) -> Shared<'g, T> {
+        unsafe { Shared::from_ptr(guard.protect(|| self.inner.load(ordering))) }
+    }
+
+    /// Store a pointer.
+    pub fn store<P: Pointer<T>>(&self, new: P, ordering: Ordering) {
+        self.inner.store(new.into_ptr(), ordering);
+    }
+
+    /// Swap the pointer.
+    ///
+    /// The old object is protected by `guard`.
+    pub fn swap<'g, P: Pointer<T>>(&self, new: P, ordering: Ordering, guard: &'g Guard)
+        -> Shared<'g, T> {
+        let new = new.into_ptr();
+        unsafe { Shared::from_ptr(guard.protect(|| self.inner.swap(new, ordering))) }
+    }
+
+    /// Store a pointer, if the current pointer is `current`.
+    ///
+    /// On success, the new pointer is returned. Otherwise, the current pointer and the new
+    /// pointer are returned. Either is protected by `guard`.
+    pub fn compare_exchange<'g, P: Pointer<T>>(
+        &self,
+        current: Shared<T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        self.compare_exchange_with(new, guard, |new| {
+            self.inner.compare_exchange(current.ptr, new, success, failure)
+        })
+    }
+
+    /// Store a pointer, if the current pointer is `current`, failing spuriously.
+    ///
+    /// This acts like `compare_exchange()`, but might fail even if the pointers are equal.
+    pub fn compare_exchange_weak<'g, P: Pointer<T>>(
+        &self,
+        current: Shared<T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        self.compare_exchange_with(new, guard, |new| {
+            self.inner.compare_exchange_weak(current.ptr, new, success, failure)
+        })
+    }
+
+    /// Compare-and-exchange the pointer through `cas`, which is given the new pointer.
+    fn compare_exchange_with<'g, P, F>(&self, new: P, guard: &'g Guard, cas: F)
+        -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>>
+    where P: Pointer<T>,
+          F: FnOnce(*mut T) -> Result<*mut T, *mut T> {
+        let new = new.into_ptr();
+        // Once stored, the new pointer might be unlinked and destroyed by other threads, so it is
+        // protected as well.
+        let mut stored = false;
+        let ptr = guard.protect(|| match cas(new) {
+            Ok(_) => {
+                stored = true;
+                new
+            },
+            Err(actual) => actual,
+        });
+
+        unsafe {
+            if stored {
+                Ok(Shared::from_ptr(ptr))
+            } else {
+                Err(CompareExchangeError {
+                    current: Shared::from_ptr(ptr),
+                    new: P::from_ptr(new),
+                })
+            }
+        }
+    }
+
+    /// Take ownership of the object.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, and no other thread may access the object anymore.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_ptr(self.inner.into_inner())
+    }
+}
+
+impl<T> Default for Atomic<T> {
+    fn default() -> Atomic<T> {
+        Atomic::null()
+    }
+}
+
+impl<T> From<Owned<T>> for Atomic<T> {
+    fn from(owned: Owned<T>) -> Atomic<T> {
+        Atomic {
+            inner: AtomicPtr::new(owned.into_ptr()),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Atomic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ptr = self.inner.load(Ordering::Relaxed);
+        f.debug_struct("Atomic")
+            .field("raw", &untag(ptr))
+            .field("tag", &tag_of(ptr))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    /// An object counting its drops.
+    struct Dropper(&'static AtomicUsize);
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Collect the garbage until `drops` reaches `n`.
+    ///
+    /// Other threads might be pinned in an earlier epoch for a moment (with feature `epoch`), so
+    /// this retries.
+    fn gc_until(drops: &AtomicUsize, n: usize) {
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(Ordering::Relaxed) == n {
+                return;
+            }
+        }
+        panic!("Garbage not destroyed.");
+    }
+
+    #[test]
+    fn load_store_swap() {
+        let a = Atomic::new(1);
+        let guard = pin();
+
+        assert_eq!(unsafe { *a.load(Ordering::Acquire, &guard).deref() }, 1);
+        let old = a.swap(Owned::new(2), Ordering::AcqRel, &guard);
+        assert_eq!(unsafe { *old.deref() }, 1);
+        unsafe { guard.defer_destroy(old); }
+
+        let old = a.load(Ordering::Acquire, &guard);
+        a.store(Shared::null(), Ordering::Release);
+        assert!(a.load(Ordering::Acquire, &guard).is_null());
+        unsafe { guard.defer_destroy(old); }
+    }
+
+    #[test]
+    fn compare_exchange() {
+        let a = Atomic::new(1);
+        let guard = pin();
+        let current = a.load(Ordering::Acquire, &guard);
+
+        let new = a.compare_exchange(current, Owned::new(2), Ordering::AcqRel, Ordering::Acquire,
+                                     &guard).unwrap();
+        assert_eq!(unsafe { *new.deref() }, 2);
+        unsafe { guard.defer_destroy(current); }
+
+        // The pointer is stale now, so the new object is given back.
+        let err = a.compare_exchange(current, Owned::new(3), Ordering::AcqRel, Ordering::Acquire,
+                                     &guard).unwrap_err();
+        assert_eq!(err.current, new);
+        assert_eq!(*err.new, 3);
+
+        drop(guard);
+        drop(unsafe { a.into_owned() });
+    }
+
+    #[test]
+    fn tags() {
+        let a = Atomic::from(Owned::new(0u64).with_tag(0b101));
+        let guard = pin();
+        let shared = a.load(Ordering::Acquire, &guard);
+        assert_eq!(shared.tag(), 0b101);
+        assert_eq!(unsafe { *shared.deref() }, 0);
+        assert_eq!(shared.with_tag(0).as_raw(), shared.as_raw());
+
+        drop(guard);
+        assert_eq!(*unsafe { a.into_owned() }.into_box(), 0);
+    }
+
+    #[test]
+    fn guard_protects() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        let a: &'static Atomic<Dropper> = Box::leak(Box::new(Atomic::new(Dropper(&DROPS))));
+        let guard = pin();
+        let shared = a.load(Ordering::Acquire, &guard);
+
+        // Unlink and destroy the object in another thread.
+        let ptr = shared.as_raw() as usize;
+        thread::spawn(move || {
+            let guard = pin();
+            let old = a.swap(Shared::null(), Ordering::AcqRel, &guard);
+            assert_eq!(old.as_raw() as usize, ptr);
+            unsafe { guard.defer_destroy(old); }
+            drop(guard);
+            ::gc();
+        }).join().unwrap();
+        ::gc();
+        ::gc();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+
+        // The hazards are cached, still protecting the object, unless they are freed.
+        drop(guard);
+        ::local::free_cached_hazards();
+        gc_until(&DROPS, 1);
+    }
+
+    #[test]
+    fn defer() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let guard = pin();
+        guard.defer(|| CALLS.fetch_add(1, Ordering::Relaxed));
+        drop(guard);
+        gc_until(&CALLS, 1);
+    }
+
+    #[test]
+    fn stack() {
+        /// A Treiber stack, as written against `crossbeam-epoch`.
+        struct Stack {
+            head: Atomic<Node>,
+        }
+
+        struct Node {
+            value: usize,
+            next: Atomic<Node>,
+        }
+
+        impl Stack {
+            fn push(&self, value: usize) {
+                let mut node = Owned::new(Node {
+                    value: value,
+                    next: Atomic::null(),
+                });
+                let guard = pin();
+
+                loop {
+                    let head = self.head.load(Ordering::Relaxed, &guard);
+                    node.next.store(head, Ordering::Relaxed);
+                    match self.head.compare_exchange(head, node, Ordering::Release,
+                                                     Ordering::Relaxed, &guard) {
+                        Ok(_) => break,
+                        Err(err) => node = err.new,
+                    }
+                }
+            }
+
+            fn pop(&self) -> Option<usize> {
+                let guard = pin();
+
+                loop {
+                    let head = self.head.load(Ordering::Acquire, &guard);
+                    match unsafe { head.as_ref() } {
+                        Some(node) => {
+                            let next = node.next.load(Ordering::Relaxed, &guard);
+                            if self.head.compare_exchange(head, next, Ordering::Relaxed,
+                                                          Ordering::Relaxed, &guard).is_ok() {
+                                unsafe { guard.defer_destroy(head); }
+                                return Some(node.value);
+                            }
+                        },
+                        None => return None,
+                    }
+                }
+            }
+        }
+
+        let stack: &'static Stack = Box::leak(Box::new(Stack {
+            head: Atomic::null(),
+        }));
+
+        let threads: Vec<_> = (0..4).map(|i| thread::spawn(move || {
+            let mut sum = 0;
+            for j in 0..1000 {
+                stack.push(i * 1000 + j);
+                sum += stack.pop().unwrap();
+            }
+            sum
+        })).collect();
+
+        let sum: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(sum, (0..4000).sum());
+        assert_eq!(stack.pop(), None);
+    }
+}
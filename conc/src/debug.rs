@@ -1,13 +1,128 @@
 //! Runtime debugging tools.
+//!
+//! Besides the internal debug mode (see `CONC_DEBUG_MODE`), this provides teardown checks for
+//! detecting leaked guards and garbage in tests, and a dump of the global state for finding the
+//! thread responsible for a blocked hazard. These require `std`.
 
 #[cfg(feature = "debug-tools")]
 extern crate backtrace;
 
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use metrics::Stats;
+#[cfg(feature = "std")]
+use {global, local};
+
+/// The maximal number of garbage collections done by `clean()`.
+///
+/// Destructors can add new garbage (e.g. nodes of a data structure dropping their successors),
+/// so a single collection might not be enough.
+#[cfg(feature = "std")]
+const MAX_CLEAN_PASSES: usize = 16;
+
+/// Collect all the garbage possible, and return what is left.
+///
+/// This frees the hazards cached in the current thread, and then exports the garbage of the
+/// current thread and collects the global garbage, until no progress is made.
+///
+/// The returned statistics tell what is left. After every other thread using `conc` is joined,
+/// any garbage left is protected by a guard still alive somewhere (or a hazard blocked forever),
+/// and any active hazard belongs to such a guard.
+#[cfg(feature = "std")]
+pub fn clean() -> Stats {
+    local::free_cached_hazards();
+
+    let mut stats = ::stats();
+    for _ in 0..MAX_CLEAN_PASSES {
+        ::gc();
+
+        let new = ::stats();
+        let progress = new.global_garbage < stats.global_garbage;
+        stats = new;
+        if stats.global_garbage == 0 || !progress {
+            break;
+        }
+    }
+
+    stats
+}
+
+/// The state of a hazard in a dump.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HazardState {
+    /// The hazard is free.
+    Free,
+    /// The hazard is dead, and is yet to be destroyed by a garbage collection.
+    Dead,
+    /// The hazard protects the pointer with this address.
+    Protect(usize),
+    /// The hazard is blocked (its owner is loading a pointer to protect).
+    ///
+    /// A hazard staying in this state prevents every garbage collection from destroying anything.
+    Blocked,
+}
+
+/// A hazard in a dump.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HazardDump {
+    /// The state of the hazard.
+    pub state: HazardState,
+    /// The thread which created the hazard, if known.
+    ///
+    /// Hazards are reused by the thread creating them, so this is usually the thread using the
+    /// hazard, except for hazards of `Domain`s, which are shared between threads.
+    pub owner: Option<thread::ThreadId>,
+}
+
+/// A dump of the global state.
+///
+/// This is returned by `dump()`.
+#[cfg(feature = "std")]
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct Dump {
+    /// Every hazard registered in the global state.
+    pub hazards: Vec<HazardDump>,
+    /// The addresses of the garbage queued in the global state.
+    ///
+    /// Garbage queued thread-locally (and not exported yet) is not included.
+    pub garbage: Vec<usize>,
+}
+
+/// Dump the hazards and the garbage of the global state.
+///
+/// This allows finding out which thread holds a hazard blocking or protecting garbage, e.g. when
+/// memory grows or garbage collections never destroy anything. Pretty-print it with `{:#?}`.
+///
+/// This waits for a running garbage collection to finish, but never for a blocked hazard.
+#[cfg(feature = "std")]
+pub fn dump() -> Dump {
+    global::dump()
+}
+
+/// Assert that no garbage or active hazards are left after cleaning up.
+///
+/// This calls `clean()`, and is intended for the end of tests, after every thread is joined, to
+/// detect leaks in lock-free structures.
+///
+/// # Panics
+///
+/// This panics if any garbage is left, or if any hazard is still active.
+#[cfg(feature = "std")]
+pub fn assert_clean() {
+    let stats = clean();
+    assert!(stats.local_garbage == 0 && stats.global_garbage == 0 && stats.active_hazards == 0,
+            "Reclamation state not clean: {} pieces of garbage and {} active hazards left ({:?}).",
+            stats.local_garbage + stats.global_garbage, stats.active_hazards, stats);
+}
+
 /// Execute closure when the environment variable, `CONC_DEBUG_MODE`, is set.
 ///
 /// When compiled in release mode, this is a NOP.
 #[cfg(feature = "debug-tools")]
-pub fn exec<F: FnOnce()>(f: F) {
+pub(crate) fn exec<F: FnOnce()>(f: F) {
     use self::backtrace::Backtrace;
     use std::env;
 
@@ -37,4 +152,46 @@ pub fn exec<F: FnOnce()>(f: F) {
 /// set.
 #[inline]
 #[cfg(not(feature = "debug-tools"))]
-pub fn exec<F: FnOnce()>(_: F) {}
+pub(crate) fn exec<F: FnOnce()>(_: F) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::thread;
+    use Guard;
+
+    #[test]
+    fn clean_cached_hazards() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(x: &'static AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        thread::spawn(|| {
+            // Leave a hazard protecting `X` in the cache.
+            drop(Guard::new(|| &X));
+            ::add_garbage(&X, dtor);
+
+            clean();
+            assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+        }).join().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    // A forgotten pinned guard keeps the thread pinned for good.
+    #[cfg_attr(feature = "epoch", ignore)]
+    fn assert_clean_leak() {
+        static X: u8 = 0;
+
+        fn nop(_: &'static u8) {}
+
+        // The guard is leaked, so the garbage can never be collected.
+        ::std::mem::forget(Guard::new(|| &X));
+        ::add_garbage(&X, nop);
+
+        assert_clean();
+    }
+}
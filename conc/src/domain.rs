@@ -0,0 +1,630 @@
+//! Independent reclamation domains.
+//!
+//! By default, every user of `conc` in the process shares the same global state: A single
+//! registry of hazards and a single garbage queue. This means that an ill-behaved user (e.g. one
+//! holding guards for a long time, or one accumulating huge amounts of hazards) slows down or
+//! stalls reclamation for everybody else.
+//!
+//! A `Domain` is an independent instance of the state, with its own hazards and garbage. Garbage
+//! added to a domain is only collected by the domain, and only the hazards of the domain are
+//! scanned when doing so. `Atomic<T>` and `Guard<T>` can be bound to a domain (through
+//! `Atomic::new_in()` and `Guard::new_in()`), while the usual constructors use the global state,
+//! which acts as the default domain (see `Global`).
+//!
+//! Domains don't cache garbage and hazards thread-locally (like the default domain does). Instead,
+//! garbage is queued in the domain directly, and freed hazards are cached by the domain, so the
+//! domains are cheap to have many of, but each operation is a bit more expensive.
+//!
+//! Each domain can have its own settings (see `Domain::with_settings()`), such that e.g. a
+//! subsystem with a lot of churn can be collected aggressively, without scanning the hazards of
+//! unrelated structures or changing the thresholds of the rest of the program.
+//!
+//! The garbage of a domain is destroyed in no particular order, unless the domain is made FIFO
+//! (see `Domain::fifo()`).
+//!
+//! # Scoped domains
+//!
+//! Since the garbage of a domain can be destroyed at any later point, `Guard<T>` and
+//! `add_garbage()` require `'static` data. A scoped domain (see `scope()`) lifts this restriction
+//! by guaranteeing that all of its garbage is destroyed when the scope ends, so it can protect and
+//! destroy objects borrowing from the stack frame of the scope.
+
+use parking_lot::Mutex;
+use std::marker::PhantomData;
+use atomics::{self as atomic, AtomicUsize};
+use std::{fmt, mem, ops};
+
+use {global, hazard, guard, metrics, rand, settings};
+use allocator::Allocator;
+use garbage::Garbage;
+use global::WouldBlock;
+use settings::Settings;
+
+/// A reclamation domain.
+///
+/// This is an alias of `Domain`.
+pub type HazardDomain = Domain;
+
+/// A reclamation domain.
+///
+/// Since guards and garbage can outlive any scope, the domain must live for the rest of the
+/// program, which is why the API takes `&'static Domain` (e.g. obtained through `lazy_static!`
+/// or by leaking a box).
+pub struct Domain {
+    /// The state of the domain.
+    state: global::State,
+    /// The cache of free hazards of the domain.
+    ///
+    /// The hazards in this cache are in state "free".
+    hazards: Mutex<Vec<hazard::Writer>>,
+    /// The settings of the domain.
+    ///
+    /// If this is `None`, the settings of the current thread are used.
+    settings: Option<Settings>,
+}
+
+impl Domain {
+    /// Create a new domain.
+    pub fn new() -> Domain {
+        Domain {
+            state: global::State::new(),
+            hazards: Mutex::new(Vec::new()),
+            settings: None,
+        }
+    }
+
+    /// Create a new domain with its own settings.
+    ///
+    /// The settings apply to the operations on the domain, regardless of the settings of the
+    /// current thread. Only the GC probability matters, as domains don't cache garbage
+    /// thread-locally.
+    pub fn with_settings(settings: Settings) -> Domain {
+        Domain {
+            state: global::State::new(),
+            hazards: Mutex::new(Vec::new()),
+            settings: Some(settings),
+        }
+    }
+
+    /// Create a new domain allocating its hazards with `allocator`.
+    ///
+    /// See the `allocator` module.
+    pub fn with_allocator(allocator: &'static Allocator) -> Domain {
+        Domain {
+            state: global::State::with_allocator(Some(allocator)),
+            hazards: Mutex::new(Vec::new()),
+            settings: None,
+        }
+    }
+
+    /// Make the domain destroy its garbage in the order it was added.
+    ///
+    /// By default, the garbage is destroyed in no particular order. This guarantees that garbage
+    /// is only destroyed once all the garbage added to the domain before it is, e.g. for objects
+    /// whose destructors depend on objects retired earlier. The price is that a protected object
+    /// holds back the destruction of all the garbage added after it.
+    ///
+    /// The order of garbage added concurrently is the order in which it reaches the domain.
+    pub fn fifo(mut self) -> Domain {
+        self.state.set_fifo();
+        self
+    }
+
+    /// Get the settings of the domain.
+    ///
+    /// These are the settings given in `with_settings()`, or the settings of the current thread.
+    pub fn settings(&self) -> Settings {
+        self.settings.unwrap_or_else(settings::get)
+    }
+
+    /// Get a blocked hazard of this domain.
+    ///
+    /// This pops a hazard from the cache of the domain, or registers a new one if the cache is
+    /// empty.
+    pub(crate) fn get_hazard(&'static self) -> hazard::Writer {
+        if let Some(hazard) = self.hazards.lock().pop() {
+            hazard.block();
+            hazard
+        } else {
+            self.state.create_hazard().in_domain(self)
+        }
+    }
+
+    /// Free a hazard of this domain to the cache of the domain.
+    pub(crate) fn free_hazard(&self, hazard: hazard::Writer) {
+        debug_assert!(!hazard.is_blocked(), "Illegally freeing a blocked hazards.");
+
+        // Hazards in the cache must be free, so that they don't keep anything alive.
+        hazard.free();
+        self.hazards.lock().push(hazard);
+    }
+
+    /// Kill the cached hazards, such that the final collection of the state can destroy them.
+    fn kill_cached_hazards(&self) {
+        for hazard in self.hazards.lock().drain(..) {
+            hazard.kill();
+        }
+    }
+
+    /// Add garbage to the domain.
+    ///
+    /// This might trigger a garbage collection of the domain, according to the settings of the
+    /// current thread.
+    fn add(&self, garbage: Garbage) {
+        metrics::with(|recorder| recorder.garbage_queued(1));
+        // Since this function can trigger a GC, it must not be called inside a guard constructor.
+        guard::debug_assert_no_create();
+
+        self.state.export_garbage(vec![garbage]);
+
+        // Tick the domain, or collect right away, if too much memory is held up by garbage.
+        let settings = self.settings();
+        if self.state.garbage_bytes() > settings.max_garbage_bytes
+            || rand::random::<usize>() < settings.gc_probability {
+            let _ = self.try_gc();
+        }
+    }
+
+    /// Declare a pointer unreachable garbage of this domain to be deleted eventually.
+    ///
+    /// This acts like `conc::add_garbage()`, but queues the garbage in this domain, so it is only
+    /// destroyed when it isn't protected by any guard of this domain.
+    pub fn add_garbage<T: Sync>(&self, ptr: &'static T, dtor: fn(&'static T)) {
+        self.add(unsafe {
+            Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
+        });
+    }
+
+    /// Declare a pointer unreachable garbage of this domain to be deleted eventually by a closure.
+    ///
+    /// This acts like `conc::add_garbage_with()`, but queues the garbage in this domain.
+    pub fn add_garbage_with<T: Sync, F>(&self, ptr: &'static T, dtor: F)
+    where F: FnOnce(*const u8) + Send + 'static {
+        self.add(Garbage::new_with(ptr as *const T as *const u8, dtor));
+    }
+
+    /// Add a heap-allocated `Box<T>` as garbage of this domain.
+    ///
+    /// This acts like `conc::add_garbage_box()`, but queues the garbage in this domain.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `conc::add_garbage_box()`.
+    pub unsafe fn add_garbage_box<T>(&self, ptr: *const T) {
+        self.add(Garbage::new_box(ptr));
+    }
+
+    /// Add a heap-allocated `Box<T>` holding `size` bytes as garbage of this domain.
+    ///
+    /// This acts like `conc::add_garbage_box_sized()`, but queues the garbage in this domain.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `conc::add_garbage_box()`.
+    pub unsafe fn add_garbage_box_sized<T>(&self, ptr: *const T, size: usize) {
+        self.add(Garbage::new_box(ptr).with_size(size));
+    }
+
+    /// Attempt to collect the garbage of this domain.
+    ///
+    /// If another thread is currently collecting the garbage of this domain, `Err(WouldBlock)`
+    /// is returned. See `conc::try_gc()`.
+    pub fn try_gc(&self) -> Result<(), WouldBlock> {
+        self.state.try_gc()
+    }
+
+    /// Attempt to collect some of the garbage of this domain.
+    ///
+    /// This acts like `conc::gc_with_budget()`, but for the garbage of this domain.
+    pub fn gc_with_budget(&self, max_items: usize) -> Result<(), WouldBlock> {
+        self.state.try_gc_with_budget(max_items)
+    }
+
+    /// Collect the garbage of this domain.
+    ///
+    /// This blocks until it can collect. See `conc::gc()`.
+    pub fn gc(&self) {
+        while let Err(WouldBlock) = self.try_gc() {}
+    }
+}
+
+/// The domain of an `Atomic<T, D>`.
+///
+/// This selects where the values of the atomic are protected and reclaimed. It is implemented by
+/// `Global` (the default), which takes no space, so an `Atomic<T>` stays a single pointer wide, and
+/// by `&'static Domain`.
+pub trait Select {
+    /// Get the domain, or `None` for the global state.
+    fn domain(&self) -> Option<&'static Domain>;
+}
+
+/// The global state, acting as the default domain.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+impl Select for Global {
+    #[inline]
+    fn domain(&self) -> Option<&'static Domain> {
+        None
+    }
+}
+
+impl Select for &'static Domain {
+    #[inline]
+    fn domain(&self) -> Option<&'static Domain> {
+        Some(*self)
+    }
+}
+
+/// Run a closure with a scoped domain.
+///
+/// The closure gets a handle to a fresh domain, which can protect and destroy objects living for
+/// `'a` (rather than `'static`). Guards of the scope cannot outlive it, and when the scope ends
+/// (by the closure returning or panicking), all the garbage of the domain is destroyed, before
+/// the data it points to goes out of scope.
+///
+/// If a guard of the scope was leaked (e.g. through `mem::forget`), the object it protects is
+/// never destroyed (nor is the domain), as that would violate the guard.
+pub fn scope<'a, F, R>(f: F) -> R
+where F: FnOnce(&Scope<'a>) -> R {
+    let scope = Scope {
+        // The domain is freed by the destructor of the scope.
+        domain: mem::ManuallyDrop::new(Box::new(Domain::new())),
+        guards: AtomicUsize::new(0),
+        _marker: PhantomData,
+    };
+
+    f(&scope)
+}
+
+/// A scoped domain.
+///
+/// This is created through `scope()`.
+pub struct Scope<'a> {
+    /// The domain of the scope.
+    ///
+    /// It is only dropped by the destructor of the scope if no guard was leaked (see `domain()`).
+    domain: mem::ManuallyDrop<Box<Domain>>,
+    /// The number of live guards of the scope.
+    guards: AtomicUsize,
+    /// Make the scope invariant over `'a`.
+    _marker: PhantomData<fn(&'a ()) -> &'a ()>,
+}
+
+impl<'a> Scope<'a> {
+    /// Get the domain of the scope.
+    ///
+    /// This is only `'static` to the hazards, which never outlive the scope. The reference is
+    /// derived from the box, as the box must keep the right to deallocate the domain.
+    fn domain(&self) -> &'static Domain {
+        unsafe { &*(&**self.domain as *const Domain) }
+    }
+
+    /// Create a guard in the scope.
+    ///
+    /// This acts like `Guard::new()`, but the pointer needs only live for `'a`, and the guard is
+    /// bound to the scope.
+    pub fn protect<T: ?Sized + 'a, F>(&self, ptr: F) -> ScopedGuard<T>
+    where F: FnOnce() -> &'a T {
+        // Get a hazard in blocked state, such that no garbage collection of the domain happens
+        // until the pointer is read and protected.
+        let hazard = self.domain().get_hazard();
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        let ptr = ptr();
+        hazard.protect(ptr as *const T as *const u8);
+        self.guards.fetch_add(1, atomic::Ordering::Relaxed);
+
+        ScopedGuard {
+            hazard: Some(hazard),
+            pointer: ptr,
+            guards: &self.guards,
+        }
+    }
+
+    /// Declare a pointer unreachable garbage of the scope to be deleted eventually.
+    ///
+    /// This acts like `Domain::add_garbage()`, but `ptr` needs only live for `'a`. The garbage is
+    /// destroyed at the latest when the scope ends.
+    pub fn add_garbage<T: Sync + 'a>(&self, ptr: &'a T, dtor: fn(&'a T)) {
+        self.domain().add(unsafe {
+            Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
+        });
+    }
+
+    /// Add a heap-allocated `Box<T>` as garbage of the scope.
+    ///
+    /// This acts like `Domain::add_garbage_box()`, but `T` needs only live for `'a`.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `conc::add_garbage_box()`.
+    pub unsafe fn add_garbage_box<T: 'a>(&self, ptr: *const T) {
+        self.domain().add(Garbage::new_box(ptr));
+    }
+
+    /// Collect the garbage of the scope.
+    ///
+    /// This blocks until it can collect. See `conc::gc()`.
+    pub fn gc(&self) {
+        self.domain().gc();
+    }
+}
+
+impl<'a> Drop for Scope<'a> {
+    fn drop(&mut self) {
+        // Every guard borrows the scope, so they are all gone, unless leaked.
+        if self.guards.load(atomic::Ordering::Relaxed) == 0 {
+            // Nothing is protected, so a collection destroys all the garbage, and the domain is
+            // freed (with a final collection destroying the dead hazards).
+            self.domain().gc();
+            // The cached hazards refer to the domain, so they are killed through a shared reference
+            // before the domain is dropped.
+            self.domain().kill_cached_hazards();
+            unsafe { mem::ManuallyDrop::drop(&mut self.domain); }
+        } else {
+            // Destroy what isn't protected, and leak the rest along with the domain, as the leaked
+            // guards still refer to it.
+            self.domain().gc();
+        }
+    }
+}
+
+/// A guard of a scoped domain.
+///
+/// This acts like `Guard<T>`, but is bound to its scope (see `scope()`).
+#[must_use = "\
+    You are getting a `conc::domain::ScopedGuard<T>` without using it, which means it is \
+    potentially unnecessary overhead.\
+"]
+pub struct ScopedGuard<'s, T: 's + ?Sized> {
+    /// The inner hazard.
+    ///
+    /// This is only `None` during destruction.
+    hazard: Option<hazard::Writer>,
+    /// The pointer to the protected object.
+    pointer: &'s T,
+    /// The number of live guards of the scope.
+    guards: &'s AtomicUsize,
+}
+
+impl<'s, T: ?Sized> ops::Deref for ScopedGuard<'s, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+impl<'s, T: ?Sized> Drop for ScopedGuard<'s, T> {
+    fn drop(&mut self) {
+        // Relocate the hazard to the domain before the scope can see the guard gone.
+        drop(self.hazard.take());
+        self.guards.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Domain")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl Default for Domain {
+    fn default() -> Domain {
+        Domain::new()
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        self.kill_cached_hazards();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{self, AtomicUsize};
+    use {Atomic, Guard};
+
+    lazy_static! {
+        static ref DOMAIN: Domain = Domain::new();
+        static ref OTHER: Domain = Domain::new();
+        static ref EAGER: Domain = Domain::with_settings(Settings {
+            gc_probability: !0,
+            .. Settings::default()
+        });
+        static ref LAZY: Domain = Domain::with_settings(Settings {
+            gc_probability: 0,
+            max_garbage_bytes: 1 << 20,
+            .. Settings::default()
+        });
+        static ref FIFO: Domain = Domain::new().fifo();
+    }
+
+    fn dtor(x: &'static AtomicUsize) {
+        x.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    #[test]
+    fn collect() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        let guard = Guard::new_in(&OTHER, || &X);
+        OTHER.add_garbage(&X, dtor);
+        OTHER.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 0);
+
+        drop(guard);
+        OTHER.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn isolated() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        // A guard of the default domain doesn't protect garbage of other domains.
+        let _guard = Guard::new(|| &X);
+        DOMAIN.add_garbage(&X, dtor);
+        DOMAIN.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+
+        // Nor is the garbage of other domains collected by the default domain.
+        DOMAIN.add_garbage(&X, dtor);
+        ::gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+        DOMAIN.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn add_garbage_with() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let guard = Guard::new_in(&OTHER, || &X);
+        let counter2 = counter.clone();
+        OTHER.add_garbage_with(&X, move |ptr| {
+            assert_eq!(ptr, &X as *const AtomicUsize as *const u8);
+            counter2.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+        OTHER.gc();
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 0);
+
+        drop(guard);
+        OTHER.gc();
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn collect_large_garbage() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        struct Foo(&'static AtomicUsize);
+
+        impl Drop for Foo {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        // Small garbage is kept.
+        unsafe { LAZY.add_garbage_box_sized(Box::into_raw(Box::new(Foo(&X))), 1000); }
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 0);
+
+        // Large garbage triggers a collection.
+        unsafe { LAZY.add_garbage_box_sized(Box::into_raw(Box::new(Foo(&X))), 1 << 20); }
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn own_settings() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        // The thread settings never collect, but the domain settings always do.
+        let mut settings = settings::get();
+        settings.disable_automatic_gc();
+        settings::set_local(settings);
+
+        assert_eq!(EAGER.settings().gc_probability, !0);
+        EAGER.add_garbage(&X, dtor);
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+
+        // Avoid messing with other tests.
+        settings::set_local(Settings::default());
+    }
+
+    #[test]
+    fn atomic() {
+        let a = Atomic::new_in(Some(Box::new(42)), &DOMAIN);
+        let guard = a.swap(Some(Box::new(43)), atomic::Ordering::Relaxed).unwrap();
+        DOMAIN.gc();
+
+        assert_eq!(*guard, 42);
+        assert_eq!(*a.load(atomic::Ordering::Relaxed).unwrap(), 43);
+    }
+
+    #[test]
+    fn fifo() {
+        use std::sync::Mutex;
+
+        lazy_static! {
+            static ref LOG: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        }
+        static X: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(1),
+                                      AtomicUsize::new(2)];
+
+        fn dtor(x: &'static AtomicUsize) {
+            LOG.lock().unwrap().push(x.load(atomic::Ordering::Relaxed));
+        }
+
+        let guard = Guard::new_in(&FIFO, || &X[1]);
+        for x in &X {
+            FIFO.add_garbage(x, dtor);
+        }
+        FIFO.gc();
+
+        // The garbage added after the protected object is held back.
+        assert_eq!(*LOG.lock().unwrap(), [0]);
+
+        drop(guard);
+        FIFO.gc();
+        assert_eq!(*LOG.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn scoped() {
+        let x = AtomicUsize::new(0);
+        let y = AtomicUsize::new(0);
+
+        fn dtor(x: &AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        scope(|s| {
+            let guard = s.protect(|| &x);
+            s.add_garbage(&x, dtor);
+            s.add_garbage(&y, dtor);
+            s.gc();
+
+            // Only the unprotected object is destroyed.
+            assert_eq!(x.load(atomic::Ordering::Relaxed), 0);
+            assert_eq!(y.load(atomic::Ordering::Relaxed), 1);
+            assert_eq!(guard.load(atomic::Ordering::Relaxed), 0);
+        });
+
+        // The rest is destroyed when the scope ends.
+        assert_eq!(x.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn scoped_leak() {
+        let x = AtomicUsize::new(0);
+
+        fn dtor(x: &AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        scope(|s| {
+            mem::forget(s.protect(|| &x));
+            s.add_garbage(&x, dtor);
+        });
+
+        // The object is still protected by the leaked guard.
+        assert_eq!(x.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn reuse_hazards() {
+        for _ in 0..1000 {
+            let _ = Guard::new_in(&DOMAIN, || "blah");
+        }
+
+        assert!(DOMAIN.hazards.lock().len() <= 4);
+    }
+}
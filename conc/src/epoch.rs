@@ -0,0 +1,343 @@
+//! Epoch-based protection.
+//!
+//! With feature `epoch`, the guards of the default domain pin the current thread rather than
+//! publishing a hazard. A pinned thread protects all the garbage retired while it is pinned (and
+//! shortly before), so reading through a guard needs neither a hazard nor a validating reload, and
+//! nested guards only bump a counter. This pays off for read-mostly structures, at the cost of
+//! garbage accumulating while some thread stays pinned.
+//!
+//! This is the usual epoch scheme: There is a global epoch, and every thread has a record holding
+//! the epoch it was pinned in. The epoch can only be advanced when every pinned thread was pinned
+//! in the current epoch. Garbage is tagged with the epoch at the time of its collection (which is
+//! after it was unlinked), and it is only destroyed once the epoch advanced twice past its tag,
+//! as no pinned thread can reach it then. It is still subject to the hazards as well, so hazards
+//! (e.g. those of guards created during thread destruction) and pins coexist.
+//!
+//! The epoch is only advanced by the garbage collection of the default domain (see
+//! `global::State::with_epochs()`).
+//!
+//! # Quiescent states
+//!
+//! Threads with natural quiescent points (e.g. an iteration of an event loop) can instead stay
+//! pinned permanently ("online"), and announce their quiescent states (see `quiescent()`), at
+//! which they update the epoch of their pin. No garbage unlinked before a quiescent state of the
+//! thread can be reached by it afterwards. Reads then cost nothing, and guards are nested pins,
+//! which don't need a fence.
+
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::fmt;
+
+use atomics::{self as atomic, AtomicBool, AtomicUsize};
+
+/// The number of bits of the pin counter of a record.
+///
+/// The rest of the bits hold the epoch the thread was pinned in, truncated. Pinned records
+/// prevent the epoch from advancing more than once past their epoch, so the truncation never
+/// makes a stale record look current.
+const COUNT_BITS: usize = 16;
+/// The mask of the pin counter of a record.
+const COUNT_MASK: usize = (1 << COUNT_BITS) - 1;
+
+/// The global epoch.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// The records of the threads.
+    ///
+    /// Records are never deallocated, but are reused once their thread exits.
+    static ref RECORDS: Mutex<Vec<&'static Record>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    /// The record of the current thread.
+    static LOCAL: Local = Local {
+        record: Record::acquire(),
+        online: RefCell::new(None),
+    };
+}
+
+/// The epoch record of a thread.
+struct Record {
+    /// The truncated epoch the thread was pinned in, and the number of active pins.
+    ///
+    /// The thread is pinned if the number of pins is non-zero.
+    state: AtomicUsize,
+    /// Is the record owned by a thread?
+    owned: AtomicBool,
+}
+
+impl Record {
+    /// Acquire a record for the current thread.
+    ///
+    /// This reuses the record of an exited thread, if any.
+    fn acquire() -> &'static Record {
+        let mut records = RECORDS.lock();
+
+        for &record in records.iter() {
+            // Only the owner of a record pins it, so an unowned, unpinned record stays unpinned.
+            // Records still pinned (by guards sent away by the exited thread) are skipped, as the
+            // new owner would otherwise inherit their epoch.
+            if record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK == 0
+                && !record.owned.swap(true, atomic::Ordering::Relaxed) {
+                return record;
+            }
+        }
+
+        let record = Box::leak(Box::new(Record {
+            state: AtomicUsize::new(0),
+            owned: AtomicBool::new(true),
+        }));
+        records.push(record);
+        record
+    }
+}
+
+/// The thread-local state.
+struct Local {
+    /// The record of the thread.
+    record: &'static Record,
+    /// The permanent pin of the thread, if it is online.
+    online: RefCell<Option<Pin>>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        // Release the record, such that a new thread can reuse it.
+        self.record.owned.store(false, atomic::Ordering::Relaxed);
+    }
+}
+
+/// A pin of a thread.
+///
+/// As long as this is alive, the thread is pinned, protecting the garbage retired meanwhile from
+/// being destroyed. It can be sent to other threads.
+#[derive(Debug)]
+pub struct Pin {
+    /// The record of the pinned thread.
+    record: &'static Record,
+}
+
+impl Clone for Pin {
+    fn clone(&self) -> Pin {
+        // The record is pinned already, so this only increments the number of pins. Pins might be
+        // cloned in other threads concurrently, so the bound is checked in the loop.
+        let mut state = self.record.state.load(atomic::Ordering::Relaxed);
+        loop {
+            assert!(state & COUNT_MASK != COUNT_MASK, "Too many pins of a thread.");
+            match self.record.state.compare_exchange_weak(state, state + 1,
+                                                          atomic::Ordering::Relaxed,
+                                                          atomic::Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+
+        Pin {
+            record: self.record,
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        // Order the reads of the protected objects before the unpinning.
+        self.record.state.fetch_sub(1, atomic::Ordering::Release);
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.load(atomic::Ordering::Relaxed);
+        f.debug_struct("Record")
+            .field("epoch", &(state >> COUNT_BITS))
+            .field("pins", &(state & COUNT_MASK))
+            .finish()
+    }
+}
+
+/// Pin the current thread.
+///
+/// If the thread is already pinned, this only increments the number of pins. `None` is returned
+/// if the thread-local state was deinitialized.
+///
+/// # Panics
+///
+/// This panics if the thread is pinned by too many guards at once (65535).
+pub fn pin() -> Option<Pin> {
+    LOCAL.try_with(|local| {
+        let record = local.record;
+        let mut state = record.state.load(atomic::Ordering::Relaxed);
+        loop {
+            let new = if state & COUNT_MASK == 0 {
+                // The first pin records the current epoch.
+                (EPOCH.load(atomic::Ordering::Relaxed) << COUNT_BITS) | 1
+            } else {
+                assert!(state & COUNT_MASK != COUNT_MASK, "Too many pins of a thread.");
+                state + 1
+            };
+
+            // Pins sent to other threads might be dropped concurrently, so the epoch and the
+            // counter are updated together.
+            match record.state.compare_exchange_weak(state, new, atomic::Ordering::Relaxed,
+                                                     atomic::Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+
+        if state & COUNT_MASK == 0 {
+            // Make sure that the pin is published before we read anything it protects. This pairs
+            // with the fence of `try_advance()`: Either the epoch is not advanced past our pin, or
+            // we see that the garbage was unlinked.
+            atomic::fence(atomic::Ordering::SeqCst);
+        }
+
+        Pin {
+            record: record,
+        }
+    }).ok()
+}
+
+/// Announce a quiescent state of the current thread.
+///
+/// If the thread is offline, this brings it online. Otherwise, if no guard of the thread is alive,
+/// the epoch of its pin is updated, allowing the garbage unlinked so far to be destroyed. If
+/// guards are alive, they might protect the garbage, so nothing is done.
+pub fn quiescent() {
+    let _ = LOCAL.try_with(|local| {
+        let mut online = local.online.borrow_mut();
+        if online.is_none() {
+            *online = pin();
+            return;
+        }
+
+        let record = local.record;
+        let state = record.state.load(atomic::Ordering::Relaxed);
+        if state & COUNT_MASK == 1 {
+            // Only the permanent pin is left, and only the owner pins the record, so we can unpin
+            // and pin it again at once. The unpinning orders the preceding reads before it.
+            let new = (EPOCH.load(atomic::Ordering::Relaxed) << COUNT_BITS) | 1;
+            record.state.store(new, atomic::Ordering::Release);
+            // Like in `pin()`, the new pin must be published before the following reads.
+            atomic::fence(atomic::Ordering::SeqCst);
+        }
+    });
+}
+
+/// Take the current thread offline.
+///
+/// This ends the permanent pin of the thread, if it is online (see `quiescent()`).
+pub fn offline() {
+    let _ = LOCAL.try_with(|local| local.online.borrow_mut().take());
+}
+
+/// Get the current epoch.
+pub fn current() -> usize {
+    EPOCH.load(atomic::Ordering::Relaxed)
+}
+
+/// Try to advance the epoch up to `n` times.
+///
+/// The epoch is advanced as long as every pinned thread was pinned in the current epoch. The
+/// resulting epoch is returned.
+pub fn try_advance(n: usize) -> usize {
+    // The lock also serializes the advances.
+    let records = RECORDS.lock();
+    let mut epoch = EPOCH.load(atomic::Ordering::Relaxed);
+
+    for _ in 0..n {
+        // Make sure that the unlinking of the garbage is ordered before reading the pins (see
+        // `pin()`).
+        atomic::fence(atomic::Ordering::SeqCst);
+
+        for record in records.iter() {
+            let state = record.state.load(atomic::Ordering::Relaxed);
+            if state & COUNT_MASK != 0 && state >> COUNT_BITS != epoch & (!0 >> COUNT_BITS) {
+                // The thread was pinned in an earlier epoch, and might still read garbage
+                // unlinked in the epoch before the current.
+                return epoch;
+            }
+        }
+
+        // Order the reads of the unpinned threads before the destruction.
+        atomic::fence(atomic::Ordering::Acquire);
+        epoch = epoch.wrapping_add(1);
+        EPOCH.store(epoch, atomic::Ordering::Relaxed);
+    }
+
+    epoch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // The epoch is global, so these only test the effects of the pins of the current thread.
+
+    #[test]
+    fn nested_pins() {
+        let a = pin().unwrap();
+        let b = pin().unwrap();
+        assert_eq!(a.record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK, 2);
+        drop(a);
+        assert_eq!(b.record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK, 1);
+        let record = b.record;
+        drop(b);
+        assert_eq!(record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK, 0);
+    }
+
+    #[test]
+    fn pin_blocks_advance() {
+        let pin = pin().unwrap();
+        let pinned = pin.record.state.load(atomic::Ordering::Relaxed) >> COUNT_BITS;
+        // The epoch can advance at most once past the pin.
+        let epoch = try_advance(4);
+        assert!(epoch.wrapping_sub(pinned) & (!0 >> COUNT_BITS) <= 1);
+        drop(pin);
+    }
+
+    #[test]
+    fn quiescent_states() {
+        let state = || LOCAL.with(|local| local.record.state.load(atomic::Ordering::Relaxed));
+
+        quiescent();
+        assert_eq!(state() & COUNT_MASK, 1);
+
+        // The epoch can advance at most once past the online thread.
+        let stamped = state() >> COUNT_BITS;
+        let epoch = try_advance(4);
+        assert!(epoch.wrapping_sub(stamped) & (!0 >> COUNT_BITS) <= 1);
+
+        // Alive guards hold up the quiescent state.
+        let pin = pin().unwrap();
+        quiescent();
+        assert_eq!(state() >> COUNT_BITS, stamped);
+        drop(pin);
+
+        quiescent();
+        assert_eq!(state() & COUNT_MASK, 1);
+        assert!(current().wrapping_sub(state() >> COUNT_BITS) & (!0 >> COUNT_BITS) <= 1);
+
+        offline();
+        assert_eq!(state() & COUNT_MASK, 0);
+    }
+
+    #[test]
+    fn clone_pin() {
+        let a = pin().unwrap();
+        let b = a.clone();
+        assert_eq!(b.record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK, 2);
+        drop(a);
+        drop(b);
+    }
+
+    #[test]
+    fn send_pin() {
+        let pin = pin().unwrap();
+        let record = pin.record;
+        thread::spawn(move || drop(pin)).join().unwrap();
+        assert_eq!(record.state.load(atomic::Ordering::Relaxed) & COUNT_MASK, 0);
+    }
+}
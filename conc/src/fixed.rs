@@ -0,0 +1,487 @@
+//! Fixed-capacity domains.
+//!
+//! The default domain, `Domain`s and `Registry`s allocate hazards on demand and queue garbage in
+//! buffers, so they allocate every now and then, even in a steady state. A `FixedDomain` instead
+//! allocates a fixed number of hazard slots up front, which threads claim (see
+//! `FixedDomain::create()`), failing once all of them are claimed. Retired objects embed a `Link`,
+//! through which they are queued in an intrusive list. Hence, after the domain is created,
+//! protecting, retiring and collecting never allocate, which suits embedded and latency-critical
+//! users.
+//!
+//! Like with registries, pointers protected through a fixed domain must only be retired to the
+//! same domain.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::fixed::{FixedDomain, Link};
+//! use std::ptr;
+//! use std::sync::atomic::{AtomicPtr, Ordering};
+//!
+//! struct Node {
+//!     value: i32,
+//!     link: Link,
+//! }
+//!
+//! unsafe fn free(node: *const Node) {
+//!     drop(Box::from_raw(node as *mut Node));
+//! }
+//!
+//! let domain = FixedDomain::new(4);
+//! let mut slot = domain.create().unwrap();
+//!
+//! let shared = AtomicPtr::new(Box::into_raw(Box::new(Node { value: 42, link: Link::new() })));
+//! {
+//!     let guard = unsafe { slot.protect_with(|| shared.load(Ordering::Acquire)) };
+//!     assert_eq!(guard.unwrap().value, 42);
+//! }
+//!
+//! // Unlink the node, and retire it through its link.
+//! let old = shared.swap(ptr::null_mut(), Ordering::AcqRel);
+//! unsafe { domain.retire(old, &(*old).link, free); }
+//! ```
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use std::cell::UnsafeCell;
+use std::{fmt, mem, ops, ptr};
+
+use atomics::{self as atomic, AtomicPtr, AtomicUsize};
+use global::WouldBlock;
+use hazard;
+use mutex::Mutex;
+
+/// Pointers to this represent a claimed slot, which doesn't protect anything.
+///
+/// Null represents an unclaimed slot.
+static CLAIMED: u8 = 0;
+
+/// The number of retired objects per slot, at which the garbage is collected.
+///
+/// Every slot protects at most one object, so such a collection destroys at least half of the
+/// garbage, amortizing its cost.
+const GARBAGE_PER_SLOT: usize = 2;
+
+/// The error of claiming a slot of an exhausted domain.
+///
+/// This is returned by `FixedDomain::create()`, when all the slots are claimed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Exhausted;
+
+/// A domain with a fixed number of hazard slots.
+///
+/// See the module documentation.
+pub struct FixedDomain {
+    /// The hazard slots.
+    ///
+    /// A slot is null if it is unclaimed, points to `CLAIMED` if it doesn't protect anything, and
+    /// holds the protected pointer otherwise.
+    slots: Box<[AtomicPtr<u8>]>,
+    /// The head of the intrusive list of garbage.
+    garbage: AtomicPtr<Link>,
+    /// The number of retired objects, which are not yet destroyed.
+    retired: AtomicUsize,
+    /// The buffer of the pointers protected by the slots.
+    ///
+    /// This is only used by the collections, which the lock serializes. It holds a pointer per
+    /// slot, so it is never grown.
+    protected: Mutex<Box<[usize]>>,
+}
+
+impl FixedDomain {
+    /// Create a new domain with `capacity` hazard slots.
+    ///
+    /// This is the only allocation of the domain.
+    pub fn new(capacity: usize) -> FixedDomain {
+        FixedDomain {
+            slots: (0..capacity).map(|_| AtomicPtr::new(ptr::null_mut())).collect::<Vec<_>>()
+                .into_boxed_slice(),
+            garbage: AtomicPtr::new(ptr::null_mut()),
+            retired: AtomicUsize::new(0),
+            protected: Mutex::new((0..capacity).map(|_| 0).collect::<Vec<_>>().into_boxed_slice()),
+        }
+    }
+
+    /// Get the number of hazard slots.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Claim a hazard slot.
+    ///
+    /// The slot is released when it is dropped. If all the slots are claimed, `Err(Exhausted)` is
+    /// returned.
+    pub fn create(&self) -> Result<Slot, Exhausted> {
+        for hazard in self.slots.iter() {
+            if hazard.compare_exchange(ptr::null_mut(), &CLAIMED as *const u8 as *mut u8,
+                                       atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_ok() {
+                return Ok(Slot {
+                    hazard: hazard,
+                });
+            }
+        }
+
+        Err(Exhausted)
+    }
+
+    /// Retire an object.
+    ///
+    /// The object is queued in the garbage of the domain through `link`, and `dtor` is called with
+    /// `ptr` once no slot protects it. Once enough objects are retired, the garbage is collected.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be unreachable for the threads not already protecting it, and `dtor` must be
+    /// safe to call with it (from any thread). `link` must stay valid until `dtor` is called (e.g.
+    /// by being part of the object), and must not be retired again meanwhile.
+    pub unsafe fn retire<T>(&self, ptr: *const T, link: &Link, dtor: unsafe fn(*const T)) {
+        *link.ptr.get() = ptr as *const u8;
+        *link.dtor.get() = Some(mem::transmute(dtor));
+
+        // Count the object before it can be destroyed, such that the count never underflows.
+        let retired = self.retired.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+
+        let link = link as *const Link as *mut Link;
+        self.push(link, link);
+
+        if retired >= self.slots.len() * GARBAGE_PER_SLOT {
+            // The garbage is collected eventually either way, so we needn't wait.
+            let _ = self.try_gc();
+        }
+    }
+
+    /// Push a list of garbage (from `head` to `tail`) to the garbage of the domain.
+    unsafe fn push(&self, head: *mut Link, tail: *mut Link) {
+        let mut next = self.garbage.load(atomic::Ordering::Relaxed);
+        loop {
+            *(*tail).next.get() = next;
+            match self.garbage.compare_exchange_weak(next, head, atomic::Ordering::Release,
+                                                     atomic::Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => next = actual,
+            }
+        }
+    }
+
+    /// Attempt to collect the garbage of the domain.
+    ///
+    /// If another thread is currently collecting the garbage, `Err(WouldBlock)` is returned.
+    pub fn try_gc(&self) -> Result<(), WouldBlock> {
+        let mut protected = match self.protected.try_lock() {
+            Some(protected) => protected,
+            None => return Err(WouldBlock),
+        };
+
+        // Take out the garbage, and make sure that it was unlinked before we read the slots.
+        let mut link = self.garbage.swap(ptr::null_mut(), atomic::Ordering::Acquire);
+        hazard::fence();
+
+        // Sort the protected pointers, such that every piece of garbage can be looked up in
+        // O(log H) without allocating a set.
+        let mut len = 0;
+        for hazard in self.slots.iter() {
+            let ptr = hazard.load(atomic::Ordering::Acquire) as *const u8;
+            if !ptr.is_null() && ptr != &CLAIMED {
+                protected[len] = ptr as usize;
+                len += 1;
+            }
+        }
+        let protected = &mut protected[..len];
+        protected.sort_unstable();
+
+        // The garbage still protected, which is queued again.
+        let mut kept = ptr::null_mut();
+        let mut kept_tail = ptr::null_mut();
+        let mut destroyed = 0;
+        while !link.is_null() {
+            unsafe {
+                let next = *(*link).next.get();
+                let ptr = *(*link).ptr.get();
+
+                if protected.binary_search(&(ptr as usize)).is_ok() {
+                    *(*link).next.get() = kept;
+                    if kept.is_null() {
+                        kept_tail = link;
+                    }
+                    kept = link;
+                } else {
+                    // The link might be destroyed along with the object, so it must not be used
+                    // after this.
+                    let dtor = (*(*link).dtor.get()).take().expect("Retired object without destructor.");
+                    dtor(ptr);
+                    destroyed += 1;
+                }
+
+                link = next;
+            }
+        }
+
+        self.retired.fetch_sub(destroyed, atomic::Ordering::Relaxed);
+        if !kept.is_null() {
+            unsafe { self.push(kept, kept_tail); }
+        }
+
+        Ok(())
+    }
+
+    /// Collect the garbage of the domain.
+    ///
+    /// This acts like `try_gc()`, but waits for other collections.
+    pub fn gc(&self) {
+        while let Err(WouldBlock) = self.try_gc() {}
+    }
+}
+
+impl Drop for FixedDomain {
+    fn drop(&mut self) {
+        // The slots borrow the domain, so they are all released, and the collection destroys all
+        // the garbage.
+        self.gc();
+    }
+}
+
+impl fmt::Debug for FixedDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FixedDomain")
+            .field("capacity", &self.slots.len())
+            .finish()
+    }
+}
+
+/// The intrusive link of a retired object.
+///
+/// Objects retired to a `FixedDomain` embed a link, through which they are queued in the garbage
+/// of the domain, so retiring them allocates nothing.
+pub struct Link {
+    /// The next link in the garbage list.
+    next: UnsafeCell<*mut Link>,
+    /// The pointer to the retired object.
+    ptr: UnsafeCell<*const u8>,
+    /// The destructor of the retired object.
+    ///
+    /// This is `None` until the object is retired.
+    dtor: UnsafeCell<Option<unsafe fn(*const u8)>>,
+}
+
+impl Link {
+    /// Create a new link.
+    pub fn new() -> Link {
+        Link {
+            next: UnsafeCell::new(ptr::null_mut()),
+            ptr: UnsafeCell::new(ptr::null()),
+            dtor: UnsafeCell::new(None),
+        }
+    }
+}
+
+// The fields are only accessed by the retiring thread before the link is published, and by the
+// collection holding the lock afterwards.
+unsafe impl Send for Link {}
+unsafe impl Sync for Link {}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link::new()
+    }
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Link { .. }")
+    }
+}
+
+/// A claimed hazard slot of a fixed domain.
+///
+/// This is obtained through `FixedDomain::create()`, and protects one pointer at a time. The slot
+/// is released when this is dropped.
+pub struct Slot<'a> {
+    /// The hazard of the slot.
+    hazard: &'a AtomicPtr<u8>,
+}
+
+impl<'a> Slot<'a> {
+    /// Protect a pointer loaded from some shared location.
+    ///
+    /// This acts like `Guard::protect_with()`: The pointer is loaded with `load`, protected, and
+    /// validated by loading it again, until the two loads agree. If `load` returns the null
+    /// pointer, `None` is returned. The slot protects the pointer until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The pointers returned by `load` must be valid while they are reachable from the location,
+    /// and they must only be destroyed through the garbage of the domain of the slot after they
+    /// were made unreachable.
+    pub unsafe fn protect_with<T, F>(&mut self, mut load: F) -> Option<FixedGuard<T>>
+    where F: FnMut() -> *const T {
+        let mut ptr = load();
+        loop {
+            if ptr.is_null() {
+                return None;
+            }
+
+            // Publish the hazard, and make sure the publication is ordered before the validating
+            // load (see `hazard::fence()`).
+            self.hazard.store(ptr as *const u8 as *mut u8, atomic::Ordering::Release);
+            atomic::fence(atomic::Ordering::SeqCst);
+
+            // Validate that the pointer is still current.
+            let new = load();
+            if new == ptr {
+                return Some(FixedGuard {
+                    pointer: &*ptr,
+                    hazard: self.hazard,
+                });
+            }
+
+            // It was replaced, so we retry with the new pointer.
+            ptr = new;
+        }
+    }
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.hazard.store(ptr::null_mut(), atomic::Ordering::Release);
+    }
+}
+
+impl<'a> fmt::Debug for Slot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Slot { .. }")
+    }
+}
+
+/// A pointer protected by a slot of a fixed domain.
+///
+/// This acts like `Guard`, but borrows its slot, which stops protecting the pointer when this is
+/// dropped.
+#[must_use = "Protecting a pointer without using it is potentially unnecessary overhead."]
+pub struct FixedGuard<'a, T: 'a> {
+    /// The protected pointer.
+    pointer: &'a T,
+    /// The hazard of the slot.
+    hazard: &'a AtomicPtr<u8>,
+}
+
+impl<'a, T> FixedGuard<'a, T> {
+    /// Get the raw pointer.
+    pub fn as_ptr(&self) -> *const T {
+        self.pointer
+    }
+}
+
+impl<'a, T> ops::Deref for FixedGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for FixedGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("FixedGuard").field(self.pointer).finish()
+    }
+}
+
+impl<'a, T> Drop for FixedGuard<'a, T> {
+    fn drop(&mut self) {
+        // Order the reads of the object before the release of the protection.
+        self.hazard.store(&CLAIMED as *const u8 as *mut u8, atomic::Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+    use std::{ptr, thread};
+
+    struct Node {
+        value: usize,
+        link: Link,
+    }
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn free(node: *const Node) {
+        DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+        drop(Box::from_raw(node as *mut Node));
+    }
+
+    fn node(value: usize) -> *mut Node {
+        Box::into_raw(Box::new(Node {
+            value: value,
+            link: Link::new(),
+        }))
+    }
+
+    #[test]
+    fn protect_and_collect() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe fn dtor(x: *const AtomicUsize) {
+            (*x).fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let domain = FixedDomain::new(2);
+        let link = Link::new();
+        let mut slot = domain.create().unwrap();
+
+        let guard = unsafe { slot.protect_with(|| &X as *const AtomicUsize) }.unwrap();
+        unsafe { domain.retire(&X, &link, dtor); }
+        domain.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 0);
+
+        drop(guard);
+        domain.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+
+        assert!(unsafe { slot.protect_with(|| ptr::null::<u8>()) }.is_none());
+    }
+
+    #[test]
+    fn exhausted() {
+        let domain = FixedDomain::new(2);
+        let a = domain.create().unwrap();
+        let b = domain.create().unwrap();
+        assert_eq!(domain.create().unwrap_err(), Exhausted);
+
+        // Released slots can be claimed again.
+        drop(a);
+        let _c = domain.create().unwrap();
+        drop(b);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        lazy_static! {
+            static ref DOMAIN: FixedDomain = FixedDomain::new(4);
+        }
+
+        // Leak the shared location, so the threads can borrow it.
+        let shared: &'static AtomicPtr<Node> = Box::leak(Box::new(AtomicPtr::new(node(0))));
+
+        let threads: Vec<_> = (0..4).map(|_| thread::spawn(move || {
+            let mut slot = DOMAIN.create().unwrap();
+            for i in 0..1000 {
+                let old = shared.swap(node(i), atomic::Ordering::AcqRel);
+                unsafe { DOMAIN.retire(old, &(*old).link, free); }
+
+                let guard = unsafe { slot.protect_with(|| shared.load(atomic::Ordering::Acquire)) };
+                assert!(guard.unwrap().value < 1000);
+            }
+        })).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every retired node is destroyed, as the slots are released.
+        DOMAIN.gc();
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 4000);
+    }
+}
@@ -1,5 +1,10 @@
 //! Literal garbage.
 
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use std::{fmt, mem, slice};
 use debug;
 
 /// An object to be deleted eventually.
@@ -9,15 +14,59 @@ use debug;
 ///
 /// When it's dropped, the destructor of the garbage runs.
 ///
+/// Garbage can also be a batch of objects of the same type, which are destroyed together (see
+/// `Garbage::new_box_batch()`).
+///
 /// See also: ideology.
 #[derive(Debug)]
 pub struct Garbage {
     /// The pointer to the object.
+    ///
+    /// If this is a batch, it is the pointer to the first object of the batch.
     ptr: *const u8,
     /// The destructor of the object.
+    dtor: Destructor,
+    /// The approximate size of the garbage in bytes.
+    ///
+    /// This is used for triggering garbage collection when much memory is held up by garbage.
+    size: usize,
+}
+
+/// The destructor of some garbage.
+#[derive(Debug)]
+enum Destructor {
+    /// The destructor of a single object.
+    ///
+    /// The argument given when called is the `Garbage.ptr` field.
+    Single(unsafe fn(*const u8)),
+    /// The destructor of a batch of objects.
+    ///
+    /// The destructor is called once with all the pointers of the batch, such that the individual
+    /// objects needn't have an individual (virtual) call each.
+    Batch {
+        /// The pointers to the objects of the batch.
+        ///
+        /// This is never empty, and the first element equals `Garbage.ptr`.
+        ptrs: Vec<*const u8>,
+        /// The destructor of the batch.
+        dtor: unsafe fn(&[*const u8]),
+    },
+    /// A closure destructor.
     ///
-    /// The argument given when called is the `self.ptr` field.
-    dtor: unsafe fn(*const u8),
+    /// The argument given when called is the `Garbage.ptr` field.
+    Closure(Closure),
+}
+
+/// A boxed closure destructor.
+///
+/// Boxed `FnOnce` closures cannot be called, so the closure is wrapped in an `FnMut` taking it
+/// out of an `Option`. It is called at most once.
+struct Closure(Box<FnMut(*const u8) + Send>);
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Closure")
+    }
 }
 
 impl Garbage {
@@ -30,7 +79,26 @@ impl Garbage {
 
         Garbage {
             ptr: ptr,
-            dtor: dtor,
+            dtor: Destructor::Single(dtor),
+            size: 0,
+        }
+    }
+
+    /// Create a new garbage item with a closure destructor.
+    ///
+    /// This acts like `new()`, but the destructor is an arbitrary closure, which can hold state of
+    /// its own (e.g. an arena to free into). It is called with `ptr` as argument.
+    pub fn new_with<F>(ptr: *const u8, dtor: F) -> Garbage
+    where F: FnOnce(*const u8) + Send + 'static {
+        debug_assert!(ptr as usize > 0, "Creating garbage with invalid pointer.");
+
+        let mut dtor = Some(dtor);
+        Garbage {
+            ptr: ptr,
+            dtor: Destructor::Closure(Closure(Box::new(move |ptr| {
+                (dtor.take().expect("Closure destructor called twice."))(ptr)
+            }))),
+            size: 0,
         }
     }
 
@@ -60,14 +128,97 @@ impl Garbage {
 
         Garbage {
             ptr: item as *const u8,
-            dtor: dtor::<T>,
+            dtor: Destructor::Single(dtor::<T>),
+            size: mem::size_of::<T>(),
         }
     }
 
+    /// Create a garbage item deallocating and dropping a box of an unsized type.
+    ///
+    /// This acts like `new_box()`, but `T` can be unsized (e.g. a trait object or a slice). The
+    /// garbage only holds the data pointer (which is what hazards protect), while the metadata is
+    /// kept by the destructor, which is a closure.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `new_box()`.
+    pub unsafe fn new_box_dyn<T: ?Sized + 'static>(item: *const T) -> Garbage {
+        /// The fat pointer to the box, which is sent to the collecting thread.
+        struct Fat<T: ?Sized>(*mut T);
+        unsafe impl<T: ?Sized> Send for Fat<T> {}
+
+        let size = mem::size_of_val(&*item);
+        let fat = Fat(item as *mut T);
+        Garbage::new_with(item as *const u8, move |_| drop(Box::from_raw(fat.0))).with_size(size)
+    }
+
+    /// Create a garbage item deallocating and dropping a batch of boxes.
+    ///
+    /// This acts like `new_box()`, but for a batch of boxes of the same type. The batch is queued,
+    /// collected and destroyed as a whole, with a single destructor call, cutting the overhead of
+    /// retiring many objects at once (e.g. when clearing a data structure).
+    ///
+    /// The batch is only destroyed when no object of it is protected.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `new_box()`.
+    pub unsafe fn new_box_batch<T>(items: Vec<*const T>) -> Garbage {
+        unsafe fn dtor<T>(ptrs: &[*const u8]) {
+            for &ptr in ptrs {
+                // Drop the box represented by `ptr`.
+                drop(Box::from_raw(ptr as *mut u8 as *mut T));
+            }
+        }
+
+        debug_assert!(!items.is_empty(), "Creating an empty garbage batch.");
+
+        // A `Vec<*const T>` has the same representation as a `Vec<*const u8>`, so we can simply
+        // rebuild it.
+        let size = items.len() * mem::size_of::<T>();
+        let mut items = items;
+        let ptrs = Vec::from_raw_parts(items.as_mut_ptr() as *mut *const u8, items.len(),
+                                       items.capacity());
+        mem::forget(items);
+
+        Garbage {
+            ptr: ptrs[0],
+            dtor: Destructor::Batch {
+                ptrs: ptrs,
+                dtor: dtor::<T>,
+            },
+            size: size,
+        }
+    }
+
+    /// Set the approximate size of the garbage in bytes.
+    ///
+    /// This should include the memory freed by the destructor (e.g. the heap buffers owned by the
+    /// object). See `Settings::max_garbage_bytes`.
+    pub fn with_size(mut self, size: usize) -> Garbage {
+        self.size = size;
+        self
+    }
+
+    /// Get the approximate size of the garbage in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     /// Get the inner pointer of the garbage.
+    ///
+    /// If this is a batch, the pointer of the first object is returned.
     pub fn ptr(&self) -> *const u8 {
         self.ptr
     }
+
+    /// Get the pointers to all the objects of the garbage.
+    pub fn ptrs(&self) -> &[*const u8] {
+        match self.dtor {
+            Destructor::Single(_) | Destructor::Closure(_) => slice::from_ref(&self.ptr),
+            Destructor::Batch { ref ptrs, .. } => ptrs,
+        }
+    }
 }
 
 impl Drop for Garbage {
@@ -75,7 +226,11 @@ impl Drop for Garbage {
         // Print message in debug mode.
         debug::exec(|| println!("Destroying garbage: {:?}", self));
 
-        unsafe { (self.dtor)(self.ptr); }
+        match self.dtor {
+            Destructor::Single(dtor) => unsafe { dtor(self.ptr); },
+            Destructor::Batch { ref ptrs, dtor } => unsafe { dtor(ptrs); },
+            Destructor::Closure(Closure(ref mut dtor)) => dtor(self.ptr),
+        }
     }
 }
 
@@ -85,13 +240,16 @@ unsafe impl Send for Garbage {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use provenance;
     use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     fn nop(_: *const u8) {}
 
     #[test]
     fn ptr() {
-        let g = Garbage::new(0x2 as *const u8, nop);
+        let g = Garbage::new(provenance::dangling(0x2), nop);
         assert_eq!(g.ptr() as usize, 2);
     }
 
@@ -102,6 +260,66 @@ mod tests {
         }
     }
 
+    #[test]
+    fn new_box_dyn() {
+        let freed = Arc::new(AtomicUsize::new(0));
+
+        /// An object counting its drops.
+        struct Dropper(Arc<AtomicUsize>);
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let b: Box<Send> = Box::new(Dropper(freed.clone()));
+        let ptr = Box::into_raw(b);
+        let g = unsafe { Garbage::new_box_dyn(ptr) };
+        assert_eq!(g.ptr(), ptr as *const u8);
+        assert_eq!(g.size(), mem::size_of::<Dropper>());
+        drop(g);
+        assert_eq!(freed.load(Ordering::Relaxed), 1);
+
+        let g = unsafe { Garbage::new_box_dyn(Box::into_raw(vec![0u16; 10].into_boxed_slice())) };
+        assert_eq!(g.size(), 20);
+    }
+
+    #[test]
+    fn new_box_batch() {
+        for _ in 0..1000 {
+            let batch = (0..10).map(|x| Box::into_raw(Box::new(x)) as *const _).collect();
+            let g = unsafe { Garbage::new_box_batch::<i32>(batch) };
+            assert_eq!(g.ptrs().len(), 10);
+            assert_eq!(g.ptrs()[0], g.ptr());
+            assert_eq!(g.size(), 40);
+        }
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(Garbage::new(provenance::dangling(0x2), nop).size(), 0);
+        assert_eq!(Garbage::new(provenance::dangling(0x2), nop).with_size(1000).size(), 1000);
+
+        let g = unsafe { Garbage::new_box(Box::into_raw(Box::new([0u64; 4]))) };
+        assert_eq!(g.size(), 32);
+    }
+
+    #[test]
+    fn new_with() {
+        let freed = Arc::new(AtomicUsize::new(0));
+
+        let freed2 = freed.clone();
+        let g = Garbage::new_with(provenance::dangling(0x8), move |ptr| {
+            freed2.fetch_add(ptr as usize, Ordering::Relaxed);
+        });
+        assert_eq!(g.ptrs(), &[provenance::dangling(0x8)]);
+        assert_eq!(freed.load(Ordering::Relaxed), 0);
+
+        drop(g);
+        assert_eq!(freed.load(Ordering::Relaxed), 8);
+    }
+
     #[cfg(debug_assertions)]
     #[test]
     #[should_panic]
@@ -1,50 +1,382 @@
 //! The global state.
 
-use parking_lot::Mutex;
-use std::collections::HashSet;
-use std::{mem, panic};
-use {rand, hazard, mpsc, debug, settings};
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeSet as HashSet;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use cbloom::Filter;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
+use std::mem;
+#[cfg(feature = "std")]
+use std::{panic, ptr};
+#[cfg(feature = "std")]
+use std::time::Instant;
+#[cfg(feature = "std")]
+use atomics::AtomicPtr;
+use atomics::{self as atomic, AtomicUsize};
+use {allocator, hazard, mpsc, debug, metrics};
+#[cfg(feature = "std")]
+use {rand, collector, settings};
+use metrics::Stats;
+#[cfg(feature = "std")]
+use debug::{Dump, HazardDump, HazardState};
 use garbage::Garbage;
+use mutex::Mutex;
+#[cfg(feature = "epoch")]
+use epoch;
 
+#[cfg(feature = "std")]
 lazy_static! {
     /// The global state.
     ///
-    /// This state is shared between all the threads.
-    static ref STATE: State = State::new();
+    /// This state is shared between all the threads. With feature `epoch`, its guards pin the
+    /// threads rather than using hazards (see `epoch`).
+    static ref STATE: State = {
+        #[cfg(feature = "epoch")]
+        let state = State::with_epochs();
+        #[cfg(not(feature = "epoch"))]
+        let state = State::new();
+
+        state
+    };
+
+    /// The callbacks to run when objects are reclaimed, by the address of the object.
+    ///
+    /// See `on_reclaim()`.
+    static ref RECLAIM_CALLBACKS: Mutex<HashMap<usize, Vec<ReclaimCallback>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The number of bytes of the hazard filter per protected pointer.
+///
+/// This gives a false positive rate of about 0.05%.
+const FILTER_BYTES_PER_HAZARD: usize = 16;
+
+/// The maximal number of garbage buffers kept for reuse by a state.
+const MAX_RECYCLED_BUFFERS: usize = 256;
+/// The maximal capacity of a garbage buffer kept for reuse.
+///
+/// Larger buffers (e.g. from threads with automatic exportation disabled) are deallocated, as
+/// they would hold up much memory.
+const MAX_RECYCLED_CAPACITY: usize = 1024;
+
+/// The number of garbage destructors which panicked.
+#[cfg(feature = "std")]
+static DESTRUCTOR_PANICS: AtomicUsize = AtomicUsize::new(0);
+
+/// A callback to run when an object is reclaimed.
+#[cfg(feature = "std")]
+type ReclaimCallback = Box<FnMut() + Send>;
+
+/// The number of objects with reclamation callbacks.
+///
+/// This allows the collector to skip looking up the callbacks, when none are registered.
+#[cfg(feature = "std")]
+static RECLAIM_CALLBACK_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The maximal number of hazards kept for recycling.
+///
+/// The hazards of exited threads beyond this are killed, as they would hold up much memory.
+const MAX_RECYCLED_HAZARDS: usize = 1024;
+
+/// The free-list of the hazards of exited threads.
+///
+/// This is a lock-free stack of batches, each holding the cached hazards of a thread. Batches are
+/// pushed one at a time, but only ever popped by taking the whole stack, so the stack doesn't suffer
+/// from the ABA problem, and no batch is freed while another thread reads it.
+#[cfg(feature = "std")]
+static RECYCLED: AtomicPtr<Batch> = AtomicPtr::new(ptr::null_mut());
+/// The approximate number of hazards in the free-list.
+#[cfg(feature = "std")]
+static RECYCLED_HAZARDS: AtomicUsize = AtomicUsize::new(0);
+
+/// A batch of free hazards in the free-list.
+#[cfg(feature = "std")]
+struct Batch {
+    /// The hazards, which are all in state "free".
+    hazards: Vec<hazard::Writer>,
+    /// The next batch in the free-list.
+    next: *mut Batch,
 }
 
 /// Create a new hazard.
 ///
 /// This creates a new hazard and registers it in the global state. It's secondary, writer part is
 /// returned.
+#[cfg(feature = "std")]
 pub fn create_hazard() -> hazard::Writer {
     STATE.create_hazard()
 }
 
+/// Recycle the hazards of an exiting thread.
+///
+/// The hazards are set to "free" and put in the free-list, from which other threads take them
+/// (see `take_recycled_hazards()`) rather than registering new hazards. This saves allocating and
+/// destroying hazards in workloads spawning many short-lived threads. If the free-list is full, the
+/// hazards are killed instead.
+#[cfg(feature = "std")]
+pub fn recycle_hazards(hazards: Vec<hazard::Writer>) {
+    if hazards.is_empty() {
+        return;
+    }
+
+    let len = hazards.len();
+    if RECYCLED_HAZARDS.fetch_add(len, atomic::Ordering::Relaxed) + len > MAX_RECYCLED_HAZARDS {
+        RECYCLED_HAZARDS.fetch_sub(len, atomic::Ordering::Relaxed);
+        for hazard in hazards {
+            hazard.kill();
+        }
+
+        return;
+    }
+
+    // The hazards might still protect the pointers they were last used with.
+    for hazard in &hazards {
+        hazard.free();
+    }
+
+    let batch = Box::into_raw(Box::new(Batch {
+        hazards: hazards,
+        next: ptr::null_mut(),
+    }));
+    unsafe { push_batches(batch, batch); }
+}
+
+/// Take a batch of recycled hazards.
+///
+/// This appends the (free) hazards of an exited thread to `into`. If the free-list is empty,
+/// `false` is returned.
+#[cfg(feature = "std")]
+pub fn take_recycled_hazards(into: &mut Vec<hazard::Writer>) -> bool {
+    // Avoid taking the cache line exclusively, if there is nothing to take.
+    if RECYCLED.load(atomic::Ordering::Relaxed).is_null() {
+        return false;
+    }
+
+    // Take the whole free-list, such that no other thread can take the batch concurrently.
+    let head = RECYCLED.swap(ptr::null_mut(), atomic::Ordering::Acquire);
+    if head.is_null() {
+        return false;
+    }
+
+    let Batch { hazards, next } = *unsafe { Box::from_raw(head) };
+    // Put back the rest of the batches.
+    if !next.is_null() {
+        let mut tail = next;
+        unsafe {
+            while !(*tail).next.is_null() {
+                tail = (*tail).next;
+            }
+
+            push_batches(next, tail);
+        }
+    }
+
+    RECYCLED_HAZARDS.fetch_sub(hazards.len(), atomic::Ordering::Relaxed);
+    into.extend(hazards);
+
+    true
+}
+
+/// Push a list of batches (from `head` to `tail`) to the free-list.
+#[cfg(feature = "std")]
+unsafe fn push_batches(head: *mut Batch, tail: *mut Batch) {
+    let mut next = RECYCLED.load(atomic::Ordering::Relaxed);
+    loop {
+        (*tail).next = next;
+        match RECYCLED.compare_exchange_weak(next, head, atomic::Ordering::Release,
+                                             atomic::Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(actual) => next = actual,
+        }
+    }
+}
+
 /// Export garbage into the global state.
 ///
 /// This adds the garbage, which will eventually be destroyed, to the global state. Note that this
 /// does not tick, and thus cannot cause garbage collection.
+#[cfg(feature = "std")]
 pub fn export_garbage(garbage: Vec<Garbage>) {
     STATE.export_garbage(garbage)
 }
 
-/// Attempt to garbage collect.
+/// Get the approximate number of bytes of garbage exported, but not yet destroyed.
+#[cfg(feature = "std")]
+pub fn garbage_bytes() -> usize {
+    STATE.garbage_bytes()
+}
+
+/// Dump the global state.
+///
+/// See `State::dump()`.
+#[cfg(feature = "std")]
+pub fn dump() -> Dump {
+    STATE.dump()
+}
+
+/// Get statistics of the global state.
 ///
-/// If another garbage collection is currently running, the thread will do nothing, and `Err(())`
-/// will be returned. Otherwise, it returns `Ok(())`.
+/// See `State::stats()`.
+#[cfg(feature = "std")]
+pub fn stats() -> Stats {
+    STATE.stats()
+}
+
+/// Take up to `n` empty garbage buffers for reuse.
+///
+/// See `State::take_buffers()`.
+#[cfg(feature = "std")]
+pub fn take_buffers(n: usize, into: &mut Vec<Vec<Garbage>>) {
+    STATE.take_buffers(n, into)
+}
+
+/// Attempt to garbage collect.
 ///
-/// # Panic
+/// If another garbage collection is currently running, the thread will do nothing, and
+/// `Err(WouldBlock)` will be returned. Otherwise, it returns `Ok(())`.
 ///
-/// If a destructor panics, this will panic as well.
-pub fn try_gc() -> Result<(), ()> {
+/// Destructors panicking are caught and counted (see `destructor_panics()`).
+#[cfg(feature = "std")]
+pub fn try_gc() -> Result<(), WouldBlock> {
     STATE.try_gc()
 }
 
+/// Attempt to garbage collect incrementally.
+///
+/// This acts like `try_gc()`, but scans at most `budget` pieces of garbage (see
+/// `State::try_gc_with_budget()`).
+#[cfg(feature = "std")]
+pub fn try_gc_with_budget(budget: usize) -> Result<(), WouldBlock> {
+    STATE.try_gc_with_budget(budget)
+}
+
+/// Get the number of garbage destructors which panicked so far.
+#[cfg(feature = "std")]
+pub fn destructor_panics() -> usize {
+    DESTRUCTOR_PANICS.load(atomic::Ordering::Relaxed)
+}
+
+/// Register a callback to run when an object is reclaimed.
+///
+/// The callback runs right after the garbage destroying the object at address `ptr` is destroyed.
+#[cfg(feature = "std")]
+pub fn on_reclaim<F: FnOnce() + Send + 'static>(ptr: usize, callback: F) {
+    // `FnOnce` cannot be called from a box, so we wrap it in an `FnMut` taking it out.
+    let mut callback = Some(callback);
+    let callback: ReclaimCallback = Box::new(move || {
+        if let Some(callback) = callback.take() {
+            callback();
+        }
+    });
+
+    let mut callbacks = RECLAIM_CALLBACKS.lock();
+    let entry = callbacks.entry(ptr).or_default();
+    if entry.is_empty() {
+        RECLAIM_CALLBACK_OBJECTS.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+    entry.push(callback);
+}
+
+/// Take the reclamation callbacks of the objects of some garbage.
+#[cfg(feature = "std")]
+fn take_reclaim_callbacks(garbage: &Garbage) -> Vec<ReclaimCallback> {
+    let mut taken = Vec::new();
+    // Most garbage has no callbacks, so we avoid the lock, if no object has.
+    if RECLAIM_CALLBACK_OBJECTS.load(atomic::Ordering::Relaxed) > 0 {
+        let mut callbacks = RECLAIM_CALLBACKS.lock();
+        for &ptr in garbage.ptrs() {
+            if let Some(mut c) = callbacks.remove(&(ptr as usize)) {
+                RECLAIM_CALLBACK_OBJECTS.fetch_sub(1, atomic::Ordering::Relaxed);
+                taken.append(&mut c);
+            }
+        }
+    }
+
+    taken
+}
+
+/// Run a function, catching and counting panics as destructor panics.
+#[cfg(feature = "std")]
+fn catch_destructor_panic<F: FnOnce()>(f: F) {
+    // The garbage is gone either way, so nothing can be observed in a broken state.
+    if panic::catch_unwind(panic::AssertUnwindSafe(f)).is_err() {
+        // Print message in debug mode.
+        debug::exec(|| println!("Garbage destructor panicked."));
+
+        DESTRUCTOR_PANICS.fetch_add(1, atomic::Ordering::Relaxed);
+        metrics::with(|recorder| recorder.destructor_panicked());
+    }
+}
+
+/// Destroy some garbage, catching panics from its destructor.
+///
+/// A panicking destructor must not abort the collection midway (leaking the rest of the garbage
+/// or leaving the state inconsistent), so the panic is caught and counted, and the collection
+/// carries on. The reclamation callbacks of the objects run afterwards (see `on_reclaim()`), and
+/// their panics are caught likewise.
+#[cfg(feature = "std")]
+fn destroy(garbage: Garbage) {
+    let callbacks = take_reclaim_callbacks(&garbage);
+
+    catch_destructor_panic(|| drop(garbage));
+    for callback in callbacks {
+        catch_destructor_panic(callback);
+    }
+
+    metrics::with(|recorder| recorder.garbage_destroyed(1));
+}
+
+/// Destroy some garbage.
+///
+/// Without `std`, panics cannot be caught, so a panicking destructor propagates.
+#[cfg(not(feature = "std"))]
+fn destroy(garbage: Garbage) {
+    drop(garbage);
+    metrics::with(|recorder| recorder.garbage_destroyed(1));
+}
+
+/// A dummy Bloom filter, which contains everything.
+///
+/// Without `std`, `cbloom` is unavailable, so every lookup goes to the set of protected pointers.
+#[cfg(not(feature = "std"))]
+struct Filter;
+
+#[cfg(not(feature = "std"))]
+impl Filter {
+    /// Create the filter.
+    fn new(_bytes: usize, _expected_items: usize) -> Filter {
+        Filter
+    }
+
+    /// Clear the filter.
+    fn clear(&self) {}
+
+    /// Insert an item into the filter.
+    fn insert(&self, _item: u64) {}
+
+    /// Might the filter contain an item?
+    fn maybe_contains(&self, _item: u64) -> bool {
+        true
+    }
+}
+
 /// Tick the clock.
 ///
 /// This shall be called when new garbage is added, as it will trigger a GC by some probability.
+///
+/// If the background collector is running, this does nothing, as the collector collects the
+/// garbage instead.
+#[cfg(feature = "std")]
 pub fn tick() {
+    if collector::is_running() {
+        return;
+    }
+
     // Generate a random number and compare it against the probability.
     if rand::random::<usize>() < settings::get().gc_probability {
         // The outfall was to (attempt at) GC.
@@ -52,6 +384,12 @@ pub fn tick() {
     }
 }
 
+/// The error of a garbage collection which would block.
+///
+/// This is returned when another thread is currently collecting the garbage (see `try_gc()`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WouldBlock;
+
 /// A message to the global state.
 enum Message {
     /// Add new garbage.
@@ -63,21 +401,39 @@ enum Message {
 /// The global state.
 ///
 /// The global state is shared between all threads and keeps track of the garbage and the active
-/// hazards.
+/// hazards. Besides the global singleton (the default domain), every `Domain` has one.
 ///
 /// It is divided into two parts: The channel and the garbo. The channel buffers messages, which
 /// will eventually be executed at garbo, which holds all the data structures and is protected by a
 /// mutex. The garbo holds the other end to the channel.
-struct State {
+pub struct State {
     /// The message-passing channel.
     chan: mpsc::Sender<Message>,
     /// The garbo part of the state.
     garbo: Mutex<Garbo>,
+    /// The approximate number of bytes of garbage exported, but not yet destroyed.
+    bytes: AtomicUsize,
+    /// The empty garbage buffers available for reuse.
+    ///
+    /// The buffers of exported garbage are emptied by the collector and put here, such that the
+    /// threads can export their garbage in them again, rather than allocating new buffers.
+    buffers: Mutex<Vec<Vec<Garbage>>>,
+    /// The allocator of the hazards.
+    ///
+    /// If this is `None`, the default allocator (see `allocator::default()`) is used.
+    allocator: Option<&'static allocator::Allocator>,
 }
 
 impl State {
     /// Initialize a new state.
-    fn new() -> State {
+    pub fn new() -> State {
+        State::with_allocator(None)
+    }
+
+    /// Initialize a new state allocating its hazards with `allocator`.
+    ///
+    /// If `allocator` is `None`, the default allocator at the time of allocation is used.
+    pub fn with_allocator(allocator: Option<&'static allocator::Allocator>) -> State {
         // Create the message-passing channel.
         let (send, recv) = mpsc::channel();
 
@@ -88,17 +444,54 @@ impl State {
                 chan: recv,
                 garbage: Vec::new(),
                 hazards: Vec::new(),
-            })
+                active: HashSet::new(),
+                filter: Filter::new(FILTER_BYTES_PER_HAZARD, 1),
+                filter_capacity: 1,
+                cursor: 0,
+                buffers: Vec::new(),
+                passes: 0,
+                fifo: false,
+                #[cfg(feature = "epoch")]
+                epochs: false,
+                #[cfg(feature = "epoch")]
+                unsealed: Vec::new(),
+                #[cfg(feature = "epoch")]
+                limbo: Vec::new(),
+            }),
+            bytes: AtomicUsize::new(0),
+            buffers: Mutex::new(Vec::new()),
+            allocator: allocator,
         }
     }
 
+    /// Initialize a new state respecting the epochs.
+    ///
+    /// Besides being subject to the hazards, the garbage of the state is only destroyed once no
+    /// pinned thread can reach it (see `epoch`).
+    #[cfg(feature = "epoch")]
+    pub fn with_epochs() -> State {
+        let mut state = State::new();
+        state.garbo.get_mut().epochs = true;
+        state
+    }
+
+    /// Destroy the garbage in the order it was added.
+    ///
+    /// Garbage is then only destroyed once all the garbage added before it is, so a protected
+    /// object holds back the destruction of all the garbage added after it.
+    #[cfg(feature = "std")]
+    pub fn set_fifo(&mut self) {
+        self.garbo.get_mut().fifo = true;
+    }
+
     /// Create a new hazard.
     ///
     /// This creates a new hazard and registers it in the global state. It's secondary, writer part
     /// is returned.
-    fn create_hazard(&self) -> hazard::Writer {
+    pub fn create_hazard(&self) -> hazard::Writer {
         // Create the hazard.
-        let (writer, reader) = hazard::create();
+        let (writer, reader) = hazard::create(self.allocator.unwrap_or_else(allocator::default));
+        metrics::with(|recorder| recorder.hazard_created());
         // Communicate the new hazard to the global state through the channel.
         self.chan.send(Message::NewHazard(reader));
         // Return the other half of the hazard.
@@ -108,7 +501,11 @@ impl State {
     /// Export garbage into the global state.
     ///
     /// This adds the garbage, which will eventually be destroyed, to the global state.
-    fn export_garbage(&self, garbage: Vec<Garbage>) {
+    pub fn export_garbage(&self, garbage: Vec<Garbage>) {
+        // Account the bytes before sending, such that the collector never subtracts bytes which
+        // weren't added yet.
+        let bytes = garbage.iter().map(Garbage::size).sum();
+        self.bytes.fetch_add(bytes, atomic::Ordering::Relaxed);
         // Send the garbage to the message-passing channel of the state.
         self.chan.send(Message::Garbage(garbage));
     }
@@ -116,25 +513,115 @@ impl State {
     /// Try to collect the garbage.
     ///
     /// This will handle all of the messages in the channel and then attempt at collect the
-    /// garbage. If another thread is currently collecting garbage, `Err(())` is returned,
-    /// otherwise it returns `Ok(())`.
+    /// garbage. If another thread is currently collecting garbage, `Err(WouldBlock)` is
+    /// returned, otherwise it returns `Ok(())`.
     ///
     /// Garbage collection works by scanning the hazards and dropping all the garbage which is not
     /// currently active in the hazards.
-    fn try_gc(&self) -> Result<(), ()> {
+    pub fn try_gc(&self) -> Result<(), WouldBlock> {
+        self.try_gc_with_budget(!0)
+    }
+
+    /// Try to collect some of the garbage.
+    ///
+    /// This acts like `try_gc()`, but at most `budget` pieces of garbage are scanned. The next
+    /// call continues where this one stopped, so repeated calls eventually scan all the garbage.
+    /// This bounds the time of a collection by the budget (and the number of hazards, which are
+    /// scanned in full), even with huge amounts of garbage.
+    pub fn try_gc_with_budget(&self, budget: usize) -> Result<(), WouldBlock> {
         // Lock the "garbo" (the part of the state needed to GC).
         if let Some(mut garbo) = self.garbo.try_lock() {
             // Collect the garbage.
-            garbo.gc();
+            let bytes = garbo.gc(budget);
+            self.bytes.fetch_sub(bytes, atomic::Ordering::Relaxed);
+
+            // Make the emptied buffers available for reuse. The ones we have no room for are
+            // deallocated.
+            let mut buffers = self.buffers.lock();
+            let room = MAX_RECYCLED_BUFFERS - buffers.len();
+            buffers.extend(garbo.buffers.drain(..).take(room));
 
             Ok(())
         } else {
             // Another thread is collecting.
-            Err(())
+            Err(WouldBlock)
+        }
+    }
+
+    /// Get the approximate number of bytes of garbage exported, but not yet destroyed.
+    pub fn garbage_bytes(&self) -> usize {
+        self.bytes.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Get statistics of the state.
+    ///
+    /// This fills in the counts of hazards and garbage of the state, and the number of garbage
+    /// collections, while the thread-local statistics are left zero. It waits for a running
+    /// garbage collection to finish, but doesn't wait for blocked hazards (which are counted as
+    /// active).
+    pub fn stats(&self) -> Stats {
+        let mut garbo = self.garbo.lock();
+        // Take the messages into account.
+        for msg in garbo.chan.recv_all() {
+            garbo.handle(msg);
+        }
+
+        let mut stats = Stats {
+            global_garbage: garbo.garbage().count(),
+            gc_passes: garbo.passes,
+            .. Stats::default()
+        };
+        for hazard in &garbo.hazards {
+            match hazard.peek() {
+                Ok(hazard::State::Free) => stats.free_hazards += 1,
+                Ok(hazard::State::Dead) => stats.dead_hazards += 1,
+                Ok(hazard::State::Protect(_)) | Err(hazard::Blocked) => stats.active_hazards += 1,
+            }
         }
+
+        stats
+    }
+
+    /// Dump the hazards and the garbage of the state.
+    ///
+    /// Like `stats()`, this waits for a running garbage collection to finish, but doesn't wait for
+    /// blocked hazards.
+    #[cfg(feature = "std")]
+    pub fn dump(&self) -> Dump {
+        let mut garbo = self.garbo.lock();
+        // Take the messages into account.
+        for msg in garbo.chan.recv_all() {
+            garbo.handle(msg);
+        }
+
+        Dump {
+            hazards: garbo.hazards.iter().map(|hazard| HazardDump {
+                state: match hazard.peek() {
+                    Ok(hazard::State::Free) => HazardState::Free,
+                    Ok(hazard::State::Dead) => HazardState::Dead,
+                    Ok(hazard::State::Protect(ptr)) => HazardState::Protect(ptr as usize),
+                    Err(hazard::Blocked) => HazardState::Blocked,
+                },
+                owner: hazard.owner(),
+            }).collect(),
+            garbage: garbo.garbage()
+                .flat_map(|garbage| garbage.ptrs().iter().map(|&ptr| ptr as usize))
+                .collect(),
+        }
+    }
+
+    /// Take up to `n` empty garbage buffers for reuse.
+    ///
+    /// The buffers are pushed to `into`. They can be used for exporting garbage (see
+    /// `export_garbage()`), saving an allocation.
+    pub fn take_buffers(&self, n: usize, into: &mut Vec<Vec<Garbage>>) {
+        let mut buffers = self.buffers.lock();
+        let len = buffers.len();
+        into.extend(buffers.drain(len.saturating_sub(n)..));
     }
 }
 
+#[cfg(feature = "std")]
 impl panic::RefUnwindSafe for State {}
 
 /// The garbo part of the state.
@@ -148,6 +635,51 @@ struct Garbo {
     garbage: Vec<Garbage>,
     /// The current hazards.
     hazards: Vec<hazard::Reader>,
+    /// The set of pointers protected by the hazards.
+    ///
+    /// This is a snapshot of the protected pointers, which is built once per collection, such that
+    /// every piece of garbage can be checked in O(1). This makes collection O(G+H) rather than
+    /// O(GH), with G being the amount of garbage and H being the number of hazards.
+    ///
+    /// It is kept here only to reuse its allocation between collections. Its content is
+    /// meaningless outside of `gc()`. The pointers are stored as addresses, as raw pointers
+    /// cannot be sent across threads.
+    active: HashSet<usize>,
+    /// A Bloom filter of the pointers protected by the hazards.
+    ///
+    /// This prefilters the lookups in `active`: Most garbage is usually unprotected, and is ruled
+    /// out by the filter at the cost of a few hashes and no cache misses in the (possibly large)
+    /// set. Only garbage hitting the filter is checked against the set.
+    ///
+    /// Like `active`, it is built once per collection and kept to reuse its allocation.
+    filter: Filter,
+    /// The number of pointers `filter` is sized for.
+    filter_capacity: usize,
+    /// The index of the garbage to scan first in the next incremental collection.
+    ///
+    /// This allows incremental collections to continue where the previous one stopped.
+    cursor: usize,
+    /// The garbage buffers emptied while handling messages.
+    ///
+    /// These are moved to `State.buffers` after the collection.
+    buffers: Vec<Vec<Garbage>>,
+    /// The number of garbage collection passes completed.
+    passes: usize,
+    /// Is the garbage destroyed in the order it was added?
+    ///
+    /// If so, `garbage` is kept in order, and only an unprotected prefix of it is destroyed.
+    fifo: bool,
+    /// Does the garbage respect the epochs?
+    #[cfg(feature = "epoch")]
+    epochs: bool,
+    /// The garbage received, but not yet tagged with an epoch.
+    #[cfg(feature = "epoch")]
+    unsealed: Vec<Garbage>,
+    /// The garbage waiting for the epoch to advance, along with the epoch it was tagged with.
+    ///
+    /// The garbage is moved to `garbage` once the epoch advanced twice past its tag.
+    #[cfg(feature = "epoch")]
+    limbo: Vec<(usize, Vec<Garbage>)>,
 }
 
 impl Garbo {
@@ -157,43 +689,147 @@ impl Garbo {
     /// effectually executing the instruction of the message.
     fn handle(&mut self, msg: Message) {
         match msg {
-            // Append the garbage bulk to the garbage list.
-            Message::Garbage(mut garbage) => self.garbage.append(&mut garbage),
+            // Append the garbage bulk to the garbage list, and keep the emptied buffer for reuse.
+            Message::Garbage(mut garbage) => {
+                #[cfg(feature = "epoch")]
+                let queue = if self.epochs { &mut self.unsealed } else { &mut self.garbage };
+                #[cfg(not(feature = "epoch"))]
+                let queue = &mut self.garbage;
+
+                queue.append(&mut garbage);
+                if garbage.capacity() > 0 && garbage.capacity() <= MAX_RECYCLED_CAPACITY {
+                    self.buffers.push(garbage);
+                }
+            },
             // Register the new hazard into the state.
             Message::NewHazard(hazard) => self.hazards.push(hazard),
         }
     }
 
-    /// Handle all the messages and garbage collect all unused garbage.
+    /// Iterate over all the garbage of the state.
+    fn garbage<'a>(&'a self) -> Box<Iterator<Item = &'a Garbage> + 'a> {
+        let garbage = self.garbage.iter();
+        #[cfg(feature = "epoch")]
+        let garbage = garbage
+            .chain(&self.unsealed)
+            .chain(self.limbo.iter().flat_map(|&(_, ref bag)| bag));
+
+        Box::new(garbage)
+    }
+
+    /// Get the amount of garbage of the state.
+    fn backlog(&self) -> usize {
+        #[cfg(feature = "epoch")]
+        let waiting = self.unsealed.len()
+            + self.limbo.iter().map(|&(_, ref bag)| bag.len()).sum::<usize>();
+        #[cfg(not(feature = "epoch"))]
+        let waiting = 0;
+
+        self.garbage.len() + waiting
+    }
+
+    /// Move the garbage no pinned thread can reach to the garbage to scan.
     ///
-    /// # Panic
+    /// The garbage received since the last collection is tagged with the current epoch, and the
+    /// epoch is advanced as far as the pinned threads allow. This must be called after the fence
+    /// ordering the unlinking of the garbage (see `hazard::fence()`).
+    #[cfg(feature = "epoch")]
+    fn release_epochs(&mut self) {
+        if !self.unsealed.is_empty() {
+            let bag = mem::replace(&mut self.unsealed, Vec::new());
+            self.limbo.push((epoch::current(), bag));
+        }
+
+        // Two advances suffice to release the garbage tagged just now.
+        let epoch = epoch::try_advance(2);
+        let mut i = 0;
+        while i < self.limbo.len() {
+            if epoch.wrapping_sub(self.limbo[i].0) >= 2 {
+                // The bags are tagged in order, so removing them in place keeps the garbage in
+                // order.
+                let (_, mut bag) = if self.fifo {
+                    self.limbo.remove(i)
+                } else {
+                    self.limbo.swap_remove(i)
+                };
+                self.garbage.append(&mut bag);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Handle all the messages and garbage collect the unused garbage.
     ///
-    /// If a destructor panics, this will panic as well.
-    fn gc(&mut self) {
+    /// At most `budget` pieces of garbage are scanned, starting at the cursor, if the budget
+    /// doesn't cover all the garbage. Otherwise, all the garbage is scanned.
+    ///
+    /// Every destructor is run even if some of them panic (see `destroy()`).
+    ///
+    /// The number of bytes of garbage destroyed is returned.
+    fn gc(&mut self, budget: usize) -> usize {
         // Print message in debug mode.
         debug::exec(|| println!("Collecting garbage."));
+        metrics::with(|recorder| recorder.gc_pass());
+        // Only time the pass, if it is reported.
+        #[cfg(feature = "std")]
+        let begin = if metrics::is_enabled() { Some(Instant::now()) } else { None };
 
         // Handle all the messages sent.
         for msg in self.chan.recv_all() {
             self.handle(msg);
         }
 
-        // Create the set which will keep the _active_ hazards.
-        let mut active = HashSet::with_capacity(self.hazards.len());
+        // Clear the set which will keep the _active_ hazards, reusing the old allocation.
+        let mut active = mem::replace(&mut self.active, HashSet::new());
+        active.clear();
+        #[cfg(feature = "std")]
+        active.reserve(self.hazards.len());
+
+        // Make sure that the garbage was unlinked before we read the hazards.
+        hazard::fence();
+
+        #[cfg(feature = "epoch")]
+        {
+            if self.epochs {
+                self.release_epochs();
+            }
+        }
 
         // Take out the hazards and go over them one-by-one.
+        #[cfg(feature = "std")]
+        let timeout = settings::get().blocked_hazard_timeout;
+        let mut stuck = false;
         let len = self.hazards.len(); // TODO: This should be substituted into next line.
         for hazard in mem::replace(&mut self.hazards, Vec::with_capacity(len)) {
-            match hazard.get() {
+            #[cfg(feature = "std")]
+            let state = match timeout {
+                Some(timeout) => hazard.try_get(timeout),
+                None => Ok(hazard.get()),
+            };
+            // Without `std`, there is no clock to time out with, so we wait for the hazard.
+            #[cfg(not(feature = "std"))]
+            let state = Ok(hazard.get());
+
+            match state {
+                // The hazard stayed blocked, so it might be about to protect any of the garbage.
+                // We keep it for the next collection.
+                Err(hazard::Blocked) => {
+                    stuck = true;
+                    self.hazards.push(hazard);
+                },
                 // The hazard is dead, so the other end (the writer) is not available anymore,
-                // hence we can safely destroy it.
-                hazard::State::Dead => unsafe { hazard.destroy() },
+                // hence we can safely destroy it. The dead state is final, so the token exists.
+                Ok(hazard::State::Dead) => {
+                    let token = hazard.death_token().expect("Dead hazard came back to life.");
+                    hazard.destroy(token);
+                },
                 // The hazard is free and must thus be put back to the hazard list.
-                hazard::State::Free => self.hazards.push(hazard),
-                hazard::State::Protect(ptr) => {
+                Ok(hazard::State::Free) => self.hazards.push(hazard),
+                Ok(hazard::State::Protect(ptr)) => {
                     // This hazard is active, hence we insert the pointer it contains in our
                     // "active" set.
-                    active.insert(ptr);
+                    active.insert(ptr as usize);
                     // Since the hazard is still alive, we must put it back to the hazard list for
                     // future use.
                     self.hazards.push(hazard);
@@ -201,15 +837,115 @@ impl Garbo {
             }
         }
 
-        // Scan the garbage for unused objects.
-        self.garbage.retain(|garbage| active.contains(&garbage.ptr()))
+        // Build the filter of the protected pointers, growing it if necessary.
+        if !active.is_empty() {
+            if active.len() > self.filter_capacity {
+                self.filter_capacity = active.len().next_power_of_two();
+                self.filter = Filter::new(self.filter_capacity * FILTER_BYTES_PER_HAZARD,
+                                          self.filter_capacity);
+            } else {
+                self.filter.clear();
+            }
+
+            for &ptr in &active {
+                self.filter.insert(ptr as u64);
+            }
+        }
+
+        let mut bytes = 0;
+        #[cfg(feature = "std")]
+        let before = self.garbage.len();
+        if stuck {
+            // A hazard was stuck, so we cannot know if any garbage is unused.
+            debug::exec(|| println!("Skipping destruction due to a blocked hazard."));
+        } else if self.fifo {
+            // Destroy the garbage up to the first protected piece, in order.
+            let len = {
+                let (filter, active) = (&self.filter, &active);
+                self.garbage.iter()
+                    .take(budget)
+                    .take_while(|garbage| !is_protected(filter, active, garbage))
+                    .count()
+            };
+            for garbage in self.garbage.drain(..len) {
+                bytes += garbage.size();
+                destroy(garbage);
+            }
+        } else if budget < self.garbage.len() {
+            // Scan a part of the garbage, continuing where the previous collection stopped.
+            if self.cursor >= self.garbage.len() {
+                self.cursor = 0;
+            }
+
+            for _ in 0..budget {
+                if self.cursor >= self.garbage.len() {
+                    // The next collection starts over.
+                    break;
+                }
+
+                if is_protected(&self.filter, &active, &self.garbage[self.cursor]) {
+                    self.cursor += 1;
+                } else {
+                    // This moves the last garbage to the cursor, so it is scanned next.
+                    let garbage = self.garbage.swap_remove(self.cursor);
+                    bytes += garbage.size();
+                    destroy(garbage);
+                }
+            }
+        } else if active.is_empty() {
+            // Nothing is protected, so we can skip the lookups and destroy all the garbage.
+            for garbage in self.garbage.drain(..) {
+                bytes += garbage.size();
+                destroy(garbage);
+            }
+        } else {
+            // Scan the garbage for unused objects.
+            let len = self.garbage.len();
+            for garbage in mem::replace(&mut self.garbage, Vec::with_capacity(len)) {
+                if is_protected(&self.filter, &active, &garbage) {
+                    self.garbage.push(garbage);
+                } else {
+                    bytes += garbage.size();
+                    destroy(garbage);
+                }
+            }
+        }
+
+        // Put the set back for the next collection.
+        self.active = active;
+        self.passes += 1;
+
+        #[cfg(feature = "std")]
+        {
+            if let Some(begin) = begin {
+                let destroyed = before - self.garbage.len();
+                metrics::with(|recorder| recorder.gc_pause(begin.elapsed(), destroyed));
+            }
+        }
+        let backlog = self.backlog();
+        metrics::with(|recorder| recorder.garbage_backlog(backlog));
+
+        bytes
     }
 }
 
+/// Is some garbage protected by the active hazards?
+///
+/// `filter` must contain the pointers of `active`, and is checked first to avoid most lookups in
+/// the set.
+///
+/// Batches are kept as long as any of their objects is protected, so we check the rest of the
+/// batch, if the first object isn't.
+fn is_protected(filter: &Filter, active: &HashSet<usize>, garbage: &Garbage) -> bool {
+    garbage.ptrs().iter().any(|&ptr| {
+        filter.maybe_contains(ptr as u64) && active.contains(&(ptr as usize))
+    })
+}
+
 impl Drop for Garbo {
     fn drop(&mut self) {
         // Do a final GC.
-        self.gc();
+        self.gc(!0);
     }
 }
 
@@ -217,7 +953,9 @@ impl Drop for Garbo {
 mod tests {
     use super::*;
     use garbage::Garbage;
-    use std::{panic, ptr};
+    use provenance;
+    use std::cell::Cell;
+    use std::{panic, thread};
 
     #[test]
     fn dtor_runs() {
@@ -229,18 +967,351 @@ mod tests {
 
         let s = State::new();
         for _ in 0..1000 {
-            let b = Box::new(0);
+            let b = Box::new(Cell::new(0u8));
             let h = s.create_hazard();
-            h.protect(&*b);
-            s.export_garbage(vec![Garbage::new(&*b, dtor)]);
+            h.protect(b.as_ptr());
+            s.export_garbage(vec![Garbage::new(b.as_ptr(), dtor)]);
             while s.try_gc().is_err() {}
-            assert_eq!(*b, 0);
+            assert_eq!(b.get(), 0);
             while s.try_gc().is_err() {}
             h.free();
             while s.try_gc().is_err() {}
-            assert_eq!(*b, 1);
+            assert_eq!(b.get(), 1);
+            h.kill();
+        }
+    }
+
+    #[test]
+    fn recycle_and_take_hazards() {
+        recycle_hazards(vec![create_hazard(), create_hazard()]);
+
+        // The threads of other tests might take the hazards first.
+        let mut hazards = Vec::new();
+        if take_recycled_hazards(&mut hazards) {
+            assert!(!hazards.is_empty());
+            for hazard in &hazards {
+                assert!(!hazard.is_blocked());
+            }
+        }
+    }
+
+    #[test]
+    fn reclaim_callbacks() {
+        use std::sync::{Arc, Mutex};
+
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let s = State::new();
+        let a = Box::new(Cell::new(0u8));
+        let b = Box::new(Cell::new(0u8));
+
+        let (log1, log2, log3) = (log.clone(), log.clone(), log.clone());
+        let bptr = b.as_ptr() as usize;
+        on_reclaim(a.as_ptr() as usize, move || log1.lock().unwrap().push(1));
+        on_reclaim(a.as_ptr() as usize, move || log2.lock().unwrap().push(2));
+        on_reclaim(bptr, move || log3.lock().unwrap().push(3));
+
+        let h = s.create_hazard();
+        h.protect(b.as_ptr());
+        s.export_garbage(vec![Garbage::new(a.as_ptr(), dtor), Garbage::new(b.as_ptr(), dtor)]);
+        while s.try_gc().is_err() {}
+
+        // The callbacks run after the destructor, and only once the object is reclaimed.
+        assert_eq!(a.get(), 1);
+        assert_eq!(*log.lock().unwrap(), [1, 2]);
+
+        h.free();
+        while s.try_gc().is_err() {}
+        assert_eq!(*log.lock().unwrap(), [1, 2, 3]);
+        assert!(!RECLAIM_CALLBACKS.lock().contains_key(&bptr));
+        h.kill();
+    }
+
+    #[test]
+    fn would_block() {
+        let s = State::new();
+        assert_eq!(s.try_gc(), Ok(()));
+
+        // Pretend another thread is collecting.
+        let garbo = s.garbo.lock();
+        assert_eq!(s.try_gc(), Err(WouldBlock));
+        drop(garbo);
+
+        assert_eq!(s.try_gc(), Ok(()));
+    }
+
+    #[test]
+    fn grow_filter() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let boxes: Vec<_> = (0..200).map(|_| Box::new(Cell::new(0u8))).collect();
+
+        // Protect a growing number of the boxes, such that the filter must grow.
+        let mut hazards = Vec::new();
+        for &n in &[1, 10, 100] {
+            while hazards.len() < n {
+                let h = s.create_hazard();
+                h.protect(boxes[hazards.len()].as_ptr());
+                hazards.push(h);
+            }
+
+            s.export_garbage(boxes[..n].iter().map(|b| Garbage::new(b.as_ptr(), dtor)).collect());
+            while s.try_gc().is_err() {}
+            assert!(boxes[..n].iter().all(|b| b.get() == 0));
+        }
+
+        s.export_garbage(boxes[100..].iter().map(|b| Garbage::new(b.as_ptr(), dtor)).collect());
+        while s.try_gc().is_err() {}
+        assert!(boxes[..100].iter().all(|b| b.get() == 0));
+        assert!(boxes[100..].iter().all(|b| b.get() == 1));
+
+        for h in hazards {
+            h.free();
             h.kill();
         }
+        while s.try_gc().is_err() {}
+        assert!(boxes.iter().all(|b| b.get() == 1));
+    }
+
+    #[test]
+    fn stats() {
+        fn nop(_: *const u8) {}
+
+        let s = State::new();
+        assert_eq!(s.stats(), Stats::default());
+
+        let free = s.create_hazard();
+        free.free();
+        let protect = s.create_hazard();
+        protect.protect(provenance::dangling(0x1));
+        let blocked = s.create_hazard();
+        s.create_hazard().kill();
+        s.export_garbage(vec![Garbage::new(provenance::dangling(0x1), nop)]);
+        s.export_garbage(vec![Garbage::new(provenance::dangling(0x2), nop)]);
+
+        assert_eq!(s.stats(), Stats {
+            active_hazards: 2,
+            free_hazards: 1,
+            dead_hazards: 1,
+            global_garbage: 2,
+            .. Stats::default()
+        });
+
+        blocked.free();
+        while s.try_gc().is_err() {}
+        assert_eq!(s.stats(), Stats {
+            active_hazards: 1,
+            free_hazards: 2,
+            global_garbage: 1,
+            gc_passes: 1,
+            .. Stats::default()
+        });
+
+        for h in [free, protect, blocked] {
+            h.free();
+            h.kill();
+        }
+    }
+
+    #[test]
+    fn dump() {
+        fn nop(_: *const u8) {}
+
+        let s = State::new();
+        assert_eq!(s.dump(), Dump::default());
+
+        let protect = s.create_hazard();
+        protect.protect(provenance::dangling(0x1));
+        let blocked = s.create_hazard();
+        s.export_garbage(vec![Garbage::new(provenance::dangling(0x2), nop)]);
+
+        let dump = s.dump();
+        assert_eq!(dump.hazards, [HazardDump {
+            state: HazardState::Protect(0x1),
+            owner: Some(thread::current().id()),
+        }, HazardDump {
+            state: HazardState::Blocked,
+            owner: Some(thread::current().id()),
+        }]);
+        assert_eq!(dump.garbage, [0x2]);
+
+        protect.free();
+        protect.kill();
+        blocked.free();
+        blocked.kill();
+    }
+
+    #[test]
+    fn recycle_buffers() {
+        fn nop(_: *const u8) {}
+
+        let s = State::new();
+        let mut garbage = Vec::with_capacity(100);
+        garbage.push(Garbage::new(provenance::dangling(0x1), nop));
+        s.export_garbage(garbage);
+        s.export_garbage(vec![Garbage::new(provenance::dangling(0x2), nop)]);
+        while s.try_gc().is_err() {}
+
+        let mut buffers = Vec::new();
+        s.take_buffers(1, &mut buffers);
+        assert_eq!(buffers.len(), 1);
+        s.take_buffers(10, &mut buffers);
+        assert_eq!(buffers.len(), 2);
+        s.take_buffers(10, &mut buffers);
+        assert_eq!(buffers.len(), 2);
+
+        assert!(buffers.iter().all(|x| x.is_empty()));
+        assert!(buffers.iter().any(|x| x.capacity() >= 100));
+    }
+
+    #[test]
+    fn budget() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let boxes: Vec<_> = (0..100).map(|_| Box::new(Cell::new(0u8))).collect();
+        let h = s.create_hazard();
+        h.protect(boxes[0].as_ptr());
+        s.export_garbage(boxes.iter().map(|b| Garbage::new(b.as_ptr(), dtor)).collect());
+
+        // Every collection scans 30 pieces of garbage, one of which is protected.
+        while s.try_gc_with_budget(30).is_err() {}
+        assert_eq!(boxes.iter().filter(|b| b.get() == 1).count(), 29);
+        while s.try_gc_with_budget(30).is_err() {}
+        assert_eq!(boxes.iter().filter(|b| b.get() == 1).count(), 59);
+        while s.try_gc_with_budget(30).is_err() {}
+        assert_eq!(boxes.iter().filter(|b| b.get() == 1).count(), 89);
+        // The remaining garbage is scanned.
+        while s.try_gc_with_budget(30).is_err() {}
+        assert_eq!(boxes.iter().filter(|b| b.get() == 1).count(), 99);
+        assert_eq!(boxes[0].get(), 0);
+
+        h.free();
+        while s.try_gc_with_budget(30).is_err() {}
+        assert!(boxes.iter().all(|b| b.get() == 1));
+        h.kill();
+    }
+
+    #[test]
+    fn garbage_bytes() {
+        fn nop(_: *const u8) {}
+
+        let s = State::new();
+        let h = s.create_hazard();
+        h.protect(provenance::dangling(0x1));
+        s.export_garbage(vec![
+            Garbage::new(provenance::dangling(0x1), nop).with_size(100),
+            Garbage::new(provenance::dangling(0x2), nop).with_size(20),
+        ]);
+        assert_eq!(s.garbage_bytes(), 120);
+
+        // Only the unprotected garbage is subtracted.
+        while s.try_gc().is_err() {}
+        assert_eq!(s.garbage_bytes(), 100);
+
+        h.free();
+        while s.try_gc().is_err() {}
+        assert_eq!(s.garbage_bytes(), 0);
+        h.kill();
+    }
+
+    #[test]
+    fn stuck_hazard() {
+        use settings::{self, Settings};
+        use std::time::Duration;
+
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        settings::set_local(Settings {
+            blocked_hazard_timeout: Some(Duration::from_millis(10)),
+            .. Settings::default()
+        });
+
+        let s = State::new();
+        let b = Box::new(Cell::new(0u8));
+        let h = s.create_hazard();
+        s.export_garbage(vec![Garbage::new(b.as_ptr(), dtor)]);
+
+        // The new hazard is blocked, so the collection gives up.
+        while s.try_gc().is_err() {}
+        assert_eq!(b.get(), 0);
+
+        h.free();
+        while s.try_gc().is_err() {}
+        assert_eq!(b.get(), 1);
+        h.kill();
+
+        // Avoid messing with other tests.
+        settings::set_local(Settings::default());
+    }
+
+    #[test]
+    fn many_hazards() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let boxes: Vec<_> = (0..1000).map(|_| Box::new(Cell::new(0u8))).collect();
+        // Protect every tenth box.
+        let hazards: Vec<_> = boxes.iter().step_by(10).map(|b| {
+            let h = s.create_hazard();
+            h.protect(b.as_ptr());
+            h
+        }).collect();
+
+        s.export_garbage(boxes.iter().map(|b| Garbage::new(b.as_ptr(), dtor)).collect());
+        while s.try_gc().is_err() {}
+
+        for (n, b) in boxes.iter().enumerate() {
+            assert_eq!(b.get(), if n % 10 == 0 { 0 } else { 1 });
+        }
+
+        for h in hazards {
+            h.free();
+            h.kill();
+        }
+        while s.try_gc().is_err() {}
+
+        assert!(boxes.iter().all(|b| b.get() == 1));
+    }
+
+    #[test]
+    fn batch() {
+        let s = State::new();
+        let boxes: Vec<_> = (0..100u64).map(|x| Box::into_raw(Box::new(x)) as *const u64).collect();
+        let h = s.create_hazard();
+        // Protect an object in the middle of the batch.
+        h.protect(boxes[50] as *const u8);
+
+        s.export_garbage(vec![unsafe { Garbage::new_box_batch(boxes.clone()) }]);
+        while s.try_gc().is_err() {}
+        // The batch is kept as a whole.
+        assert!(boxes.iter().enumerate().all(|(n, &b)| unsafe { *b } == n as u64));
+
+        h.free();
+        while s.try_gc().is_err() {}
+        h.kill();
     }
 
     #[test]
@@ -252,13 +1323,13 @@ mod tests {
         }
 
         for _ in 0..1000 {
-            let b = Box::new(0);
+            let b = Box::new(Cell::new(0u8));
             {
                 let s = State::new();
-                s.export_garbage(vec![Garbage::new(&*b, dtor)]);
+                s.export_garbage(vec![Garbage::new(b.as_ptr(), dtor)]);
             }
 
-            assert_eq!(*b, 1);
+            assert_eq!(b.get(), 1);
         }
     }
 
@@ -275,38 +1346,50 @@ mod tests {
         }
 
         let s = State::new();
-        let b = Box::new(0);
+        let b = Box::new(Cell::new(0u8));
         let h = create_hazard();
-        h.protect(&*b);
-        s.export_garbage(vec![Garbage::new(&*b, dtor), Garbage::new(0x2 as *const u8, panic)]);
+        h.protect(b.as_ptr());
+        s.export_garbage(vec![Garbage::new(b.as_ptr(), dtor), Garbage::new(provenance::dangling(0x2), panic)]);
         let _ = panic::catch_unwind(|| {
             while s.try_gc().is_err() {}
         });
-        assert_eq!(*b, 0);
+        assert_eq!(b.get(), 0);
         h.free();
         while s.try_gc().is_err() {}
-        assert_eq!(*b, 1);
+        assert_eq!(b.get(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn panic_in_dtor() {
-        fn dtor(_: *const u8) {
+        fn panic(_: *const u8) {
             panic!();
         }
 
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
         let s = State::new();
-        s.export_garbage(vec![Garbage::new(ptr::null(), dtor)]);
+        let b = Box::new(Cell::new(0u8));
+        let panics = destructor_panics();
+        s.export_garbage(vec![Garbage::new(provenance::dangling(0x2), panic), Garbage::new(b.as_ptr(), dtor),
+                              Garbage::new(provenance::dangling(0x3), panic)]);
         while s.try_gc().is_err() {}
+
+        // The rest of the garbage was destroyed regardless.
+        assert_eq!(b.get(), 1);
+        assert!(destructor_panics() >= panics + 2);
     }
 
-    #[cfg(debug_assertions)]
     #[test]
-    #[should_panic]
-    fn debug_more_hazards() {
+    fn leak_live_hazards() {
+        // Dropping the state with a live hazard leaks the hazard, rather than panicking.
         let s = State::new();
         let h = s.create_hazard();
         h.free();
         mem::forget(h);
+        drop(s);
     }
 }
@@ -1,8 +1,14 @@
 //! RAII guards for hazards.
 
-use std::ops;
-use std::sync::atomic;
+use std::os::raw::c_void;
+use std::slice::SliceIndex;
+use std::{fmt, mem, ops};
+use std::sync::Arc;
+use atomics as atomic;
 use {hazard, local};
+use domain::Domain;
+#[cfg(feature = "epoch")]
+use epoch;
 
 #[cfg(debug_assertions)]
 use std::cell::Cell;
@@ -28,6 +34,17 @@ pub fn debug_assert_no_create() {
 ///
 /// This "guards" the held pointer against garbage collection. First when all guards of said
 /// pointer is gone (the data is unreachable), it can be collected.
+///
+/// The pointer is protected by a hazard, or, with feature `epoch`, by pinning the current thread
+/// if the guard belongs to the default domain.
+///
+/// `T` can be unsized, e.g. a trait object or a slice: Only the data pointer is stored in the
+/// hazard, while the metadata (the vtable or length) stays in the guard. Boxes of unsized types
+/// are added as garbage through `conc::add_garbage_box_dyn()`.
+///
+/// Guards are `Send` whenever `T` is `Sync`. A guard which moves between threads for long, e.g.
+/// when a future holding it is migrated between the worker threads of an executor, should be a
+/// `SendGuard<T>` though, which is never protected by a pin.
 // TODO: Remove this `'static` bound.
 #[must_use = "\
     You are getting a `conc::Guard<T>` without using it, which means it is potentially \
@@ -36,12 +53,54 @@ pub fn debug_assert_no_create() {
 "]
 #[derive(Debug)]
 pub struct Guard<T: 'static + ?Sized> {
-    /// The inner hazard.
-    hazard: hazard::Writer,
+    /// The protection of the pointer.
+    protection: Protection,
     /// The pointer to the protected object.
     pointer: &'static T,
 }
 
+/// The protection of the pointer of a guard.
+///
+/// It is only held for its destructor, which ends the protection.
+#[derive(Debug)]
+#[allow(dead_code)]
+enum Protection {
+    /// A hazard protecting the pointer.
+    Hazard(hazard::Writer),
+    /// A hazard shared by several guards (see `Guard::share()`).
+    Shared(Arc<hazard::Writer>),
+    /// A pin of the thread, protecting all the garbage of the default domain (see `epoch`).
+    #[cfg(feature = "epoch")]
+    Pin(epoch::Pin),
+}
+
+impl Protection {
+    /// Create another protection of the pointer this protects.
+    ///
+    /// The pointer of a mapped guard needn't be the protected one, so the protected pointer is
+    /// read from the hazard.
+    fn duplicate(&self) -> Protection {
+        match *self {
+            Protection::Hazard(ref hazard) => Protection::Hazard(duplicate_hazard(hazard)),
+            Protection::Shared(ref hazard) => Protection::Shared(hazard.clone()),
+            #[cfg(feature = "epoch")]
+            Protection::Pin(ref pin) => Protection::Pin(pin.clone()),
+        }
+    }
+}
+
+/// Get a new hazard protecting the pointer `hazard` protects.
+fn duplicate_hazard(hazard: &hazard::Writer) -> hazard::Writer {
+    // The pointer can't be destroyed while we protect it, so the new hazard needs no validation.
+    let new = match hazard.domain() {
+        Some(domain) => domain.get_hazard(),
+        None => local::get_hazard(),
+    };
+    new.protect(hazard.protected());
+
+    new
+}
+
 impl<T: ?Sized> Guard<T> {
     /// Failably create a new guard.
     ///
@@ -50,14 +109,39 @@ impl<T: ?Sized> Guard<T> {
     ///
     /// This means that the closure can return and error and abort the creation of the guard.
     pub fn try_new<F, E>(ptr: F) -> Result<Guard<T>, E>
+    where F: FnOnce() -> Result<&'static T, E> {
+        // Pin the thread, if possible. As the pin protects everything, the pointer is protected
+        // as soon as it is read.
+        #[cfg(feature = "epoch")]
+        {
+            if let Some(pin) = epoch::pin() {
+                return ptr().map(|ptr| Guard {
+                    protection: Protection::Pin(pin),
+                    pointer: ptr,
+                });
+            }
+        }
+
+        // Get a hazard in blocked state.
+        Guard::try_new_with_hazard(local::get_hazard(), ptr)
+    }
+
+    /// Failably create a new guard in some domain.
+    ///
+    /// This acts like `try_new`, but the guard protects the pointer from the garbage collection
+    /// of `domain` (see `conc::domain`) rather than the default domain.
+    pub fn try_new_in<F, E>(domain: &'static Domain, ptr: F) -> Result<Guard<T>, E>
+    where F: FnOnce() -> Result<&'static T, E> {
+        Guard::try_new_with_hazard(domain.get_hazard(), ptr)
+    }
+
+    /// Failably create a new guard from a blocked hazard.
+    fn try_new_with_hazard<F, E>(hazard: hazard::Writer, ptr: F) -> Result<Guard<T>, E>
     where F: FnOnce() -> Result<&'static T, E> {
         // Increment the number of guards currently being created.
         #[cfg(debug_assertions)]
         CURRENT_CREATING.with(|x| x.set(x.get() + 1));
 
-        // Get a hazard in blocked state.
-        let hazard = local::get_hazard();
-
         // This fence is necessary for ensuring that `hazard` does not get reordered to after `ptr`
         // has run.
         // TODO: Is this fence even necessary?
@@ -81,7 +165,7 @@ impl<T: ?Sized> Guard<T> {
                 hazard.protect(ptr as *const T as *const u8);
 
                 Ok(Guard {
-                    hazard: hazard,
+                    protection: Protection::Hazard(hazard),
                     pointer: ptr,
                 })
             },
@@ -113,6 +197,14 @@ impl<T: ?Sized> Guard<T> {
         Guard::try_new::<_, ()>(|| Ok(ptr())).unwrap()
     }
 
+    /// Create a new guard in some domain.
+    ///
+    /// This acts like `new`, but in domain `domain`. See `try_new_in`.
+    pub fn new_in<F>(domain: &'static Domain, ptr: F) -> Guard<T>
+    where F: FnOnce() -> &'static T {
+        Guard::try_new_in::<_, ()>(domain, || Ok(ptr())).unwrap()
+    }
+
     /// Conditionally create a new guard.
     ///
     /// This acts `try_new`, but with `Option` instead of `Result`.
@@ -121,6 +213,91 @@ impl<T: ?Sized> Guard<T> {
         Guard::try_new(|| ptr().ok_or(())).ok()
     }
 
+    /// Conditionally create a new guard in some domain.
+    ///
+    /// This acts `try_new_in`, but with `Option` instead of `Result`.
+    pub fn maybe_new_in<F>(domain: &'static Domain, ptr: F) -> Option<Guard<T>>
+    where F: FnOnce() -> Option<&'static T> {
+        Guard::try_new_in(domain, || ptr().ok_or(())).ok()
+    }
+
+    /// Protect a pointer loaded from some shared location.
+    ///
+    /// This implements the usual hazard pointer protocol, for pointers which are read through a
+    /// raw load (e.g. of an `AtomicPtr`): The pointer is loaded with `load`, the hazard is set to
+    /// protect it, and the location is loaded again to validate that the pointer was not replaced
+    /// (and possibly destroyed) in the meantime. This is repeated until the two loads agree, at
+    /// which point the pointer is guaranteed to be protected. If `load` returns the null pointer,
+    /// `None` is returned.
+    ///
+    /// Like the closure of `new()`, `load` must not cause a garbage collection.
+    ///
+    /// # Safety
+    ///
+    /// The pointers returned by `load` must be valid while they are reachable from the location,
+    /// and they must only be destroyed through the garbage of the default domain (e.g. through
+    /// `conc::add_garbage_box()`) after they were made unreachable.
+    pub unsafe fn protect_with<F>(mut load: F) -> Option<Guard<T>>
+    where F: FnMut() -> *const T {
+        // Pin the thread, if possible. The pointer can't be destroyed after being loaded, so it
+        // needn't be validated.
+        #[cfg(feature = "epoch")]
+        {
+            if let Some(pin) = epoch::pin() {
+                let ptr = load();
+                return if ptr.is_null() {
+                    None
+                } else {
+                    Some(Guard {
+                        protection: Protection::Pin(pin),
+                        pointer: &*ptr,
+                    })
+                };
+            }
+        }
+
+        Guard::protect_with_hazard(local::get_hazard(), load)
+    }
+
+    /// Protect a pointer loaded from some shared location in some domain.
+    ///
+    /// This acts like `protect_with`, but the pointers must only be destroyed through the garbage
+    /// of `domain`.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `protect_with`.
+    pub unsafe fn protect_with_in<F>(domain: &'static Domain, load: F) -> Option<Guard<T>>
+    where F: FnMut() -> *const T {
+        Guard::protect_with_hazard(domain.get_hazard(), load)
+    }
+
+    /// Protect a pointer loaded from some shared location with a blocked hazard.
+    unsafe fn protect_with_hazard<F>(hazard: hazard::Writer, load: F) -> Option<Guard<T>>
+    where F: FnMut() -> *const T {
+        #[cfg(debug_assertions)]
+        CURRENT_CREATING.with(|x| x.set(x.get() + 1));
+
+        let ptr = hazard.protect_loaded(load);
+        let res = if ptr.is_null() { None } else { Some(&*ptr) };
+
+        #[cfg(debug_assertions)]
+        CURRENT_CREATING.with(|x| x.set(x.get() - 1));
+
+        match res {
+            Some(ptr) => Some(Guard {
+                protection: Protection::Hazard(hazard),
+                pointer: ptr,
+            }),
+            None => {
+                // Set the hazard to free to ensure that the hazard doesn't remain blocking.
+                hazard.free();
+
+                None
+            },
+        }
+    }
+
     /// Map the pointer to another.
     ///
     /// This allows one to map a pointer to a pointer e.g. to an object referenced by the old. It
@@ -129,7 +306,7 @@ impl<T: ?Sized> Guard<T> {
     pub fn map<U: ?Sized, F>(self, f: F) -> Guard<U>
     where F: FnOnce(&T) -> &U {
         Guard {
-            hazard: self.hazard,
+            protection: self.protection,
             pointer: f(self.pointer),
         }
     }
@@ -141,7 +318,7 @@ impl<T: ?Sized> Guard<T> {
     pub fn try_map<U: ?Sized, E, F>(self, f: F) -> Result<Guard<U>, E>
     where F: FnOnce(&T) -> Result<&U, E> {
         Ok(Guard {
-            hazard: self.hazard,
+            protection: self.protection,
             pointer: f(self.pointer)?,
         })
     }
@@ -151,9 +328,9 @@ impl<T: ?Sized> Guard<T> {
     /// This acts `try_map`, but with `Option` instead of `Result`.
     pub fn maybe_map<U: ?Sized, F>(self, f: F) -> Option<Guard<U>>
     where F: FnOnce(&T) -> Option<&U> {
-        let hazard = self.hazard;
+        let protection = self.protection;
         f(self.pointer).map(|res| Guard {
-            hazard: hazard,
+            protection: protection,
             pointer: res,
         })
     }
@@ -162,6 +339,253 @@ impl<T: ?Sized> Guard<T> {
     pub fn as_ptr(&self) -> *const T {
         self.pointer
     }
+
+    /// Share the protection of the guard between its clones.
+    ///
+    /// Cloning a guard publishes a new hazard protecting the same pointer. The clones of a shared
+    /// guard instead use the hazard of the guard, which is reference counted, making cloning
+    /// nearly free. This pays off when many handles to the same object are held, e.g. by futures
+    /// or task-local contexts. The hazard is released when the last clone is dropped.
+    pub fn share(self) -> Guard<T> {
+        let protection = match self.protection {
+            Protection::Hazard(hazard) => Protection::Shared(Arc::new(hazard)),
+            // Pins are nearly free to clone already.
+            protection => protection,
+        };
+
+        Guard {
+            protection: protection,
+            pointer: self.pointer,
+        }
+    }
+
+    /// Convert the guard into a guard which can be sent to other threads.
+    ///
+    /// If the guard is shared (see `share()`), and has clones, it gets a hazard of its own.
+    pub fn into_send(self) -> SendGuard<T>
+    where T: Sync {
+        let protection = match self.protection {
+            Protection::Shared(hazard) => match Arc::try_unwrap(hazard) {
+                Ok(hazard) => Protection::Hazard(hazard),
+                Err(hazard) => Protection::Hazard(duplicate_hazard(&hazard)),
+            },
+            protection => protection,
+        };
+
+        SendGuard {
+            guard: Guard {
+                protection: protection,
+                pointer: self.pointer,
+            },
+        }
+    }
+
+    /// Decompose the guard into its pointer and a token owning its protection.
+    ///
+    /// This is an escape hatch for passing protected pointers where the guard can't go, e.g.
+    /// through the user data of a C callback (see `GuardToken::into_ptr()`). The pointer stays
+    /// protected until the token is dropped, usually after reassembling the guard through
+    /// `Guard::from_raw()`.
+    pub fn into_raw(self) -> (*const T, GuardToken) {
+        (self.pointer, GuardToken {
+            protection: Box::into_raw(Box::new(self.protection)),
+        })
+    }
+
+    /// Reassemble a guard decomposed by `into_raw()`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `token` must be obtained from the same call to `into_raw()` on a `Guard<T>` in
+    /// the current thread.
+    pub unsafe fn from_raw(ptr: *const T, token: GuardToken) -> Guard<T> {
+        let protection = token.into_ptr() as *mut Protection;
+
+        Guard {
+            protection: *Box::from_raw(protection),
+            pointer: &*ptr,
+        }
+    }
+}
+
+impl<T> Guard<[T]> {
+    /// Project the guard onto a subslice.
+    ///
+    /// The subslice stays protected by the protection of the whole slice, so vectors and buffers
+    /// can hand out parts of their contents without any pointer arithmetic.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the range is out of bounds.
+    pub fn slice<R>(self, range: R) -> Guard<[T]>
+    where R: SliceIndex<[T], Output = [T]> {
+        self.map(|slice| &slice[range])
+    }
+
+    /// Project the guard onto an element of the slice.
+    ///
+    /// If `index` is out of bounds, `None` is returned.
+    pub fn project(self, index: usize) -> Option<Guard<T>> {
+        self.maybe_map(|slice| slice.get(index))
+    }
+}
+
+/// A guard which can be sent to other threads.
+///
+/// This is obtained from a guard through `Guard::into_send()` (or `From`), and converted back the
+/// same way. It protects the pointer just like the guard, but its protection is never shared with
+/// other guards. Like the guard, it is `Send` (and `Sync`) whenever `T` is `Sync`, that is, whenever
+/// the reference to the object could be sent itself.
+///
+/// This makes it the guard of async code, where a guard held across an `.await` moves along with
+/// its task (see `SendGuard::maybe_new()` and `Atomic::load_async()`).
+#[must_use = "\
+    You are getting a `conc::SendGuard<T>` without using it, which means it is potentially \
+    unnecessary overhead. Consider replacing the method with something that doesn't \
+    return a guard.\
+"]
+#[derive(Debug)]
+pub struct SendGuard<T: 'static + ?Sized> {
+    /// The guard.
+    ///
+    /// Its protection is never `Protection::Shared`.
+    guard: Guard<T>,
+}
+
+impl<T: ?Sized + Sync> SendGuard<T> {
+    /// Conditionally create a new guard protected by a hazard.
+    ///
+    /// This acts like `Guard::maybe_new()`, but the pointer is never protected by pinning the
+    /// thread (see feature `epoch`). Guards held across the suspension points of async code might
+    /// be held for long, and a pin would hold up the destruction of all the garbage meanwhile.
+    ///
+    /// The hazard is only blocked while the closure runs, which is synchronous, so a blocked hazard
+    /// can never be held across a suspension point.
+    pub fn maybe_new<F>(ptr: F) -> Option<SendGuard<T>>
+    where F: FnOnce() -> Option<&'static T> {
+        SendGuard::maybe_new_with_hazard(local::get_hazard(), ptr)
+    }
+
+    /// Conditionally create a new guard in some domain.
+    ///
+    /// This acts like `maybe_new`, but in domain `domain`. See `Guard::try_new_in`.
+    pub fn maybe_new_in<F>(domain: &'static Domain, ptr: F) -> Option<SendGuard<T>>
+    where F: FnOnce() -> Option<&'static T> {
+        SendGuard::maybe_new_with_hazard(domain.get_hazard(), ptr)
+    }
+
+    /// Conditionally create a new guard from a blocked hazard.
+    fn maybe_new_with_hazard<F>(hazard: hazard::Writer, ptr: F) -> Option<SendGuard<T>>
+    where F: FnOnce() -> Option<&'static T> {
+        Guard::try_new_with_hazard(hazard, || ptr().ok_or(())).ok().map(|guard| SendGuard {
+            guard: guard,
+        })
+    }
+}
+
+impl<T: ?Sized> SendGuard<T> {
+    /// Convert into a thread-local guard.
+    pub fn into_guard(self) -> Guard<T> {
+        self.guard
+    }
+
+    /// Map the pointer to another.
+    ///
+    /// This corresponds to `Guard::map()`.
+    pub fn map<U: ?Sized, F>(self, f: F) -> SendGuard<U>
+    where F: FnOnce(&T) -> &U {
+        SendGuard {
+            guard: self.guard.map(f),
+        }
+    }
+
+    /// Get the raw pointer of this guard.
+    pub fn as_ptr(&self) -> *const T {
+        self.guard.as_ptr()
+    }
+}
+
+impl<T: ?Sized + Sync> From<Guard<T>> for SendGuard<T> {
+    fn from(guard: Guard<T>) -> SendGuard<T> {
+        guard.into_send()
+    }
+}
+
+impl<T: ?Sized> From<SendGuard<T>> for Guard<T> {
+    fn from(guard: SendGuard<T>) -> Guard<T> {
+        guard.into_guard()
+    }
+}
+
+impl<T: ?Sized> Clone for SendGuard<T> {
+    fn clone(&self) -> SendGuard<T> {
+        // Cloning never shares the protection.
+        SendGuard {
+            guard: self.guard.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> ops::Deref for SendGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+/// An opaque token owning the protection of a decomposed guard.
+///
+/// This is obtained by `Guard::into_raw()`. Like the guard, it must stay in the thread it was
+/// created in. Dropping the token ends the protection.
+pub struct GuardToken {
+    /// The boxed protection.
+    protection: *mut Protection,
+}
+
+impl GuardToken {
+    /// Convert the token into a raw pointer.
+    ///
+    /// This allows passing it through FFI. The protection is leaked, unless the pointer is
+    /// converted back through `from_ptr()`.
+    pub fn into_ptr(self) -> *mut c_void {
+        let ptr = self.protection;
+        mem::forget(self);
+        ptr as *mut c_void
+    }
+
+    /// Convert a raw pointer back into a token.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be obtained from `into_ptr()` in the current thread, and must not be converted
+    /// back more than once.
+    pub unsafe fn from_ptr(ptr: *mut c_void) -> GuardToken {
+        GuardToken {
+            protection: ptr as *mut Protection,
+        }
+    }
+}
+
+impl Drop for GuardToken {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.protection) });
+    }
+}
+
+impl fmt::Debug for GuardToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("GuardToken { .. }")
+    }
+}
+
+impl<T: ?Sized> Clone for Guard<T> {
+    fn clone(&self) -> Guard<T> {
+        Guard {
+            protection: self.protection.duplicate(),
+            pointer: self.pointer,
+        }
+    }
 }
 
 impl<T: ?Sized> ops::Deref for Guard<T> {
@@ -177,7 +601,7 @@ mod tests {
     use super::*;
     use std::mem;
 
-    use Atomic;
+    use {Atomic, add_garbage_box};
     use std::sync::atomic;
 
     #[test]
@@ -239,6 +663,231 @@ mod tests {
         assert_eq!(*g, 13);
     }
 
+    #[test]
+    fn protect_with() {
+        let a = Atomic::new(Some(Box::new(42)));
+        let g = unsafe { Guard::protect_with(|| a.load_raw(atomic::Ordering::Acquire)) }.unwrap();
+        a.store(None, atomic::Ordering::Relaxed);
+        ::gc();
+        assert_eq!(*g, 42);
+
+        assert!(unsafe { Guard::protect_with(|| a.load_raw(atomic::Ordering::Acquire)) }.is_none());
+    }
+
+    #[test]
+    // Pinned guards don't validate the pointer.
+    #[cfg_attr(feature = "epoch", ignore)]
+    fn protect_with_retry() {
+        let a = Atomic::new(Some(Box::new(1)));
+        let old = a.load_raw(atomic::Ordering::Acquire);
+        let mut loads = 0;
+
+        // Replace the pointer between the first load and the validation.
+        let g = unsafe {
+            Guard::protect_with(|| {
+                loads += 1;
+                if loads == 1 {
+                    a.get_inner().store(Box::into_raw(Box::new(2)), atomic::Ordering::Release);
+                    old
+                } else {
+                    a.load_raw(atomic::Ordering::Acquire)
+                }
+            })
+        }.unwrap();
+
+        // The replaced pointer is unreachable, and no longer protected.
+        unsafe { add_garbage_box(old); }
+        ::gc();
+
+        assert_eq!(*g, 2);
+        assert_eq!(loads, 3);
+    }
+
+    #[test]
+    fn into_raw_from_raw() {
+        extern "C" fn callback(ptr: *const i32, data: *mut c_void) -> i32 {
+            let g = unsafe { Guard::from_raw(ptr, GuardToken::from_ptr(data)) };
+            *g
+        }
+
+        let a = Atomic::new(Some(Box::new(42)));
+        let (ptr, token) = a.load(atomic::Ordering::Acquire).unwrap().into_raw();
+        a.store(None, atomic::Ordering::Relaxed);
+        ::gc();
+        assert_eq!(callback(ptr, token.into_ptr()), 42);
+    }
+
+    #[test]
+    fn fat_pointers() {
+        use std::fmt::Debug;
+
+        static DROPS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        #[derive(Debug)]
+        struct Dropper;
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let b: Box<Debug + Sync> = Box::new(Dropper);
+        let ptr: *const (Debug + Sync) = Box::into_raw(b);
+        let g = unsafe { Guard::protect_with(|| ptr) }.unwrap();
+        unsafe { ::add_garbage_box_dyn(ptr); }
+        ::gc();
+        assert_eq!(format!("{:?}", &*g), "Dropper");
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 0);
+
+        // The cached hazards keep protecting the object, unless they are freed.
+        drop(g);
+        local::free_cached_hazards();
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if DROPS.load(atomic::Ordering::Relaxed) == 1 {
+                break;
+            }
+        }
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 1);
+
+        let s: &'static [u8] = Box::leak(vec![1, 2, 3].into_boxed_slice());
+        let g = Guard::new(|| s);
+        assert_eq!(g.len(), 3);
+        assert_eq!(g.map(|s| &s[1..]).as_ptr() as *const u8, &s[1] as *const u8);
+    }
+
+    #[test]
+    fn slice() {
+        let b = vec![1, 2, 3, 4].into_boxed_slice();
+        let ptr: *const [i32] = Box::into_raw(b);
+        let g = unsafe { Guard::protect_with(|| ptr) }.unwrap();
+
+        // The subslice is protected along with the slice.
+        let sub = g.slice(1..3);
+        unsafe { ::add_garbage_box_dyn(ptr); }
+        ::gc();
+        assert_eq!(&*sub, &[2, 3]);
+
+        assert_eq!(*sub.slice(1..).project(0).unwrap(), 3);
+        assert!(Guard::new(|| &[1, 2][..]).project(2).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn slice_out_of_bounds() {
+        let _ = Guard::new(|| &[1, 2][..]).slice(1..3);
+    }
+
+    #[test]
+    fn clone() {
+        let a = Atomic::new(Some(Box::new(42)));
+        let g = a.load(atomic::Ordering::Acquire).unwrap();
+        let g2 = g.clone();
+
+        // The clones of the shared guard keep the object alive.
+        let shared = g.share();
+        let clones: Vec<_> = (0..10).map(|_| shared.clone()).collect();
+        a.store(None, atomic::Ordering::Relaxed);
+        drop(shared);
+        ::gc();
+        for g in &clones {
+            assert_eq!(**g, 42);
+        }
+
+        assert_eq!(*g2, 42);
+    }
+
+    #[test]
+    fn clone_mapped() {
+        static DROPS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        struct Dropper(u8, u8);
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let a = Atomic::new(Some(Box::new(Dropper(7, 13))));
+        let g = a.load(atomic::Ordering::Acquire).unwrap().map(|x| {
+            assert_eq!(x.0, 7);
+            &x.1
+        });
+        let clone = g.clone();
+
+        // Only the clone protects the object now.
+        drop(g);
+        local::free_cached_hazards();
+        drop(a);
+        ::gc();
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(*clone, 13);
+    }
+
+    #[test]
+    fn send() {
+        fn assert_send<T: Send>() {}
+
+        assert_send::<Guard<u8>>();
+        assert_send::<Guard<[u8]>>();
+    }
+
+    #[test]
+    fn send_guard() {
+        use std::thread;
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let a = Atomic::new(Some(Box::new(42)));
+        let g = a.load(atomic::Ordering::Acquire).unwrap().share();
+        let clone = g.clone();
+        let g = g.into_send();
+        assert_send(&g);
+
+        // The sent guard still protects the object.
+        let g = thread::spawn(move || {
+            a.store(None, atomic::Ordering::Relaxed);
+            ::gc();
+            assert_eq!(*g, 42);
+            g
+        }).join().unwrap();
+        drop(clone);
+        ::gc();
+
+        let g: Guard<_> = g.clone().into();
+        assert_eq!(*g, 42);
+        assert_eq!(*SendGuard::from(g.share()), 42);
+    }
+
+    #[test]
+    fn into_send_mapped() {
+        static DROPS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        struct Dropper(u8, u8);
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let a = Atomic::new(Some(Box::new(Dropper(7, 13))));
+        let g = a.load(atomic::Ordering::Acquire).unwrap().map(|x| {
+            assert_eq!(x.0, 7);
+            &x.1
+        }).share();
+        let clone = g.clone();
+        let g = g.into_send();
+
+        // Only the sent guard protects the object now.
+        drop(clone);
+        local::free_cached_hazards();
+        drop(a);
+        ::gc();
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(*g, 13);
+    }
+
     #[test]
     #[should_panic]
     fn panic_during_guard_creation() {
@@ -246,6 +895,8 @@ mod tests {
     }
 
     #[test]
+    // A forgotten pinned guard keeps the thread pinned for good.
+    #[cfg_attr(feature = "epoch", ignore)]
     fn nested_guard_creation() {
         for _ in 0..100 {
             let _ = Guard::new(|| {
@@ -255,9 +906,41 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "epoch")]
+    #[test]
+    fn pin_delays_destruction() {
+        static DROPS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        struct Dropper;
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let a = Atomic::new(Some(Box::new(Dropper)));
+        let g = a.load(atomic::Ordering::Acquire).unwrap();
+        a.store(None, atomic::Ordering::Release);
+        ::gc();
+        ::gc();
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 0);
+
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        drop(g);
+        for _ in 0..1000 {
+            ::gc();
+            if DROPS.load(atomic::Ordering::Relaxed) == 1 {
+                return;
+            }
+        }
+        panic!("Garbage not destroyed after the pin ended.");
+    }
+
     #[cfg(debug_assertions)]
     #[test]
     #[should_panic]
+    // Pinned guards don't block the collection.
+    #[cfg_attr(feature = "epoch", ignore)]
     fn debug_catch_infinite_blockage() {
         let _ = Guard::new(|| {
             local::export_garbage();
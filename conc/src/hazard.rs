@@ -13,13 +13,33 @@
 //! The asymmetry of a hazard pair is strictly speaking not necessary, but it allows to enforce
 //! rules (e.g. only the reader/global part may deallocate the hazard box).
 
-use std::sync::atomic::{self, AtomicPtr};
-use std::{mem, thread};
-
-use {debug, local};
+use std::mem;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "std")]
+use std::thread;
+
+use allocator::{self, Allocator};
+use debug;
+use primitives::{self as atomic, AtomicPtr};
+#[cfg(feature = "std")]
+use {local, metrics, settings, parking_lot_core};
+#[cfg(feature = "std")]
+use domain::Domain;
+
+// The states other than "protect" are represented by pointers to the following statics. They are
+// distinct allocations, so they never collide with protected pointers, and they are only ever
+// compared by address. In particular, no integer is ever cast to a pointer, so the encoding is
+// sound under strict provenance.
 
 /// Pointers to this represents the blocked state.
 static BLOCKED: u8 = 0;
+/// Pointers to this represents the blocked state, with threads parked on the hazard.
+///
+/// Readers move the hazard from `BLOCKED` to this before parking, which tells the writer to wake
+/// them up when it unblocks the hazard. This keeps the cost of parking off the writer, unless
+/// someone is actually parked.
+static PARKED: u8 = 0;
 /// Pointers to this represents the free state.
 static FREE: u8 = 0;
 /// Pointers to this represents the dead state.
@@ -60,96 +80,274 @@ pub enum State {
 /// Furthermore, there is an additional state: Blocked. If the hazard is in this state, reading it
 /// will block until it no longer is. This is useful for blocking garbage collection while a value
 /// is being read (avoiding the ABA problem).
-pub fn create() -> (Writer, Reader) {
-    // Allocate the hazard on the heap.
+///
+/// The hazard is allocated by `allocator`, which the reader deallocates it with.
+///
+/// With `std`, the reader records the current thread as the owner of the hazard (see
+/// `Reader::owner()`).
+pub fn create(allocator: &'static Allocator) -> (Writer, Reader) {
+    // Allocate the hazard.
     let ptr = unsafe {
-        &*Box::into_raw(Box::new(AtomicPtr::new(&BLOCKED as *const u8 as *mut u8)))
+        &*allocator::new(allocator, AtomicPtr::new(&BLOCKED as *const u8 as *mut u8))
     };
 
     // Construct the values.
     (Writer {
         ptr: ptr,
+        #[cfg(feature = "std")]
+        domain: None,
     }, Reader {
         ptr: ptr,
+        allocator: allocator,
+        #[cfg(feature = "std")]
+        owner: local::thread_id(),
     })
 }
 
+/// Order the unlinking of garbage before the reading of hazards.
+///
+/// A collection must call this before reading the hazards. It pairs with the fence of
+/// `Writer::protect_loaded()`: Either the collection sees the hazard protecting a pointer, or the
+/// validating load of the writer sees that the pointer was unlinked, and retries.
+///
+/// Note that the garbage is passed to the collection through locks, which only order it by
+/// acquire and release. This doesn't order the preceding unlinking store before the loads of the
+/// hazards, which is why the fence is needed.
+pub fn fence() {
+    atomic::fence(atomic::Ordering::SeqCst);
+}
+
+/// A proof that the writer of a hazard is dead.
+///
+/// This is obtained through `Reader::death_token()`, and allows destroying the hazard (see
+/// `Reader::destroy()`).
+#[derive(Debug)]
+pub struct DeathToken {
+    /// The pointer to the heap-allocated hazard.
+    ptr: &'static AtomicPtr<u8>,
+}
+
+/// The error of a hazard staying blocked past a deadline.
+///
+/// This is returned by `Reader::try_get()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Blocked;
+
 /// An hazard reader.
 ///
 /// This wraps a hazard and provides only ability to read and deallocate it. It is created through
 /// the `create()` function.
 ///
-/// The destructor will, for the sake of safety, panic. To deallocate, use `self.destroy()`
-/// instead.
+/// The hazard is deallocated when the reader is dropped, if the writer is dead. Otherwise, the
+/// writer might still use it, so it is leaked. To ensure deallocation, use `self.destroy()`.
 pub struct Reader {
     /// The pointer to the heap-allocated hazard.
     ptr: &'static AtomicPtr<u8>,
+    /// The allocator of the hazard.
+    allocator: &'static Allocator,
+    /// The thread which created the hazard, if known.
+    #[cfg(feature = "std")]
+    owner: Option<thread::ThreadId>,
 }
 
 impl Reader {
+    /// Get the thread which created the hazard, if known.
+    ///
+    /// Hazards are cached and reused by the thread creating them, so this is usually the thread
+    /// using the hazard. The exception is hazards of a `Domain`, which are shared between threads.
+    /// It is unknown for hazards created after the thread-local state was deinitialized.
+    #[cfg(feature = "std")]
+    pub fn owner(&self) -> Option<thread::ThreadId> {
+        self.owner
+    }
+
     /// Get the state of the hazard.
     ///
-    /// It will spin until the hazard is no longer in a blocked state, unless it is in debug mode,
+    /// It will wait until the hazard is no longer in a blocked state, unless it is in debug mode,
     /// where it will panic given enough spins.
+    ///
+    /// The thread spins for the number of spins given by the backoff policy of the settings of
+    /// the current thread, and is then parked until the writer unblocks the hazard (see
+    /// `Writer::set()`). If `park_blocked_hazards` is disabled in the settings, it follows the
+    /// rest of the backoff policy (yielding and sleeping) instead.
+    ///
+    /// Without `std`, this simply spins until the hazard is unblocked.
+    #[cfg(feature = "std")]
     pub fn get(&self) -> State {
-        // In debug mode, we count the number of spins. In release mode, this should be trivially
-        // optimized out.
+        match self.get_until(None) {
+            Ok(state) => state,
+            Err(Blocked) => unreachable!(),
+        }
+    }
+
+    /// Get the state of the hazard.
+    ///
+    /// See the `std` version.
+    #[cfg(not(feature = "std"))]
+    pub fn get(&self) -> State {
+        loop {
+            if let Ok(state) = self.peek() {
+                return state;
+            }
+
+            atomic::spin_loop_hint();
+        }
+    }
+
+    /// Get the state of the hazard without waiting.
+    ///
+    /// If the hazard is blocked, `Err(Blocked)` is returned right away.
+    pub fn peek(&self) -> Result<State, Blocked> {
+        let ptr = self.ptr.load(atomic::Ordering::Acquire) as *const u8;
+
+        if ptr == &BLOCKED || ptr == &PARKED {
+            Err(Blocked)
+        } else if ptr == &FREE {
+            Ok(State::Free)
+        } else if ptr == &DEAD {
+            Ok(State::Dead)
+        } else {
+            Ok(State::Protect(ptr))
+        }
+    }
+
+    /// Get the state of the hazard, giving up after some time.
+    ///
+    /// This acts like `get()`, but if the hazard is still blocked after `timeout`, `Err(Blocked)`
+    /// is returned. This allows the caller to skip a hazard, which is stuck in the blocked state
+    /// (e.g. because its writer was descheduled for a long time), rather than waiting for it.
+    #[cfg(feature = "std")]
+    pub fn try_get(&self, timeout: Duration) -> Result<State, Blocked> {
+        self.get_until(Some(Instant::now() + timeout))
+    }
+
+    /// Get the state of the hazard, waiting until some deadline (if any) for it to be unblocked.
+    #[cfg(feature = "std")]
+    fn get_until(&self, deadline: Option<Instant>) -> Result<State, Blocked> {
         let mut spins = 0;
+        let settings = settings::get();
+        // The time the hazard was first seen blocked, if the wait is reported.
+        let mut blocked_since = None;
 
         // Spin until not blocked.
         loop {
-            let ptr = self.ptr.load(atomic::Ordering::Acquire) as *const u8;
-
             // Blocked means that the hazard is blocked by another thread, and we must loop until
             // it assumes another state.
-            if ptr == &BLOCKED {
-                // Increment the number of spins.
-                spins += 1;
-                debug_assert!(spins < 100_000_000, "\
-                    Hazard blocked for 100 millions rounds. Panicking as chances are that it will \
-                    never get unblocked.\
-                ");
-
-                continue;
-            } else if ptr == &FREE {
-                return State::Free;
-            } else if ptr == &DEAD {
-                return State::Dead;
+            if let Ok(state) = self.peek() {
+                record_wait(blocked_since);
+                return Ok(state);
+            }
+
+            if blocked_since.is_none() && metrics::is_enabled() {
+                blocked_since = Some(Instant::now());
+            }
+
+            // Increment the number of spins.
+            spins += 1;
+            debug_assert!(deadline.is_some() || spins < 100_000_000, "\
+                Hazard blocked for 100 millions rounds. Panicking as chances are that it will \
+                never get unblocked.\
+            ");
+
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    record_wait(blocked_since);
+                    return Err(Blocked);
+                }
+            }
+
+            if settings.park_blocked_hazards && spins >= settings.backoff.spins {
+                // The blocker is likely descheduled, so we sleep until it unblocks (or the
+                // deadline passes).
+                self.park(deadline);
             } else {
-                return State::Protect(ptr);
+                settings.backoff.snooze(spins);
             }
         }
     }
 
-    /// Destroy the hazard.
+    /// Park the current thread until the hazard is (potentially) unblocked.
     ///
-    /// # Safety
+    /// If `deadline` is given, this returns when it passes, at the latest. This might return
+    /// spuriously, so the state must be checked again afterwards.
+    #[cfg(feature = "std")]
+    fn park(&self, deadline: Option<Instant>) {
+        // Announce that we are parking, such that the writer wakes us up when unblocking the
+        // hazard. If another thread is parked already, the hazard is announced as parked already.
+        let blocked = &BLOCKED as *const u8 as *mut u8;
+        let parked = &PARKED as *const u8 as *mut u8;
+        match self.ptr.compare_exchange(blocked, parked, atomic::Ordering::Relaxed,
+                                        atomic::Ordering::Relaxed) {
+            Ok(_) => (),
+            Err(ptr) if ptr == parked => (),
+            // The hazard was unblocked meanwhile.
+            Err(_) => return,
+        }
+
+        unsafe {
+            parking_lot_core::park(
+                self.ptr as *const AtomicPtr<u8> as usize,
+                // Only sleep if the hazard is still blocked. The writer replaces the state before
+                // waking up the parked threads, so either we see it unblocked here, or we are
+                // woken up.
+                || self.ptr.load(atomic::Ordering::Relaxed) == parked,
+                || {},
+                |_, _| {},
+                parking_lot_core::DEFAULT_PARK_TOKEN,
+                deadline,
+            );
+        }
+    }
+
+    /// Get a proof that the writer is dead, if it is.
     ///
-    /// This is unsafe as it relies on the writer part being dead and not used anymore. There is
-    /// currently no way to express this invariant through the type system, so we must rely on the
-    /// caller to ensure that.
+    /// This returns `Some` if the hazard is in state "dead", which it never leaves (the writer is
+    /// consumed when it dies). It doesn't wait if the hazard is blocked, but returns `None`.
+    pub fn death_token(&self) -> Option<DeathToken> {
+        if self.ptr.load(atomic::Ordering::Acquire) as *const u8 == &DEAD {
+            Some(DeathToken {
+                ptr: self.ptr,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Destroy the hazard.
+    ///
+    /// `token` proves that the writer is dead and no longer uses the hazard (see
+    /// `death_token()`), so the hazard can be deallocated.
     ///
     /// # Panics
     ///
-    /// In debug mode, this will panic if the state of the hazard is not "dead".
-    pub unsafe fn destroy(self) {
-        debug_assert!(self.get() == State::Dead, "Prematurely freeing an active hazard.");
+    /// This will panic if `token` belongs to another hazard.
+    pub fn destroy(self, token: DeathToken) {
+        assert!(self.ptr as *const AtomicPtr<u8> == token.ptr as *const AtomicPtr<u8>,
+                "Destroying a hazard with the death token of another hazard.");
 
-        // Load the pointer and deallocate it.
-        Box::from_raw(self.ptr as *const AtomicPtr<u8> as *mut AtomicPtr<u8>);
-        // Ensure that the RAII destructor doesn't kick in and crashes the program.
-        mem::forget(self);
+        // The destructor deallocates the dead hazard.
     }
 }
 
-/// Panic when it is dropped outside `Reader::destroy()`.
+/// Report the wait for a hazard blocked since `blocked_since` (if any) to the metrics recorder.
+#[cfg(feature = "std")]
+fn record_wait(blocked_since: Option<Instant>) {
+    if let Some(since) = blocked_since {
+        metrics::with(|recorder| recorder.hazard_wait(since.elapsed()));
+    }
+}
+
+/// Deallocate the hazard, if the writer is dead.
 ///
-/// This ought to catch e.g. unwinding.
+/// If it isn't (e.g. when a state is torn down during unwinding), the hazard is leaked, as the
+/// writer might still use it.
 impl Drop for Reader {
     fn drop(&mut self) {
-        panic!("\
-            Hazard readers ought to be destroyed manually through the `Reader::destroy()` method.\
-        ");
+        if self.death_token().is_some() {
+            unsafe {
+                allocator::free(self.allocator, self.ptr as *const AtomicPtr<u8> as *mut AtomicPtr<u8>);
+            }
+        }
     }
 }
 
@@ -158,17 +356,49 @@ impl Drop for Reader {
 /// This wraps a hazard and provides only ability to read and deallocate it. It is created through
 /// the `create()` function.
 ///
-/// The destructor relocate the hazard to the thread-local cache.
+/// The destructor relocate the hazard to the thread-local cache (or the cache of its domain).
 #[derive(Debug)]
 pub struct Writer {
     /// The pointer to the heap-allocated hazard.
     ptr: &'static AtomicPtr<u8>,
+    /// The domain the hazard is registered in.
+    ///
+    /// `None` means the default (global) domain.
+    #[cfg(feature = "std")]
+    domain: Option<&'static Domain>,
 }
 
 impl Writer {
+    /// Bind the hazard to a domain.
+    ///
+    /// This makes the destructor relocate the hazard to the cache of `domain` rather than the
+    /// thread-local cache. The reader part must be registered in `domain`.
+    #[cfg(feature = "std")]
+    pub fn in_domain(mut self, domain: &'static Domain) -> Writer {
+        self.domain = Some(domain);
+        self
+    }
+
+    /// Get the domain the hazard is registered in.
+    ///
+    /// `None` means the default domain.
+    #[cfg(feature = "std")]
+    pub fn domain(&self) -> Option<&'static Domain> {
+        self.domain
+    }
+
     /// Is the hazard blocked?
     pub fn is_blocked(&self) -> bool {
-        self.ptr.load(atomic::Ordering::Acquire) as *const u8 == &BLOCKED
+        let ptr = self.ptr.load(atomic::Ordering::Acquire) as *const u8;
+        ptr == &BLOCKED || ptr == &PARKED
+    }
+
+    /// Get the pointer protected by the hazard.
+    ///
+    /// The hazard must be in protecting state.
+    pub fn protected(&self) -> *const u8 {
+        // Only the writer sets a non-blocked hazard, so there is nothing to synchronize with.
+        self.ptr.load(atomic::Ordering::Relaxed) as *const u8
     }
 
     /// Block the hazard.
@@ -176,11 +406,40 @@ impl Writer {
         self.ptr.store(&BLOCKED as *const u8 as *mut u8, atomic::Ordering::Release);
     }
 
+    /// Set the state of the hazard, waking up the threads parked on it.
+    ///
+    /// `ptr` must not represent the blocked state.
+    fn set(&self, ptr: *const u8) {
+        // Threads only park on blocked hazards, and only the writer blocks the hazard, so if it
+        // isn't blocked, no one can be parked on it, and a plain store will do. This keeps the
+        // common path free of read-modify-writes.
+        #[cfg(feature = "std")]
+        {
+            if self.is_blocked() {
+                // Get the key of the parking lot before the swap, as the hazard might be
+                // deallocated by the reader as soon as it is set to "dead".
+                let key = self.ptr as *const AtomicPtr<u8> as usize;
+
+                // Swap, rather than store, such that we see any reader announcing that it parks
+                // (see `Reader::park()`).
+                if self.ptr.swap(ptr as *mut u8, atomic::Ordering::Release) as *const u8 == &PARKED {
+                    unsafe {
+                        parking_lot_core::unpark_all(key, parking_lot_core::DEFAULT_UNPARK_TOKEN);
+                    }
+                }
+
+                return;
+            }
+        }
+
+        self.ptr.store(ptr as *mut u8, atomic::Ordering::Release);
+    }
+
     /// Set the hazard to "free".
     ///
     /// This sets the state to `State::Free`.
     pub fn free(&self) {
-        self.ptr.store(&FREE as *const u8 as *mut u8, atomic::Ordering::Release);
+        self.set(&FREE);
     }
 
     /// Protect a pointer with the hazard.
@@ -190,7 +449,43 @@ impl Writer {
     pub fn protect(&self, ptr: *const u8) {
         debug::exec(|| println!("Protecting: 0x{:x}", ptr as usize));
 
-        self.ptr.store(ptr as *mut u8, atomic::Ordering::Release);
+        self.set(ptr);
+    }
+
+    /// Protect a pointer loaded from some shared location.
+    ///
+    /// This implements the publication step of the hazard pointer protocol: The pointer is loaded
+    /// with `load`, the hazard is set to protect it, and the location is loaded again to validate
+    /// that the pointer was not unlinked in the meantime. This is repeated until the two loads
+    /// agree. The protected pointer is returned.
+    ///
+    /// If `load` returns the null pointer, it is returned without changing the hazard.
+    ///
+    /// The hazard is published before the validating load is done (see `fence()`), so either a
+    /// collection sees the hazard, or the validation sees the pointer being unlinked.
+    pub fn protect_loaded<T: ?Sized, F>(&self, mut load: F) -> *const T
+    where F: FnMut() -> *const T {
+        let mut ptr = load();
+        loop {
+            if ptr.is_null() {
+                return ptr;
+            }
+
+            // Publish the hazard, and make sure the publication is ordered before the validating
+            // load. This is the only fence, as `set()` doesn't fence the store.
+            self.protect(ptr as *const u8);
+            atomic::fence(atomic::Ordering::SeqCst);
+
+            // Validate that the pointer is still current. Only the addresses are compared, as the
+            // metadata of wide pointers doesn't matter here.
+            let new = load();
+            if new as *const u8 == ptr as *const u8 {
+                return ptr;
+            }
+
+            // It was replaced, so we retry with the new pointer.
+            ptr = new;
+        }
     }
 
     /// Set the hazard to "dead".
@@ -202,7 +497,7 @@ impl Writer {
     /// This is unsafe as usage after this has been called is breaking invariants. Use
     /// `Writer::kill()` to ensure safety through the type system.
     unsafe fn dead(&self) {
-        self.ptr.store(&DEAD as *const u8 as *mut u8, atomic::Ordering::Release);
+        self.set(&DEAD);
     }
 
     /// Set the hazard to "dead".
@@ -212,15 +507,17 @@ impl Writer {
     /// It is consuming to ensure that the caller doesn't accidentally use the hazard reader
     /// afterwards, causing undefined behavior.
     pub fn kill(self) {
+        // Avoid the RAII destructor. This must be done before the hazard is set to dead, as the
+        // reader might deallocate the hazard right away, so the writer must not be moved after.
+        let writer = mem::ManuallyDrop::new(self);
         // Set the state to dead (this is safe as we ensure, by move, that it is not used
         // afterwards).
-        unsafe { self.dead(); }
-        // Avoid the RAII destructor.
-        mem::forget(self);
+        unsafe { writer.dead(); }
     }
 }
 
 impl Drop for Writer {
+    #[cfg(feature = "std")]
     fn drop(&mut self) {
         // Implementation note: Freeing to local state in the destructor does lead to issues with
         // panicking, which this conditional is supposed to solve. The alternative is to outright
@@ -237,24 +534,42 @@ impl Drop for Writer {
             // "dead" and move on. Setting it to dead is safe, as Rust ensures that it is not used
             // after the destructor (i.e. this function).
             unsafe { self.dead(); }
+        } else if let Some(domain) = self.domain {
+            // The hazard belongs to another domain, so it goes back to the cache of that domain,
+            // as the collector of the default domain doesn't scan it.
+            domain.free_hazard(Writer {
+                ptr: self.ptr,
+                domain: self.domain,
+            });
         } else {
             // Free the hazard to the thread-local cache. We have to clone the hazard to get around the
             // fact that `drop` takes `&mut self`.
             local::free_hazard(Writer {
                 ptr: self.ptr,
+                domain: None,
             });
         }
     }
+
+    /// Without `std`, there is no thread-local cache to relocate the hazard to, so it is simply
+    /// set to "dead" (the owners of hazards, e.g. `registry::Participant`, must take care of
+    /// recycling them).
+    #[cfg(not(feature = "std"))]
+    fn drop(&mut self) {
+        unsafe { self.dead(); }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use allocator::Global;
+    use provenance;
     use std::{ptr, thread};
 
     #[test]
     fn set_get() {
-        let (w, r) = create();
+        let (w, r) = create(&Global);
         assert!(w.is_blocked());
 
         w.free();
@@ -271,18 +586,16 @@ mod tests {
 
         w.protect(ptr::null());
         assert_eq!(r.get(), State::Protect(ptr::null()));
-        w.protect(0x1 as *const u8);
-        assert_eq!(r.get(), State::Protect(0x1 as *const u8));
+        w.protect(provenance::dangling(0x1));
+        assert_eq!(r.get(), State::Protect(provenance::dangling(0x1)));
 
         w.kill();
-        unsafe {
-            r.destroy();
-        }
+        reclaim(r);
     }
 
     #[test]
     fn hazard_pair() {
-        let (w, r) = create();
+        let (w, r) = create(&Global);
         let x = 2;
 
         w.free();
@@ -292,33 +605,155 @@ mod tests {
         w.kill();
         assert_eq!(r.get(), State::Dead);
 
-        unsafe {
-            r.destroy();
-        }
+        reclaim(r);
     }
 
     #[test]
     fn cross_thread() {
         for _ in 0..64 {
-            let (w, r) = create();
+            let (w, r) = create(&Global);
 
             thread::spawn(move || {
                 w.kill();
             }).join().unwrap();
 
             assert_eq!(r.get(), State::Dead);
-            unsafe { r.destroy(); }
+            reclaim(r);
+        }
+    }
+
+    #[test]
+    fn park() {
+        use settings;
+        use std::time::Duration;
+
+        for _ in 0..16 {
+            let (w, r) = create(&Global);
+
+            let waiter = thread::spawn(move || {
+                // Parking is the default.
+                assert!(settings::get().park_blocked_hazards);
+
+                // This parks until the hazard is unblocked below.
+                assert_eq!(r.get(), State::Dead);
+                reclaim(r);
+            });
+
+            thread::sleep(Duration::from_millis(10));
+            w.kill();
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn try_get() {
+        use std::time::Duration;
+
+        let (w, r) = create(&Global);
+
+        // The hazard is created blocked.
+        assert_eq!(r.try_get(Duration::from_millis(10)), Err(Blocked));
+
+        w.free();
+        assert_eq!(r.try_get(Duration::from_millis(10)), Ok(State::Free));
+
+        w.kill();
+        reclaim(r);
+    }
+
+    #[test]
+    fn backoff() {
+        use settings::{self, Backoff, Settings};
+        use std::time::Duration;
+
+        for _ in 0..16 {
+            let (w, r) = create(&Global);
+
+            let waiter = thread::spawn(move || {
+                settings::set_local(Settings {
+                    park_blocked_hazards: false,
+                    backoff: Backoff {
+                        spins: 16,
+                        yields: 16,
+                        max_sleep: Duration::from_millis(1),
+                    },
+                    .. Settings::default()
+                });
+
+                // This sleeps until the hazard is unblocked below.
+                assert_eq!(r.get(), State::Dead);
+                reclaim(r);
+            });
+
+            thread::sleep(Duration::from_millis(10));
+            w.kill();
+            waiter.join().unwrap();
         }
     }
 
+    #[test]
+    fn protect_loaded_wide() {
+        let (w, r) = create(&Global);
+        let xs = [1, 2, 3];
+
+        // The length changes between the loads, but the address doesn't, so the validation
+        // succeeds.
+        let mut len = 1;
+        let ptr = w.protect_loaded(|| {
+            len += 1;
+            &xs[..len] as *const [i32]
+        });
+        assert_eq!(ptr as *const u8, xs.as_ptr() as *const u8);
+        assert_eq!(r.get(), State::Protect(xs.as_ptr() as *const u8));
+
+        w.kill();
+        reclaim(r);
+    }
+
+    /// Destroy a hazard whose writer is dead.
+    fn reclaim(r: Reader) {
+        let token = r.death_token().unwrap();
+        r.destroy(token);
+    }
+
+    #[test]
+    fn death_token() {
+        let (w, r) = create(&Global);
+        assert!(r.death_token().is_none());
+        w.free();
+        assert!(r.death_token().is_none());
+        w.kill();
+        assert!(r.death_token().is_some());
+        reclaim(r);
+    }
+
+    #[test]
+    #[should_panic]
+    fn foreign_death_token() {
+        let (w1, r1) = create(&Global);
+        let (w2, r2) = create(&Global);
+        w1.kill();
+        w2.kill();
+
+        let token = r2.death_token().unwrap();
+        r1.destroy(token);
+    }
+
+    #[test]
+    fn drop_alive() {
+        // Dropping the reader of a live hazard leaks it, rather than panicking.
+        let (w, r) = create(&Global);
+        mem::drop(r);
+        w.free();
+        w.kill();
+    }
+
     #[test]
     fn drop() {
         for _ in 0..9000 {
-            let (w, r) = create();
+            let (w, r) = create(&Global);
             w.kill();
-            unsafe {
-                r.destroy();
-            }
+            reclaim(r);
         }
     }
 
@@ -327,7 +762,7 @@ mod tests {
         #[test]
         #[should_panic]
         fn debug_infinite_blockage() {
-            let (w, r) = create();
+            let (w, r) = create(&Global);
             let _ = r.get();
 
             w.kill();
@@ -338,7 +773,7 @@ mod tests {
         #[test]
         #[should_panic]
         fn debug_premature_free() {
-            let (writer, reader) = create();
+            let (writer, reader) = create(&Global);
             writer.set(State::Free);
             mem::forget(reader);
             unsafe {
@@ -347,3 +782,73 @@ mod tests {
         }
     */
 }
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use allocator::Global;
+    use loom::cell::UnsafeCell;
+    use loom::sync::Arc;
+    use loom::thread;
+    use std::ptr;
+
+    /// Check that an object is never destroyed while a hazard protects it.
+    ///
+    /// One thread protects the object through `Writer::protect_loaded()`, while another unlinks
+    /// it, and destroys it, unless the hazard protects it (like a collection does). The destruction
+    /// is modeled by a write to the object, which loom reports if it races with the read of the
+    /// protecting thread.
+    fn publish_scan(recycled: bool) {
+        loom::model(move || {
+            let obj = Box::into_raw(Box::new(UnsafeCell::new(1))) as *mut UnsafeCell<u8>;
+            let shared = Arc::new(AtomicPtr::new(obj));
+            let (writer, reader) = create(&Global);
+            if recycled {
+                // The hazard was used before, so a stale state can be read.
+                writer.free();
+                writer.block();
+            }
+
+            let shared2 = shared.clone();
+            let protector = thread::spawn(move || {
+                let ptr = writer.protect_loaded(|| {
+                    shared2.load(atomic::Ordering::Acquire) as *const UnsafeCell<u8>
+                });
+                if !ptr.is_null() {
+                    unsafe { (*ptr).with(|x| assert_eq!(*x, 1)); }
+                }
+
+                writer.free();
+                writer.kill();
+            });
+
+            // Unlink the object, and collect it.
+            let old = shared.swap(ptr::null_mut(), atomic::Ordering::AcqRel);
+            fence();
+            let protected = match reader.peek() {
+                Ok(State::Protect(ptr)) => ptr == old as *const u8,
+                // The hazard might be about to protect the object.
+                Err(Blocked) => true,
+                Ok(_) => false,
+            };
+            if !protected {
+                unsafe { (*old).with_mut(|x| *x = 0); }
+            }
+
+            protector.join().unwrap();
+            let token = reader.death_token().unwrap();
+            reader.destroy(token);
+            drop(unsafe { Box::from_raw(old) });
+        });
+    }
+
+    #[test]
+    fn publish_scan_new() {
+        publish_scan(false);
+    }
+
+    #[test]
+    fn publish_scan_recycled() {
+        publish_scan(true);
+    }
+}
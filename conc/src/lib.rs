@@ -10,13 +10,27 @@
 //!     * `Atomic<T>` for an lockless readable and writable container.
 //!     * `sync` for basic datastructures implemented through `conc`.
 //!         - `Treiber<T>` for concurrent stacks.
+//!         - `Queue<T>` for concurrent FIFO queues.
+//!         - `Worker<T>` and `Stealer<T>` for work-stealing deques, e.g. of schedulers.
+//!         - `AtomicArc<T>` for atomically swappable `Arc<T>`s.
+//!         - `RcuCell<T>` for atomically replaced snapshots, e.g. of configurations.
 //!         - `Stm<T>` for a simple implementation of STM.
 //! - **Low-level API**
 //!     * `add_garbage()` for queuing destruction of garbage.
 //!     * `Guard<T>` for blocking destruction.
+//!         - `Guard<[T]>` for protecting slices, and projecting onto their parts.
+//!         - `SendGuard<T>` for guards moving between threads, e.g. held by migrating tasks.
+//!     * `fixed` for reclamation without allocations, with a fixed number of hazards.
 //! - **Runtime control**
 //!     * `gc()` for collecting garbage to reduce memory.
 //!     * `settings` for reconfiguring the system on-the-go.
+//!     * `Domain` for isolating the reclamation of some structures from the rest.
+//!     * `domain::scope()` for protecting non-`'static` data.
+//!     * `collector` for collecting garbage in a background thread.
+//!     * `metrics` for reporting the activity of the system to a metrics backend.
+//!     * `thread` for spawning threads, which tear down their state reliably.
+//! - **Compatibility**
+//!     * `compat::epoch` for porting code written against `crossbeam-epoch`.
 //!
 //! ## Why?
 //!
@@ -53,6 +67,11 @@
 //! `CONC_DEBUG_MODE=1 cargo test --features debug-tools`. To get stacktraces after each message,
 //! set environment variable `CONC_DEBUG_STACKTRACE`.
 //!
+//! If you suspect a bug related to memory orderings, enable feature `paranoid`. It makes every
+//! atomic operation of `conc` (including those of `Atomic<T>`) sequentially consistent and
+//! surrounds it with fences. If the bug persists, the orderings aren't the cause. This is very
+//! slow, so it is only meant for debugging.
+//!
 //! ### Examples
 //!
 //! See the [`sync` source code](https://github.com/redox-os/tfs/tree/master/conc/src/sync).
@@ -89,7 +108,8 @@
 //!   call side to pin epochs or similar. This is particularly nice when you design more
 //!   complicated structures.
 //! - `conc` objects (`Guard<T>`) is not bound to a lifetime or similar, meaning that it is
-//!   `future-rs` compatible among other.
+//!   `future-rs` compatible among other. Guards held across suspension points of async code
+//!   should be `SendGuard<T>`s (see `Atomic::load_async()`).
 //! - In `conc`, threads can export garbage while there are active objects, meaning that memory
 //!   won't accumulate on non-stopping usage.
 //! - `conc` is runtime configurable through the `settings` module.
@@ -103,6 +123,20 @@
 //! - In many cases, it is slower.
 //! - Fewer pre-implemented data structures (for now).
 //!
+//! ### Epochs
+//!
+//! If the reads dominate, you can have the best of both through feature `epoch`: The guards of
+//! the default domain then pin the current thread (like epochs do) rather than publishing a
+//! hazard, which makes creating them cheap, especially when nested. The API stays the same, so the
+//! data structures need no changes. Domains keep using hazards.
+//!
+//! The garbage is then only destroyed once no pinned thread can reach it, so a thread holding a
+//! guard for a long time (or leaking one) holds up the destruction of all the garbage of the
+//! default domain, bringing back the memory blow-up described above.
+//!
+//! Threads with natural quiescent points (e.g. server threads running an event loop) can go even
+//! further, and announce them through `conc::quiescent()`, which makes their reads free.
+//!
 //! ## Design & internals
 //!
 //! It based on hazard pointers, although there are several differences. The idea is essentially
@@ -128,6 +162,12 @@
 //! could be protected by hazards. Others might not have been exported from the thread-local cache
 //! yet.
 //!
+//! If some threads add far more garbage than others, the threads can instead pay for the
+//! collection in proportion to the garbage they add (see `Settings::debt_limit`).
+//!
+//! Alternatively, the garbage can be collected in a background thread (see `collector`), such that
+//! other threads never pay for garbage collection.
+//!
 //! ## Performance
 //!
 //! It is worth noting that atomic reads through this library usually requires three atomic CPU
@@ -141,32 +181,108 @@
 //! There are also presets. For example, if you experience high memory usage, you can do:
 //!
 //! ```rust
-//! conc::settings::set_local(conc::settings::Settings::low_memory());
+//! conc::settings::set(conc::settings::Settings::low_memory());
 //! ```
+//!
+//! This sets the settings for every thread. To only change the current thread's settings, use
+//! `settings::set_local()`.
+//!
+//! ## `no_std`
+//!
+//! Without the (default) feature `std`, `conc` only requires `alloc` and `core::sync::atomic`.
+//! Thread-local storage is unavailable then, so the high-level API (`Guard`, `Atomic`, `Domain`,
+//! etc.) is left out. Instead, threads register explicitly in a `Registry`, through which they
+//! protect pointers and add garbage (see `registry`).
+//!
+//! The hazards are allocated on the global allocator by default. To place them elsewhere (e.g. in
+//! an arena), see `allocator`.
 
-#![feature(thread_local_state, const_fn)]
+#![cfg_attr(feature = "std", feature(thread_local_state, const_fn))]
+#![cfg_attr(not(feature = "std"), feature(alloc))]
+#![cfg_attr(not(feature = "std"), no_std)]
+// Parts of the internals are only used by the API requiring `std`.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
 #![deny(missing_docs)]
 
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate cbloom;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "std")]
 extern crate rand;
+#[cfg(feature = "std")]
 extern crate parking_lot;
+#[cfg(feature = "std")]
+extern crate parking_lot_core;
+#[cfg(loom)]
+extern crate loom;
 
+/// The parts of `std` available in `core`.
+///
+/// This allows the modules to refer to `std` regardless of whether it is available.
+#[cfg(not(feature = "std"))]
+mod std {
+    pub use core::*;
+}
+
+/// Discard a message.
+///
+/// Without `std`, there is no standard output for the debug messages (see `debug::exec()`) to go
+/// to.
+#[cfg(not(feature = "std"))]
+macro_rules! println {
+    ($($arg:tt)*) => { () };
+}
+
+pub mod allocator;
+#[cfg(feature = "std")]
 mod atomic;
-mod debug;
+mod atomics;
+#[cfg(feature = "std")]
+pub mod collector;
+#[cfg(feature = "std")]
+pub mod compat;
+pub mod debug;
+#[cfg(feature = "std")]
+pub mod domain;
+#[cfg(feature = "epoch")]
+mod epoch;
+pub mod fixed;
 mod garbage;
 mod global;
+#[cfg(feature = "std")]
 mod guard;
 mod hazard;
+#[cfg(feature = "std")]
 mod local;
 mod mpsc;
+pub mod metrics;
+mod mutex;
+mod primitives;
+mod provenance;
+pub mod registry;
+#[cfg(feature = "std")]
 pub mod settings;
+#[cfg(feature = "std")]
 pub mod sync;
+#[cfg(feature = "std")]
+pub mod thread;
 
-pub use atomic::Atomic;
-pub use guard::Guard;
+#[cfg(feature = "std")]
+pub use atomic::{Atomic, WeakGuard, protect_all};
+#[cfg(feature = "std")]
+pub use domain::{Domain, HazardDomain};
+pub use global::WouldBlock;
+#[cfg(feature = "std")]
+pub use guard::{Guard, GuardToken, SendGuard};
+pub use registry::{Participant, Registry};
 
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
 use garbage::Garbage;
 
 /// Attempt to collect garbage.
@@ -179,8 +295,9 @@ use garbage::Garbage;
 /// If another thread is currently doing 2., it will be skipped. This makes it different from
 /// `conc::gc()`, which will block.
 ///
-/// If 2. fails (that is, another thread is garbage collecting), `Err(())` is returned. Otherwise
-/// `Ok(())` is returned.
+/// If 2. fails (that is, another thread is garbage collecting), `Err(WouldBlock)` is returned.
+/// Otherwise `Ok(())` is returned. Hence, this never waits for another thread, making it suitable
+/// for latency-sensitive threads collecting opportunistically.
 ///
 /// # Use case
 ///
@@ -197,14 +314,35 @@ use garbage::Garbage;
 ///
 /// # Panic
 ///
-/// If a destructor panics during the garbage collection, theis function will panic aswell.
-pub fn try_gc() -> Result<(), ()> {
+/// If a destructor panics during the garbage collection, the panic is caught, and the collection
+/// continues with the rest of the garbage. See `destructor_panics()`.
+#[cfg(feature = "std")]
+pub fn try_gc() -> Result<(), WouldBlock> {
     // Export the local garbage to ensure that the garbage of the current thread gets collected.
     local::export_garbage();
     // Run the global GC.
     global::try_gc()
 }
 
+/// Attempt to collect garbage incrementally.
+///
+/// This acts like `try_gc()`, but scans at most `max_items` pieces of garbage. The next call
+/// continues where this one stopped, so calling it repeatedly eventually scans all the garbage.
+///
+/// # Use case
+///
+/// This bounds the time spent collecting garbage in a single call, even when millions of pieces
+/// of garbage are queued, making it suitable for soft real-time threads. Note that the hazards are
+/// still scanned in full, and that a garbage collection blocked by another thread returns
+/// `Err(WouldBlock)` rather than waiting.
+#[cfg(feature = "std")]
+pub fn gc_with_budget(max_items: usize) -> Result<(), WouldBlock> {
+    // Export the local garbage to ensure that the garbage of the current thread gets collected.
+    local::export_garbage();
+    // Run the global GC.
+    global::try_gc_with_budget(max_items)
+}
+
 /// Collect garbage.
 ///
 /// This function does two things:
@@ -231,12 +369,147 @@ pub fn try_gc() -> Result<(), ()> {
 ///
 /// # Panic
 ///
-/// If a destructor panics during the garbage collection, theis function will panic aswell.
+/// If a destructor panics during the garbage collection, the panic is caught, and the collection
+/// continues with the rest of the garbage. See `destructor_panics()`.
+#[cfg(feature = "std")]
 pub fn gc() {
     // Export the local garbage to ensure that the garbage of the current thread gets collected.
     local::export_garbage();
     // Try to garbage collect until it succeeds.
-    while let Err(()) = global::try_gc() {}
+    while let Err(WouldBlock) = global::try_gc() {}
+}
+
+/// Export the garbage of the current thread.
+///
+/// Garbage is queued thread-locally, and only exported to the global state (where it can be
+/// collected) once enough is accumulated, or when the thread exits. This exports it right away.
+///
+/// # Use case
+///
+/// Thread pools reusing their threads never exit them, so the garbage queued by a task might
+/// linger in the thread until later tasks add enough garbage. Frameworks can call this at task
+/// boundaries to flush the garbage of a task.
+///
+/// Unlike `conc::gc()`, this doesn't collect the garbage itself, but it might tick (see
+/// `Settings::gc_probability`).
+#[cfg(feature = "std")]
+pub fn export_garbage() {
+    local::export_garbage();
+}
+
+/// Register a hook to run when the current thread exits.
+///
+/// The hook runs as part of the destructor of the thread-local state of `conc`, right before the
+/// hazards of the thread are recycled and its garbage is exported. This allows libraries building on
+/// `conc` to tear down their thread-local state in a well-defined order relative to `conc`'s,
+/// rather than relying on the (unspecified) order of thread-local destructors.
+///
+/// Hooks run in the order they were registered. They can still use `conc`, but any garbage they
+/// add goes directly to the global state. If the thread is already exiting, the hook runs right
+/// away.
+///
+/// # Panics
+///
+/// A hook panicking while the thread exits aborts the process, as with any thread-local
+/// destructor.
+#[cfg(feature = "std")]
+pub fn on_thread_exit<F: FnOnce() + 'static>(hook: F) {
+    local::on_thread_exit(hook);
+}
+
+/// Set the maximal number of hazards cached by the current thread.
+///
+/// Hazards are cached thread-locally for reuse, and the cache grows to the number of guards the
+/// thread held at once. After a burst of guards, this can hold up a lot of hazards, so long-lived
+/// threads can bound the cache: Hazards beyond the limit are killed (and eventually deallocated)
+/// rather than cached. By default, the cache is unbounded.
+#[cfg(feature = "std")]
+pub fn set_max_cached_hazards(max: usize) {
+    local::set_max_cached_hazards(max);
+}
+
+/// Release the hazards cached by the current thread.
+///
+/// This kills the cached hazards, and releases the memory of the cache, e.g. for a worker thread
+/// going idle after a spike of activity. New hazards are created as they are needed.
+#[cfg(feature = "std")]
+pub fn shrink_hazard_cache() {
+    local::shrink_to_fit();
+}
+
+/// Announce a quiescent state of the current thread.
+///
+/// A quiescent state is a point at which the thread holds no references to objects of the
+/// default domain, e.g. between two iterations of an event loop. The first call brings the thread
+/// "online": From then on, it is considered to be reading at all times, except at its quiescent
+/// states, so the garbage of the default domain is only destroyed once every online thread
+/// announced a quiescent state after it was unlinked.
+///
+/// In return, reads are free: A pointer loaded from the default domain by an online thread (e.g.
+/// through `Atomic::load_raw()`) stays valid until the next quiescent state of the thread, so it
+/// can be dereferenced without a guard. Guards still work as usual, and are cheaper. A quiescent
+/// state announced while guards of the thread are alive has no effect.
+///
+/// An online thread which stops announcing quiescent states holds up the destruction of all the
+/// garbage, so threads about to block for a long time should go offline (see `conc::offline()`).
+/// Threads go offline when they exit.
+///
+/// This requires feature `epoch`.
+#[cfg(feature = "epoch")]
+pub fn quiescent() {
+    epoch::quiescent();
+}
+
+/// Take the current thread offline.
+///
+/// This ends the online state started by `conc::quiescent()`. Pointers loaded without a guard are
+/// no longer valid afterwards.
+#[cfg(feature = "epoch")]
+pub fn offline() {
+    epoch::offline();
+}
+
+/// Get statistics of the reclamation system.
+///
+/// This returns a snapshot of the number of hazards (by state), the amount of garbage queued in
+/// the current thread and globally, and the number of garbage collections completed, which is
+/// useful for monitoring why memory grows (see also `metrics`).
+///
+/// This waits for a running garbage collection to finish.
+#[cfg(feature = "std")]
+pub fn stats() -> metrics::Stats {
+    metrics::Stats {
+        local_garbage: local::garbage_len(),
+        .. global::stats()
+    }
+}
+
+/// Get the number of garbage destructors which panicked.
+///
+/// Panics in destructors are caught by the garbage collector, such that the collection can finish
+/// destroying the rest of the garbage. This counts them, for the application to detect
+/// misbehaving destructors.
+#[cfg(feature = "std")]
+pub fn destructor_panics() -> usize {
+    global::destructor_panics()
+}
+
+/// Register a callback to run when an object is reclaimed.
+///
+/// The callback runs on the collecting thread, right after the garbage destroying the object
+/// pointed to by `ptr` is destroyed (i.e. once no guard protects it anymore), e.g. for metrics or
+/// for releasing an external resource tied to the object. This applies to garbage of any domain.
+/// Several callbacks can be registered for the same object, and they run in the order they were
+/// registered.
+///
+/// The callbacks are looked up by address, so they must only be registered for objects which are
+/// retired as garbage (or are garbage already). Otherwise, they are never run, or run when another
+/// object with the same address is reclaimed. Panics in callbacks are caught and counted like
+/// panics in destructors (see `conc::destructor_panics()`).
+#[cfg(feature = "std")]
+pub fn on_reclaim<T: ?Sized, F>(ptr: *const T, callback: F)
+where F: FnOnce() + Send + 'static {
+    global::on_reclaim(ptr as *const u8 as usize, callback);
 }
 
 /// Declare a pointer unreachable garbage to be deleted eventually.
@@ -263,14 +536,39 @@ pub fn gc() {
 ///
 /// # Destruction
 ///
-/// If the destructor provided panics under execution, it will cause panic in the garbage
-/// collection, and the destructor won't run again.
+/// If the destructor provided panics under execution, the panic is caught and counted (see
+/// `destructor_panics()`), and the destructor won't run again.
+#[cfg(feature = "std")]
 pub fn add_garbage<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
     local::add_garbage(unsafe {
         Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
     });
 }
 
+/// Declare a pointer unreachable garbage to be deleted eventually by a closure.
+///
+/// This acts like `add_garbage`, but the destructor is a closure, which is called with the
+/// pointer once `ptr` is no longer protected. This allows for custom teardown logic holding state
+/// (e.g. freeing into an arena, decrementing counters or closing file descriptors).
+///
+/// The closure is called at most once. The same criteria as for `add_garbage` apply.
+#[cfg(feature = "std")]
+pub fn add_garbage_with<T: Sync, F>(ptr: &'static T, dtor: F)
+where F: FnOnce(*const u8) + Send + 'static {
+    local::add_garbage(Garbage::new_with(ptr as *const T as *const u8, dtor));
+}
+
+/// Declare a pointer unreachable garbage holding `size` bytes to be deleted eventually.
+///
+/// This acts like `add_garbage`, but the garbage is accounted as `size` bytes, which should be the
+/// memory freed by `dtor` (see `Settings::max_garbage_bytes`).
+#[cfg(feature = "std")]
+pub fn add_garbage_sized<T: Sync>(ptr: &'static T, dtor: fn(&'static T), size: usize) {
+    local::add_garbage(unsafe {
+        Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
+    }.with_size(size));
+}
+
 /// Add a heap-allocated `Box<T>` as garbage.
 ///
 /// This adds a `Box<T>` represented by pointer `ptr` to the to-be-destroyed garbage queue.
@@ -282,8 +580,63 @@ pub fn add_garbage<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
 /// This is unsafe as the pointer could be aliased or invalid. To satisfy invariants, the pointer
 /// shall be a valid object, allocated through `Box::new(x)` or alike, and shall only be used as
 /// long as there are hazard protecting it.
+#[cfg(feature = "std")]
 pub unsafe fn add_garbage_box<T>(ptr: *const T) {
     local::add_garbage(
         Garbage::new_box(ptr)
     );
 }
+
+/// Add a heap-allocated `Box<T>` holding `size` bytes as garbage.
+///
+/// This acts like `add_garbage_box`, but the garbage is accounted as `size` bytes rather than the
+/// size of `T`. This should include the heap memory owned by the object (e.g. the buffer of a
+/// `Vec`), such that large garbage is collected in time (see `Settings::max_garbage_bytes`).
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+#[cfg(feature = "std")]
+pub unsafe fn add_garbage_box_sized<T>(ptr: *const T, size: usize) {
+    local::add_garbage(
+        Garbage::new_box(ptr).with_size(size)
+    );
+}
+
+/// Add a heap-allocated box of an unsized type as garbage.
+///
+/// This acts like `add_garbage_box`, but for boxes of unsized types, such as `Box<Trait>` or
+/// `Box<[T]>`, whose pointers are fat. The garbage is destroyed once no guard protects its data
+/// pointer, regardless of the metadata (the vtable or length) of the guards.
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+#[cfg(feature = "std")]
+pub unsafe fn add_garbage_box_dyn<T: ?Sized + 'static>(ptr: *const T) {
+    local::add_garbage(
+        Garbage::new_box_dyn(ptr)
+    );
+}
+
+/// Add a batch of heap-allocated `Box<T>`s as garbage.
+///
+/// This adds the boxes represented by the pointers `ptrs` to the to-be-destroyed garbage queue as
+/// a single item. The batch is destroyed as a whole (all at once) when none of its boxes are
+/// protected, which is considerably cheaper than adding the boxes individually, when many objects
+/// are retired at once.
+///
+/// For more details, see `add_garbage_box`.
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+#[cfg(feature = "std")]
+pub unsafe fn add_garbage_box_batch<T>(ptrs: Vec<*const T>) {
+    // Empty batches have nothing to destroy.
+    if !ptrs.is_empty() {
+        local::add_garbage(
+            Garbage::new_box_batch(ptrs)
+        );
+    }
+}
@@ -2,9 +2,17 @@
 
 use std::{mem, thread};
 use std::cell::RefCell;
-use {global, hazard, guard, debug, settings};
+use {collector, global, hazard, guard, debug, metrics, settings};
 use garbage::Garbage;
 
+/// The number of recycled garbage buffers taken from the global state at a time.
+const BUFFERS_TAKEN: usize = 4;
+/// The number of pieces of garbage scanned per piece added, when paying off debt.
+///
+/// This is more than one, such that the collection keeps up with the garbage, even if some of it
+/// is protected (and hence scanned repeatedly).
+const DEBT_WORK_FACTOR: usize = 2;
+
 thread_local! {
     /// The state of this thread.
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -13,10 +21,13 @@ thread_local! {
 /// Add new garbage to be deleted.
 ///
 /// This garbage is pushed to a thread-local queue. When enough garbage is accumulated in the
-/// thread, it is exported to the global state.
+/// thread, it is exported to the global state. If the outstanding garbage exceeds the byte limit
+/// (see `Settings::max_garbage_bytes`), it is collected right away. If the thread pays for the
+/// collection through debt (see `Settings::debt_limit`), it collects once its debt is too high.
 pub fn add_garbage(garbage: Garbage) {
     // Print message in debug mode.
     debug::exec(|| println!("Adding garbage: {:?}", garbage));
+    metrics::with(|recorder| recorder.garbage_queued(1));
     // Since this function can trigger a GC, it must not be called inside a guard constructor.
     guard::debug_assert_no_create();
 
@@ -25,7 +36,21 @@ pub fn add_garbage(garbage: Garbage) {
         global::export_garbage(vec![garbage]);
     } else {
         // Add the garbage.
-        if STATE.with(|s| s.borrow_mut().add_garbage(garbage)) {
+        let exported = STATE.with(|s| s.borrow_mut().add_garbage(garbage));
+        let settings = settings::get();
+        if exported && global::garbage_bytes() > settings.max_garbage_bytes {
+            // Too much memory is held up by garbage, so we collect it now (or let the background
+            // collector do it).
+            if collector::is_running() {
+                collector::wake();
+            } else {
+                let _ = global::try_gc();
+            }
+        } else if let Some(limit) = settings.debt_limit {
+            if STATE.with(|s| s.borrow().debt) > limit {
+                pay_debt();
+            }
+        } else if exported {
             // The local state exported garbage to the global state, hence we must tick in order to
             // ensure that the garbage is periodically collected.
             global::tick();
@@ -33,6 +58,38 @@ pub fn add_garbage(garbage: Garbage) {
     }
 }
 
+/// Pay off the debt of this thread by doing a share of the collection work.
+///
+/// The garbage of the thread is exported, and then some garbage is collected, scanning
+/// `DEBT_WORK_FACTOR` times as many pieces as the thread added since it last paid. If another
+/// thread is collecting, the debt is kept, and paid off by a later call.
+fn pay_debt() {
+    // Only exported garbage can be collected.
+    let (debt, pieces) = STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.export_garbage();
+        (s.debt, s.debt_pieces)
+    });
+
+    // The state must not be borrowed while collecting, as the destructors might use it.
+    let paid = if collector::is_running() {
+        // The background collector does the work for us.
+        collector::wake();
+        true
+    } else {
+        global::try_gc_with_budget(pieces.saturating_mul(DEBT_WORK_FACTOR)).is_ok()
+    };
+
+    if paid {
+        // The destructors might have added garbage meanwhile, so we only subtract what we paid.
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            s.debt -= debt;
+            s.debt_pieces -= pieces;
+        });
+    }
+}
+
 /// Get a blocked hazard.
 ///
 /// If possible, this will simply pop one of the thread-local cache of hazards. Otherwise, one must
@@ -81,6 +138,103 @@ pub fn free_hazard(hazard: hazard::Writer) {
     }
 }
 
+/// Free the hazards cached in this thread.
+///
+/// The cached hazards might still protect the pointer they were last used with (see
+/// `Settings::max_non_free_hazards`). This sets them to "free", such that they cannot hold back
+/// any garbage.
+pub fn free_cached_hazards() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| s.borrow_mut().free_cached_hazards());
+    }
+}
+
+/// Set the maximal number of hazards cached in this thread.
+///
+/// Hazards freed while the cache is full are killed rather than cached, and the cached hazards
+/// exceeding the limit are killed right away. Hazards adopted from exited threads are held to the
+/// limit as well. By default, the cache is unbounded.
+pub fn set_max_cached_hazards(max: usize) {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| s.borrow_mut().set_max_cached_hazards(max));
+    }
+}
+
+/// Release the hazards cached in this thread.
+///
+/// This kills the cached hazards, such that the global state can deallocate them, and releases
+/// the memory of the cache itself.
+pub fn shrink_to_fit() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| s.borrow_mut().shrink_to_fit());
+    }
+}
+
+/// Get the id of the current thread.
+///
+/// This returns `None` if the thread-local state was deinitialized, as the thread might not be
+/// available anymore at that point.
+pub fn thread_id() -> Option<thread::ThreadId> {
+    if STATE.state() == thread::LocalKeyState::Destroyed {
+        None
+    } else {
+        Some(thread::current().id())
+    }
+}
+
+/// Get the amount of garbage queued in this thread.
+pub fn garbage_len() -> usize {
+    if STATE.state() == thread::LocalKeyState::Destroyed {
+        // The state was deinitialized, so the garbage was exported.
+        0
+    } else {
+        STATE.with(|s| s.borrow().garbage.len())
+    }
+}
+
+/// Register a hook to run when this thread exits.
+///
+/// The hooks run in the order they were registered, when the thread-local state is deinitialized,
+/// before its hazards are recycled and its garbage exported. If the state was already deinitialized
+/// (i.e. the thread is exiting), the hook is run right away.
+pub fn on_thread_exit<F: FnOnce() + 'static>(hook: F) {
+    if STATE.state() == thread::LocalKeyState::Destroyed {
+        hook();
+    } else {
+        // `FnOnce` cannot be called from a box, so we wrap it in an `FnMut` taking it out.
+        let mut hook = Some(hook);
+        STATE.with(|s| s.borrow_mut().exit_hooks.push(Box::new(move || {
+            if let Some(hook) = hook.take() {
+                hook();
+            }
+        })));
+    }
+}
+
+/// Tear down the state of this thread ahead of its exit.
+///
+/// This does what the deinitialization of the thread-local state does: It runs the exit hooks,
+/// hands the cached hazards over to other threads (see `global::recycle_hazards()`), and exports
+/// the garbage to the global state. The state can still be used afterwards.
+pub fn clean_up() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        // Run the hooks without borrowing the state, as they might use it (or register new
+        // hooks).
+        loop {
+            let hooks = STATE.with(|s| mem::replace(&mut s.borrow_mut().exit_hooks, Vec::new()));
+            if hooks.is_empty() {
+                break;
+            }
+
+            for mut hook in hooks {
+                hook();
+            }
+        }
+
+        STATE.with(|s| s.borrow_mut().clean_up());
+    }
+}
+
 /// Export the garbage of this thread to the global state.
 ///
 /// This is useful for propagating accumulated garbage such that it can be destroyed by the next
@@ -104,6 +258,21 @@ pub fn export_garbage() {
 struct State {
     /// The cached garbage waiting to be exported to the global state.
     garbage: Vec<Garbage>,
+    /// The approximate number of bytes of the cached garbage.
+    garbage_bytes: usize,
+    /// The debt of the thread in bytes.
+    ///
+    /// This is only accumulated if the collection is paid for through debt (see
+    /// `Settings::debt_limit`).
+    debt: usize,
+    /// The number of pieces of garbage added since the debt was last paid off.
+    debt_pieces: usize,
+    /// The pool of empty garbage buffers.
+    ///
+    /// Exported garbage is sent to the global state in its buffer, which is recycled after the
+    /// garbage was taken out of it. To avoid allocating a new buffer for every exportation, the
+    /// recycled buffers are taken from the global state a few at a time and kept here.
+    buffers: Vec<Vec<Garbage>>,
     /// The cache of currently available hazards.
     ///
     /// We maintain this cache to avoid the performance hit of creating new hazards.
@@ -119,6 +288,14 @@ struct State {
     ///
     /// It is useful for knowing when to free the hazards to allow garbage collection.
     available_hazards_free_before: usize,
+    /// The maximal number of hazards in the cache.
+    ///
+    /// `None` means that the cache is unbounded. See `set_max_cached_hazards()`.
+    max_cached_hazards: Option<usize>,
+    /// The hooks to run when the thread exits.
+    ///
+    /// See `on_thread_exit()`.
+    exit_hooks: Vec<Box<FnMut()>>,
 }
 
 impl State {
@@ -130,9 +307,28 @@ impl State {
     /// See `get_hazard()`.
     fn get_hazard(&mut self) -> hazard::Writer {
         // Check if there is hazards in the cache.
+        if self.available_hazards.is_empty()
+            && global::take_recycled_hazards(&mut self.available_hazards) {
+            // We adopted the hazards of an exited thread, which are all free. The ones exceeding
+            // the limit of the cache (besides the one taken now) are put back for other threads.
+            if let Some(max) = self.max_cached_hazards {
+                if self.available_hazards.len() > max + 1 {
+                    let rest = self.available_hazards.split_off(max + 1);
+                    global::recycle_hazards(rest);
+                }
+            }
+
+            self.available_hazards_free_before = self.available_hazards.len();
+        }
+
         if let Some(hazard) = self.available_hazards.pop() {
             // There is; we don't need to create a new hazard.
 
+            // The popped hazard might have been free.
+            if self.available_hazards_free_before > self.available_hazards.len() {
+                self.available_hazards_free_before = self.available_hazards.len();
+            }
+
             // Since the hazard popped from the cache is not blocked, we must block the hazard to
             // satisfy the requirements of this function.
             hazard.block();
@@ -148,17 +344,57 @@ impl State {
         // FIXME: This can lead to some subtle bugs, since the dtor is unpredictable as there is no
         //        way of predicting when the hazard is cleared.
 
+        // Check if the cache is full.
+        if let Some(max) = self.max_cached_hazards {
+            if self.available_hazards.len() >= max {
+                // It is; the hazard is killed, such that it can be deallocated.
+                hazard.kill();
+                return;
+            }
+        }
+
         // Push the given hazard to the cache.
         self.available_hazards.push(hazard);
 
         // Check if we exceeded the limit.
         if self.non_free_hazards() > settings::get().max_non_free_hazards {
             // We did; we must now set the non-free hazards to "free".
-            for i in &self.available_hazards[self.available_hazards_free_before..] {
-                i.free();
+            self.free_cached_hazards();
+        }
+    }
+
+    /// See `free_cached_hazards()`.
+    fn free_cached_hazards(&mut self) {
+        for i in &self.available_hazards[self.available_hazards_free_before..] {
+            i.free();
+        }
+
+        // Update the counter such that we mark the new hazards set to "free".
+        self.available_hazards_free_before = self.available_hazards.len();
+    }
+
+    /// See `set_max_cached_hazards()`.
+    fn set_max_cached_hazards(&mut self, max: usize) {
+        self.max_cached_hazards = Some(max);
+        self.kill_cached_hazards(max);
+    }
+
+    /// See `shrink_to_fit()`.
+    fn shrink_to_fit(&mut self) {
+        self.kill_cached_hazards(0);
+        self.available_hazards.shrink_to_fit();
+    }
+
+    /// Kill the cached hazards, except for the first `keep` of them.
+    fn kill_cached_hazards(&mut self, keep: usize) {
+        if self.available_hazards.len() > keep {
+            for hazard in self.available_hazards.drain(keep..) {
+                hazard.kill();
             }
+        }
 
-            // Update the counter such that we mark the new hazards set to "free".
+        // The killed hazards might have been free.
+        if self.available_hazards_free_before > self.available_hazards.len() {
             self.available_hazards_free_before = self.available_hazards.len();
         }
     }
@@ -173,48 +409,82 @@ impl State {
     /// When this happens (i.e. the global state gets the garbage), it returns `true`. Otherwise,
     /// it returns `false`.
     fn add_garbage(&mut self, garbage: Garbage) -> bool {
+        let settings = settings::get();
+        if settings.debt_limit.is_some() {
+            // Every piece costs at least the space it takes in the queues.
+            self.debt = self.debt.saturating_add(garbage.size() + mem::size_of::<Garbage>());
+            self.debt_pieces += 1;
+        }
+
         // Push the garbage to the cache of garbage.
+        self.garbage_bytes += garbage.size();
         self.garbage.push(garbage);
 
-        // Export the garbage if it exceeds the limit.
-        // TODO: use memory instead of items as a metric.
-        if self.garbage.len() > settings::get().max_garbage_before_export {
+        // Export the garbage if it exceeds the limit, or if the outstanding garbage holds up too
+        // much memory.
+        if self.garbage.len() > settings.max_garbage_before_export
+            || self.garbage_bytes.saturating_add(global::garbage_bytes())
+                > settings.max_garbage_bytes {
             self.export_garbage();
             true
         } else { false }
     }
 
+    /// See `clean_up()`.
+    fn clean_up(&mut self) {
+        // Hand the hazards over to other threads.
+        global::recycle_hazards(mem::replace(&mut self.available_hazards, Vec::new()));
+        self.available_hazards_free_before = 0;
+
+        self.export_garbage();
+    }
+
     /// See `export_garbage()` for more information.
     fn export_garbage(&mut self) {
         // Print message in debug mode.
         debug::exec(|| println!("Exporting garbage."));
 
+        // Get a new buffer, preferably a recycled one.
+        if self.buffers.is_empty() {
+            global::take_buffers(BUFFERS_TAKEN, &mut self.buffers);
+        }
+        let buffer = self.buffers.pop().unwrap_or_else(Vec::new);
+
         // Clear the vector and export the garbage.
-        global::export_garbage(mem::replace(&mut self.garbage, Vec::new()));
+        self.garbage_bytes = 0;
+        global::export_garbage(mem::replace(&mut self.garbage, buffer));
     }
 }
 
 impl Drop for State {
     fn drop(&mut self) {
-        // Clear every hazard to "dead" state.
-        for hazard in self.available_hazards.drain(..) {
-            hazard.kill();
+        // Run the exit hooks first, such that they can still free hazards and add garbage (which
+        // goes directly to the global state, as this state is deinitialized).
+        for mut hook in self.exit_hooks.drain(..) {
+            hook();
         }
 
+        // Hand the hazards over to other threads.
+        global::recycle_hazards(mem::replace(&mut self.available_hazards, Vec::new()));
+
         // The thread is exiting, thus we must export the garbage to the global state to avoid
         // memory leaks. It is very important that this does indeed not tick, as causing garbage
         // collection means accessing RNG state, a TLS variable, which cannot be done when, we are
         // here, after it has deinitialized.
-        // TODO: Figure out a way we can tick anyway.
-        self.export_garbage();
+        // TODO: Figure out a way we can tick anyway. No new buffer is needed, so we don't take a
+        //       recycled one.
+        global::export_garbage(mem::replace(&mut self.garbage, Vec::new()));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use allocator::Global;
     use garbage::Garbage;
     use hazard;
+    use provenance;
+    use std::cell::Cell;
     use std::thread;
 
     #[test]
@@ -226,20 +496,21 @@ mod tests {
         }
 
         for _ in 0..1000 {
-            let b = Box::new(0);
+            let b = Box::new(Cell::new(0u8));
             let h = get_hazard();
-            h.protect(&*b);
-            add_garbage(Garbage::new(&*b, dtor));
+            h.protect(b.as_ptr());
+            add_garbage(Garbage::new(b.as_ptr(), dtor));
             ::gc();
-            assert_eq!(*b, 0);
+            assert_eq!(b.get(), 0);
             ::gc();
             h.free();
             ::gc();
-            assert_eq!(*b, 1);
+            assert_eq!(b.get(), 1);
         }
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn dtor_runs_cross_thread() {
         fn dtor(x: *const u8) {
             unsafe {
@@ -248,20 +519,20 @@ mod tests {
         }
 
         for _ in 0..1000 {
-            let b = Box::new(0);
-            let bptr = &*b as *const _ as usize;
+            let b = Box::new(Cell::new(0u8));
+            let bptr = b.as_ptr() as usize;
             let h = thread::spawn(move || {
                 let h = get_hazard();
-                h.protect(bptr as *const u8);
+                h.protect(provenance::dangling(bptr));
                 h
             }).join().unwrap();
-            add_garbage(Garbage::new(&*b, dtor));
+            add_garbage(Garbage::new(b.as_ptr(), dtor));
             ::gc();
-            assert_eq!(*b, 0);
+            assert_eq!(b.get(), 0);
             ::gc();
             h.free();
             ::gc();
-            assert_eq!(*b, 1);
+            assert_eq!(b.get(), 1);
         }
     }
 
@@ -270,8 +541,8 @@ mod tests {
         let mut s = State::default();
         let mut v = Vec::new();
         for _ in 0..100 {
-            let (w, r) = hazard::create();
-            w.protect(0x1 as *const u8);
+            let (w, r) = hazard::create(&Global);
+            w.protect(provenance::dangling(0x1));
             v.push(r);
             s.free_hazard(w);
         }
@@ -280,10 +551,15 @@ mod tests {
             assert_eq!(i.get(), hazard::State::Free);
         }
 
+        // The hazards aren't registered in the global state, so they must not be recycled.
+        for hazard in s.available_hazards.drain(..) {
+            hazard.kill();
+        }
         mem::forget(v);
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn kill_hazards() {
         fn dtor(x: *const u8) {
             unsafe {
@@ -293,26 +569,116 @@ mod tests {
 
         for _ in 0..1000 {
             let b = thread::spawn(move || {
-                let b = Box::new(0);
+                let b = Box::new(Cell::new(0u8));
                 let h = get_hazard();
-                h.protect(&*b);
-                add_garbage(Garbage::new(&*b, dtor));
+                h.protect(b.as_ptr());
+                add_garbage(Garbage::new(b.as_ptr(), dtor));
                 ::gc();
-                assert_eq!(*b, 0);
+                assert_eq!(b.get(), 0);
                 b
             }).join().unwrap();
             ::gc();
-            assert_eq!(*b, 1);
+            assert_eq!(b.get(), 1);
         }
     }
 
+    #[test]
+    fn exit_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (log1, log2) = (log.clone(), log.clone());
+        thread::spawn(move || {
+            on_thread_exit(move || log1.lock().unwrap().push(1));
+            on_thread_exit(move || log2.lock().unwrap().push(2));
+        }).join().unwrap();
+
+        assert_eq!(*log.lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn pay_debt() {
+        use settings::Settings;
+        use std::sync::atomic::{self, AtomicUsize};
+
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(_: *const u8) {
+            X.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        thread::spawn(|| {
+            // The garbage is never exported by its count, so only the debt can get it collected.
+            settings::set_local(Settings {
+                max_garbage_before_export: !0,
+                debt_limit: Some(1024),
+                .. Settings::default()
+            });
+
+            for _ in 0..100000 {
+                add_garbage(Garbage::new(provenance::dangling(0x1), dtor));
+                assert!(garbage_len() <= 1024 / mem::size_of::<Garbage>() + 1);
+                if X.load(atomic::Ordering::Relaxed) > 0 {
+                    break;
+                }
+            }
+
+            assert!(X.load(atomic::Ordering::Relaxed) > 0);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn max_cached_hazards() {
+        thread::spawn(|| {
+            let hazards: Vec<_> = (0..100).map(|_| get_hazard()).collect();
+            for hazard in hazards {
+                hazard.free();
+                free_hazard(hazard);
+            }
+            assert!(STATE.with(|s| s.borrow().available_hazards.len()) >= 100);
+
+            set_max_cached_hazards(8);
+            assert_eq!(STATE.with(|s| s.borrow().available_hazards.len()), 8);
+
+            let hazards: Vec<_> = (0..100).map(|_| get_hazard()).collect();
+            for hazard in hazards {
+                hazard.free();
+                free_hazard(hazard);
+            }
+            assert_eq!(STATE.with(|s| s.borrow().available_hazards.len()), 8);
+
+            shrink_to_fit();
+            STATE.with(|s| {
+                let s = s.borrow();
+                assert!(s.available_hazards.is_empty());
+                assert_eq!(s.available_hazards_free_before, 0);
+            });
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn max_cached_hazards_recycled() {
+        thread::spawn(|| {
+            set_max_cached_hazards(2);
+            global::recycle_hazards((0..16).map(|_| global::create_hazard()).collect());
+
+            // The threads of other tests might take the hazards first, but the cache never
+            // exceeds the limit either way.
+            let hazard = get_hazard();
+            assert!(STATE.with(|s| s.borrow().available_hazards.len()) <= 2);
+            hazard.free();
+            free_hazard(hazard);
+            assert!(STATE.with(|s| s.borrow().available_hazards.len()) <= 2);
+        }).join().unwrap();
+    }
+
     #[cfg(debug_assertions)]
     #[test]
     #[should_panic]
     fn debug_free_blocked() {
         use std::mem;
 
-        let (writer, reader) = hazard::create();
+        let (writer, reader) = hazard::create(&Global);
         mem::forget(reader);
 
         free_hazard(writer);
@@ -0,0 +1,221 @@
+//! Metrics exportation.
+//!
+//! This allows for reporting the activity of the reclamation system to a metrics backend, such
+//! that the health of `conc` (hazard and garbage accumulation, GC frequency) can be monitored
+//! along with the rest of the application.
+//!
+//! To do so, implement `Recorder` and install it with `set_recorder()`. With feature `metrics`,
+//! the `MetricsRecorder` adapter reports to the [`metrics`](https://docs.rs/metrics) crate.
+//!
+//! Besides counters, the recorder receives timings (see `Recorder::gc_pause()` and
+//! `Recorder::hazard_wait()`), which help tracking down latency spikes caused by inline
+//! collection.
+//!
+//! Alternatively, a snapshot of the state of the system can be taken with `conc::stats()`.
+
+use atomics::{self as atomic, AtomicPtr};
+use std::ptr;
+use std::time::Duration;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+/// A recorder of metrics.
+///
+/// The methods are called in the hot paths of the system, so they should be cheap (e.g. atomic
+/// counter increments). All of them do nothing by default.
+pub trait Recorder: Send + Sync {
+    /// A new hazard was allocated.
+    fn hazard_created(&self) {}
+    /// Some amount of garbage was queued for destruction.
+    fn garbage_queued(&self, _amount: usize) {}
+    /// Some amount of garbage was destroyed.
+    fn garbage_destroyed(&self, _amount: usize) {}
+    /// A garbage collection pass was done.
+    fn gc_pass(&self) {}
+    /// A garbage destructor panicked.
+    fn destructor_panicked(&self) {}
+    /// A garbage collection pass finished.
+    ///
+    /// `duration` is the time the pass took, including the destructors, and `destroyed` is the
+    /// amount of garbage it destroyed. Unless the background collector runs (see `collector`),
+    /// passes run inline in the threads adding garbage, so long passes add to their latency.
+    ///
+    /// This requires `std`, as there is no clock otherwise.
+    fn gc_pause(&self, _duration: Duration, _destroyed: usize) {}
+    /// The amount of garbage left after a garbage collection pass.
+    ///
+    /// This is reported after every pass, and can be compared against a threshold to detect
+    /// garbage accumulating (e.g. because it is protected for long).
+    fn garbage_backlog(&self, _amount: usize) {}
+    /// The collector waited for a blocked hazard.
+    ///
+    /// `duration` is the time the hazard stayed blocked while the collector waited for it (see
+    /// `Settings::blocked_hazard_timeout`). This is only reported if the hazard was blocked.
+    fn hazard_wait(&self, _duration: Duration) {}
+}
+
+/// A snapshot of the state of the reclamation system.
+///
+/// This is returned by `conc::stats()`.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Stats {
+    /// The number of hazards protecting some object (or being blocked).
+    ///
+    /// Note that the hazards cached thread-locally keep protecting their last object until the
+    /// cache is cleaned up (see `Settings::max_non_free_hazards`).
+    pub active_hazards: usize,
+    /// The number of free hazards.
+    pub free_hazards: usize,
+    /// The number of dead hazards, which are yet to be destroyed by a garbage collection.
+    pub dead_hazards: usize,
+    /// The amount of garbage queued in the current thread.
+    pub local_garbage: usize,
+    /// The amount of garbage queued in the global state.
+    pub global_garbage: usize,
+    /// The number of garbage collection passes completed.
+    pub gc_passes: usize,
+}
+
+/// The installed recorder.
+///
+/// This is null if no recorder was installed. Otherwise, it points to a leaked reference to the
+/// recorder (as trait objects cannot be stored in an atomic).
+static RECORDER: AtomicPtr<&'static Recorder> = AtomicPtr::new(ptr::null_mut());
+
+/// Install a recorder.
+///
+/// The recorder can only be installed once, and this returns `Err(())` if some recorder is
+/// already installed.
+pub fn set_recorder(recorder: &'static Recorder) -> Result<(), ()> {
+    let new = Box::into_raw(Box::new(recorder));
+
+    if RECORDER.compare_and_swap(ptr::null_mut(), new, atomic::Ordering::AcqRel).is_null() {
+        Ok(())
+    } else {
+        // Another recorder was installed, so we must free our reference.
+        drop(unsafe { Box::from_raw(new) });
+        Err(())
+    }
+}
+
+/// Is a recorder installed?
+///
+/// This allows skipping work which is only needed for reporting (e.g. reading the clock).
+pub fn is_enabled() -> bool {
+    !RECORDER.load(atomic::Ordering::Acquire).is_null()
+}
+
+/// Apply a closure to the installed recorder, if any.
+pub fn with<F: FnOnce(&Recorder)>(f: F) {
+    let recorder = RECORDER.load(atomic::Ordering::Acquire);
+    if !recorder.is_null() {
+        // The reference is never freed once installed.
+        f(unsafe { *recorder });
+    }
+}
+
+/// A recorder reporting to the `metrics` crate.
+///
+/// The counters are named `conc.hazards_created`, `conc.garbage_queued`,
+/// `conc.garbage_destroyed`, `conc.gc_passes` and `conc.destructor_panics`. The timings are
+/// recorded in seconds to the histograms `conc.gc_pause_seconds` and `conc.hazard_wait_seconds`,
+/// and the backlog is set in the gauge `conc.garbage_backlog`.
+#[cfg(feature = "metrics")]
+pub struct MetricsRecorder;
+
+#[cfg(feature = "metrics")]
+impl Recorder for MetricsRecorder {
+    fn hazard_created(&self) {
+        exporter::counter!("conc.hazards_created").increment(1);
+    }
+
+    fn garbage_queued(&self, amount: usize) {
+        exporter::counter!("conc.garbage_queued").increment(amount as u64);
+    }
+
+    fn garbage_destroyed(&self, amount: usize) {
+        exporter::counter!("conc.garbage_destroyed").increment(amount as u64);
+    }
+
+    fn gc_pass(&self) {
+        exporter::counter!("conc.gc_passes").increment(1);
+    }
+
+    fn destructor_panicked(&self) {
+        exporter::counter!("conc.destructor_panics").increment(1);
+    }
+
+    fn gc_pause(&self, duration: Duration, _destroyed: usize) {
+        exporter::histogram!("conc.gc_pause_seconds").record(duration.as_secs_f64());
+    }
+
+    fn garbage_backlog(&self, amount: usize) {
+        exporter::gauge!("conc.garbage_backlog").set(amount as f64);
+    }
+
+    fn hazard_wait(&self, duration: Duration) {
+        exporter::histogram!("conc.hazard_wait_seconds").record(duration.as_secs_f64());
+    }
+}
+
+#[cfg(feature = "metrics")]
+extern crate metrics as exporter;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use {Garbage, local, provenance};
+
+    struct Counter {
+        garbage_queued: AtomicUsize,
+        gc_passes: AtomicUsize,
+        gc_pauses: AtomicUsize,
+        backlogs: AtomicUsize,
+    }
+
+    impl Recorder for Counter {
+        fn garbage_queued(&self, amount: usize) {
+            self.garbage_queued.fetch_add(amount, atomic::Ordering::Relaxed);
+        }
+
+        fn gc_pass(&self) {
+            self.gc_passes.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn gc_pause(&self, _duration: Duration, _destroyed: usize) {
+            self.gc_pauses.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn garbage_backlog(&self, _amount: usize) {
+            self.backlogs.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    static COUNTER: Counter = Counter {
+        garbage_queued: AtomicUsize::new(0),
+        gc_passes: AtomicUsize::new(0),
+        gc_pauses: AtomicUsize::new(0),
+        backlogs: AtomicUsize::new(0),
+    };
+
+    #[test]
+    fn record() {
+        fn nop(_: *const u8) {}
+
+        assert!(!is_enabled());
+        set_recorder(&COUNTER).unwrap();
+        assert!(set_recorder(&COUNTER).is_err());
+        assert!(is_enabled());
+
+        // Other tests run concurrently, so we only check that ours are counted.
+        local::add_garbage(Garbage::new(provenance::dangling(0x1), nop));
+        local::add_garbage(Garbage::new(provenance::dangling(0x1), nop));
+        ::gc();
+
+        assert!(COUNTER.garbage_queued.load(atomic::Ordering::Relaxed) >= 2);
+        assert!(COUNTER.gc_passes.load(atomic::Ordering::Relaxed) >= 1);
+        assert!(COUNTER.gc_pauses.load(atomic::Ordering::Relaxed) >= 1);
+        assert!(COUNTER.backlogs.load(atomic::Ordering::Relaxed) >= 1);
+    }
+}
@@ -8,9 +8,14 @@
 //! although this is reasonably fast as the lock is only held for very short time, it is
 //! sub-optimal, and blocking.
 
-use parking_lot::Mutex;
+#[cfg(not(feature = "std"))]
+use alloc::sync::Arc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::sync::Arc;
 use std::mem;
+use mutex::Mutex;
 
 /// Create a MPSC pair.
 ///
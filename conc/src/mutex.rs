@@ -0,0 +1,90 @@
+//! Mutual exclusion.
+//!
+//! With `std`, this is the mutex of `parking_lot`. Without it, threads cannot be parked, so a
+//! spinlock with the same API is used instead.
+
+#[cfg(feature = "std")]
+pub use parking_lot::Mutex;
+
+#[cfg(not(feature = "std"))]
+pub use self::spin::Mutex;
+
+#[cfg(not(feature = "std"))]
+mod spin {
+    use std::cell::UnsafeCell;
+    use std::ops;
+    use std::sync::atomic::spin_loop_hint;
+    use atomics::{self as atomic, AtomicBool};
+
+    /// A spinlock.
+    pub struct Mutex<T> {
+        /// Is the lock held?
+        locked: AtomicBool,
+        /// The protected data.
+        data: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        /// Create a new unlocked spinlock.
+        pub fn new(data: T) -> Mutex<T> {
+            Mutex {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(data),
+            }
+        }
+
+        /// Acquire the lock, spinning until it is available.
+        pub fn lock(&self) -> MutexGuard<T> {
+            loop {
+                if let Some(guard) = self.try_lock() {
+                    return guard;
+                }
+
+                // Spin on a plain load to avoid bouncing the cache line while the lock is held.
+                while self.locked.load(atomic::Ordering::Relaxed) {
+                    spin_loop_hint();
+                }
+            }
+        }
+
+        /// Acquire the lock, if it is available.
+        pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+            if self.locked.compare_and_swap(false, true, atomic::Ordering::Acquire) {
+                None
+            } else {
+                Some(MutexGuard {
+                    mutex: self,
+                })
+            }
+        }
+    }
+
+    /// A guard releasing the spinlock when dropped.
+    pub struct MutexGuard<'a, T: 'a> {
+        /// The locked spinlock.
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<'a, T> ops::Deref for MutexGuard<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> ops::DerefMut for MutexGuard<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> Drop for MutexGuard<'a, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, atomic::Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! The atomic primitives of the hazard protocol.
+//!
+//! The hazards use these rather than `std::sync::atomic` directly, such that they can be swapped
+//! for the model-checked primitives of [`loom`](https://docs.rs/loom) under `cfg(loom)`, allowing
+//! the protocol to be verified exhaustively for every interleaving and reordering. The loom tests
+//! are run through:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --lib --release loom
+//! ```
+//!
+//! Under `cfg(loom)`, the primitives can only be used inside a loom model, so the rest of the
+//! tests are not meaningful then. Otherwise, they are those of `atomics`.
+
+#[cfg(not(loom))]
+pub use atomics::{AtomicPtr, Ordering, fence};
+/// Spinning is only done without `std`, which parks threads instead.
+#[cfg(all(not(loom), not(feature = "std")))]
+pub use std::sync::atomic::spin_loop_hint;
+
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicPtr, Ordering, fence};
+/// Spinning must yield to the other threads of the model, as they would never progress otherwise.
+#[cfg(all(loom, not(feature = "std")))]
+pub use loom::thread::yield_now as spin_loop_hint;
@@ -0,0 +1,33 @@
+//! Pointer arithmetic preserving provenance.
+//!
+//! Casting an integer to a pointer loses track of the allocation the pointer belongs to (its
+//! provenance), which is not allowed under strict provenance (as checked by Miri). Pointers are
+//! instead only ever cast to integers to read their address, and new pointers are derived from
+//! existing ones by offsetting them.
+//!
+//! The tests are checked by Miri through:
+//!
+//! ```text
+//! MIRIFLAGS="-Zmiri-strict-provenance -Zmiri-ignore-leaks" cargo +nightly miri test --lib
+//! ```
+//!
+//! Leaks are ignored, as the hazards and garbage left when the program exits are deliberately
+//! leaked. The stress tests are skipped, as they are too slow to interpret, and so are the tests of
+//! the background collector, as `parking_lot` casts integers to pointers when parking threads.
+
+#[cfg(test)]
+use std::ptr;
+
+/// Change the address of a pointer, keeping its provenance.
+pub fn with_addr<T>(ptr: *mut T, addr: usize) -> *mut T {
+    (ptr as *mut u8).wrapping_offset(addr.wrapping_sub(ptr as usize) as isize) as *mut T
+}
+
+/// Get a pointer with some address, but no provenance.
+///
+/// The pointer must never be dereferenced, but it can be compared to other pointers, which is
+/// all the hazards and garbage of the tests need from it.
+#[cfg(test)]
+pub fn dangling(addr: usize) -> *const u8 {
+    with_addr(ptr::null_mut::<u8>(), addr)
+}
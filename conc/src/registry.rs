@@ -0,0 +1,386 @@
+//! Explicitly registered threads.
+//!
+//! The default domain and `Domain`s keep the state of every thread (its cache of hazards and its
+//! queue of garbage) in thread-local storage, which requires `std`. A `Registry` is a reclamation
+//! state, in which threads register explicitly instead, keeping their state in a `Participant`.
+//! It only requires `alloc`, so it is available without `std`.
+//!
+//! Every thread using the registry must register for a participant of its own, through which it
+//! protects pointers (see `Participant::protect_with()`) and adds garbage. The garbage of a
+//! registry is only ever destroyed by the collections of the registry, so pointers protected
+//! through a registry must only be added as garbage to the same registry.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::atomic::{AtomicPtr, Ordering};
+//!
+//! let registry = conc::Registry::new();
+//! let participant = registry.register();
+//!
+//! let shared = AtomicPtr::new(Box::into_raw(Box::new(42)));
+//! {
+//!     let guard = unsafe { participant.protect_with(|| shared.load(Ordering::Acquire)) };
+//!     assert_eq!(*guard.unwrap(), 42);
+//! }
+//!
+//! // Replace the value, and add the old box as garbage.
+//! let old = shared.swap(Box::into_raw(Box::new(43)), Ordering::AcqRel);
+//! unsafe { participant.add_garbage_box(old); }
+//! # unsafe { participant.add_garbage_box(shared.load(Ordering::Acquire)); }
+//! ```
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use std::cell::RefCell;
+use std::{fmt, mem, ops};
+
+use allocator::Allocator;
+use garbage::Garbage;
+use global::{self, WouldBlock};
+use hazard;
+use metrics::Stats;
+
+/// The number of pieces of garbage a participant queues before exporting them.
+///
+/// Every exportation is followed by an attempt at collecting the garbage of the registry.
+const MAX_GARBAGE_BEFORE_EXPORT: usize = 64;
+
+/// A reclamation state with explicitly registered threads.
+///
+/// See the module documentation.
+pub struct Registry {
+    /// The state of the registry.
+    state: global::State,
+}
+
+impl Registry {
+    /// Create a new registry.
+    pub fn new() -> Registry {
+        Registry {
+            state: global::State::new(),
+        }
+    }
+
+    /// Create a new registry allocating its hazards with `allocator`.
+    ///
+    /// See the `allocator` module.
+    pub fn with_allocator(allocator: &'static Allocator) -> Registry {
+        Registry {
+            state: global::State::with_allocator(Some(allocator)),
+        }
+    }
+
+    /// Register the current thread.
+    ///
+    /// The returned participant holds the state of the thread in this registry. Its hazards are
+    /// killed and its garbage exported when it is dropped.
+    pub fn register(&self) -> Participant {
+        Participant {
+            registry: self,
+            hazards: RefCell::new(Vec::new()),
+            garbage: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Attempt to collect the garbage of the registry.
+    ///
+    /// This collects the garbage exported by the participants. If another thread is currently
+    /// collecting the garbage, `Err(WouldBlock)` is returned.
+    pub fn try_gc(&self) -> Result<(), WouldBlock> {
+        self.state.try_gc()
+    }
+
+    /// Collect the garbage of the registry.
+    ///
+    /// This acts like `try_gc()`, but waits for other collections.
+    pub fn gc(&self) {
+        while let Err(WouldBlock) = self.state.try_gc() {}
+    }
+
+    /// Get statistics of the registry.
+    ///
+    /// This acts like `conc::stats()`, but the garbage queued in participants isn't counted.
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Registry { .. }")
+    }
+}
+
+/// A thread registered in a registry.
+///
+/// This is obtained through `Registry::register()`, and holds the cache of hazards and the queue
+/// of garbage of the thread.
+pub struct Participant<'a> {
+    /// The registry the participant belongs to.
+    registry: &'a Registry,
+    /// The cache of free hazards.
+    hazards: RefCell<Vec<hazard::Writer>>,
+    /// The garbage waiting to be exported to the registry.
+    garbage: RefCell<Vec<Garbage>>,
+}
+
+impl<'a> Participant<'a> {
+    /// Protect a pointer loaded from some shared location.
+    ///
+    /// This acts like `Guard::protect_with()`: The pointer is loaded with `load`, protected, and
+    /// validated by loading it again, until the two loads agree. If `load` returns the null
+    /// pointer, `None` is returned.
+    ///
+    /// # Safety
+    ///
+    /// The pointers returned by `load` must be valid while they are reachable from the location,
+    /// and they must only be destroyed through the garbage of this registry after they were made
+    /// unreachable.
+    pub unsafe fn protect_with<T, F>(&self, load: F) -> Option<Protected<T>>
+    where F: FnMut() -> *const T {
+        let hazard = self.get_hazard();
+
+        let ptr = hazard.protect_loaded(load);
+        if ptr.is_null() {
+            // Put the hazard back, as we don't need it.
+            self.free_hazard(hazard);
+            None
+        } else {
+            Some(Protected {
+                hazard: Some(hazard),
+                pointer: &*ptr,
+                hazards: &self.hazards,
+            })
+        }
+    }
+
+    /// Add garbage to the registry.
+    ///
+    /// This acts like `conc::add_garbage()`, but in the registry.
+    pub fn add_garbage<T: Sync>(&self, ptr: &'static T, dtor: fn(&'static T)) {
+        self.add(unsafe {
+            Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
+        });
+    }
+
+    /// Add a heap-allocated `Box<T>` as garbage to the registry.
+    ///
+    /// This acts like `conc::add_garbage_box()`, but in the registry.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `conc::add_garbage_box()`.
+    pub unsafe fn add_garbage_box<T>(&self, ptr: *const T) {
+        self.add(Garbage::new_box(ptr));
+    }
+
+    /// Export the garbage of the participant to the registry.
+    ///
+    /// This doesn't collect the garbage (see `Registry::try_gc()`).
+    pub fn export_garbage(&self) {
+        let garbage = mem::replace(&mut *self.garbage.borrow_mut(), Vec::new());
+        if !garbage.is_empty() {
+            self.registry.state.export_garbage(garbage);
+        }
+    }
+
+    /// Queue some garbage, exporting and collecting if enough is queued.
+    fn add(&self, garbage: Garbage) {
+        let len = {
+            let mut queue = self.garbage.borrow_mut();
+            queue.push(garbage);
+            queue.len()
+        };
+
+        if len > MAX_GARBAGE_BEFORE_EXPORT {
+            self.export_garbage();
+            // The garbage is collected eventually either way, so we needn't wait.
+            let _ = self.registry.try_gc();
+        }
+    }
+
+    /// Get a hazard in blocked state.
+    fn get_hazard(&self) -> hazard::Writer {
+        if let Some(hazard) = self.hazards.borrow_mut().pop() {
+            hazard.block();
+            hazard
+        } else {
+            self.registry.state.create_hazard()
+        }
+    }
+
+    /// Free a hazard to the cache.
+    fn free_hazard(&self, hazard: hazard::Writer) {
+        hazard.free();
+        self.hazards.borrow_mut().push(hazard);
+    }
+}
+
+impl<'a> Drop for Participant<'a> {
+    fn drop(&mut self) {
+        // Clear every hazard to "dead" state.
+        for hazard in self.hazards.borrow_mut().drain(..) {
+            hazard.kill();
+        }
+
+        self.export_garbage();
+    }
+}
+
+impl<'a> fmt::Debug for Participant<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Participant { .. }")
+    }
+}
+
+/// A pointer protected through a participant.
+///
+/// This acts like `Guard`, but its hazard goes back to the participant when it is dropped.
+#[must_use = "Protecting a pointer without using it is potentially unnecessary overhead."]
+pub struct Protected<'a, T: 'a> {
+    /// The hazard protecting the pointer.
+    ///
+    /// This is only `None` while dropping.
+    hazard: Option<hazard::Writer>,
+    /// The protected pointer.
+    pointer: &'a T,
+    /// The cache of hazards of the participant.
+    hazards: &'a RefCell<Vec<hazard::Writer>>,
+}
+
+impl<'a, T> Protected<'a, T> {
+    /// Get the raw pointer.
+    pub fn as_ptr(&self) -> *const T {
+        self.pointer
+    }
+}
+
+impl<'a, T> ops::Deref for Protected<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for Protected<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Protected").field(self.pointer).finish()
+    }
+}
+
+impl<'a, T> Drop for Protected<'a, T> {
+    fn drop(&mut self) {
+        let hazard = self.hazard.take().unwrap();
+        hazard.free();
+        self.hazards.borrow_mut().push(hazard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+    use std::{ptr, thread};
+
+    #[test]
+    fn protect_and_collect() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(x: &'static AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let registry = Registry::new();
+        let participant = registry.register();
+
+        let guard = unsafe { participant.protect_with(|| &X as *const AtomicUsize) }.unwrap();
+        participant.add_garbage(&X, dtor);
+        participant.export_garbage();
+        registry.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 0);
+
+        drop(guard);
+        registry.gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+
+        // The hazard was recycled.
+        assert_eq!(participant.hazards.borrow().len(), 1);
+        assert!(unsafe { participant.protect_with(|| ptr::null::<u8>()) }.is_none());
+    }
+
+    #[test]
+    fn participants() {
+        lazy_static! {
+            static ref REGISTRY: Registry = Registry::new();
+        }
+
+        // Leak the shared location, so the threads can borrow it.
+        let shared: &'static AtomicPtr<usize> = unsafe {
+            &*Box::into_raw(Box::new(AtomicPtr::new(Box::into_raw(Box::new(0)))))
+        };
+
+        let threads: Vec<_> = (0..4).map(|_| thread::spawn(move || {
+            let participant = REGISTRY.register();
+            for _ in 0..1000 {
+                let new = Box::into_raw(Box::new(0));
+                let old = shared.swap(new, atomic::Ordering::AcqRel);
+                unsafe { participant.add_garbage_box(old); }
+
+                let guard = unsafe {
+                    participant.protect_with(|| shared.load(atomic::Ordering::Acquire))
+                };
+                assert_eq!(*guard.unwrap(), 0);
+            }
+        })).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every participant exported its garbage when dropped.
+        REGISTRY.gc();
+        assert_eq!(REGISTRY.stats().global_garbage, 0);
+    }
+
+    #[test]
+    fn custom_allocator() {
+        use allocator::Global;
+        use std::alloc::Layout;
+
+        /// An allocator counting the live allocations.
+        struct Counting(AtomicUsize);
+
+        unsafe impl Allocator for Counting {
+            fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+                Global.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+                Global.dealloc(ptr, layout)
+            }
+        }
+
+        static ALLOCATOR: Counting = Counting(AtomicUsize::new(0));
+
+        let registry = Registry::with_allocator(&ALLOCATOR);
+        let participant = registry.register();
+        let x = Box::new(0);
+        drop(unsafe { participant.protect_with(|| &*x as *const i32) });
+        assert_eq!(ALLOCATOR.0.load(atomic::Ordering::Relaxed), 1);
+
+        // The dead hazard is deallocated through the allocator by the collection.
+        drop(participant);
+        registry.gc();
+        assert_eq!(ALLOCATOR.0.load(atomic::Ordering::Relaxed), 0);
+    }
+}
@@ -1,10 +1,47 @@
 //! Settings and presets.
+//!
+//! Every thread has its settings. Unless they are set for the thread specifically (see
+//! `set_local()`), they are the global settings (see `set()`).
 
+use parking_lot::RwLock;
 use std::cell::Cell;
+use std::thread;
+use atomics::{self as atomic, AtomicUsize};
+use std::time::Duration;
+
+lazy_static! {
+    /// The global settings.
+    static ref GLOBAL_SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+}
+
+/// The generation of the global settings.
+///
+/// This is incremented every time the global settings are set, such that threads know when to
+/// reload their copy of them.
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
 
 thread_local! {
     /// The settings for the current thread.
-    static LOCAL_SETTINGS: Cell<Settings> = Cell::new(Settings::default())
+    static LOCAL_SETTINGS: Cell<Local> = Cell::new(Local {
+        settings: Settings::default(),
+        generation: 0,
+        local: false,
+    })
+}
+
+/// The settings of a thread.
+#[derive(Copy, Clone)]
+struct Local {
+    /// The settings.
+    settings: Settings,
+    /// The generation of the global settings which `settings` is a copy of.
+    ///
+    /// The generation `0` means that the global settings weren't loaded yet.
+    generation: usize,
+    /// Were the settings set specifically for this thread?
+    ///
+    /// If so, the global settings are ignored.
+    local: bool,
 }
 
 /// Settings for the system.
@@ -23,12 +60,113 @@ pub struct Settings {
     /// When the local state's garbage queue exceeds this limit, it exports it to the global
     /// garbage queue.
     pub max_garbage_before_export: usize,
+    /// The maximal amount of outstanding garbage in bytes before collecting.
+    ///
+    /// The garbage count says little about the memory held by the garbage, as some of it might be
+    /// huge buffers. Hence, the (approximate) size of the garbage is tracked as well, and when the
+    /// garbage not yet destroyed exceeds this many bytes, the local garbage is exported and
+    /// collected right away, regardless of `gc_probability`.
+    ///
+    /// The size of garbage defaults to the size of the object itself (or zero for garbage added
+    /// through `add_garbage()`), so heap memory owned by the object must be supplied by the caller
+    /// (see e.g. `add_garbage_box_sized()`).
+    pub max_garbage_bytes: usize,
     /// The maximal amount of non-free hazards in the thread-local cache.
     ///
     /// When it exceeds this limit, it will clean up the cached hazards. With "cleaning up" we mean
     /// setting the state of the hazards to "free" in order to allow garbage collection of the
     /// object it is currently protecting.
     pub max_non_free_hazards: usize,
+    /// Park the thread while waiting for a hazard to get unblocked.
+    ///
+    /// Hazards are only blocked for very short periods, unless the blocking thread is descheduled,
+    /// in which case spinning on the hazard burns a full core for nothing. With this enabled (the
+    /// default), a thread garbage collecting spins briefly on a blocked hazard, and then parks
+    /// itself (on a futex on Linux, and through thread parking elsewhere) until the hazard is
+    /// unblocked. With this disabled, it spins until the hazard is unblocked, which has slightly
+    /// lower latency when the blocking thread is guaranteed to be running.
+    pub park_blocked_hazards: bool,
+    /// The backoff policy when waiting for a blocked hazard.
+    ///
+    /// When parking is enabled, only the spinning phase of the policy is used.
+    pub backoff: Backoff,
+    /// The maximal time for the garbage collector to wait for a blocked hazard.
+    ///
+    /// A hazard stays blocked only while its writer is in the middle of creating a guard, but if
+    /// the writer is descheduled (or stuck) meanwhile, a garbage collection waits for it. If this
+    /// is set, the collection instead gives up after this time, keeping all the garbage for a
+    /// later collection (as a blocked hazard might be about to protect any of it). If it is
+    /// `None` (the default), the collection waits indefinitely.
+    pub blocked_hazard_timeout: Option<Duration>,
+    /// The maximal debt of a thread in bytes, if collection is paid for through debt.
+    ///
+    /// Ticking spreads the collections over the threads evenly, so under asymmetric workloads
+    /// (e.g. a producer retiring lots of garbage while the consumers only read), the garbage can
+    /// pile up faster than it is collected. If this is set, `gc_probability` is ignored, and every
+    /// thread instead accumulates "debt" for the garbage it adds (its size, plus a fixed cost per
+    /// piece). When the debt exceeds this limit, the thread exports its garbage and pays the debt
+    /// off by doing a share of the collection work proportional to the garbage it added. If
+    /// another thread is collecting meanwhile, the debt is carried over.
+    ///
+    /// This only applies to the default domain, as domains have no per-thread state. It defaults
+    /// to `None`.
+    pub debt_limit: Option<usize>,
+}
+
+/// A backoff policy.
+///
+/// This determines how a thread waits for a blocked hazard: It first spins `spins` times, then
+/// yields its timeslice `yields` times, and then sleeps for exponentially increasing periods (from
+/// a microsecond, doubling every round), capped at `max_sleep`.
+///
+/// Spinning has the lowest latency, but burns CPU (and starves the blocking thread on contended
+/// cores), while sleeping frees the CPU at the cost of latency.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Backoff {
+    /// The number of spins before yielding.
+    pub spins: usize,
+    /// The number of yields before sleeping.
+    pub yields: usize,
+    /// The maximal period to sleep for.
+    ///
+    /// If this is zero, the thread never sleeps, but yields indefinitely.
+    pub max_sleep: Duration,
+}
+
+impl Backoff {
+    /// A policy which spins indefinitely.
+    pub fn spin() -> Backoff {
+        Backoff {
+            spins: !0,
+            yields: 0,
+            max_sleep: Duration::from_secs(0),
+        }
+    }
+
+    /// Back off in some round of waiting.
+    ///
+    /// `round` is the number of times the condition has been checked so far.
+    pub(crate) fn snooze(&self, round: usize) {
+        if round < self.spins {
+            // Spin.
+        } else if round - self.spins < self.yields || self.max_sleep == Duration::from_secs(0) {
+            thread::yield_now();
+        } else {
+            // Cap the exponent to avoid overflow (a microsecond times 2^20 is over a second).
+            let exp = (round - self.spins - self.yields).min(20) as u32;
+            thread::sleep((Duration::new(0, 1000) * (1 << exp)).min(self.max_sleep));
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            spins: 1 << 12,
+            yields: 64,
+            max_sleep: Duration::from_millis(1),
+        }
+    }
 }
 
 impl Default for Settings {
@@ -36,7 +174,12 @@ impl Default for Settings {
         Settings {
             gc_probability: (!0) / 128,
             max_garbage_before_export: 64,
+            max_garbage_bytes: 1 << 26,
             max_non_free_hazards: 16,
+            park_blocked_hazards: true,
+            backoff: Backoff::default(),
+            blocked_hazard_timeout: None,
+            debt_limit: None,
         }
     }
 }
@@ -47,7 +190,12 @@ impl Settings {
         Settings {
             gc_probability: (!0) / 32,
             max_garbage_before_export: 16,
+            max_garbage_bytes: 1 << 24,
             max_non_free_hazards: 4,
+            park_blocked_hazards: true,
+            backoff: Backoff::default(),
+            blocked_hazard_timeout: None,
+            debt_limit: None,
         }
     }
 
@@ -56,7 +204,16 @@ impl Settings {
         Settings {
             gc_probability: (!0) / 256,
             max_garbage_before_export: 128,
+            max_garbage_bytes: 1 << 28,
             max_non_free_hazards: 32,
+            park_blocked_hazards: true,
+            backoff: Backoff {
+                spins: 1 << 8,
+                yields: 16,
+                max_sleep: Duration::from_millis(4),
+            },
+            blocked_hazard_timeout: None,
+            debt_limit: None,
         }
     }
 
@@ -66,6 +223,8 @@ impl Settings {
     /// can still be propagated and destroyed, it will just not happen in this thread.
     pub fn disable_automatic_gc(&mut self) {
         self.gc_probability = 0;
+        self.max_garbage_bytes = !0;
+        self.debt_limit = None;
     }
 
     /// Disable automatic exportation.
@@ -77,12 +236,48 @@ impl Settings {
         // than one byte) queue would have to fill more than the whole memory space, which is
         // obviously impossible.
         self.max_garbage_before_export = !0;
+        // Likewise, the byte limit can never be reached.
+        self.max_garbage_bytes = !0;
+        // Paying off debt exports the garbage as well.
+        self.debt_limit = None;
     }
 }
 
 /// Get the settings of the current thread.
+///
+/// These are the global settings, unless other settings were set for this thread.
 pub fn get() -> Settings {
-    LOCAL_SETTINGS.with(|x| x.get())
+    LOCAL_SETTINGS.with(|x| {
+        let local = x.get();
+        if local.local || local.generation == GENERATION.load(atomic::Ordering::Acquire) {
+            local.settings
+        } else {
+            // The global settings changed since we last loaded them. The generation is read
+            // again under the lock, as the settings might have been set meanwhile.
+            let settings = GLOBAL_SETTINGS.read();
+            x.set(Local {
+                settings: *settings,
+                generation: GENERATION.load(atomic::Ordering::Relaxed),
+                local: false,
+            });
+
+            *settings
+        }
+    })
+}
+
+/// Set the settings for every thread.
+///
+/// This sets the global settings, which are used by every thread (including the ones already
+/// running), except for the threads with settings of their own (see `set_local()`).
+///
+/// This is typically used for tuning the system at startup.
+pub fn set(settings: Settings) {
+    let mut global = GLOBAL_SETTINGS.write();
+    *global = settings;
+    // Bump the generation while holding the lock, such that threads reloading the settings see
+    // the generation matching them.
+    GENERATION.fetch_add(1, atomic::Ordering::Release);
 }
 
 /// Set the settings for the current thread.
@@ -90,17 +285,22 @@ pub fn get() -> Settings {
 /// # Important
 ///
 /// This is not global. That is, if you call this in thread A, the setting change won't affect
-/// thread B. If you want to have the same settings in multiple threads, you should call this
-/// function in the start of every thread you spawn with the `Settings`, you want.
+/// thread B. If you want to have the same settings in every thread, use `set()`.
+///
+/// Once called, the global settings no longer affect the current thread.
 pub fn set_local(settings: Settings) {
-    LOCAL_SETTINGS.with(|x| x.set(settings))
+    LOCAL_SETTINGS.with(|x| x.set(Local {
+        settings: settings,
+        generation: 0,
+        local: true,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::thread;
-    use {Garbage, local};
+    use {Garbage, local, provenance};
 
     #[test]
     fn set_get() {
@@ -113,12 +313,49 @@ mod tests {
 
     #[test]
     fn default() {
+        use std::sync::mpsc;
+
+        thread::spawn(|| {
+            assert_eq!(get(), Settings::default());
+        }).join().unwrap();
+
+        // The global settings are tested here, as they would change the default for other tests
+        // running concurrently. Only touch settings not affecting other tests.
+        let settings = Settings {
+            max_non_free_hazards: 17,
+            .. Settings::default()
+        };
+
+        // A thread which is already running.
+        let (send, recv) = mpsc::channel();
+        let (done_send, done_recv) = mpsc::channel();
+        let running = thread::spawn(move || {
+            get();
+            recv.recv().unwrap();
+            assert_eq!(get().max_non_free_hazards, 17);
+            done_recv.recv().unwrap();
+        });
+
+        set(settings);
+        send.send(()).unwrap();
+
+        // A new thread.
         thread::spawn(|| {
+            assert_eq!(get().max_non_free_hazards, 17);
+
+            // Local settings take precedence.
+            set_local(Settings::default());
             assert_eq!(get(), Settings::default());
         }).join().unwrap();
+
+        // Avoid messing with other tests.
+        set(Settings::default());
+        done_send.send(()).unwrap();
+        running.join().unwrap();
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn disable_automatic_gc() {
         thread_local! {
             static X: Cell<bool> = Cell::default();
@@ -133,7 +370,7 @@ mod tests {
         set_local(settings);
 
         for _ in 0..100000 {
-            local::add_garbage(Garbage::new(0x1 as *const u8, dtor));
+            local::add_garbage(Garbage::new(provenance::dangling(0x1), dtor));
             assert!(!X.with(|x| x.get()));
         }
 
@@ -142,6 +379,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn disable_automatic_exportation() {
         fn dtor(x: *const u8) {
             unsafe {
@@ -154,9 +392,11 @@ mod tests {
         set_local(settings);
 
         for _ in 0..100000 {
-            let b = Box::new(0);
-            local::add_garbage(Garbage::new(&*b, dtor));
-            assert_eq!(*b, 0);
+            // The box is leaked, as the garbage is exported when the thread exits, and destroyed
+            // after the test has finished.
+            let b = Box::into_raw(Box::new(0u8));
+            local::add_garbage(Garbage::new(b, dtor));
+            assert_eq!(unsafe { *b }, 0);
         }
 
         // Avoid messing with other tests.
@@ -170,6 +410,33 @@ mod tests {
 
         assert!(low.gc_probability > high.gc_probability);
         assert!(high.max_garbage_before_export > low.max_garbage_before_export);
+        assert!(high.max_garbage_bytes > low.max_garbage_bytes);
         assert!(high.max_non_free_hazards > low.max_non_free_hazards);
+        assert!(low.backoff.spins > high.backoff.spins);
+    }
+
+    #[test]
+    fn backoff() {
+        use std::time::Instant;
+
+        let backoff = Backoff {
+            spins: 2,
+            yields: 2,
+            max_sleep: Duration::from_millis(2),
+        };
+
+        // Spinning and yielding return (nearly) immediately.
+        let start = Instant::now();
+        for round in 0..4 {
+            backoff.snooze(round);
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Sleeping is capped.
+        let start = Instant::now();
+        backoff.snooze(1000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2));
+        assert!(elapsed < Duration::from_millis(500));
     }
 }
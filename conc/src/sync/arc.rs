@@ -0,0 +1,217 @@
+//! Atomically swappable `Arc`s.
+
+use atomics::{self as atomic, AtomicPtr};
+use std::sync::Arc;
+use std::{fmt, ptr};
+use {Guard, add_garbage_with};
+
+/// An atomically swappable `Arc<T>`.
+///
+/// This holds an `Arc<T>`, which can be loaded and replaced concurrently, like the `arc-swap`
+/// crate. Rather than incrementing the reference count, which makes the readers contend, loading
+/// protects the object by a guard, which is wait-free. The reference held by the container is
+/// released through the garbage when it is replaced, so the object outlives the guards.
+pub struct AtomicArc<T: Send + Sync + 'static> {
+    /// The pointer of the current `Arc<T>` (see `Arc::into_raw()`).
+    ///
+    /// This owns a reference to the object.
+    inner: AtomicPtr<T>,
+}
+
+impl<T: Send + Sync + 'static> AtomicArc<T> {
+    /// Create a new container.
+    pub fn new(arc: Arc<T>) -> AtomicArc<T> {
+        AtomicArc {
+            inner: AtomicPtr::new(Arc::into_raw(arc) as *mut T),
+        }
+    }
+
+    /// Load the current object.
+    ///
+    /// The guard protects the object without holding a reference to it.
+    pub fn load(&self) -> Guard<T> {
+        Guard::new(|| unsafe { &*self.inner.load(atomic::Ordering::Acquire) })
+    }
+
+    /// Load a new reference to the current object.
+    pub fn load_full(&self) -> Arc<T> {
+        // The raw pointer is kept alongside the guard, as pointers derived from the guard must not
+        // be used to modify the reference count.
+        let mut ptr = ptr::null();
+        let _guard = Guard::new(|| unsafe {
+            ptr = self.inner.load(atomic::Ordering::Acquire);
+            &*ptr
+        });
+
+        // The reference of the container is released only when the guard is gone, so the object
+        // is still alive.
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Store a new object.
+    ///
+    /// The reference to the old object is released when no guard protects it anymore.
+    pub fn store(&self, new: Arc<T>) {
+        let old = self.inner.swap(Arc::into_raw(new) as *mut T, atomic::Ordering::AcqRel);
+        unsafe { retire(old); }
+    }
+
+    /// Swap the current object with a new.
+    ///
+    /// This returns a reference to the old object.
+    pub fn swap(&self, new: Arc<T>) -> Arc<T> {
+        let old = self.inner.swap(Arc::into_raw(new) as *mut T, atomic::Ordering::AcqRel);
+
+        unsafe {
+            // Guards might still protect the old object, so rather than handing over the reference
+            // of the container, we release it through the garbage, and return a new one.
+            Arc::increment_strong_count(old);
+            retire(old);
+            Arc::from_raw(old)
+        }
+    }
+
+    /// Store a new object if the current object is `current`.
+    ///
+    /// If it is, a reference to the old object is returned. Otherwise, `new` is handed back in
+    /// `Err`.
+    pub fn compare_and_swap(&self, current: *const T, new: Arc<T>) -> Result<Arc<T>, Arc<T>> {
+        let new = Arc::into_raw(new) as *mut T;
+
+        match self.inner.compare_exchange(current as *mut T, new, atomic::Ordering::AcqRel,
+                                          atomic::Ordering::Acquire) {
+            // See `swap()`.
+            Ok(old) => unsafe {
+                Arc::increment_strong_count(old);
+                retire(old);
+                Ok(Arc::from_raw(old))
+            },
+            Err(_) => Err(unsafe { Arc::from_raw(new) }),
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // Guards might outlive the container, so the reference is released through the garbage.
+        unsafe { retire(*self.inner.get_mut()); }
+    }
+}
+
+impl<T: Send + Sync + 'static> From<Arc<T>> for AtomicArc<T> {
+    fn from(arc: Arc<T>) -> AtomicArc<T> {
+        AtomicArc::new(arc)
+    }
+}
+
+impl<T: Send + Sync + Default + 'static> Default for AtomicArc<T> {
+    fn default() -> AtomicArc<T> {
+        AtomicArc::new(Arc::default())
+    }
+}
+
+impl<T: Send + Sync + fmt::Debug + 'static> fmt::Debug for AtomicArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AtomicArc").field(&*self.load()).finish()
+    }
+}
+
+/// Release the reference of a container to an object, once no guard protects it.
+///
+/// # Safety
+///
+/// `ptr` must be obtained from `Arc::into_raw()`, and be unreachable from the container.
+unsafe fn retire<T: Send + Sync + 'static>(ptr: *const T) {
+    add_garbage_with(&*ptr, |ptr| drop(Arc::from_raw(ptr as *const T)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use local;
+    use std::thread;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Dropper {
+        d: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.d.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn load_store_swap() {
+        let arc = AtomicArc::new(Arc::new(1));
+        assert_eq!(*arc.load(), 1);
+
+        arc.store(Arc::new(2));
+        assert_eq!(*arc.load_full(), 2);
+        assert_eq!(*arc.swap(Arc::new(3)), 2);
+
+        let cur = arc.load_full();
+        assert!(arc.compare_and_swap(&2, Arc::new(4)).is_err());
+        assert_eq!(*arc.compare_and_swap(&*cur, Arc::new(4)).unwrap(), 3);
+        assert_eq!(*arc.load(), 4);
+    }
+
+    #[test]
+    fn guard_protects() {
+        let drops = Arc::new(AtomicUsize::default());
+        let arc = AtomicArc::new(Arc::new(Dropper {
+            d: drops.clone(),
+        }));
+        let g = arc.load();
+        let full = arc.load_full();
+
+        // The guard keeps the object alive, without holding a reference.
+        arc.store(Arc::new(Dropper {
+            d: Arc::new(AtomicUsize::default()),
+        }));
+        ::gc();
+        assert_eq!(Arc::strong_count(&full), 2);
+        drop(full);
+        ::gc();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+
+        // The cached hazards keep protecting the object, unless they are freed.
+        drop(g);
+        local::free_cached_hazards();
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(atomic::Ordering::Relaxed) == 1 {
+                break;
+            }
+        }
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn multi_threaded() {
+        let arc = Arc::new(AtomicArc::new(Arc::new(0)));
+
+        let mut j = Vec::new();
+        for i in 0..16 {
+            let arc = arc.clone();
+            j.push(thread::spawn(move || {
+                for _ in 0..10_000 {
+                    assert!(*arc.load() <= 15);
+                    let full = arc.load_full();
+                    arc.store(Arc::new(i));
+                    assert!(*full <= 15);
+                }
+            }))
+        }
+
+        for i in j {
+            i.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Chase-Lev work-stealing deques.
+
+use atomics::{self as atomic, AtomicPtr, AtomicUsize};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::{fmt, mem, ptr};
+use {Guard, add_garbage_box};
+
+/// The initial capacity of the buffer of a deque.
+const INITIAL_CAPACITY: usize = 16;
+
+/// The owner of a Chase-Lev deque.
+///
+/// Chase-Lev deques are the work-stealing deques of schedulers: The owner (usually a worker
+/// thread) pushes and pops items at the bottom end of its deque, like a stack, while other threads
+/// steal items from the top end through `Stealer`s. The owner only contends with the thieves when
+/// the deque is nearly empty.
+///
+/// The items are stored in a circular buffer, which the owner replaces by a larger one when it is
+/// full. Thieves might still read the old buffer, so it is retired through the garbage, and
+/// protected by a guard while stealing.
+pub struct Worker<T: Send> {
+    /// The deque.
+    inner: Arc<Inner<T>>,
+    /// Make the worker `!Sync`, as only one thread can own the deque.
+    _marker: PhantomData<Cell<()>>,
+}
+
+impl<T: Send> Worker<T> {
+    /// Create a new, empty deque.
+    pub fn new() -> Worker<T> {
+        Worker {
+            inner: Arc::new(Inner {
+                top: AtomicUsize::new(0),
+                bottom: AtomicUsize::new(0),
+                buffer: AtomicPtr::new(Box::into_raw(Box::new(Buffer::new(INITIAL_CAPACITY)))),
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a stealer of the deque.
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Push an item to the bottom of the deque.
+    pub fn push(&self, item: T)
+    where T: 'static {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(atomic::Ordering::Relaxed);
+        let top = inner.top.load(atomic::Ordering::Acquire);
+        // Only the owner replaces the buffer, so it needn't be protected.
+        let mut buffer = inner.buffer.load(atomic::Ordering::Relaxed);
+
+        // Grow the buffer, if it is full.
+        if bottom.wrapping_sub(top) >= unsafe { (*buffer).cap } {
+            buffer = unsafe { inner.grow(top, bottom) };
+        }
+
+        unsafe { ptr::write((*buffer).at(bottom), item); }
+        // Publish the item to the thieves.
+        inner.bottom.store(bottom.wrapping_add(1), atomic::Ordering::Release);
+    }
+
+    /// Pop an item from the bottom of the deque.
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        // Reserve the bottom item, before checking if the thieves left it.
+        let bottom = inner.bottom.load(atomic::Ordering::Relaxed).wrapping_sub(1);
+        inner.bottom.store(bottom, atomic::Ordering::Relaxed);
+        atomic::fence(atomic::Ordering::SeqCst);
+        let top = inner.top.load(atomic::Ordering::Relaxed);
+
+        if (bottom.wrapping_sub(top) as isize) < 0 {
+            // The deque is empty.
+            inner.bottom.store(bottom.wrapping_add(1), atomic::Ordering::Relaxed);
+            return None;
+        }
+
+        let buffer = inner.buffer.load(atomic::Ordering::Relaxed);
+        let item = unsafe { ptr::read((*buffer).at(bottom)) };
+        if bottom != top {
+            // There are other items left, so the thieves cannot take this one.
+            return Some(item);
+        }
+
+        // This is the last item, so we race the thieves for it.
+        let won = inner.top.compare_exchange(top, top.wrapping_add(1), atomic::Ordering::SeqCst,
+                                             atomic::Ordering::Relaxed).is_ok();
+        inner.bottom.store(bottom.wrapping_add(1), atomic::Ordering::Relaxed);
+        if won {
+            Some(item)
+        } else {
+            // A thief took the item, so our copy must not be dropped.
+            mem::forget(item);
+            None
+        }
+    }
+}
+
+impl<T: Send> Default for Worker<T> {
+    fn default() -> Worker<T> {
+        Worker::new()
+    }
+}
+
+impl<T: Send> fmt::Debug for Worker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("Worker { .. }")
+    }
+}
+
+/// A thief of a Chase-Lev deque.
+///
+/// This steals items from the top of the deque of a `Worker`. It can be cloned and shared
+/// between threads.
+pub struct Stealer<T: Send> {
+    /// The deque.
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Send> Stealer<T> {
+    /// Steal an item from the top of the deque.
+    ///
+    /// This returns `None` if the deque is empty. Otherwise, it retries until it wins the race for
+    /// the top item against the other thieves (and the owner, if it is the last item).
+    pub fn steal(&self) -> Option<T>
+    where T: 'static {
+        let inner = &*self.inner;
+        loop {
+            let top = inner.top.load(atomic::Ordering::Acquire);
+            atomic::fence(atomic::Ordering::SeqCst);
+            let bottom = inner.bottom.load(atomic::Ordering::Acquire);
+
+            if (bottom.wrapping_sub(top) as isize) <= 0 {
+                // The deque is empty.
+                return None;
+            }
+
+            // The buffer is read after the bottom, so it holds the top item. Even if the owner
+            // replaces it meanwhile, the old buffer keeps a copy of the item, and the guard keeps
+            // the old buffer alive.
+            let buffer = Guard::new(|| unsafe {
+                &*inner.buffer.load(atomic::Ordering::Acquire)
+            });
+            let item = unsafe { ptr::read(buffer.at(top)) };
+
+            if inner.top.compare_exchange(top, top.wrapping_add(1), atomic::Ordering::SeqCst,
+                                          atomic::Ordering::Relaxed).is_ok() {
+                return Some(item);
+            }
+
+            // Another thread took the item, so our copy must not be dropped.
+            mem::forget(item);
+        }
+    }
+}
+
+impl<T: Send> Clone for Stealer<T> {
+    fn clone(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Send> fmt::Debug for Stealer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("Stealer { .. }")
+    }
+}
+
+/// The state of a deque.
+struct Inner<T> {
+    /// The index of the top item.
+    ///
+    /// The indices wrap around, so they are compared through their difference.
+    top: AtomicUsize,
+    /// The index after the bottom item.
+    bottom: AtomicUsize,
+    /// The buffer of the items.
+    ///
+    /// This is only replaced by the owner.
+    buffer: AtomicPtr<Buffer<T>>,
+}
+
+impl<T> Inner<T> {
+    /// Replace the buffer by one of twice the capacity, holding the items between `top` and
+    /// `bottom`.
+    ///
+    /// The new buffer is returned.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called by the owner.
+    unsafe fn grow(&self, top: usize, bottom: usize) -> *mut Buffer<T> {
+        let old = self.buffer.load(atomic::Ordering::Relaxed);
+        let new = Box::into_raw(Box::new(Buffer::new((*old).cap * 2)));
+
+        // Copy the items. The thieves might still take them from the old buffer, which is why the
+        // old buffer keeps its copies.
+        let mut i = top;
+        while i != bottom {
+            ptr::copy_nonoverlapping((*old).at(i), (*new).at(i), 1);
+            i = i.wrapping_add(1);
+        }
+
+        self.buffer.store(new, atomic::Ordering::Release);
+        // The thieves might still read the old buffer, so it is destroyed through the garbage.
+        add_garbage_box(old);
+
+        new
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // There are neither the owner nor thieves left, so we can safely destroy the items and the
+        // buffer.
+        let (top, bottom) = (*self.top.get_mut(), *self.bottom.get_mut());
+        let buffer = unsafe { Box::from_raw(*self.buffer.get_mut()) };
+
+        let mut i = top;
+        while i != bottom {
+            unsafe { ptr::drop_in_place(buffer.at(i)); }
+            i = i.wrapping_add(1);
+        }
+    }
+}
+
+/// A circular buffer of items.
+///
+/// This doesn't drop the items, when it is dropped.
+struct Buffer<T> {
+    /// The pointer to the slots.
+    ptr: *mut T,
+    /// The number of slots.
+    ///
+    /// This is a power of two.
+    cap: usize,
+}
+
+impl<T> Buffer<T> {
+    /// Allocate a new buffer of `cap` slots.
+    fn new(cap: usize) -> Buffer<T> {
+        debug_assert!(cap.is_power_of_two(), "Capacity is not a power of two.");
+
+        let mut vec = Vec::with_capacity(cap);
+        let ptr = vec.as_mut_ptr();
+        mem::forget(vec);
+
+        Buffer {
+            ptr: ptr,
+            cap: cap,
+        }
+    }
+
+    /// Get the slot of index `i`.
+    fn at(&self, i: usize) -> *mut T {
+        unsafe { self.ptr.add(i & (self.cap - 1)) }
+    }
+}
+
+impl<T> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        // Deallocate the slots without dropping any item.
+        unsafe { drop(Vec::from_raw_parts(self.ptr, 0, self.cap)); }
+    }
+}
+
+// We must do this manually due to the raw pointer. The buffer is shared between the owner and the
+// thieves, which move the items between threads.
+unsafe impl<T: Send> Send for Buffer<T> {}
+unsafe impl<T: Send> Sync for Buffer<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Clone)]
+    struct Dropper {
+        d: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.d.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn push_pop_steal() {
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        assert!(worker.pop().is_none());
+        assert!(stealer.steal().is_none());
+
+        // The buffer grows a few times.
+        for i in 0..1000 {
+            worker.push(i);
+        }
+
+        // The owner pops from the bottom, the thieves steal from the top.
+        assert_eq!(worker.pop(), Some(999));
+        assert_eq!(stealer.steal(), Some(0));
+        assert_eq!(stealer.clone().steal(), Some(1));
+        for i in (2..999).rev() {
+            assert_eq!(worker.pop(), Some(i));
+        }
+        assert!(worker.pop().is_none());
+        assert!(stealer.steal().is_none());
+    }
+
+    #[test]
+    fn drop_items() {
+        let drops = Arc::new(AtomicUsize::default());
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+
+        for _ in 0..100 {
+            worker.push(Dropper {
+                d: drops.clone(),
+            });
+        }
+        drop(worker.pop());
+        drop(stealer.steal());
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 2);
+
+        // The remaining items are dropped with the last handle, but not twice (the old buffers
+        // keep copies of them).
+        drop(worker);
+        drop(stealer);
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn work_stealing() {
+        const ITEMS: usize = 100_000;
+
+        let worker = Worker::new();
+        let mut j = Vec::new();
+
+        for _ in 0..4 {
+            let stealer = worker.stealer();
+            j.push(thread::spawn(move || {
+                let mut stolen = Vec::new();
+                for _ in 0..ITEMS {
+                    if let Some(x) = stealer.steal() {
+                        stolen.push(x);
+                    }
+                }
+                stolen
+            }));
+        }
+
+        let mut items = Vec::new();
+        for i in 0..ITEMS {
+            worker.push(i);
+            if i % 3 == 0 {
+                items.extend(worker.pop());
+            }
+        }
+        while let Some(x) = worker.pop() {
+            items.push(x);
+        }
+
+        for i in j {
+            items.extend(i.join().unwrap());
+        }
+
+        // Every item is taken exactly once.
+        items.sort();
+        assert_eq!(items, (0..ITEMS).collect::<Vec<_>>());
+    }
+}
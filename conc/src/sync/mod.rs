@@ -1,7 +1,15 @@
 //! Various simple lock-free data structures built on `conc`.
 
+mod arc;
+mod deque;
+mod queue;
+mod rcu;
 mod stm;
 mod treiber;
 
+pub use self::arc::AtomicArc;
+pub use self::deque::{Stealer, Worker};
+pub use self::queue::Queue;
+pub use self::rcu::RcuCell;
 pub use self::stm::Stm;
-pub use self::treiber::Treiber;
+pub use self::treiber::{Iter, PopAll, Treiber};
@@ -0,0 +1,298 @@
+//! Michael-Scott queues.
+
+use atomics::{self as atomic, AtomicPtr};
+use std::marker::PhantomData;
+use std::ptr;
+use {Guard, add_garbage_box};
+
+/// A Michael-Scott queue.
+///
+/// Michael-Scott queues are lock-free FIFO queues, which any number of threads can push to and pop
+/// from concurrently.
+///
+/// The queue is a linked list with a dummy node at its head. Items are pushed by linking a node
+/// after the tail, and popped by moving the head to the next node, which becomes the new dummy
+/// node. Pops hold two guards, one on the head and one on its successor, as the successor can only
+/// be read safely while the head is still in the queue.
+pub struct Queue<T> {
+    /// The head node.
+    ///
+    /// This is the dummy node. Its item (if any) was popped already. The head never passes the
+    /// tail, so the tail never points to a popped node.
+    head: AtomicPtr<Node<T>>,
+    /// The tail node.
+    ///
+    /// This is the last node or (while another thread is pushing) the one before it.
+    tail: AtomicPtr<Node<T>>,
+    /// Make the `Sync` and `Send` (and other OIBITs) transitive.
+    _marker: PhantomData<T>,
+}
+
+impl<T> Queue<T> {
+    /// Create a new, empty queue.
+    pub fn new() -> Queue<T> {
+        let dummy = Box::into_raw(Box::new(Node {
+            item: None,
+            next: AtomicPtr::default(),
+        }));
+
+        Queue {
+            head: AtomicPtr::new(dummy),
+            tail: AtomicPtr::new(dummy),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Push an item to the back of the queue.
+    pub fn push(&self, item: T)
+    where T: 'static {
+        let node = Box::into_raw(Box::new(Node {
+            item: Some(item),
+            next: AtomicPtr::default(),
+        }));
+
+        loop {
+            // Protect the tail. The raw pointer is kept alongside the guard, as pointers derived
+            // from the guard must not be used in the CASs.
+            let mut tail = ptr::null_mut();
+            let snapshot = Guard::new(|| unsafe {
+                tail = self.tail.load(atomic::Ordering::Acquire);
+                &*tail
+            });
+
+            // The next node is never dereferenced, so it needn't be protected.
+            let next = snapshot.next.load(atomic::Ordering::Acquire);
+            if next.is_null() {
+                // The tail is the last node, so we try to link the new node after it.
+                if snapshot.next.compare_exchange(ptr::null_mut(), node, atomic::Ordering::AcqRel,
+                                                  atomic::Ordering::Relaxed).is_ok() {
+                    // The node is pushed. We try to move the tail to it, but if it fails, another
+                    // thread did so already.
+                    let _ = self.tail.compare_exchange(tail, node, atomic::Ordering::AcqRel,
+                                                       atomic::Ordering::Relaxed);
+                    return;
+                }
+            } else {
+                // Another thread is pushing, and hasn't moved the tail yet, so we help it.
+                let _ = self.tail.compare_exchange(tail, next, atomic::Ordering::AcqRel,
+                                                   atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pop an item from the front of the queue.
+    pub fn pop(&self) -> Option<Guard<T>> {
+        loop {
+            // Protect the head. It is never null, as there is always the dummy node.
+            let mut head = ptr::null_mut();
+            let snapshot = Guard::new(|| unsafe {
+                head = self.head.load(atomic::Ordering::Acquire);
+                &*head
+            });
+
+            // Protect the next node. It can only be read safely while the head is still in the
+            // queue, since it is popped (and destroyed) after the head.
+            let mut next = ptr::null_mut();
+            let next_snapshot = Guard::try_new(|| unsafe {
+                next = snapshot.next.load(atomic::Ordering::Acquire);
+                if self.head.load(atomic::Ordering::Acquire) != head {
+                    // The head was popped meanwhile, so we must start over.
+                    Err(Pop::Retry)
+                } else if let Some(next) = next.as_ref() {
+                    Ok(next)
+                } else {
+                    Err(Pop::Empty)
+                }
+            });
+
+            let next_snapshot = match next_snapshot {
+                Ok(next_snapshot) => next_snapshot,
+                Err(Pop::Retry) => continue,
+                // The dummy node is the last node, so the queue is empty.
+                Err(Pop::Empty) => return None,
+            };
+
+            let tail = self.tail.load(atomic::Ordering::Acquire);
+            if tail == head {
+                // The tail lags behind, as another thread is pushing. The head must never pass the
+                // tail, so we help moving the tail first.
+                let _ = self.tail.compare_exchange(tail, next, atomic::Ordering::AcqRel,
+                                                   atomic::Ordering::Relaxed);
+                continue;
+            }
+
+            // Move the head to the next node, which becomes the new dummy node.
+            if self.head.compare_exchange(head, next, atomic::Ordering::AcqRel,
+                                          atomic::Ordering::Relaxed).is_ok() {
+                // As we popped the old head, we must queue its deletion. Its item was popped
+                // already, when it became the dummy node.
+                unsafe { add_garbage_box(head); }
+                // Map the guard to refer the item of the new dummy node.
+                return Some(next_snapshot.map(|x| {
+                    x.item.as_ref().expect("The popped node has no item.")
+                }));
+            }
+        }
+    }
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Queue<T> {
+        Queue::new()
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // The dummy node holds the last popped item, which might still be guarded, so it is
+        // destroyed through the garbage. There are no guards of the rest of the nodes, so we can
+        // safely deallocate them.
+        let head = *self.head.get_mut();
+        let mut node = unsafe { *(*head).next.get_mut() };
+        unsafe { add_garbage_box(head); }
+
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            node = *boxed.next.get_mut();
+        }
+    }
+}
+
+/// The reason a pop failed to protect the next node.
+enum Pop {
+    /// The head was popped meanwhile.
+    Retry,
+    /// The queue is empty.
+    Empty,
+}
+
+/// A node in the queue.
+struct Node<T> {
+    /// The item this node holds.
+    ///
+    /// This is `None` for the initial dummy node.
+    item: Option<T>,
+    /// The next node.
+    next: AtomicPtr<Node<T>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use local;
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Clone)]
+    struct Dropper {
+        d: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Dropper {
+        fn drop(&mut self) {
+            self.d.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn simple() {
+        let queue = Queue::new();
+        assert!(queue.pop().is_none());
+
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(*queue.pop().unwrap(), 1);
+        queue.push(3);
+        assert_eq!(*queue.pop().unwrap(), 2);
+        assert_eq!(*queue.pop().unwrap(), 3);
+        assert!(queue.pop().is_none());
+
+        for i in 0..10000 {
+            queue.push(i);
+        }
+        for i in 0..10000 {
+            assert_eq!(*queue.pop().unwrap(), i);
+        }
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn drop_items() {
+        let drops = Arc::new(AtomicUsize::default());
+        let queue = Queue::new();
+
+        for _ in 0..10 {
+            queue.push(Dropper {
+                d: drops.clone(),
+            });
+        }
+        let popped = queue.pop().unwrap();
+        drop(queue);
+
+        // The popped item outlives the queue.
+        ::gc();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 9);
+        drop(popped);
+
+        // The cached hazards keep protecting the popped item, unless they are freed.
+        local::free_cached_hazards();
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(atomic::Ordering::Relaxed) == 10 {
+                break;
+            }
+        }
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn mpmc() {
+        const PRODUCERS: usize = 4;
+        const ITEMS: usize = 100_000;
+
+        let queue = Arc::new(Queue::new());
+        let mut j = Vec::new();
+
+        for p in 0..PRODUCERS {
+            let q = queue.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..ITEMS {
+                    q.push((p, i));
+                }
+                Vec::new()
+            }));
+        }
+
+        for _ in 0..4 {
+            let q = queue.clone();
+            j.push(thread::spawn(move || {
+                // The items of every producer are popped in the order they were pushed.
+                let mut last = [None; PRODUCERS];
+                let mut popped = Vec::new();
+                for _ in 0..ITEMS {
+                    if let Some(x) = q.pop() {
+                        let (p, i) = *x;
+                        assert!(last[p] < Some(i));
+                        last[p] = Some(i);
+                        popped.push((p, i));
+                    }
+                }
+                popped
+            }));
+        }
+
+        let mut popped: Vec<_> = j.into_iter().flat_map(|i| i.join().unwrap()).collect();
+        while let Some(x) = queue.pop() {
+            popped.push(*x);
+        }
+
+        // Every item is popped exactly once.
+        popped.sort();
+        assert_eq!(popped.len(), PRODUCERS * ITEMS);
+        popped.dedup();
+        assert_eq!(popped.len(), PRODUCERS * ITEMS);
+    }
+}
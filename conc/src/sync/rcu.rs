@@ -0,0 +1,126 @@
+//! Read-copy-update cells.
+
+use {Atomic, Guard};
+use std::sync::atomic;
+
+/// A read-copy-update cell.
+///
+/// This holds a snapshot of some data (e.g. a configuration), which readers can read without any
+/// locking, and writers replace as a whole. Updating copies the current snapshot, modifies the
+/// copy, and swaps it in, while the old snapshot is destroyed once no reader holds it anymore.
+pub struct RcuCell<T> {
+    /// The current snapshot.
+    ///
+    /// This is never `None`.
+    inner: Atomic<T>,
+}
+
+impl<T> RcuCell<T> {
+    /// Create a new RCU cell.
+    pub fn new(data: T) -> RcuCell<T> {
+        RcuCell {
+            inner: Atomic::new(Some(Box::new(data))),
+        }
+    }
+
+    /// Read the current snapshot.
+    ///
+    /// The guard keeps the snapshot alive, even if it is replaced meanwhile.
+    pub fn read(&self) -> Guard<T> {
+        self.inner.load(atomic::Ordering::Acquire).expect("The snapshot of an RCU cell is missing.")
+    }
+
+    /// Update the snapshot.
+    ///
+    /// This applies closure `f` to the current snapshot, and replaces it by the result, unless it
+    /// was updated in the meantime, in which case the closure is reevaluated. The old snapshot is
+    /// returned.
+    pub fn update<F>(&self, mut f: F) -> Guard<T>
+    where
+        F: FnMut(&T) -> T,
+        T: 'static,
+    {
+        match self.inner.fetch_update(atomic::Ordering::AcqRel, atomic::Ordering::Acquire,
+                                      |old| Some(f(old))) {
+            Ok(old) => old,
+            Err(_) => unreachable!("The snapshot of an RCU cell is missing."),
+        }
+    }
+
+    /// Replace the snapshot.
+    ///
+    /// The old snapshot is returned.
+    pub fn replace(&self, data: T) -> Guard<T> {
+        self.inner.swap(Some(Box::new(data)), atomic::Ordering::AcqRel)
+            .expect("The snapshot of an RCU cell is missing.")
+    }
+}
+
+impl<T: Clone> RcuCell<T> {
+    /// Update the snapshot by modifying a copy of it.
+    ///
+    /// This clones the current snapshot and applies `f` to the clone, which then replaces the
+    /// snapshot, unless it was updated in the meantime. See `update`.
+    pub fn modify<F>(&self, mut f: F) -> Guard<T>
+    where
+        F: FnMut(&mut T),
+        T: 'static,
+    {
+        self.update(|old| {
+            let mut new = old.clone();
+            f(&mut new);
+            new
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::sync::Arc;
+
+    #[test]
+    fn single_threaded() {
+        let cell = RcuCell::new(vec![1]);
+
+        let old = cell.read();
+        assert_eq!(*cell.update(|x| x.iter().map(|x| x + 1).collect()), [1]);
+        assert_eq!(*cell.modify(|x| x.push(4)), [2]);
+        assert_eq!(*cell.replace(vec![5]), [2, 4]);
+
+        // The old snapshot is still readable.
+        ::gc();
+        assert_eq!(*old, [1]);
+        assert_eq!(*cell.read(), [5]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn multi_threaded() {
+        let cell = Arc::new(RcuCell::new((0, 0)));
+
+        let mut j = Vec::new();
+        for _ in 0..16 {
+            let cell = cell.clone();
+            j.push(thread::spawn(move || {
+                for _ in 0..10_000 {
+                    // The snapshots are always consistent.
+                    let (a, b) = *cell.read();
+                    assert_eq!(a, b);
+                    let old = cell.modify(|x| {
+                        x.0 += 1;
+                        x.1 += 1;
+                    });
+                    assert_eq!(old.0, old.1);
+                }
+            }))
+        }
+
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert_eq!(*cell.read(), (160_000, 160_000));
+    }
+}
@@ -67,6 +67,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn multi_threaded() {
         let stm = Arc::new(Stm::new(Some(Box::new(0))));
 
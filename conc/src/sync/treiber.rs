@@ -1,6 +1,6 @@
 //! Treiber stacks.
 
-use std::sync::atomic::{self, AtomicPtr};
+use atomics::{self as atomic, AtomicPtr, AtomicUsize};
 use std::marker::PhantomData;
 use std::ptr;
 use {Guard, add_garbage_box};
@@ -16,6 +16,11 @@ use {Guard, add_garbage_box};
 pub struct Treiber<T> {
     /// The head node.
     head: AtomicPtr<Node<T>>,
+    /// The number of pops.
+    ///
+    /// This is incremented before the popped nodes are retired, so a reader seeing it unchanged
+    /// knows that none of the nodes it reached was destroyed (see `Iter`).
+    pops: AtomicUsize,
     /// Make the `Sync` and `Send` (and other OIBITs) transitive.
     _marker: PhantomData<T>,
 }
@@ -25,6 +30,7 @@ impl<T> Treiber<T> {
     pub fn new() -> Treiber<T> {
         Treiber {
             head: AtomicPtr::default(),
+            pops: AtomicUsize::new(0),
             _marker: PhantomData,
         }
     }
@@ -33,79 +39,150 @@ impl<T> Treiber<T> {
     // TODO: Change this return type.
     pub fn pop(&self) -> Option<Guard<T>> {
         // TODO: Use `catch {}` here when it lands.
-        // Read the head snapshot.
+        // Read the head snapshot. The raw pointer is kept alongside the guard, as pointers derived
+        // from the guard must not be used to deallocate the node.
+        let mut head = ptr::null_mut();
         let mut snapshot = Guard::maybe_new(|| unsafe {
-            self.head.load(atomic::Ordering::Acquire).as_ref()
+            head = self.head.load(atomic::Ordering::Acquire);
+            head.as_ref()
         });
 
         // Unless the head snapshot is `None`, try to replace it with the tail.
         while let Some(old) = snapshot {
             // Attempt to replace the head with the tail of the head.
+            let mut actual = ptr::null_mut();
             snapshot = Guard::maybe_new(|| unsafe {
-                self.head.compare_and_swap(
-                    old.as_ptr() as *mut _,
-                    old.next as *mut Node<T>,
-                    atomic::Ordering::Release,
-                ).as_ref()
+                actual = self.head.compare_and_swap(
+                    head,
+                    old.next,
+                    // The actual head is read on failure, so we must acquire it.
+                    atomic::Ordering::AcqRel,
+                );
+                actual.as_ref()
             });
 
             // If it match, we are done as the previous head node was replaced by the tail, popping
             // the top element. The element we return is the one carried by the previous head.
-            if let Some(ref new) = snapshot {
-                if new.as_ptr() == old.as_ptr() {
-                    // As we overwrote the old head (the CAS was successful), we must queue its
-                    // deletion.
-                    unsafe { add_garbage_box(old.as_ptr()); }
-                    // Map the guard to refer the item.
-                    return Some(old.map(|x| &x.item));
-                }
-            } else {
-                // Short-circuit.
-                break;
+            if actual == head {
+                // As we overwrote the old head (the CAS was successful), we must queue its
+                // deletion, after announcing the pop to the iterators.
+                self.pops.fetch_add(1, atomic::Ordering::SeqCst);
+                unsafe { add_garbage_box(head); }
+                // Map the guard to refer the item.
+                return Some(old.map(|x| &x.item));
             }
+
+            // Otherwise, we retry with the actual head (unless it is null, ending the loop).
+            head = actual;
         }
 
         // As the head was empty, there is nothing to pop.
         None
     }
 
+    /// Pop all the items from the stack.
+    ///
+    /// This takes the whole stack at once, through a single swap of the head, and returns an
+    /// iterator over the taken items, from the top. The items which aren't iterated over are
+    /// destroyed along with the iterator.
+    pub fn pop_all(&self) -> PopAll<T> {
+        let head = self.head.swap(ptr::null_mut(), atomic::Ordering::AcqRel);
+        if !head.is_null() {
+            // The nodes are retired by the iterator, so the pop must be announced now.
+            self.pops.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+
+        PopAll {
+            next: head,
+        }
+    }
+
+    /// Iterate over the items of the stack, from the top.
+    ///
+    /// The items are protected by guards, so the stack can be modified while iterating. Items
+    /// pushed after the iteration started are not yielded. If items are popped meanwhile, the
+    /// iteration might end early, as the rest of the stack can then not be reached safely.
+    pub fn iter(&self) -> Iter<T> {
+        // The number of pops is read before the head, such that any pop of the nodes we reach
+        // changes it.
+        let pops = self.pops.load(atomic::Ordering::SeqCst);
+
+        Iter {
+            stack: self,
+            pops: pops,
+            next: Guard::maybe_new(|| unsafe {
+                self.head.load(atomic::Ordering::Acquire).as_ref()
+            }),
+        }
+    }
+
     /// Push an item to the stack.
     pub fn push(&self, item: T)
     where T: 'static {
-        // Load the head snapshot.
-        let mut snapshot = Guard::maybe_new(|| unsafe {
-            self.head.load(atomic::Ordering::Relaxed).as_ref()
-        });
-
-        // TODO: Use `catch {}` here when it lands.
         // Construct a node, which will be the new head.
-        let mut node = Box::into_raw(Box::new(Node {
+        let node = Box::into_raw(Box::new(Node {
             item: item,
-            // Placeholder; we will replace it with an actual value in the loop.
+            // Placeholder; it is replaced in `link()`.
             next: ptr::null_mut(),
         }));
 
+        unsafe { self.link(node, node); }
+    }
+
+    /// Push the items of an iterator to the stack.
+    ///
+    /// The items are linked up in advance and pushed at once, so the head is only replaced once.
+    /// The last item ends up on the top, as if the items were pushed one by one.
+    pub fn extend<I>(&self, items: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: 'static,
+    {
+        // Build the chain of nodes from the bottom up.
+        let mut top = ptr::null_mut();
+        let mut bottom: *mut Node<T> = ptr::null_mut();
+        for item in items {
+            top = Box::into_raw(Box::new(Node {
+                item: item,
+                next: top,
+            }));
+
+            if bottom.is_null() {
+                bottom = top;
+            }
+        }
+
+        if !top.is_null() {
+            unsafe { self.link(top, bottom); }
+        }
+    }
+
+    /// Link a chain of nodes on top of the stack.
+    ///
+    /// `top` is the first node of the chain, and `bottom` is its last node, whose next-pointer is
+    /// replaced by the head.
+    ///
+    /// # Safety
+    ///
+    /// The chain must consist of valid, unshared nodes.
+    unsafe fn link(&self, top: *mut Node<T>, bottom: *mut Node<T>) {
+        // Load the head snapshot. It is never dereferenced, so it needn't be protected.
+        let mut head = self.head.load(atomic::Ordering::Relaxed);
         loop {
-            // Construct the next-pointer of the new node from the head snapshot.
-            let next = snapshot.map_or(ptr::null_mut(), |x| x.as_ptr() as *mut _);
-            unsafe { (*node).next = next; }
+            // Construct the next-pointer of the bottom node from the head snapshot.
+            (*bottom).next = head;
 
-            // CAS from the read pointer (that is, the one we placed as `node.next`) to the new
+            // CAS from the read pointer (that is, the one we placed as `bottom.next`) to the new
             // head.
-            match Guard::maybe_new(|| unsafe {
-                // TODO: This should be something that ignores the guard creation when the CAS
-                // succeeds, because it's expensive to do and not used anyway. It should be easy
-                // enough to implement, but I am struggling to come up with a good name for the
-                // method.
-                self.head.compare_and_swap(next, node, atomic::Ordering::Release).as_ref()
-            }) {
-                // If it succeeds (that is, the pointers matched and the CAS ran), the item has
-                // been pushed.
-                Some(ref new) if new.as_ptr() == next => break,
-                None if next.is_null() => break,
-                // If it fails, we will retry the CAS with updated values.
-                new => snapshot = new,
+            let actual = self.head.compare_and_swap(head, top, atomic::Ordering::Release);
+            // If it succeeds (that is, the pointers matched and the CAS ran), the item has been
+            // pushed.
+            if actual == head {
+                break;
             }
+
+            // If it fails, we will retry the CAS with the updated value.
+            head = actual;
         }
     }
 }
@@ -129,6 +206,81 @@ impl<T> Drop for Treiber<T> {
     }
 }
 
+/// An iterator over the items popped by `Treiber::pop_all()`.
+///
+/// The items are yielded from the top of the stack.
+pub struct PopAll<T> {
+    /// The next node to yield.
+    ///
+    /// This chain of nodes is owned by the iterator, but other threads might still read the nodes
+    /// (e.g. through `Iter`), so they are destroyed through the garbage.
+    next: *mut Node<T>,
+}
+
+impl<T: 'static> Iterator for PopAll<T> {
+    type Item = Guard<T>;
+
+    fn next(&mut self) -> Option<Guard<T>> {
+        let node = self.next;
+        if node.is_null() {
+            return None;
+        }
+
+        // Protect the node before it is retired, and read its successor before it is destroyed.
+        let guard = Guard::new(|| unsafe { &*node });
+        self.next = guard.next;
+        unsafe { add_garbage_box(node); }
+
+        Some(guard.map(|x| &x.item))
+    }
+}
+
+impl<T> Drop for PopAll<T> {
+    fn drop(&mut self) {
+        // Retire the rest of the nodes.
+        while !self.next.is_null() {
+            let node = self.next;
+            self.next = unsafe { (*node).next };
+            unsafe { add_garbage_box(node); }
+        }
+    }
+}
+
+/// An iterator over the items of a Treiber stack.
+///
+/// See `Treiber::iter()`.
+pub struct Iter<'a, T: 'static> {
+    /// The stack.
+    stack: &'a Treiber<T>,
+    /// The number of pops of the stack when the iteration started.
+    pops: usize,
+    /// The next node to yield.
+    next: Option<Guard<Node<T>>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = Guard<T>;
+
+    fn next(&mut self) -> Option<Guard<T>> {
+        let node = self.next.take()?;
+
+        let (stack, pops) = (self.stack, self.pops);
+        self.next = Guard::maybe_new(|| unsafe {
+            let next = node.next.as_ref();
+            // The successor is only valid if it is still in the stack, which it is, unless
+            // something was popped since the iteration started (since the nodes are popped from
+            // the top, and the current node was in the stack then).
+            if stack.pops.load(atomic::Ordering::SeqCst) == pops {
+                next
+            } else {
+                None
+            }
+        });
+
+        Some(node.map(|x| &x.item))
+    }
+}
+
 /// A node in the stack.
 struct Node<T> {
     /// The data this node holds.
@@ -164,6 +316,7 @@ impl<T> Node<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use local;
     use std::thread;
     use std::sync::Arc;
     use std::sync::atomic::AtomicUsize;
@@ -265,6 +418,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn push_pop() {
         let stack = Arc::new(Treiber::new());
         let mut j = Vec::new();
@@ -284,6 +438,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn increment() {
         let stack = Arc::new(Treiber::<u64>::new());
         stack.push(0);
@@ -312,6 +467,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn sum() {
         let stack = Arc::new(Treiber::<i64>::new());
         let mut j = Vec::new();
@@ -393,6 +549,107 @@ mod tests {
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 20 * 16 + 16);
     }
 
+    #[test]
+    fn pop_all() {
+        let drops = Arc::new(AtomicUsize::default());
+        let stack = Treiber::new();
+
+        stack.extend(vec![1, 2, 3]);
+        stack.push(4);
+        let items: Vec<_> = stack.pop_all().map(|x| *x).collect();
+        assert_eq!(items, [4, 3, 2, 1]);
+        assert!(stack.pop().is_none());
+        assert!(stack.pop_all().next().is_none());
+
+        // The items which aren't iterated over are destroyed.
+        let stack = Treiber::new();
+        for _ in 0..10 {
+            stack.push(Dropper {
+                d: drops.clone(),
+            });
+        }
+        let mut all = stack.pop_all();
+        all.next();
+        drop(all);
+        // The cached hazards keep protecting the yielded item, unless they are freed.
+        local::free_cached_hazards();
+        // Other threads might be pinned in an earlier epoch for a moment, so we retry.
+        for _ in 0..1000 {
+            ::gc();
+            if drops.load(atomic::Ordering::Relaxed) == 10 {
+                break;
+            }
+        }
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn extend() {
+        let stack = Treiber::new();
+        stack.extend(Vec::new());
+        assert!(stack.pop().is_none());
+
+        stack.push(0);
+        stack.extend(1..4);
+        for i in (0..4).rev() {
+            assert_eq!(*stack.pop().unwrap(), i);
+        }
+        assert!(stack.pop().is_none());
+    }
+
+    #[test]
+    fn iter() {
+        let stack = Treiber::new();
+        stack.extend(0..4);
+        assert_eq!(stack.iter().map(|x| *x).collect::<Vec<_>>(), [3, 2, 1, 0]);
+
+        // Pushing doesn't disturb the iteration.
+        let mut iter = stack.iter();
+        assert_eq!(*iter.next().unwrap(), 3);
+        stack.push(4);
+        assert_eq!(iter.map(|x| *x).collect::<Vec<_>>(), [2, 1, 0]);
+
+        // Popping ends it.
+        let mut iter = stack.iter();
+        assert_eq!(*iter.next().unwrap(), 4);
+        stack.pop();
+        assert_eq!(*iter.next().unwrap(), 3);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn iter_pop_all_concurrent() {
+        let stack = Arc::new(Treiber::new());
+        let mut j = Vec::new();
+
+        for _ in 0..4 {
+            let s = stack.clone();
+            j.push(thread::spawn(move || {
+                for _ in 0..10_000 {
+                    s.extend(0..8);
+                    s.pop();
+                    s.pop_all().count();
+                }
+            }));
+        }
+
+        for _ in 0..4 {
+            let s = stack.clone();
+            j.push(thread::spawn(move || {
+                for _ in 0..10_000 {
+                    for x in s.iter() {
+                        assert!(*x < 8);
+                    }
+                }
+            }));
+        }
+
+        for i in j {
+            i.join().unwrap();
+        }
+    }
+
     #[test]
     #[should_panic]
     fn panic_in_dtor() {
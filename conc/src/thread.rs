@@ -0,0 +1,123 @@
+//! Threads with a reliable teardown.
+//!
+//! Normally, the hazards of a thread are recycled and its garbage exported when its thread-local
+//! state is deinitialized. The order of the thread-local destructors is unspecified, so this might
+//! happen after the thread was joined, or never (e.g. if another destructor aborts).
+//!
+//! The threads spawned through this module tear down their state (see `conc::on_thread_exit()`)
+//! right after their closure returns or panics. Since this is done before the thread finishes,
+//! joining it guarantees that its state is merged into the global state, such that a following
+//! `conc::gc()` can collect all its garbage.
+
+use std::{io, panic};
+use std::thread::{self, JoinHandle};
+use local;
+
+/// Spawn a new thread, which tears down its state reliably.
+///
+/// This acts like `std::thread::spawn()`, but runs the exit hooks of the thread, kills its hazards,
+/// and exports its garbage after `f` returns, even if it panics.
+///
+/// # Panics
+///
+/// This panics if the OS fails to create a thread. Use `Builder::spawn()` to handle that.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+    Builder::new().spawn(f).expect("Failed to spawn thread.")
+}
+
+/// A thread factory, which spawns threads with a reliable teardown.
+///
+/// This wraps `std::thread::Builder`. See `spawn()`.
+#[derive(Debug)]
+pub struct Builder {
+    /// The inner builder.
+    inner: thread::Builder,
+}
+
+impl Builder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> Builder {
+        Builder {
+            inner: thread::Builder::new(),
+        }
+    }
+
+    /// Name the thread.
+    ///
+    /// See `std::thread::Builder::name()`.
+    pub fn name(self, name: String) -> Builder {
+        Builder {
+            inner: self.inner.name(name),
+        }
+    }
+
+    /// Set the size of the stack of the thread.
+    ///
+    /// See `std::thread::Builder::stack_size()`.
+    pub fn stack_size(self, size: usize) -> Builder {
+        Builder {
+            inner: self.inner.stack_size(size),
+        }
+    }
+
+    /// Spawn the thread.
+    ///
+    /// This acts like `std::thread::Builder::spawn()`, but tears down the state of the thread
+    /// after `f` returns (see `spawn()`).
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static {
+        self.inner.spawn(move || {
+            // Catch the panic, such that we can tear down before propagating it.
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(f));
+            local::clean_up();
+
+            match res {
+                Ok(x) => x,
+                Err(err) => panic::resume_unwind(err),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::sync::Arc;
+    use Guard;
+
+    #[test]
+    fn teardown() {
+        static X: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(x: &'static AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let hook_ran = Arc::new(AtomicUsize::new(0));
+        let hook_ran2 = hook_ran.clone();
+        let res = Builder::new().name("conc-test".to_owned()).spawn(move || {
+            ::on_thread_exit(move || {
+                hook_ran2.fetch_add(1, atomic::Ordering::Relaxed);
+            });
+            // Leave a hazard protecting `X` in the cache, and garbage in the local queue.
+            drop(Guard::new(|| &X));
+            ::add_garbage(&X, dtor);
+            panic!("Panicking thread.");
+        }).unwrap().join();
+
+        assert!(res.is_err());
+        assert_eq!(hook_ran.load(atomic::Ordering::Relaxed), 1);
+
+        ::gc();
+        assert_eq!(X.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn spawn_return() {
+        assert_eq!(spawn(|| 42).join().unwrap(), 42);
+    }
+}
@@ -0,0 +1,173 @@
+//! Device geometry-aware allocation.
+//!
+//! Flash devices have a preferred I/O size (typically a few kilobytes) as well as an erase-block
+//! size (typically in the order of megabytes), which is the unit in which the device reclaims
+//! space internally. Writes which are not aligned to the preferred I/O size cause read-modify-write
+//! cycles, and erase blocks mixing long-lived and short-lived data must have their long-lived data
+//! moved around when the short-lived data dies. Both lead to write amplification.
+//!
+//! To reduce this, the allocator arranges the free clusters it loads from the freelist, such that
+//! clusters starting aligned, contiguous runs are handed out first, and such that short-lived
+//! metadata pages are grouped in clusters of a single erase block.
+
+use disk::{self, cluster, Disk};
+
+/// The lifetime of a page, as expected by the allocating party.
+///
+/// This is a hint used to place pages with similar lifetimes close to each other on the device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lifetime {
+    /// The page is expected to live for a long time (e.g. file data).
+    Long,
+    /// The page is expected to be replaced shortly (e.g. metadata).
+    Short,
+}
+
+/// The geometry of the device, in clusters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Geometry {
+    /// The number of clusters in the preferred I/O size.
+    ///
+    /// This is never zero.
+    io_clusters: u64,
+    /// The number of clusters in an erase block.
+    ///
+    /// This is never zero.
+    erase_block_clusters: u64,
+}
+
+impl Geometry {
+    /// Query the geometry of some disk.
+    pub fn new<D: Disk>(disk: &D) -> Geometry {
+        let capabilities = disk.capabilities();
+        Geometry::from_sizes(capabilities.io_size, capabilities.erase_block_size)
+    }
+
+    /// Construct the geometry from the preferred I/O size and erase-block size in bytes.
+    ///
+    /// Sizes smaller than a cluster (or not a multiple of the cluster size) are rounded, such that
+    /// every unit is at least one cluster.
+    fn from_sizes(io_size: usize, erase_block_size: usize) -> Geometry {
+        // Calculate the number of clusters in a unit, flooring to at least one cluster.
+        let clusters = |size| (size as u64 / disk::SECTOR_SIZE as u64).max(1);
+
+        let io_clusters = clusters(io_size);
+        Geometry {
+            io_clusters: io_clusters,
+            // Erase blocks are always at least as large as the preferred I/O size.
+            erase_block_clusters: clusters(erase_block_size).max(io_clusters),
+        }
+    }
+
+    /// Get the erase block some cluster belongs to.
+    pub fn erase_block(&self, cluster: cluster::Pointer) -> u64 {
+        cluster.number() / self.erase_block_clusters
+    }
+
+    /// Is some cluster aligned to the preferred I/O size?
+    pub fn is_aligned(&self, cluster: cluster::Pointer) -> bool {
+        cluster.number() % self.io_clusters == 0
+    }
+
+    /// Arrange a batch of free clusters for allocation.
+    ///
+    /// This takes a batch of free clusters and splits it into the clusters for general use and
+    /// the clusters for short-lived pages (see `Lifetime`). Both are sorted in the order in which
+    /// they should be allocated.
+    ///
+    /// The general clusters are ordered such that contiguous runs starting on an aligned cluster
+    /// come first, meaning that a thread allocating many clusters in a row will be likely to get
+    /// aligned, contiguous clusters.
+    ///
+    /// If `short_lived` is true, the clusters of the erase block with the most free clusters in the
+    /// batch are set aside for short-lived pages, unless that would leave no general clusters.
+    pub fn arrange(
+        &self,
+        mut free: Vec<cluster::Pointer>,
+        short_lived: bool,
+    ) -> (Vec<cluster::Pointer>, Vec<cluster::Pointer>) {
+        // Sort the clusters, so contiguous clusters are next to each other.
+        free.sort();
+
+        let mut metadata = Vec::new();
+        if short_lived {
+            // Find the erase block with the most free clusters. As the clusters are sorted, the
+            // clusters of an erase block are consecutive.
+            let mut best = (0, 0);
+            let mut start = 0;
+            for i in 1..free.len() + 1 {
+                if i == free.len() || self.erase_block(free[i]) != self.erase_block(free[start]) {
+                    // The group of erase block starting at `start` ended.
+                    if i - start > best.1 - best.0 {
+                        best = (start, i);
+                    }
+                    start = i;
+                }
+            }
+
+            // Set aside the clusters of the erase block, unless it would exhaust the batch.
+            if best.1 - best.0 < free.len() {
+                metadata = free.drain(best.0..best.1).collect();
+            }
+        }
+
+        // Split the rest into contiguous runs.
+        let mut runs: Vec<&[cluster::Pointer]> = Vec::new();
+        let mut start = 0;
+        for i in 1..free.len() + 1 {
+            if i == free.len() || free[i - 1].offset(1) != free[i] {
+                runs.push(&free[start..i]);
+                start = i;
+            }
+        }
+        // Place the aligned runs first, and the longest runs first within that. The sort is
+        // stable, so equal runs keep their ascending order.
+        runs.sort_by_key(|run| (!self.is_aligned(run[0]), !0 - run.len()));
+
+        (runs.concat(), metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clusters(from: &[u64]) -> Vec<cluster::Pointer> {
+        from.iter().map(|&x| cluster::Pointer::new(x).unwrap()).collect()
+    }
+
+    #[test]
+    fn from_sizes() {
+        let geometry = Geometry::from_sizes(4096, 1 << 20);
+        assert_eq!(geometry.io_clusters, 8);
+        assert_eq!(geometry.erase_block_clusters, 2048);
+
+        // Rotating disks usually have no meaningful larger units.
+        let geometry = Geometry::from_sizes(disk::SECTOR_SIZE, 0);
+        assert_eq!(geometry.io_clusters, 1);
+        assert_eq!(geometry.erase_block_clusters, 1);
+    }
+
+    #[test]
+    fn aligned_runs_first() {
+        let geometry = Geometry::from_sizes(4 * disk::SECTOR_SIZE, 0);
+        let (data, metadata) = geometry.arrange(clusters(&[3, 9, 8, 10, 1, 2, 5, 6]), false);
+
+        assert_eq!(data, clusters(&[8, 9, 10, 1, 2, 3, 5, 6]));
+        assert!(metadata.is_empty());
+    }
+
+    #[test]
+    fn group_short_lived() {
+        let geometry = Geometry::from_sizes(disk::SECTOR_SIZE, 4 * disk::SECTOR_SIZE);
+        let (data, metadata) = geometry.arrange(clusters(&[1, 4, 5, 7, 9, 13]), true);
+
+        assert_eq!(metadata, clusters(&[4, 5, 7]));
+        assert_eq!(data, clusters(&[1, 9, 13]));
+
+        // All the clusters are in the same erase block.
+        let (data, metadata) = geometry.arrange(clusters(&[4, 5]), true);
+        assert_eq!(data, clusters(&[4, 5]));
+        assert!(metadata.is_empty());
+    }
+}
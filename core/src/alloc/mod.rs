@@ -9,17 +9,35 @@
 //! # Cluster allocator
 //!
 //! The allocator is a basic unrolled list of clusters.
+//!
+//! # Device geometry
+//!
+//! Free clusters are arranged according to the geometry of the device (see `geometry`), such that
+//! allocations tend to be aligned to the preferred I/O size, and short-lived pages are grouped in
+//! the same erase block.
+//!
+//! # Reclamation
+//!
+//! Dead clusters are not reclaimed inline. Instead, they're queued and reclaimed in batches by a
+//! background task (see `reclaim`), such that freeing large amounts of space (e.g. deleting a
+//! snapshot) doesn't cause latency spikes.
 
 mod dedup;
+mod geometry;
 pub mod page;
+pub mod reclaim;
 pub mod state_block;
 
+pub use self::geometry::Lifetime;
+
 use crossbeam::sync::SegQueue;
 use futures::{future, Future};
 use std::mem;
-use std::sync::atomic;
+use std::sync::{atomic, Arc};
+use budget::{self, Budget};
 use disk::{self, cluster, Disk};
-use {little_endian, lz4_compress, thread_object, Error};
+use fs::extent::{self, Extent};
+use {integrity, little_endian, lz4_compress, thread_object, Error};
 
 /// The atomic ordering used in the allocator.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
@@ -37,6 +55,8 @@ struct Options {
     state_block: state_block::Options,
     /// The options from the disk header.
     disk_header: disk::header::Options,
+    /// The memory budget (in bytes) of the system.
+    memory_budget: usize,
 
     // In the future, allocator specific options may be added here.
 }
@@ -85,11 +105,37 @@ pub struct Allocator<D> {
     /// (i.e. the pages cannot compress to the cluster size or less), a new cluster will be
     /// allocated.
     last_cluster: thread_object::Object<Option<ClusterState>>,
+    /// The last allocated cluster for short-lived pages for this thread.
+    ///
+    /// This serves the same purpose as `last_cluster`, but for pages allocated with
+    /// `Lifetime::Short`. Keeping it separate ensures that short-lived and long-lived pages never
+    /// share a cluster.
+    last_short_lived_cluster: thread_object::Object<Option<ClusterState>>,
+    /// The free-cache of clusters for short-lived pages.
+    ///
+    /// The clusters in this cache belong to the same erase block (as arranged by
+    /// `geometry::Geometry::arrange()`), such that the erase block can be reclaimed by the device
+    /// without moving long-lived data, once the pages die.
+    short_lived_free: conc::sync::Treiber<cluster::Pointer>,
+    /// The geometry of the underlying device.
+    geometry: geometry::Geometry,
     /// The deduplication table.
     ///
     /// This table allows the allocator for searching for candidates to use instead of allocating a
     /// new cluster. In particular, it searches for duplicates of the allocated page.
     dedup_table: dedup::Table,
+    /// The reclamation queue.
+    ///
+    /// This contains the dead clusters, which are yet to be trimmed and pushed to the freelist.
+    reclaim_queue: reclaim::Queue,
+    /// The memory budget of the system.
+    ///
+    /// This is shared with the cache, and charged for the memory held by the system.
+    budget: Arc<Budget>,
+    /// The reporter of integrity events.
+    ///
+    /// This is shared with the upper layers, which report the failures they detect through it.
+    integrity: Arc<integrity::Reporter>,
 }
 
 impl<D: Disk> Allocator<D> {
@@ -98,8 +144,14 @@ impl<D: Disk> Allocator<D> {
     /// This future creates a future, which loads the state page and other things from a the disk
     /// `disk`. If it fails, the future will return an error.
     pub fn open(disk: D) -> future!(Allocator<D>) {
+        // Query the geometry of the device before it is wrapped.
+        let geometry = geometry::Geometry::new(&disk);
+        // Set up the memory budget. The table of the deduplicator has a fixed size, so we charge
+        // it once and for all.
+        let budget = Arc::new(Budget::default());
+        budget.charge(budget::Consumer::DedupTable, mem::size_of::<dedup::Table>());
         // Initialize the disk and cache.
-        let cache = disk::open(disk);
+        let cache = disk::open(disk, budget.clone());
         // Read the state block.
         cache.read(0).map(|state_block| {
             // Parse the state block.
@@ -113,7 +165,13 @@ impl<D: Disk> Allocator<D> {
                 options: options,
                 free: SegQueue::new(),
                 last_cluster: thread_object::Object::default(),
+                last_short_lived_cluster: thread_object::Object::default(),
+                short_lived_free: SegQueue::new(),
+                geometry: geometry,
                 dedup_table: dedup::Table::default(),
+                reclaim_queue: reclaim::Queue::default(),
+                budget: budget,
+                integrity: Arc::default(),
             }
         })
     }
@@ -128,8 +186,13 @@ impl<D: Disk> Allocator<D> {
     pub fn init(disk: D, options: Options) -> future!(Allocator<D>) {
         unimplemented!();
 
+        // Query the geometry of the device before it is wrapped.
+        let geometry = geometry::Geometry::new(&disk);
+        // Set up the memory budget (see `Allocator::open()`).
+        let budget = Arc::new(Budget::new(options.memory_budget));
+        budget.charge(budget::Consumer::DedupTable, mem::size_of::<dedup::Table>());
         // Initialize the disk (below the allocator stack).
-        disk::init(disk, options.disk_header).and_then(|cache| {
+        disk::init(disk, options.disk_header, budget.clone()).and_then(|cache| {
             // Write the state block to the start of the disk.
             cache.write(0, options.state_block.encode()).map(|_| cache)
         }).map(|cache| Allocator {
@@ -138,7 +201,13 @@ impl<D: Disk> Allocator<D> {
             options: options.state_block,
             free: SegQueue::new(),
             last_cluster: thread_object::Object::default(),
+            last_short_lived_cluster: thread_object::Object::default(),
+            short_lived_free: SegQueue::new(),
+            geometry: geometry,
             dedup_table: dedup::Table::default(),
+            reclaim_queue: reclaim::Queue::default(),
+            budget: budget,
+            integrity: Arc::default(),
         })
     }
 
@@ -162,9 +231,10 @@ impl<D: Disk> Allocator<D> {
         buf: Box<disk::SectorBuf>,
         last_cluster: &mut Option<ClusterState>,
         cksum: u32,
+        lifetime: Lifetime,
     ) -> future!(page::Pointer) {
         // Pop the cluster from the freelist, then attempt to compress the data.
-        self.freelist_pop().and_then(|cluster| if let Some(compressed) = self.compress(buf) {
+        self.freelist_pop(lifetime).and_then(|cluster| if let Some(compressed) = self.compress(buf) {
             // We were able to compress the page to fit into the cluster. At first, compressing the
             // first page seems unnecessary as it is guaranteed to fit in without compression, but
             // it has a purpose: namely that it allows us to extend the cluster. Enabling
@@ -213,7 +283,8 @@ impl<D: Disk> Allocator<D> {
     /// Allocate a page eagerly and without deduplication.
     ///
     /// This allocates buffer `buf` with checksum (as calculated by `self.checksum()`) `cksum`, and
-    /// returns the page pointer wrapped in a future.
+    /// returns the page pointer wrapped in a future. `lifetime` determines which clusters the page
+    /// is placed in.
     ///
     /// This **does not** update the deduplication table, nor does it try to look for duplicates.
     /// Futhermore, some of the logic acts eagerly, and thus it ought to be wrapped in
@@ -222,11 +293,12 @@ impl<D: Disk> Allocator<D> {
         &self,
         buf: Box<disk::SectorBuf>,
         cksum: u32,
+        lifetime: Lifetime,
     ) -> future!(page::Pointer) {
         // Handle the case where compression is disabled.
         if self.options.compression_algorithm == state_block::CompressionAlgorithm::Identity {
             // Pop a cluster from the freelist.
-            return self.freelist_pop()
+            return self.freelist_pop(lifetime)
                 // Write the cluster with the raw, uncompressed data.
                 .and_then(|cluster| self.cache.write(cluster, buf).map(|_| cluster))
                 .map(|cluster| page::Pointer {
@@ -239,7 +311,13 @@ impl<D: Disk> Allocator<D> {
         // If you have followed this path, compression is enabled (we won't use `else` in order to
         // flatten the code).
 
-        self.last_cluster.with(|last_cluster| {
+        // Short-lived and long-lived pages are packed into separate clusters.
+        let last_cluster = match lifetime {
+            Lifetime::Long => &self.last_cluster,
+            Lifetime::Short => &self.last_short_lived_cluster,
+        };
+
+        last_cluster.with(|last_cluster| {
             if let Some(state) = last_cluster {
                 // We have earlier allocated a cluster, meaning that we can potentially append
                 // more pages into the cluster.
@@ -265,7 +343,7 @@ impl<D: Disk> Allocator<D> {
             // We were unable to extend the last allocated cluster, either because there is no
             // last allocated cluster, or because the cluster could not contain the page. We'll
             // allocate a new cluster to contain our page.
-            self.alloc_in_new_cluster(buf, last_cluster)
+            self.alloc_in_new_cluster(buf, last_cluster, cksum, lifetime)
         })
     }
 
@@ -276,7 +354,21 @@ impl<D: Disk> Allocator<D> {
     ///
     /// The algorithm works greedily by fitting as many pages as possible into the most recently
     /// used cluster.
+    ///
+    /// The page is assumed to be long-lived. See `alloc_with_lifetime` for short-lived pages.
     pub fn alloc(&mut self, buf: Box<disk::SectorBuf>) -> future!(page::Pointer) {
+        self.alloc_with_lifetime(buf, Lifetime::Long)
+    }
+
+    /// Allocate a page with some expected lifetime.
+    ///
+    /// This acts like `alloc`, but places the page according to `lifetime`, such that pages with
+    /// similar lifetimes end up close to each other on the device.
+    pub fn alloc_with_lifetime(
+        &mut self,
+        buf: Box<disk::SectorBuf>,
+        lifetime: Lifetime,
+    ) -> future!(page::Pointer) {
         // TODO: The variables are named things like `ptr`, which kinda contradicts the style of
         //       the rest of the code.
 
@@ -296,7 +388,7 @@ impl<D: Disk> Allocator<D> {
         // other), we use a lazy evaluated future.
         future::lazy(|| {
             // Do the core of the allocation.
-            self.alloc_eager(buf, cksum)
+            self.alloc_eager(buf, cksum, lifetime)
         }).map(|page| {
             // Insert the page pointer into the deduplication table to allow future use as
             // duplicate.
@@ -307,6 +399,115 @@ impl<D: Disk> Allocator<D> {
         })
     }
 
+    /// Allocate a run of clusters as extents.
+    ///
+    /// This allocates a cluster for every buffer in `bufs` and writes the buffers into them,
+    /// uncompressed. The returned extents cover the buffers in order, so they describe the run
+    /// when inserted at consecutive file-relative indexes.
+    ///
+    /// The free clusters are handed out in contiguous runs (see `geometry`), so this is typically
+    /// a single extent (or a handful, if the run is longer than `MAX_EXTENT_LEN`), but the run is
+    /// split wherever the popped clusters are not contiguous.
+    ///
+    /// Unlike `alloc()`, this neither deduplicates nor compresses, as extents can only describe
+    /// uncompressed clusters.
+    pub fn alloc_extents(&mut self, bufs: Vec<Box<disk::SectorBuf>>) -> future!(Vec<Extent>) {
+        debug!(self, "allocating extents"; "clusters" => bufs.len());
+
+        // Pop a cluster for every buffer.
+        self.pop_extents(bufs.len()).and_then(move |(clusters, mut extents)| {
+            // The checksum of an extent covers its concatenated content.
+            let mut rest = &bufs[..];
+            for extent in &mut extents {
+                let (bufs, next) = rest.split_at(extent.len as usize);
+                rest = next;
+
+                let content = bufs.iter().flat_map(|buf| buf.iter().cloned()).collect::<Vec<_>>();
+                extent.checksum = self.cache.disk_header().hash(&content) as u32;
+            }
+
+            // Write the clusters.
+            future::join_all(clusters.into_iter().zip(bufs).map(|(cluster, buf)| {
+                self.cache.write(cluster, buf)
+            }).collect::<Vec<_>>()).map(|_| extents)
+        })
+    }
+
+    /// Pop a run of clusters from the freelist, grouped into extents.
+    ///
+    /// This pops `clusters` clusters, and groups them into extents wherever they are contiguous.
+    /// The clusters and the extents (covering them in order) are returned, wrapped in a future.
+    /// The checksums of the extents are left zero.
+    fn pop_extents(&mut self, clusters: usize) -> future!((Vec<cluster::Pointer>, Vec<Extent>)) {
+        // The futures are polled in order, so the clusters come out in the order they are arranged
+        // in the free-cache.
+        future::join_all((0..clusters).map(|_| {
+            self.freelist_pop(Lifetime::Long)
+        }).collect::<Vec<_>>()).map(|clusters| {
+            // Group the clusters into extents.
+            let mut extents: Vec<Extent> = Vec::new();
+            for &cluster in &clusters {
+                if let Some(extent) = extents.last_mut() {
+                    if extent.start.offset(extent.len as u64) == cluster
+                        && extent.len < extent::MAX_EXTENT_LEN {
+                        // The cluster follows the last extent, so we extend it.
+                        extent.len += 1;
+                        continue;
+                    }
+                }
+
+                // The cluster starts a new extent.
+                extents.push(Extent {
+                    start: cluster,
+                    len: 1,
+                    checksum: 0,
+                    unwritten: false,
+                });
+            }
+
+            (clusters, extents)
+        })
+    }
+
+    /// Reserve a run of clusters as unwritten extents.
+    ///
+    /// This allocates `clusters` clusters like `alloc_extents()` does, but writes nothing to them.
+    /// The returned extents are unwritten (see `fs::extent`), and are written through
+    /// `write_extent()`.
+    pub fn reserve_extents(&mut self, clusters: usize) -> future!(Vec<Extent>) {
+        debug!(self, "reserving extents"; "clusters" => clusters);
+
+        self.pop_extents(clusters).map(|(_, extents)| {
+            extents.into_iter().map(|extent| Extent {
+                unwritten: true,
+                .. extent
+            }).collect()
+        })
+    }
+
+    /// Write an unwritten extent.
+    ///
+    /// This writes `bufs` (which must hold a buffer for every cluster of `extent`) into the
+    /// reserved clusters of `extent`, and returns the extent as written, with its checksum,
+    /// wrapped in a future.
+    pub fn write_extent(&self, extent: Extent, bufs: Vec<Box<disk::SectorBuf>>) -> future!(Extent) {
+        trace!(self, "writing extent"; "start" => extent.start, "length" => extent.len);
+        debug_assert!(extent.unwritten, "Writing the written extent {:?}.", extent);
+        debug_assert_eq!(bufs.len(), extent.len as usize);
+
+        // The checksum covers the concatenated content.
+        let content = bufs.iter().flat_map(|buf| buf.iter().cloned()).collect::<Vec<_>>();
+        let extent = Extent {
+            checksum: self.cache.disk_header().hash(&content) as u32,
+            unwritten: false,
+            .. extent
+        };
+
+        future::join_all(bufs.into_iter().enumerate().map(|(offset, buf)| {
+            self.cache.write(extent.cluster(offset as u32), buf)
+        }).collect::<Vec<_>>()).map(move |_| extent)
+    }
+
     /// Read/dereference a page.
     ///
     /// This reads page `page` and returns the content, wrapped in a future.
@@ -345,7 +546,41 @@ impl<D: Disk> Allocator<D> {
             } else {
                 Ok(buf)
             }
-        })
+        }).then(move |res| self.integrity.check_read(res, page.cluster))
+    }
+
+    /// Read an extent.
+    ///
+    /// This reads the clusters of `extent` and returns their concatenated content, wrapped in a
+    /// future. The content is checked against the checksum of the extent.
+    pub fn read_extent(&self, extent: Extent) -> future!(Vec<u8>) {
+        trace!(self, "reading extent"; "start" => extent.start, "length" => extent.len);
+
+        // Unwritten extents hold no data, and read as zeros.
+        if extent.unwritten {
+            return future::Either::A(future::ok(vec![0; extent.len as usize * disk::SECTOR_SIZE]));
+        }
+
+        // Read every cluster of the extent.
+        future::Either::B(future::join_all((0..extent.len).map(|offset| {
+            self.cache.read_then(extent.cluster(offset), |cluster| Ok(cluster))
+        }).collect::<Vec<_>>()).and_then(move |clusters| {
+            // Concatenate the clusters, as the checksum covers the whole extent.
+            let mut buf = Vec::with_capacity(extent.len as usize * disk::SECTOR_SIZE as usize);
+            for cluster in &clusters {
+                buf.extend_from_slice(&cluster[..]);
+            }
+
+            // Check the data against the stored checksum.
+            let cksum = self.cache.disk_header().hash(&buf) as u32;
+            if cksum != extent.checksum {
+                // The checksums mismatched, thrown an error.
+                Err(err!(Corruption, "mismatching checksums in extent {:?} - expected {:x}, found \
+                         {:x}", extent, extent.checksum, cksum))
+            } else {
+                Ok(buf)
+            }
+        }).then(move |res| self.integrity.check_read(res, extent.start)))
     }
 
     /// Calculate the checksum of some buffer, based on the user choice.
@@ -433,14 +668,25 @@ impl<D: Disk> Allocator<D> {
 
     /// Pop from the freelist.
     ///
-    /// This returns a future, which wraps a cluster pointer popped from the freelist.
-    fn freelist_pop(&mut self) -> future!(page::Pointer) {
+    /// This returns a future, which wraps a cluster pointer popped from the freelist. If
+    /// `lifetime` is `Lifetime::Short`, the clusters set aside for short-lived pages are preferred.
+    fn freelist_pop(&mut self, lifetime: Lifetime) -> future!(page::Pointer) {
         // In order to avoid eager evaluation (and potentially prematurely exhausting the
         // freelist), we use lazy popping by constructing the future when evaluated.
         future::lazy(|| {
-            trace!(self, "popping from freelist");
+            trace!(self, "popping from freelist"; "lifetime" => format!("{:?}", lifetime));
+
+            // Short-lived pages are preferably placed in the clusters set aside for them.
+            let short_lived = if lifetime == Lifetime::Short {
+                self.short_lived_free.pop()
+            } else {
+                None
+            };
 
-            if let Some(free) = self.free.pop() {
+            if let Some(free) = short_lived {
+                // We had a cluster in the short-lived free-cache.
+                free
+            } else if let Some(free) = self.free.pop() {
                 // We had a cluster in the free-cache.
                 free
             } else {
@@ -457,7 +703,12 @@ impl<D: Disk> Allocator<D> {
                         // Check that the checksum matches.
                         let found = self.checksum(buf);
                         if head.checksum != found {
-                            // Checksums do not match; throw an error.
+                            // Checksums do not match; report and throw an error.
+                            self.integrity.report(integrity::Event {
+                                kind: integrity::Kind::ChecksumMismatch,
+                                cluster: Some(head.cluster),
+                                object: None,
+                            });
                             return Err(err!(Corruption, "mismatching checksums in metacluster {:x} \
                                             - expected {:x}, found {:x}", head.cluster,
                                             head.checksum, found));
@@ -510,28 +761,144 @@ impl<D: Disk> Allocator<D> {
                 }).map(|free| {
                     // At this point, the transaction have run and the state block is flushed.
 
+                    // Arrange the clusters according to the device geometry. Short-lived pages
+                    // only get here if the clusters set aside for them ran out, in which case we
+                    // set aside some more.
+                    let (free, short_lived) = self.geometry.arrange(free,
+                                                                    lifetime == Lifetime::Short);
+
+                    // The free-caches are LIFO, so we push in reverse to make the clusters come out
+                    // in the order `arrange` gave us.
+                    for &i in short_lived.iter().rev() {
+                        self.short_lived_free.push(i);
+                    }
                     // Push every (except one) element of our temporary vector of free clusters.
-                    for i in free[1..] {
+                    for &i in free[1..].iter().rev() {
                         self.free.push(i);
                     }
 
-                    // We use the last cluster as the popped cluster.
+                    // We use the first cluster as the popped cluster.
                     free[0]
                 })
             }
         })
     }
 
+    /// Deallocate a dead cluster.
+    ///
+    /// This queues `cluster` for reclamation by the background task (see `reclaimer()`). If too
+    /// many clusters are already pending reclamation, a batch is reclaimed before the returned
+    /// future completes, bounding the amount of space which is dead, but not yet reusable.
+    pub fn dealloc(&self, cluster: cluster::Pointer) -> future!(()) {
+        trace!(self, "queuing cluster for reclamation"; "cluster" => cluster);
+
+        if self.reclaim_queue.push(cluster) {
+            // The queue is full, so we help the background task out.
+            debug!(self, "reclamation queue full; reclaiming inline");
+            future::Either::A(self.reclaim(reclaim::BATCH_SIZE).map(|_| ()))
+        } else {
+            future::Either::B(future::ok(()))
+        }
+    }
+
+    /// Reclaim a batch of dead clusters.
+    ///
+    /// This trims at most `max` of the clusters pending reclamation and pushes them to the
+    /// freelist. The number of reclaimed clusters is returned, wrapped in a future. If it is zero,
+    /// there is nothing left to reclaim.
+    ///
+    /// The background task (see `reclaimer()`) calls this whenever clusters are queued, but it can
+    /// also be called directly, e.g. to make the dead space reusable right away.
+    pub fn reclaim(&self, max: usize) -> future!(usize) {
+        // Pop lazily, so the clusters aren't taken out of the queue before the future runs.
+        future::lazy(|| {
+            let batch = self.reclaim_queue.pop_batch(max);
+            trace!(self, "reclaiming batch"; "length" => batch.len());
+
+            // Trim the clusters, as their content is dead.
+            future::join_all(batch.iter().map(|&cluster| self.cache.trim(cluster)).collect::<Vec<_>>())
+                .map(|_| batch)
+        }).map(|batch| {
+            // The clusters are now ready for reuse.
+            for &cluster in &batch {
+                self.freelist_push(cluster);
+            }
+            self.reclaim_queue.complete(batch.len() as u64);
+
+            batch.len()
+        })
+    }
+
+    /// Run the background reclamation.
+    ///
+    /// The returned future reclaims the dead clusters in batches as they are deallocated, and is
+    /// meant to be spawned on the executor driving the system. It never completes by itself (it
+    /// only stops when it fails, or is dropped), and there must only be one of it at a time.
+    pub fn reclaimer(&self) -> future!(()) {
+        info!(self, "starting background reclamation");
+
+        future::loop_fn((), move |()| {
+            self.reclaim_queue.wait().and_then(move |()| {
+                self.reclaim(reclaim::BATCH_SIZE)
+            }).map(|_| future::Loop::Continue(()))
+        })
+    }
+
+    /// Get the progress of the background reclamation.
+    pub fn reclaim_progress(&self) -> reclaim::Progress {
+        self.reclaim_queue.progress()
+    }
+
+    /// Get the memory budget of the system.
+    pub fn memory_budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    /// Get the reporter of integrity events of the system.
+    ///
+    /// Callbacks registered here receive the integrity failures detected anywhere in the stack
+    /// (see `integrity`).
+    pub fn integrity(&self) -> &Arc<integrity::Reporter> {
+        &self.integrity
+    }
+
+    /// Change the memory budget of the system.
+    ///
+    /// This sets the limit on the memory used by the system to `limit` bytes. If the system uses
+    /// more than that, the cache is shrunk immediately.
+    pub fn set_memory_budget(&self, limit: usize) {
+        info!(self, "changing memory budget"; "limit" => limit);
+
+        self.budget.set_limit(limit);
+        self.cache.relieve_pressure();
+    }
+
     /// Push to the freelist.
     ///
     /// No I/O logic happens, since pushes are buffered.
-    fn freelist_push(&mut self, cluster: cluster::Pointer) {
+    fn freelist_push(&self, cluster: cluster::Pointer) {
         trace!(self, "pushing to freelist"; "cluster" => cluster);
 
         // Push the cluster to the freelist.
         self.free.push(cluster);
     }
 
+    /// Make the allocator state and every completed write durable.
+    ///
+    /// This flushes the buffered free clusters and then the disk (see `Cached::flush()`). When the
+    /// returned future completes, the data of every allocation which had completed before this
+    /// was called is durable, and so is the allocator state, such that those clusters won't be
+    /// handed out again after a crash.
+    ///
+    /// Allocations still in progress are not covered, so callers must wait for the writes they
+    /// need to be durable before syncing.
+    pub fn sync(&self) -> future!(()) {
+        debug!(self, "syncing");
+
+        self.flush_free();
+        self.cache.flush()
+    }
+
     pub fn flush_free(&self) {
         // TODO: Important!! Remember to trim the sectors which gets dealloc'd. This can be done
         //       through `self.cache.trim`
@@ -67,6 +67,13 @@ pub struct Pointer {
     checksum: u32,
 }
 
+impl Pointer {
+    /// Get the cluster in which the page is stored.
+    pub fn cluster(&self) -> cluster::Pointer {
+        self.cluster
+    }
+}
+
 impl little_endian::Encode for Pointer {
     fn write_le(self, into: &mut [u8]) {
         // The lowest bytes are dedicated to the cluster pointer.
@@ -0,0 +1,210 @@
+//! Background reclamation of dead clusters.
+//!
+//! When a cluster dies (e.g. because the last snapshot referencing it was deleted), it must be
+//! trimmed and pushed to the freelist. Doing so inline makes operations like snapshot deletion
+//! take time proportional to the amount of space freed, causing unpredictable latency spikes.
+//!
+//! Instead, dead clusters are queued in the reclamation queue, and a background task (see
+//! `Allocator::reclaimer()`) reclaims them in batches. To avoid the queue growing without bound
+//! (e.g. if the background task can't keep up), the queue has a limit on the number of pending
+//! clusters. When it is exceeded, the thread killing the cluster must help by reclaiming a batch
+//! inline, trading latency for space.
+
+use futures::task::AtomicTask;
+use futures::{Async, Future, Poll};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicU64};
+
+use Error;
+use disk::cluster;
+
+/// The atomic ordering used in the queue.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The default maximal number of clusters pending reclamation.
+pub const DEFAULT_MAX_PENDING: u64 = 1 << 16;
+/// The number of clusters reclaimed in a batch.
+pub const BATCH_SIZE: usize = 64;
+
+/// The progress of the reclamation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Progress {
+    /// The number of clusters pending reclamation.
+    ///
+    /// This includes the clusters which are currently being reclaimed.
+    pub pending: u64,
+    /// The total number of clusters reclaimed.
+    pub reclaimed: u64,
+}
+
+/// A reclamation queue.
+///
+/// This holds the dead clusters which are not yet reclaimed.
+pub struct Queue {
+    /// The dead clusters, in the order they died.
+    queue: Mutex<VecDeque<cluster::Pointer>>,
+    /// The maximal number of pending clusters.
+    max_pending: u64,
+    /// The number of pending clusters.
+    ///
+    /// This is the number of clusters in the queue plus the number of clusters popped, but not
+    /// yet reclaimed.
+    pending: AtomicU64,
+    /// The total number of reclaimed clusters.
+    reclaimed: AtomicU64,
+    /// The task waiting for clusters to reclaim (see `wait()`).
+    task: AtomicTask,
+}
+
+impl Queue {
+    /// Create a new reclamation queue with some limit on the number of pending clusters.
+    pub fn new(max_pending: u64) -> Queue {
+        Queue {
+            queue: Mutex::new(VecDeque::new()),
+            max_pending: max_pending,
+            pending: AtomicU64::new(0),
+            reclaimed: AtomicU64::new(0),
+            task: AtomicTask::new(),
+        }
+    }
+
+    /// Queue a dead cluster for reclamation.
+    ///
+    /// This returns `true` if the limit on pending clusters is exceeded, in which case the caller
+    /// must reclaim a batch inline.
+    pub fn push(&self, cluster: cluster::Pointer) -> bool {
+        self.queue.lock().unwrap().push_back(cluster);
+        // Wake up the background task, if it is waiting.
+        self.task.notify();
+
+        self.pending.fetch_add(1, ORDERING) + 1 > self.max_pending
+    }
+
+    /// Wait for clusters to reclaim.
+    ///
+    /// The returned future completes when the queue is non-empty. Only one task may wait at a
+    /// time.
+    pub fn wait(&self) -> Wait {
+        Wait {
+            queue: self,
+        }
+    }
+
+    /// Pop a batch of at most `n` clusters to reclaim.
+    ///
+    /// The clusters are still counted as pending until `complete()` is called.
+    pub fn pop_batch(&self, n: usize) -> Vec<cluster::Pointer> {
+        let mut queue = self.queue.lock().unwrap();
+        let n = n.min(queue.len());
+
+        queue.drain(..n).collect()
+    }
+
+    /// Mark `n` popped clusters as reclaimed.
+    pub fn complete(&self, n: u64) {
+        self.pending.fetch_sub(n, ORDERING);
+        self.reclaimed.fetch_add(n, ORDERING);
+    }
+
+    /// Get the progress of the reclamation.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            pending: self.pending.load(ORDERING),
+            reclaimed: self.reclaimed.load(ORDERING),
+        }
+    }
+}
+
+/// A future waiting for clusters to reclaim.
+///
+/// This is created through `Queue::wait()`.
+pub struct Wait<'a> {
+    /// The queue to wait on.
+    queue: &'a Queue,
+}
+
+impl<'a> Future for Wait<'a> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        // Register the task before checking the queue, such that a push in between is not missed.
+        self.queue.task.register();
+
+        if self.queue.queue.lock().unwrap().is_empty() {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Queue {
+        Queue::new(DEFAULT_MAX_PENDING)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn cluster(n: u64) -> cluster::Pointer {
+        cluster::Pointer::new(n).unwrap()
+    }
+
+    #[test]
+    fn batches() {
+        let queue = Queue::default();
+        for i in 1..6 {
+            assert!(!queue.push(cluster(i)));
+        }
+
+        assert_eq!(queue.pop_batch(3), vec![cluster(1), cluster(2), cluster(3)]);
+        // Popped clusters are pending until completed.
+        assert_eq!(queue.progress(), Progress { pending: 5, reclaimed: 0 });
+        queue.complete(3);
+        assert_eq!(queue.progress(), Progress { pending: 2, reclaimed: 3 });
+
+        assert_eq!(queue.pop_batch(3), vec![cluster(4), cluster(5)]);
+        queue.complete(2);
+        assert_eq!(queue.progress(), Progress { pending: 0, reclaimed: 5 });
+        assert!(queue.pop_batch(3).is_empty());
+    }
+
+    #[test]
+    fn bound() {
+        let queue = Queue::new(2);
+        assert!(!queue.push(cluster(1)));
+        assert!(!queue.push(cluster(2)));
+        assert!(queue.push(cluster(3)));
+
+        // Reclaiming brings it back under the limit.
+        let batch = queue.pop_batch(BATCH_SIZE);
+        queue.complete(batch.len() as u64);
+        assert!(!queue.push(cluster(4)));
+    }
+
+    #[test]
+    fn wait() {
+        let queue = Arc::new(Queue::default());
+        queue.push(cluster(1));
+        queue.wait().wait().unwrap();
+        queue.pop_batch(BATCH_SIZE);
+
+        // The waiting task is woken up by the push.
+        let pusher = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                queue.push(cluster(2));
+            })
+        };
+        queue.wait().wait().unwrap();
+        assert_eq!(queue.pop_batch(BATCH_SIZE), vec![cluster(2)]);
+        pusher.join().unwrap();
+    }
+}
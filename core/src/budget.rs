@@ -0,0 +1,134 @@
+//! Memory budgeting.
+//!
+//! Every mounted filesystem has a memory budget, which bounds the memory used by its caches and
+//! buffers collectively. This makes it safe to embed TFS in memory-constrained processes, since
+//! the memory use doesn't grow with the size or activity of the filesystem.
+//!
+//! The budget is shared by a number of consumers (see `Consumer`), each of which charges the
+//! budget for the memory it holds. Charging never fails, as some consumers (e.g. in-flight
+//! buffers) cannot simply drop their memory. Instead, exceeding the budget puts it under
+//! pressure, and the consumers which can shrink (the cache) are expected to do so until the
+//! pressure is relieved.
+
+use std::sync::atomic::{self, AtomicUsize};
+
+/// The atomic ordering used for the budget.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The default memory budget in bytes.
+pub const DEFAULT_LIMIT: usize = 64 << 20;
+
+/// A consumer of memory.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Consumer {
+    /// The sector cache.
+    ///
+    /// This is the only consumer which shrinks under pressure.
+    Cache = 0,
+    /// The deduplication table.
+    DedupTable = 1,
+    /// Buffers of operations which are yet to complete.
+    InFlight = 2,
+}
+
+/// The number of consumers.
+const CONSUMERS: usize = 3;
+
+/// A memory budget.
+pub struct Budget {
+    /// The maximal number of bytes used in total.
+    limit: AtomicUsize,
+    /// The number of bytes used by each consumer.
+    ///
+    /// This is indexed by `Consumer`.
+    usage: [AtomicUsize; CONSUMERS],
+}
+
+impl Budget {
+    /// Create a new budget with some limit (in bytes).
+    pub fn new(limit: usize) -> Budget {
+        Budget {
+            limit: AtomicUsize::new(limit),
+            usage: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Get the limit of the budget.
+    pub fn limit(&self) -> usize {
+        self.limit.load(ORDERING)
+    }
+
+    /// Change the limit of the budget.
+    ///
+    /// If the new limit is lower than the current usage, the budget will be under pressure until
+    /// the consumers shrink.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, ORDERING);
+    }
+
+    /// Get the number of bytes used by some consumer.
+    pub fn usage(&self, consumer: Consumer) -> usize {
+        self.usage[consumer as usize].load(ORDERING)
+    }
+
+    /// Get the total number of bytes used.
+    pub fn total(&self) -> usize {
+        self.usage.iter().map(|x| x.load(ORDERING)).sum()
+    }
+
+    /// Get the number of bytes the usage exceeds the limit by.
+    ///
+    /// If this is non-zero, the budget is under pressure.
+    pub fn excess(&self) -> usize {
+        self.total().saturating_sub(self.limit())
+    }
+
+    /// Charge some consumer for some number of bytes.
+    ///
+    /// This returns `true` if the budget is under pressure afterwards.
+    pub fn charge(&self, consumer: Consumer, bytes: usize) -> bool {
+        self.usage[consumer as usize].fetch_add(bytes, ORDERING);
+
+        self.excess() != 0
+    }
+
+    /// Release some number of bytes previously charged by some consumer.
+    pub fn release(&self, consumer: Consumer, bytes: usize) {
+        self.usage[consumer as usize].fetch_sub(bytes, ORDERING);
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Budget {
+        Budget::new(DEFAULT_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure() {
+        let budget = Budget::new(100);
+        assert!(!budget.charge(Consumer::DedupTable, 50));
+        assert!(!budget.charge(Consumer::Cache, 50));
+        assert!(budget.charge(Consumer::InFlight, 10));
+        assert_eq!(budget.excess(), 10);
+
+        budget.release(Consumer::Cache, 10);
+        assert_eq!(budget.excess(), 0);
+        assert_eq!(budget.usage(Consumer::Cache), 40);
+        assert_eq!(budget.total(), 100);
+    }
+
+    #[test]
+    fn set_limit() {
+        let budget = Budget::new(100);
+        budget.charge(Consumer::Cache, 80);
+
+        budget.set_limit(50);
+        assert_eq!(budget.excess(), 30);
+        budget.set_limit(200);
+        assert_eq!(budget.excess(), 0);
+    }
+}
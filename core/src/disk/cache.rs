@@ -1,6 +1,10 @@
-use futures::Future;
+use futures::{future, Future};
 use atomic_hashmap::AtomicHashMap;
+use std::cmp;
+use std::ops::Range;
+use std::sync::Arc;
 use {mlcr, Error};
+use budget::{self, Budget};
 use disk::{self, vdev, Disk};
 use disk::header::DiskHeader;
 
@@ -22,15 +26,53 @@ pub struct Cached<D> {
     tracker: mlcr::ConcurrentCache,
     /// The sector-number-to-data block map.
     sectors: AtomicHashMap<disk::Sector, disk::SectorBuf>,
+    /// The memory budget of the filesystem.
+    ///
+    /// The cached sectors and the buffers of in-flight writes are charged to this. When it is
+    /// under pressure, the cache shrinks.
+    budget: Arc<Budget>,
 }
 
 impl<D: Disk> Cached<D> {
-    /// Create a cache from a backing disk.
-    fn new(disk: D) -> Cached<D> {
+    /// Create a cache from a backing disk, charging its memory to some budget.
+    fn new(disk: D, budget: Arc<Budget>) -> Cached<D> {
         Cached {
             disk: disk,
             tracker: mlcr::ConcurrentCache::new(),
             sectors: AtomicHashMap::with_capacity(INITIAL_CAPACITY),
+            budget: budget,
+        }
+    }
+
+    /// Insert a sector into the cache.
+    ///
+    /// This charges the budget, and shrinks the cache if it is under pressure.
+    fn insert(&self, sector: disk::Sector, buf: Box<disk::SectorBuf>) {
+        // If the sector was already cached, its old buffer is replaced, and so is its charge.
+        if self.sectors.insert(sector, buf).is_none()
+            && self.budget.charge(budget::Consumer::Cache, disk::SECTOR_SIZE) {
+            self.relieve_pressure();
+        }
+    }
+
+    /// Remove a sector from the cache.
+    fn remove(&self, sector: disk::Sector) {
+        if self.sectors.remove(sector).is_some() {
+            self.budget.release(budget::Consumer::Cache, disk::SECTOR_SIZE);
+        }
+    }
+
+    /// Shrink the cache until the budget is no longer under pressure.
+    ///
+    /// If the pressure is caused by other consumers, the cache might be emptied entirely.
+    pub fn relieve_pressure(&self) {
+        let excess = self.budget.excess();
+        if excess != 0 {
+            // Calculate the number of sectors to evict, rounding up.
+            let evict = (excess + disk::SECTOR_SIZE - 1) / disk::SECTOR_SIZE;
+            let cached = self.budget.usage(budget::Consumer::Cache) / disk::SECTOR_SIZE;
+
+            self.reduce(cached.saturating_sub(evict));
         }
     }
 
@@ -45,9 +87,16 @@ impl<D: Disk> Cached<D> {
         debug!(self, "writing sector"; "sector" => sector);
 
         // Then insert it into the cache.
-        self.sectors.insert(sector, buf);
+        self.insert(sector, buf);
+        // The buffer of the write is held until the write completes, so we charge it to the
+        // budget in the meantime.
+        self.budget.charge(budget::Consumer::InFlight, disk::SECTOR_SIZE);
         // Write the data to the disk.
-        self.disk.write(sector, &buf)
+        let budget = self.budget.clone();
+        self.disk.write(sector, &buf).then(move |res| {
+            budget.release(budget::Consumer::InFlight, disk::SECTOR_SIZE);
+            res
+        })
     }
 
     /// Drop a sector from the cache and trim it.
@@ -62,9 +111,27 @@ impl<D: Disk> Cached<D> {
         // Update the cache tracker.
         self.tracker.remove(sector);
         // Update the sector map.
-        self.sectors.remove(sector);
-        // Finally, trim the sector.
-        self.disk.trim(sector)
+        self.remove(sector);
+        // Finally, trim the sector, unless the disk is known not to support it, in which case there
+        // is no point in issuing the operation.
+        if self.disk.capabilities().discard {
+            future::Either::A(self.disk.trim(sector))
+        } else {
+            trace!(self, "disk doesn't support discarding; skipping trim"; "sector" => sector);
+            future::Either::B(future::ok(()))
+        }
+    }
+
+    /// Make the completed writes durable.
+    ///
+    /// The cache is write-through: A write completes when the disk has completed it, and no
+    /// writes are held back in memory. Hence, once the returned future completes, every write
+    /// which completed before this was called is durable, as it is merely a matter of flushing the
+    /// write cache of the disk (see `Disk::flush()`).
+    pub fn flush(&self) -> future!(()) {
+        debug!(self, "flushing disk");
+
+        self.disk.flush()
     }
 
     /// Read a sector.
@@ -76,7 +143,17 @@ impl<D: Disk> Cached<D> {
     /// returned.
     fn read_then<F, T>(&self, sector: disk::Sector, map: F) -> future!(T)
     where F: Fn(atomic_hash_map::Value<disk::SectorBuf>) -> future!(T) {
-        debug!(self, "reading sector"; "sector" => sector);
+        self.read_then_with_hint(sector, disk::Hint::Normal, map)
+    }
+
+    /// Read a sector with some access pattern hint.
+    ///
+    /// This acts like `read_then()`, but adjusts the caching to `hint` (see `disk::Hint`): Cache
+    /// misses read `hint.read_ahead()` sectors ahead, and under `Hint::DontNeed`, the sector is
+    /// not kept in the cache.
+    fn read_then_with_hint<F, T>(&self, sector: disk::Sector, hint: disk::Hint, map: F) -> future!(T)
+    where F: Fn(atomic_hash_map::Value<disk::SectorBuf>) -> future!(T) {
+        debug!(self, "reading sector"; "sector" => sector, "hint" => format!("{:?}", hint));
 
         // Check if the sector is already available in the cache.
         if let Some(buf) = self.sectors.get(sector) {
@@ -86,22 +163,81 @@ impl<D: Disk> Cached<D> {
             // Touch the sector.
             self.tracker.touch(sector);
 
-            map(buf)
+            future::Either::A(map(buf))
         } else {
             trace!(self, "cache miss; reading from disk"; "sector" => sector);
 
             // Insert the sector into the cache tracker.
             self.tracker.touch(sector);
 
+            // Read the following sectors along with this one, if the hint asks for it.
+            let end = cmp::min(sector + 1 + hint.read_ahead(), self.disk.number_of_sectors());
+            let read_ahead = self.prefetch(sector + 1..end);
+
             // Fetch the data from the disk.
-            self.disk.read(sector).map(|buf| {
+            future::Either::B(self.disk.read(sector).join(read_ahead).map(|(buf, ())| {
                 // Insert the read data into the hash table.
-                self.sectors.get_mut_or(sector, buf)
-            }).and_then(map)
+                self.insert(sector, buf);
+                let buf = self.sectors.get(sector);
+
+                // The value outlives its removal from the map, so we can drop the sector from the
+                // cache right away, if it isn't worth caching.
+                if !hint.caches() {
+                    self.tracker.remove(sector);
+                    self.remove(sector);
+                }
+
+                buf
+            }).and_then(map))
             // TODO: If the above failed, try to recover the data through the vdev redundancy.
         }
     }
 
+    /// Fetch some sectors into the cache.
+    ///
+    /// The sectors which are already cached are skipped. As this is merely an optimization, I/O
+    /// errors are ignored (the sectors are simply not cached).
+    fn prefetch(&self, sectors: Range<disk::Sector>) -> future!(()) {
+        trace!(self, "prefetching sectors"; "start" => sectors.start, "end" => sectors.end);
+
+        future::join_all(sectors.filter(|&sector| self.sectors.get(sector).is_none()).map(|sector| {
+            self.disk.read(sector).then(move |res| {
+                if let Ok(buf) = res {
+                    self.tracker.touch(sector);
+                    self.insert(sector, buf);
+                }
+
+                Ok(())
+            })
+        }).collect::<Vec<_>>()).map(|_| ())
+    }
+
+    /// Advise the cache of the access pattern of some sectors.
+    ///
+    /// Under `Hint::WillNeed`, the sectors are fetched into the cache, and under `Hint::DontNeed`,
+    /// they are dropped from it. The hint is forwarded to the disk (see `Disk::advise()`) in any
+    /// case. The hints affecting reads (read-ahead and whether to cache) are given per read, with
+    /// `read_then_with_hint()`.
+    pub fn advise(&self, sectors: Range<disk::Sector>, hint: disk::Hint) -> future!(()) {
+        debug!(self, "advising cache"; "start" => sectors.start, "end" => sectors.end,
+               "hint" => format!("{:?}", hint));
+
+        self.disk.advise(sectors.clone(), hint);
+
+        match hint {
+            disk::Hint::WillNeed => future::Either::A(self.prefetch(sectors)),
+            disk::Hint::DontNeed => {
+                for sector in sectors {
+                    self.tracker.remove(sector);
+                    self.remove(sector);
+                }
+
+                future::Either::B(future::ok(()))
+            },
+            _ => future::Either::B(future::ok(())),
+        }
+    }
+
     /// Reduce the cache.
     ///
     /// This reduces the cache to exactly `to` blocks.
@@ -114,7 +250,7 @@ impl<D: Disk> Cached<D> {
         // Remove all the coldest sectors.
         for i in tracker.trim(to) {
             // Remove that piece of shit.
-            self.sectors.remove(i);
+            self.remove(i);
         }
     }
 }
@@ -0,0 +1,186 @@
+//! Device capabilities.
+//!
+//! Different storage devices have very different properties: Flash devices have large internal
+//! units and support discarding, rotating disks have expensive seeks, some devices have volatile
+//! write caches needing explicit flushes, and so on. This module describes these properties, such
+//! that the upper layers can adapt batching, alignment, and read-ahead, instead of guessing.
+
+use std::{fs, io, path};
+
+use disk;
+
+/// The flush semantics of a device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flush {
+    /// The device has no volatile write cache.
+    ///
+    /// Every completed write is durable, so flushing is a no-op.
+    WriteThrough,
+    /// The device has a volatile write cache.
+    ///
+    /// Completed writes are not durable until the cache is flushed.
+    WriteBack,
+    /// The device has a volatile write cache, but supports FUA (forced unit access).
+    ///
+    /// Completed writes are not durable until the cache is flushed, but individual writes can be
+    /// made durable without flushing the whole cache.
+    WriteBackFua,
+}
+
+/// The capabilities of some device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    /// The logical sector size in bytes.
+    ///
+    /// This is the smallest unit the device can address.
+    pub logical_sector_size: usize,
+    /// The physical sector size in bytes.
+    ///
+    /// This is the smallest unit the device can write without a read-modify-write cycle.
+    pub physical_sector_size: usize,
+    /// The preferred I/O size in bytes.
+    ///
+    /// Writes which are a multiple of and aligned to this size are the most efficient.
+    pub io_size: usize,
+    /// The erase-block size in bytes.
+    ///
+    /// This is the unit in which flash devices reclaim space internally. Devices without such a
+    /// notion use zero.
+    pub erase_block_size: usize,
+    /// Does the device support discarding (trimming) sectors?
+    ///
+    /// Trims are only skipped when this is `false`, so it must only be `false` if the device is
+    /// known not to support discarding.
+    pub discard: bool,
+    /// The flush semantics of the device.
+    pub flush: Flush,
+    /// Is the device rotational?
+    ///
+    /// Rotational devices have expensive seeks, making read-ahead and sequential layout more
+    /// important.
+    pub rotational: bool,
+}
+
+impl Default for Capabilities {
+    /// The conservative defaults.
+    ///
+    /// This assumes the least about the device: It is assumed to be a rotational disk operating in
+    /// the logical sector size with a volatile write cache. Discarding is assumed to be supported,
+    /// as trims are harmless on devices which ignore them, while skipping them on devices which
+    /// don't hurts their performance.
+    fn default() -> Capabilities {
+        Capabilities {
+            logical_sector_size: disk::SECTOR_SIZE,
+            physical_sector_size: disk::SECTOR_SIZE,
+            io_size: disk::SECTOR_SIZE,
+            erase_block_size: 0,
+            discard: true,
+            flush: Flush::WriteBack,
+            rotational: true,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Query the capabilities of a block device from the OS.
+    ///
+    /// `queue` is the path to the queue attributes of the block device in sysfs, e.g.
+    /// `/sys/block/sda/queue`. Attributes which are missing are replaced by the conservative
+    /// defaults.
+    #[cfg(target_os = "linux")]
+    pub fn from_sysfs<P: AsRef<path::Path>>(queue: P) -> io::Result<Capabilities> {
+        let queue = queue.as_ref();
+        // Read some attribute of the queue.
+        let read = |attr| -> io::Result<Option<String>> {
+            match fs::read_to_string(queue.join(attr)) {
+                Ok(x) => Ok(Some(x.trim().to_owned())),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        };
+        // Read some numeric attribute of the queue, defaulting to zero.
+        let read_num = |attr| -> io::Result<usize> {
+            Ok(read(attr)?.and_then(|x| x.parse().ok()).unwrap_or(0))
+        };
+
+        let default = Capabilities::default();
+        // Zero is used by the kernel when a value is unknown, so we replace it by the default.
+        let or = |x, default| if x == 0 { default } else { x };
+
+        let logical_sector_size = or(read_num("logical_block_size")?, default.logical_sector_size);
+        let physical_sector_size = or(read_num("physical_block_size")?, logical_sector_size);
+        // Prefer the optimal I/O size, and fall back to the minimal.
+        let io_size = or(read_num("optimal_io_size")?, or(read_num("minimum_io_size")?,
+                                                          physical_sector_size));
+
+        Ok(Capabilities {
+            logical_sector_size: logical_sector_size,
+            physical_sector_size: physical_sector_size,
+            io_size: io_size,
+            // The kernel doesn't expose the erase-block size, but the discard granularity is the
+            // closest approximation.
+            erase_block_size: read_num("discard_granularity")?,
+            discard: read("discard_max_bytes")?.map_or(default.discard, |x| x != "0"),
+            flush: match (read("write_cache")?, read_num("fua")? != 0) {
+                (Some(ref x), _) if x == "write through" => Flush::WriteThrough,
+                (_, true) => Flush::WriteBackFua,
+                (_, false) => Flush::WriteBack,
+            },
+            rotational: read("rotational")?.map_or(default.rotational, |x| x != "0"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn from_sysfs() {
+        let dir = env::temp_dir().join("tfs-capabilities-test");
+        fs::create_dir_all(&dir).unwrap();
+        for &(attr, val) in &[("logical_block_size", "512"), ("physical_block_size", "4096"),
+                              ("minimum_io_size", "4096"), ("optimal_io_size", "0"),
+                              ("discard_granularity", "524288"), ("discard_max_bytes", "2147450880"),
+                              ("write_cache", "write back"), ("fua", "1"), ("rotational", "0")] {
+            fs::write(dir.join(attr), val).unwrap();
+        }
+
+        assert_eq!(Capabilities::from_sysfs(&dir).unwrap(), Capabilities {
+            logical_sector_size: 512,
+            physical_sector_size: 4096,
+            io_size: 4096,
+            erase_block_size: 524288,
+            discard: true,
+            flush: Flush::WriteBackFua,
+            rotational: false,
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn from_empty_sysfs() {
+        let dir = env::temp_dir().join("tfs-capabilities-empty-test");
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(Capabilities::from_sysfs(&dir).unwrap(), Capabilities::default());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn from_sysfs_without_discard() {
+        let dir = env::temp_dir().join("tfs-capabilities-discard-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("discard_max_bytes"), "0").unwrap();
+
+        assert!(!Capabilities::from_sysfs(&dir).unwrap().discard);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
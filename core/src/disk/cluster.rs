@@ -7,8 +7,32 @@ pub const POINTER_SIZE: usize = 8;
 
 /// A pointer to some cluster.
 // TODO: Use `NonZero`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Pointer(u64);
 
+impl Pointer {
+    /// Create a cluster pointer from the cluster number.
+    ///
+    /// If `cluster` is zero (i.e. the null pointer), `None` is returned.
+    pub fn new(cluster: u64) -> Option<Pointer> {
+        if cluster == 0 {
+            None
+        } else {
+            Some(Pointer(cluster))
+        }
+    }
+
+    /// Get the cluster number.
+    pub fn number(self) -> u64 {
+        self.0
+    }
+
+    /// Get the pointer to the cluster `n` clusters after this one.
+    pub fn offset(self, n: u64) -> Pointer {
+        Pointer(self.0 + n)
+    }
+}
+
 impl little_endian::Encode for Pointer {
     fn write_le(self, into: &mut [u8]) {
         if let Some(ptr) = self {
@@ -0,0 +1,99 @@
+//! Access pattern hints.
+//!
+//! Applications often know how they are going to access a file (e.g. a media player streaming it
+//! sequentially, or a database doing random lookups), which the cache cannot guess until it is
+//! too late. Hints (like `posix_fadvise`) let them say so, such that the cache can adjust
+//! read-ahead, and whether the sectors are worth caching at all.
+//!
+//! Hints are advisory: They never change the result of an operation, only its performance.
+
+/// The number of sectors read ahead under `Hint::Sequential`.
+pub const SEQUENTIAL_READ_AHEAD: usize = 64;
+
+/// An access pattern hint.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hint {
+    /// No particular access pattern.
+    ///
+    /// This is the default.
+    Normal,
+    /// The data is accessed sequentially.
+    ///
+    /// The cache reads ahead aggressively.
+    Sequential,
+    /// The data is accessed in random order.
+    ///
+    /// Reading ahead is pointless, so the cache doesn't.
+    Random,
+    /// The data will be accessed in the near future.
+    ///
+    /// The cache fetches it eagerly.
+    WillNeed,
+    /// The data will not be accessed in the near future.
+    ///
+    /// The cache drops it, and doesn't cache it when it is read.
+    DontNeed,
+}
+
+impl Default for Hint {
+    fn default() -> Hint {
+        Hint::Normal
+    }
+}
+
+impl Hint {
+    /// Get the number of sectors to read ahead of a cache miss.
+    pub fn read_ahead(self) -> usize {
+        match self {
+            Hint::Sequential => SEQUENTIAL_READ_AHEAD,
+            _ => 0,
+        }
+    }
+
+    /// Should sectors read under this hint be cached?
+    pub fn caches(self) -> bool {
+        self != Hint::DontNeed
+    }
+
+    /// Get the corresponding `posix_fadvise` advice.
+    ///
+    /// This is the value of the `POSIX_FADV_*` constant, for disks backed by a file to forward the
+    /// hint to the OS (see `Disk::advise()`).
+    pub fn posix_advice(self) -> i32 {
+        match self {
+            Hint::Normal => 0,
+            Hint::Random => 1,
+            Hint::Sequential => 2,
+            Hint::WillNeed => 3,
+            Hint::DontNeed => 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_ahead() {
+        assert_eq!(Hint::default().read_ahead(), 0);
+        assert_eq!(Hint::Random.read_ahead(), 0);
+        assert_eq!(Hint::Sequential.read_ahead(), SEQUENTIAL_READ_AHEAD);
+    }
+
+    #[test]
+    fn caches() {
+        assert!(Hint::Normal.caches());
+        assert!(Hint::WillNeed.caches());
+        assert!(!Hint::DontNeed.caches());
+    }
+
+    #[test]
+    fn posix_advice() {
+        assert_eq!(Hint::Normal.posix_advice(), 0);
+        assert_eq!(Hint::Random.posix_advice(), 1);
+        assert_eq!(Hint::Sequential.posix_advice(), 2);
+        assert_eq!(Hint::WillNeed.posix_advice(), 3);
+        assert_eq!(Hint::DontNeed.posix_advice(), 4);
+    }
+}
@@ -1,11 +1,21 @@
 mod cache;
 mod crypto;
 mod vdev;
+pub mod capabilities;
 pub mod cluster;
 pub mod header;
+pub mod hint;
+pub mod qos;
+pub mod resilver;
+
+pub use self::capabilities::Capabilities;
+pub use self::hint::Hint;
 
 use futures::Future;
+use std::ops::Range;
+use std::sync::Arc;
 use {slog, Error};
+use budget::Budget;
 
 /// The logical sector size.
 pub const SECTOR_SIZE: usize = 512;
@@ -22,17 +32,22 @@ pub type TfsDisk<D> = cache::Cached<vdev::Driver<D>>;
 
 /// Load the TFS disk.
 ///
-/// This does not initialize or create the structure. It will merely load the disk.
-pub fn open<D: Disk>(disk: D, password: &[u8]) -> future!(TfsDisk<D>) {
-    vdev::Driver::open(disk).cached()
+/// This does not initialize or create the structure. It will merely load the disk. The memory of
+/// the cache is charged to `budget`.
+pub fn open<D: Disk>(disk: D, password: &[u8], budget: Arc<Budget>) -> future!(TfsDisk<D>) {
+    vdev::Driver::open(disk).map(|driver| driver.cached(budget))
 }
 
 /// Initialize/create the TFS disk.
 ///
 /// This creates the structure (given some options given in `options`) of the disk, and effectively
-/// initializes a system.
-pub fn init<D: Disk>(disk: D, options: header::Options) -> future!(TfsDisk<D>) {
-    vdev::Driver::init(disk, options).cached()
+/// initializes a system. The memory of the cache is charged to `budget`.
+pub fn init<D: Disk>(
+    disk: D,
+    options: header::Options,
+    budget: Arc<Budget>,
+) -> future!(TfsDisk<D>) {
+    vdev::Driver::init(disk, options).map(|driver| driver.cached(budget))
 }
 
 /// A storage device.
@@ -48,6 +63,8 @@ pub trait Disk: slog::Drain {
     type WriteFuture: Future<Item = (), Error = Error>;
     /// The future returned from the trim operations.
     type TrimFuture: Future<Item = (), Error = Error>;
+    /// The future returned from the flush operations.
+    type FlushFuture: Future<Item = (), Error = Error>;
 
     /// The number of sectors on this disk.
     fn number_of_sectors(&self) -> Sector;
@@ -66,9 +83,41 @@ pub trait Disk: slog::Drain {
     /// This returns a future, which carries the operation trimming sector `sector`. First when the
     /// future has completed, the operation has been executed.
     fn trim(&self, sector: Sector) -> Self::TrimFuture;
+    /// Flush the write cache of the disk.
+    ///
+    /// This returns a future, which carries the operation. When it has completed, every write
+    /// which had completed before the flush was issued is durable, i.e. it survives power loss.
+    /// Writes still in flight are not covered.
+    ///
+    /// Implementations for devices without a volatile write cache (see `Flush::WriteThrough`) can
+    /// complete immediately.
+    fn flush(&self) -> Self::FlushFuture;
+    /// Write data to the disk durably.
+    ///
+    /// This acts like `write()`, but the write is durable when the future completes, without
+    /// making other writes durable. Devices supporting FUA (see `Flush::WriteBackFua`) should
+    /// issue a FUA write, while others must emulate it, e.g. by writing and then flushing.
+    fn write_fua(&self, sector: Sector, buf: &SectorBuf) -> Self::WriteFuture;
+
+    /// Query the capabilities of the disk.
+    ///
+    /// This describes the geometry and features of the device (see `Capabilities`), such that the
+    /// upper layers can adapt to it. Implementations backed by an actual device should populate
+    /// this from the OS (e.g. through `Capabilities::from_sysfs()`). By default, the conservative
+    /// defaults are used.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Advise the disk of the access pattern of some sectors.
+    ///
+    /// Implementations backed by a file should forward this to the OS (through `posix_fadvise`
+    /// with `hint.posix_advice()`), such that the page cache of the host doesn't work against the
+    /// cache of TFS. By default, the hint is ignored.
+    fn advise(&self, sectors: Range<Sector>, hint: Hint) {}
 
-    /// Create a cached version of the disk.
-    fn cached(self) -> cache::Cached<Self> {
-        cache::Cached::new(self)
+    /// Create a cached version of the disk, charging the cache to some memory budget.
+    fn cached(self, budget: Arc<Budget>) -> cache::Cached<Self> {
+        cache::Cached::new(self, budget)
     }
 }
@@ -0,0 +1,338 @@
+//! I/O throttling and quality of service.
+//!
+//! Background work (scrubbing, compaction, deduplication, etc.) can issue enough I/O to saturate
+//! the device, tanking the latency of interactive (foreground) operations. To avoid this, every
+//! operation has a QoS class, and background operations are throttled through a token bucket.
+//!
+//! Foreground operations are never delayed. Instead, they are charged to the bucket as well
+//! (potentially putting it in debt), meaning that background operations automatically back off
+//! when the device is busy serving foreground operations, and use the spare bandwidth otherwise.
+//!
+//! Background operations wait for their turn through a future (see `Admit`), so the threads
+//! driving them are never blocked. The bandwidth can be changed at runtime (see
+//! `Throttle::set_rate()`).
+
+use futures::{task, Async, Future, Poll};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::thread;
+
+use Error;
+
+/// The default bandwidth (in bytes per second) available to background operations.
+pub const DEFAULT_BACKGROUND_RATE: u64 = 64 << 20;
+/// The default number of bytes background operations may issue at once.
+pub const DEFAULT_BACKGROUND_BURST: u64 = 1 << 20;
+
+/// The QoS class of an operation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Class {
+    /// Interactive operations, which are latency sensitive.
+    ///
+    /// These are never throttled.
+    Foreground,
+    /// Background operations (scrubbing, compaction, dedup, etc.).
+    ///
+    /// These are throttled, such that they never starve the foreground operations.
+    Background,
+}
+
+/// The mutable state of a token bucket.
+struct BucketState {
+    /// The number of tokens (bytes) currently in the bucket.
+    ///
+    /// This can be negative, in which case the bucket is in debt.
+    tokens: i64,
+    /// The last time the bucket was refilled.
+    last_refill: Instant,
+    /// The number of tokens added per second.
+    rate: u64,
+}
+
+/// A token bucket.
+///
+/// Tokens (each representing a byte of I/O) are added at a constant rate, up to some maximal
+/// burst size. Operations take tokens from the bucket, and if there aren't enough, they must wait.
+pub struct TokenBucket {
+    /// The maximal number of tokens in the bucket.
+    burst: u64,
+    /// The state of the bucket.
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Create a new, full token bucket.
+    ///
+    /// `rate` is the number of bytes per second, and `burst` the maximal number of bytes which can
+    /// be taken at once.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket {
+            burst: burst,
+            state: Mutex::new(BucketState {
+                tokens: burst as i64,
+                last_refill: Instant::now(),
+                rate: rate,
+            }),
+        }
+    }
+
+    /// Refill the bucket according to the elapsed time.
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.duration_since(state.last_refill);
+        // Calculate the number of new tokens. We use nanoseconds for precision.
+        let nanos = elapsed.as_secs() as u128 * 1_000_000_000 + elapsed.subsec_nanos() as u128;
+        let new = (nanos * state.rate as u128 / 1_000_000_000) as i64;
+
+        if new > 0 {
+            state.tokens = (state.tokens + new).min(self.burst as i64);
+            state.last_refill = now;
+        } else if state.rate == 0 {
+            // Nothing is added while the rate is zero, so the elapsed time must not be credited
+            // when it is raised again.
+            state.last_refill = now;
+        }
+    }
+
+    /// Try to take some number of tokens at some point in time.
+    ///
+    /// If the bucket doesn't hold `n` tokens, `Err(wait)` is returned, where `wait` is the time
+    /// until it will. Requests larger than the burst size are satisfied when the bucket is full.
+    fn try_take_at(&self, n: u64, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        // Cap the request to the burst size, as it could never be satisfied otherwise.
+        let needed = n.min(self.burst) as i64;
+        if state.tokens >= needed {
+            state.tokens -= n as i64;
+            Ok(())
+        } else if state.rate == 0 {
+            // The bucket never refills, so we just wait some arbitrary time and hope for the
+            // rate to change.
+            Err(Duration::from_millis(100))
+        } else {
+            let missing = (needed - state.tokens) as u64;
+            Err(Duration::from_nanos(missing * 1_000_000_000 / state.rate + 1))
+        }
+    }
+
+    /// Charge some number of tokens at some point in time.
+    ///
+    /// This never fails, but may put the bucket in debt.
+    fn charge_at(&self, n: u64, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        // Don't let the debt exceed a burst, so background operations are not starved
+        // indefinitely after a foreground spike.
+        state.tokens = (state.tokens - n as i64).max(-(self.burst as i64));
+    }
+
+    /// Set the rate at some point in time.
+    ///
+    /// The tokens added until `now` are added at the old rate.
+    fn set_rate_at(&self, rate: u64, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        state.rate = rate;
+    }
+
+    /// Take some number of tokens.
+    ///
+    /// The returned future completes when the tokens are taken.
+    pub fn take(&self, n: u64) -> Admit {
+        Admit {
+            bucket: Some(self),
+            tokens: n,
+        }
+    }
+
+    /// Take some number of tokens without blocking, potentially putting the bucket in debt.
+    pub fn charge(&self, n: u64) {
+        self.charge_at(n, Instant::now());
+    }
+
+    /// Set the number of tokens added per second.
+    pub fn set_rate(&self, rate: u64) {
+        self.set_rate_at(rate, Instant::now());
+    }
+}
+
+/// A future admitting an operation.
+///
+/// This completes when the throttle admits the operation. While the operation waits, the task
+/// polling it is parked, and woken up when the tokens are expected to be available.
+pub struct Admit<'a> {
+    /// The bucket to take the tokens from.
+    ///
+    /// `None` means that the operation needn't wait.
+    bucket: Option<&'a TokenBucket>,
+    /// The number of tokens to take.
+    tokens: u64,
+}
+
+impl<'a> Future for Admit<'a> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        let bucket = match self.bucket {
+            Some(bucket) => bucket,
+            None => return Ok(Async::Ready(())),
+        };
+
+        match bucket.try_take_at(self.tokens, Instant::now()) {
+            Ok(()) => {
+                self.bucket = None;
+                Ok(Async::Ready(()))
+            },
+            Err(wait) => {
+                // Wake the task up when the tokens are there. The timer sleeps in a thread of its
+                // own, such that the executor is free to run other tasks in the meantime.
+                let task = task::current();
+                thread::spawn(move || {
+                    thread::sleep(wait);
+                    task.notify();
+                });
+
+                Ok(Async::NotReady)
+            },
+        }
+    }
+}
+
+/// An I/O throttle.
+///
+/// This admits operations according to their QoS class.
+pub struct Throttle {
+    /// The token bucket shared by all the classes.
+    bucket: TokenBucket,
+}
+
+impl Throttle {
+    /// Create a new throttle.
+    ///
+    /// `rate` is the bandwidth (in bytes per second) available to background operations when the
+    /// device is otherwise idle, and `burst` is the number of bytes they may issue at once.
+    pub fn new(rate: u64, burst: u64) -> Throttle {
+        Throttle {
+            bucket: TokenBucket::new(rate, burst),
+        }
+    }
+
+    /// Charge a foreground operation of `bytes` bytes.
+    ///
+    /// This is equivalent to admitting it in class `Class::Foreground`, which is immediate.
+    pub fn charge(&self, bytes: u64) {
+        self.bucket.charge(bytes);
+    }
+
+    /// Admit an operation of `bytes` bytes in QoS class `class`.
+    ///
+    /// The returned future completes when the operation is allowed to execute. Foreground
+    /// operations are admitted right away, and the future is already complete.
+    pub fn admit(&self, class: Class, bytes: u64) -> Admit {
+        match class {
+            // Foreground operations go right through, but push the background operations back.
+            Class::Foreground => {
+                self.bucket.charge(bytes);
+
+                Admit {
+                    bucket: None,
+                    tokens: bytes,
+                }
+            },
+            // Background operations must wait for their turn.
+            Class::Background => self.bucket.take(bytes),
+        }
+    }
+
+    /// Set the bandwidth (in bytes per second) available to background operations.
+    ///
+    /// This takes effect right away, also for the operations already waiting.
+    pub fn set_rate(&self, rate: u64) {
+        self.bucket.set_rate(rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_and_refill() {
+        let bucket = TokenBucket::new(1000, 100);
+        let now = Instant::now();
+
+        assert!(bucket.try_take_at(60, now).is_ok());
+        assert!(bucket.try_take_at(40, now).is_ok());
+        // Empty bucket; we must wait 50 ms for 50 tokens.
+        let wait = bucket.try_take_at(50, now).unwrap_err();
+        assert!(wait >= Duration::from_millis(50) && wait <= Duration::from_millis(51));
+
+        assert!(bucket.try_take_at(50, now + Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn burst_cap() {
+        let bucket = TokenBucket::new(1000, 100);
+        let now = Instant::now();
+
+        // The bucket doesn't fill beyond the burst size.
+        assert!(bucket.try_take_at(100, now + Duration::from_secs(10)).is_ok());
+        assert!(bucket.try_take_at(1, now + Duration::from_secs(10)).is_err());
+
+        // Oversized requests go through on a full bucket.
+        assert!(bucket.try_take_at(1000, now + Duration::from_secs(20)).is_ok());
+    }
+
+    #[test]
+    fn foreground_debt() {
+        let bucket = TokenBucket::new(1000, 100);
+        let now = Instant::now();
+
+        // Foreground I/O puts the bucket in debt, which the background must wait out.
+        bucket.charge_at(150, now);
+        assert!(bucket.try_take_at(10, now + Duration::from_millis(50)).is_err());
+        assert!(bucket.try_take_at(10, now + Duration::from_millis(60)).is_ok());
+
+        // The debt is bounded by the burst size.
+        bucket.charge_at(10000, now + Duration::from_millis(60));
+        assert!(bucket.try_take_at(10, now + Duration::from_millis(170)).is_ok());
+    }
+
+    #[test]
+    fn set_rate() {
+        let bucket = TokenBucket::new(1000, 100);
+        let now = Instant::now();
+
+        // Empty the bucket, and refill it at half the rate.
+        assert!(bucket.try_take_at(100, now).is_ok());
+        bucket.set_rate_at(500, now);
+        let wait = bucket.try_take_at(50, now).unwrap_err();
+        assert!(wait >= Duration::from_millis(100) && wait <= Duration::from_millis(101));
+        assert!(bucket.try_take_at(50, now + Duration::from_millis(100)).is_ok());
+
+        // Stopped buckets don't refill, and don't credit the time they were stopped.
+        bucket.set_rate_at(0, now + Duration::from_millis(100));
+        assert!(bucket.try_take_at(1, now + Duration::from_secs(10)).is_err());
+        bucket.set_rate_at(1000, now + Duration::from_secs(10));
+        assert!(bucket.try_take_at(10, now + Duration::from_secs(10)).is_err());
+        assert!(bucket.try_take_at(10, now + Duration::from_millis(10010)).is_ok());
+    }
+
+    #[test]
+    fn admit() {
+        let throttle = Throttle::new(1000, 100);
+
+        // Foreground operations are admitted right away, even when the bucket is in debt.
+        throttle.admit(Class::Foreground, 200).wait().unwrap();
+        throttle.admit(Class::Foreground, 200).wait().unwrap();
+
+        // Background operations wait for the debt to be paid off.
+        let start = Instant::now();
+        throttle.admit(Class::Background, 10).wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,175 @@
+//! Resilvering of mirrors.
+//!
+//! When a mirror member has failed and is replaced, its half of the disk holds garbage, and the
+//! redundancy is lost until the data is copied back onto it. Resilvering does so by reading every
+//! allocated sector from the surviving member and writing it to the replaced member. The replaced
+//! member is never read, as it holds garbage until resilvered.
+//!
+//! Only allocated sectors are copied, so resilvering a mostly empty disk is quick. Resilvering
+//! runs in the background QoS class (see `qos`), such that it doesn't starve the foreground
+//! operations, and reports its progress through a `Tracker`.
+
+use std::sync::atomic::{self, AtomicU64};
+use std::time::{Duration, Instant};
+
+use disk;
+
+/// The atomic ordering used in the tracker.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+
+/// A member of a mirror.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Member {
+    /// The member holding the lower half of the disk.
+    ///
+    /// Reads are served from this member.
+    Primary,
+    /// The member holding the higher half of the disk.
+    Secondary,
+}
+
+impl Member {
+    /// Split the copies of some sector, given that this member was replaced.
+    ///
+    /// `copies` are the sectors holding the copies, with the copy of the primary member first. The
+    /// copy of the surviving member (which shall be read) is returned along with the copies of
+    /// this member (which shall be written). If there is no surviving copy, `None` is returned.
+    pub fn split(self, copies: &[disk::Sector]) -> Option<(disk::Sector, Vec<disk::Sector>)> {
+        if copies.len() < 2 {
+            return None;
+        }
+
+        Some(match self {
+            Member::Primary => (copies[1], copies[..1].to_vec()),
+            Member::Secondary => (copies[0], copies[1..].to_vec()),
+        })
+    }
+}
+
+/// The progress of a resilver.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Progress {
+    /// The number of sectors copied.
+    pub done: u64,
+    /// The total number of sectors to copy.
+    pub total: u64,
+    /// The estimated time until completion.
+    ///
+    /// This is extrapolated from the rate so far, and is `None` until some sectors are copied.
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Is the resilver complete?
+    pub fn is_complete(&self) -> bool {
+        self.done >= self.total
+    }
+}
+
+/// A tracker of the progress of a resilver.
+///
+/// This is shared between the resilvering task and whoever wants to monitor it.
+pub struct Tracker {
+    /// The total number of sectors to copy.
+    total: u64,
+    /// The number of sectors copied.
+    done: AtomicU64,
+    /// The time the resilver started.
+    started: Instant,
+}
+
+impl Tracker {
+    /// Start tracking a resilver of `total` sectors.
+    pub fn new(total: u64) -> Tracker {
+        Tracker {
+            total: total,
+            done: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Record that some number of sectors were copied.
+    pub fn advance(&self, sectors: u64) {
+        self.done.fetch_add(sectors, ORDERING);
+    }
+
+    /// Get the current progress.
+    pub fn progress(&self) -> Progress {
+        self.progress_at(Instant::now())
+    }
+
+    /// Get the progress at some point in time.
+    fn progress_at(&self, now: Instant) -> Progress {
+        let done = self.done.load(ORDERING).min(self.total);
+        let elapsed = now.duration_since(self.started);
+
+        Progress {
+            done: done,
+            total: self.total,
+            eta: if done == 0 {
+                None
+            } else {
+                // Extrapolate linearly: The remaining sectors take as long per sector as the ones
+                // copied so far.
+                let nanos = elapsed.as_secs() as u128 * 1_000_000_000
+                    + elapsed.subsec_nanos() as u128;
+                let remaining = nanos * (self.total - done) as u128 / done as u128;
+
+                Some(Duration::new((remaining / 1_000_000_000) as u64,
+                                   (remaining % 1_000_000_000) as u32))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let tracker = Tracker::new(100);
+        let now = tracker.started;
+
+        assert_eq!(tracker.progress_at(now), Progress {
+            done: 0,
+            total: 100,
+            eta: None,
+        });
+
+        tracker.advance(25);
+        let progress = tracker.progress_at(now + Duration::from_secs(10));
+        assert_eq!(progress.done, 25);
+        assert_eq!(progress.eta, Some(Duration::from_secs(30)));
+        assert!(!progress.is_complete());
+
+        tracker.advance(75);
+        let progress = tracker.progress_at(now + Duration::from_secs(20));
+        assert_eq!(progress.eta, Some(Duration::from_secs(0)));
+        assert!(progress.is_complete());
+    }
+
+    #[test]
+    fn primary_replaced() {
+        // The primary copy is garbage, so it must be written from the secondary, and not read.
+        assert_eq!(Member::Primary.split(&[3, 6]), Some((6, vec![3])));
+    }
+
+    #[test]
+    fn secondary_replaced() {
+        assert_eq!(Member::Secondary.split(&[3, 6]), Some((3, vec![6])));
+        assert_eq!(Member::Secondary.split(&[3, 6, 6, 12]), Some((3, vec![6, 6, 12])));
+    }
+
+    #[test]
+    fn not_mirrored() {
+        assert_eq!(Member::Primary.split(&[3]), None);
+        assert_eq!(Member::Secondary.split(&[3]), None);
+    }
+
+    #[test]
+    fn empty() {
+        let tracker = Tracker::new(0);
+        assert!(tracker.progress().is_complete());
+    }
+}
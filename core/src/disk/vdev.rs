@@ -13,10 +13,11 @@
 //! leave to an inconsistent state, unless the inner vdev does.
 
 use std::mem;
-use futures::{future, Future};
+use std::ops::Range;
+use futures::{future, stream, Future, Stream};
 
 use Error;
-use disk::{self, Disk};
+use disk::{self, qos, resilver, Disk};
 use disk::header::{self, DiskHeader};
 
 /// A driver transforming a normal disk into a disk respecting the vdev setup.
@@ -35,6 +36,11 @@ pub struct Driver<D> {
     /// In reality, we could fetch this from the `disk` field as-we-go, but that hurts performance,
     /// so we cache it in memory.
     pub header: header::DiskHeader,
+    /// The I/O throttle.
+    ///
+    /// This schedules the operations according to their QoS class, such that background work
+    /// doesn't starve the foreground operations.
+    throttle: qos::Throttle,
     /// The inner disk.
     // TODO: Remove this vtable?
     disk: D,
@@ -57,6 +63,8 @@ impl<D: Disk> Driver<D> {
         disk.read(0).and_then(|header| {
             let driver = Driver {
                 header: DiskHeader::decode(header)?,
+                throttle: qos::Throttle::new(qos::DEFAULT_BACKGROUND_RATE,
+                                             qos::DEFAULT_BACKGROUND_BURST),
                 disk: disk,
             };
 
@@ -101,6 +109,7 @@ impl<D: Disk> Driver<D> {
         // Write the header to the disk.
         disk.write(0, header.encode()).map(|_| Driver {
             header: header,
+            throttle: qos::Throttle::new(qos::DEFAULT_BACKGROUND_RATE, qos::DEFAULT_BACKGROUND_BURST),
             disk: disk,
         })
     }
@@ -115,42 +124,30 @@ impl<D: Disk> Driver<D> {
         // Encode and write it to the disk.
         self.disk.write(0, &self.header.encode())
     }
-}
 
-impl<D: Disk> Drop for Driver<D> {
-    fn drop(&mut self) {
-        info!(self, "closing the driver");
+    /// Set the bandwidth available to background operations.
+    ///
+    /// `rate` is in bytes per second. See `qos::Throttle::set_rate()`.
+    pub fn set_background_rate(&self, rate: u64) {
+        info!(self, "setting the background rate"; "rate" => rate);
 
-        // Set the state flag to close so we know that it was a proper shutdown.
-        debug!(self, "setting state flag to 'closed'");
-        self.header.state_flag = header::StateFlag::Closed;
-        // Flush the header.
-        self.flush_header().wait().unwrap();
+        self.throttle.set_rate(rate);
     }
-}
-
-delegate_log!(Driver.disk);
-
-impl<D: Disk> Disk for Driver<D> {
-    type ReadFuture  = D::ReadFuture;
-    type WriteFuture = D::WriteFuture;
-    type TrimFuture  = D::TrimFuture;
-
-    fn number_of_sectors(&self) -> disk::Sector {
-        // Start out with the raw number of sectors. We subtract one to cut of the disk header.
-        let mut sectors = self.disk.number_of_sectors() - 1;
 
-        // Go over the vdev stack.
-        for vdev in self.header.vdev_stack {
-            match vdev {
-                // Mirrors divide the disk in half, as the higher half must mirror the lower.
-                header::Vdev::Mirror => sectors /= 2,
-                header::Vdev::Speck => (),
-            }
-        }
+    /// Read a sector with some QoS class.
+    ///
+    /// This acts like `Disk::read()` (which uses `qos::Class::Foreground`), but lets the caller
+    /// choose the QoS class. Background operations are delayed until the throttle admits them.
+    pub fn read_with_class(&self, sector: disk::Sector, class: qos::Class)
+        -> future!(Box<disk::SectorBuf>) {
+        // Wait for our turn.
+        self.throttle.admit(class, disk::SECTOR_SIZE as u64).and_then(move |()| {
+            self.read_inner(sector)
+        })
     }
 
-    fn read(&self, sector: disk::Sector) -> D::ReadFuture {
+    /// Read a sector through the vdev stack.
+    fn read_inner(&self, sector: disk::Sector) -> D::ReadFuture {
         // We start out by reading the inner buffer. We subtract one to cut of the disk header.
         let mut buf = self.disk.read(sector + 1);
 
@@ -167,31 +164,82 @@ impl<D: Disk> Disk for Driver<D> {
         }
     }
 
-    fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> D::WriteFuture {
-        // Start a vector to hold the writes. This allows us to rewrite the write operations for
-        // every vdev transformation.
-        let mut writes = vec![(sector, buf)];
+    /// Get the copies of some sector on the inner disk.
+    ///
+    /// This applies the vdev stack to `sector`, giving the sectors of the inner disk holding a
+    /// copy of it. The copy on the primary member of the mirrors (the lower half) comes first.
+    fn copies(&self, sector: disk::Sector) -> Vec<disk::Sector> {
+        // Start a vector to hold the copies. This allows us to rewrite the sectors for every vdev
+        // transformation.
+        let mut copies = vec![sector];
 
         // Go over the vdev stack.
         for vdev in self.header.vdev_stack {
             match vdev {
                 // Mirror the higher and lower half.
-                header::Vdev::Mirror => for i in 0..writes.len() {
-                    // Write the higher half.
-                    writes.push((writes[i].0 * 2, writes[i].1));
+                header::Vdev::Mirror => for i in 0..copies.len() {
+                    // Copy to the higher half.
+                    copies.push(copies[i] * 2);
                 },
                 // TODO
                 header::Vdev::Speck => unimplemented!(),
             }
         }
 
+        copies
+    }
+
+    /// Get the writes of the inner disk writing some sector.
+    ///
+    /// This applies the vdev stack to the write of `buf` into `sector`, giving the writes which
+    /// should be issued to the inner disk.
+    fn inner_writes<'a>(
+        &self,
+        sector: disk::Sector,
+        buf: &'a disk::SectorBuf,
+    ) -> Vec<(disk::Sector, &'a disk::SectorBuf)> {
+        self.copies(sector).into_iter().map(|sector| (sector, buf)).collect()
+    }
+
+    /// Write a sector with some QoS class.
+    ///
+    /// This acts like `Disk::write()` (which uses `qos::Class::Foreground`), but lets the caller
+    /// choose the QoS class. See `read_with_class()` for details.
+    pub fn write_with_class(
+        &self,
+        sector: disk::Sector,
+        buf: Box<disk::SectorBuf>,
+        class: qos::Class,
+    ) -> future!(()) {
+        // Wait for our turn. We account for every write issued to the inner disk, as mirrors
+        // multiply the amount of I/O.
+        let bytes = self.inner_writes(sector, &buf).len() * disk::SECTOR_SIZE;
+        self.throttle.admit(class, bytes as u64).and_then(move |()| {
+            self.write_inner(sector, &buf)
+        })
+    }
+
+    /// Write a sector through the vdev stack.
+    fn write_inner(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> D::WriteFuture {
         // Execute all the writes, we've buffered.
-        future::join_all(writes.into_iter().map(|(sector, buf)| {
+        future::join_all(self.inner_writes(sector, buf).into_iter().map(|(sector, buf)| {
             self.disk.write(sector, buf)
         }))
     }
 
-    fn trim(&self, sector: disk::Sector) -> D::TrimFuture {
+    /// Trim a sector with some QoS class.
+    ///
+    /// This acts like `Disk::trim()` (which uses `qos::Class::Foreground`), but lets the caller
+    /// choose the QoS class. See `read_with_class()` for details.
+    pub fn trim_with_class(&self, sector: disk::Sector, class: qos::Class) -> future!(()) {
+        // Trims carry no data, but they still occupy the device, so we charge them as a sector
+        // each.
+        let bytes = self.inner_trims(sector).len() * disk::SECTOR_SIZE;
+        self.throttle.admit(class, bytes as u64).and_then(move |()| self.trim_inner(sector))
+    }
+
+    /// Get the trims of the inner disk trimming some sector.
+    fn inner_trims(&self, sector: disk::Sector) -> Vec<disk::Sector> {
         // Start a vector to track what sectors to trim.
         let mut trims = vec![sector];
 
@@ -208,9 +256,142 @@ impl<D: Disk> Disk for Driver<D> {
             }
         }
 
+        trims
+    }
+
+    /// Trim a sector through the vdev stack.
+    fn trim_inner(&self, sector: disk::Sector) -> D::TrimFuture {
         // Execute all the trims, we've buffered.
-        future::join_all(trims.into_iter().map(|sector| {
+        future::join_all(self.inner_trims(sector).into_iter().map(|sector| {
             self.disk.trim(sector)
         }))
     }
+
+    /// Resilver a replaced mirror member.
+    ///
+    /// This copies every sector of `sectors` (which should be the allocated sectors) from the
+    /// surviving member onto `replaced`, restoring the redundancy after `replaced` was replaced.
+    /// The replaced member is never read, and the surviving member is never written. The
+    /// operations are in the background QoS class, and the sectors are copied one at a time, so
+    /// the resilver yields to foreground operations.
+    ///
+    /// The progress is reported to `tracker`, which should have been created with the number of
+    /// sectors in `sectors`. If the disk isn't mirrored, an error is returned.
+    pub fn resilver<I>(
+        &self,
+        replaced: resilver::Member,
+        sectors: I,
+        tracker: &resilver::Tracker,
+    ) -> future!(())
+    where I: IntoIterator<Item = disk::Sector> {
+        info!(self, "resilvering mirror member"; "member" => format!("{:?}", replaced));
+
+        // Every sector has the same number of copies, so if the first has no surviving copy, none
+        // has.
+        if replaced.split(&self.copies(0)).is_none() {
+            return future::Either::A(future::err(err!(InvalidOption, "the disk is not mirrored; \
+                                                                       nothing to resilver from")));
+        }
+
+        future::Either::B(stream::iter_ok(sectors).for_each(move |sector| {
+            trace!(self, "resilvering sector"; "sector" => sector);
+
+            let (source, targets) = replaced.split(&self.copies(sector))
+                .expect("Sector without a surviving copy.");
+
+            // Wait for our turn. Every write is charged along with the read.
+            let bytes = (1 + targets.len()) * disk::SECTOR_SIZE;
+            self.throttle.admit(qos::Class::Background, bytes as u64).and_then(move |()| {
+                self.disk.read(source)
+            }).and_then(move |buf| {
+                let writes: Vec<_> = targets.into_iter().map(|sector| {
+                    self.disk.write(sector, &buf)
+                }).collect();
+
+                future::join_all(writes)
+            }).map(|_| tracker.advance(1))
+        }))
+    }
+}
+
+impl<D: Disk> Drop for Driver<D> {
+    fn drop(&mut self) {
+        info!(self, "closing the driver");
+
+        // Set the state flag to close so we know that it was a proper shutdown.
+        debug!(self, "setting state flag to 'closed'");
+        self.header.state_flag = header::StateFlag::Closed;
+        // Flush the header.
+        self.flush_header().wait().unwrap();
+    }
+}
+
+delegate_log!(Driver.disk);
+
+impl<D: Disk> Disk for Driver<D> {
+    type ReadFuture  = D::ReadFuture;
+    type WriteFuture = D::WriteFuture;
+    type TrimFuture  = D::TrimFuture;
+    type FlushFuture = D::FlushFuture;
+
+    fn number_of_sectors(&self) -> disk::Sector {
+        // Start out with the raw number of sectors. We subtract one to cut of the disk header.
+        let mut sectors = self.disk.number_of_sectors() - 1;
+
+        // Go over the vdev stack.
+        for vdev in self.header.vdev_stack {
+            match vdev {
+                // Mirrors divide the disk in half, as the higher half must mirror the lower.
+                header::Vdev::Mirror => sectors /= 2,
+                header::Vdev::Speck => (),
+            }
+        }
+    }
+
+    // Foreground operations are admitted right away, so they are merely charged to the throttle,
+    // and issued without waiting on it.
+
+    fn read(&self, sector: disk::Sector) -> D::ReadFuture {
+        self.throttle.charge(disk::SECTOR_SIZE as u64);
+        self.read_inner(sector)
+    }
+
+    fn write(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> D::WriteFuture {
+        self.throttle.charge((self.inner_writes(sector, buf).len() * disk::SECTOR_SIZE) as u64);
+        self.write_inner(sector, buf)
+    }
+
+    fn trim(&self, sector: disk::Sector) -> D::TrimFuture {
+        self.throttle.charge((self.inner_trims(sector).len() * disk::SECTOR_SIZE) as u64);
+        self.trim_inner(sector)
+    }
+
+    fn flush(&self) -> D::FlushFuture {
+        // Every copy lives on the inner disk, so flushing it makes all of them durable.
+        self.disk.flush()
+    }
+
+    fn write_fua(&self, sector: disk::Sector, buf: &disk::SectorBuf) -> D::WriteFuture {
+        let writes = self.inner_writes(sector, buf);
+
+        // Durable writes are issued by the ones waiting for them, so they are foreground.
+        self.throttle.charge((writes.len() * disk::SECTOR_SIZE) as u64);
+
+        // Every copy must be durable, as the write is only as durable as the copy read back.
+        future::join_all(writes.into_iter().map(|(sector, buf)| {
+            self.disk.write_fua(sector, buf)
+        }))
+    }
+
+    fn capabilities(&self) -> disk::Capabilities {
+        // None of the vdevs change the geometry or features of the inner disk. Mirrors write
+        // both halves, but do so with the same granularity.
+        self.disk.capabilities()
+    }
+
+    fn advise(&self, sectors: Range<disk::Sector>, hint: disk::Hint) {
+        // Sector `n` is sector `n + 1` of the inner disk, as the disk header comes first. The
+        // mirrored halves are read on recovery only, so there is no point in advising them.
+        self.disk.advise(sectors.start + 1..sectors.end + 1, hint);
+    }
 }
@@ -7,8 +7,12 @@ pub enum Kind {
     Corruption,
     /// No more space to use.
     OutOfSpace,
+    /// Invalid configuration option.
+    InvalidOption,
     /// Implementation issue.
     Implementation,
+    /// Encrypted data, whose key is not unlocked.
+    Locked,
 }
 
 /// A TFS error.
@@ -1,27 +1,533 @@
-use futures::Future;
+use futures::{future, Future};
+use std::collections::{BTreeMap, BTreeSet};
 use std::marker::PhantomData;
+use std::{cmp, mem};
 use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
 
 use {disk, fs, Error};
 use alloc::page;
+use fs::{delalloc, extent, range_lock, usage};
+use fs::verify::{Check, Report};
 
 const POINTERS_IN_NODE: u64 = disk::SECTOR_SIZE / page::POINTER_SIZE;
 
+/// How preallocation affects the length of an array.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SizeMode {
+    /// The length is kept, even if the preallocated range extends beyond it.
+    ///
+    /// This corresponds to `FALLOC_FL_KEEP_SIZE`, and is used to reserve space for appends.
+    Keep,
+    /// The length is extended to cover the preallocated range.
+    Extend,
+}
+
+/// The outcome of defragmenting an array.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Defragmentation {
+    /// The number of extents before defragmenting.
+    before: usize,
+    /// The number of extents after defragmenting.
+    after: usize,
+}
+
+/// Join a batch of allocations, deallocating them all if any fails.
+///
+/// The allocations yield extents along with the file-relative index of their first cluster. The
+/// extents are not linked into the mapping yet, so nothing refers to them, and if an allocation of
+/// the batch fails, the others would leak. Instead, they are deallocated right away, and the first
+/// error is returned.
+fn join_allocations<'a, F>(fs: &'a fs::State, allocations: Vec<F>)
+    -> future!(Vec<(u64, Vec<extent::Extent>)>)
+where F: Future<Item = (u64, Vec<extent::Extent>), Error = Error> + 'a {
+    future::join_all(allocations.into_iter().map(|allocation| {
+        // Catch the errors, so the other allocations run to completion.
+        allocation.then(Ok::<_, Error>)
+    }).collect::<Vec<_>>()).and_then(move |results| {
+        if results.iter().all(Result::is_ok) {
+            return future::Either::A(future::ok(results.into_iter().map(Result::unwrap).collect()));
+        }
+
+        let mut err = None;
+        let mut clusters = Vec::new();
+        for res in results {
+            match res {
+                Ok((_, extents)) => clusters.extend(extents.into_iter().flat_map(|extent| {
+                    (0..extent.len).map(move |offset| extent.cluster(offset))
+                })),
+                Err(x) => {
+                    err.get_or_insert(x);
+                },
+            }
+        }
+        debug!(fs, "deallocating the allocations of a failed batch"; "clusters" => clusters.len());
+
+        future::Either::B(future::join_all(clusters.into_iter().map(|cluster| {
+            fs.alloc.dealloc(cluster)
+        }).collect::<Vec<_>>()).and_then(move |_| Err(err.unwrap())))
+    })
+}
+
 struct Array<T> {
     root: page::Pointer,
-    len: u64,
+    /// The length of the array, in clusters.
+    ///
+    /// This is locked, such that preallocation can extend it (see `allocate()`).
+    len: RwLock<u64>,
+    /// The extents of the array.
+    ///
+    /// Physically contiguous runs of clusters are described by extents rather than by one page
+    /// pointer per cluster in the tree, such that they can be read as one large I/O.
+    ///
+    /// This is locked, such that the array stays readable while extents are switched (see
+    /// `defragment()`).
+    extents: RwLock<extent::Map>,
+    /// The lock serializing overlapping writes to the array.
+    ///
+    /// The ranges are cluster indexes. Writers of disjoint ranges proceed in parallel.
+    writers: range_lock::RangeLock,
+    /// The dirty clusters, pending allocation.
+    ///
+    /// Written clusters are only allocated when the array is flushed (see `fs::delalloc`).
+    dirty: Mutex<delalloc::Buffer>,
+    /// The extents which are no longer mapped, pending deallocation.
+    ///
+    /// Replaced extents can't be deallocated right away, as the committed mapping refers to them
+    /// until the new mapping is committed (see `take_retired()`).
+    retired: Mutex<Vec<extent::Extent>>,
     _phantom: PhantomData<T>,
 }
 
 impl<T> Array<T> {
+    /// Get the length of the array, in clusters.
+    fn len(&self) -> u64 {
+        *self.len.read().unwrap()
+    }
+
     fn is_leaf(&self) -> bool {
-        self.len <= POINTERS_IN_NODE
+        self.len() <= POINTERS_IN_NODE
     }
 
     fn for_each<F>(&self, fs: &fs::State, range: Range<u64>, f: F) -> future!(())
     where F: Fn(usize, page::Pointer) {
         unimplemented!();
     }
+
+    /// Apply a closure to every extent covering some range.
+    ///
+    /// This visits the extents needed to read `range` in order. Indexes of `range` which are not
+    /// described by any extent must be visited through `for_each`.
+    fn for_each_extent<F>(&self, range: Range<u64>, f: F)
+    where F: FnMut(extent::Extent) {
+        let runs = self.extents.read().unwrap().runs(range);
+        runs.into_iter().for_each(f);
+    }
+
+    /// Lock some range of the array for writing.
+    ///
+    /// `range` is a range of cluster indexes. Writes must hold the lock of the range they write
+    /// until they complete, such that overlapping writes are applied in the order they were issued
+    /// (see `fs::range_lock`). This covers writes to the dirty buffer (`write_delayed()`) as well
+    /// as writes of the mapping (`flush()`, `allocate()` and `defragment()`).
+    fn lock_range(&self, range: Range<u64>) -> range_lock::Guard {
+        self.writers.lock(range)
+    }
+
+    /// Take the retired extents.
+    ///
+    /// Extents replaced in the mapping (see `flush()` and `defragment()`) are retired rather than
+    /// deallocated, as the committed mapping still refers to them. The commit takes them while
+    /// holding the lock of the mapping it commits (such that they match), and deallocates them once
+    /// that mapping is reachable from the state block (see `notes/directories.md`). Reusing their
+    /// clusters any earlier would corrupt the committed mapping, which is what is left after a
+    /// crash.
+    fn take_retired(&self) -> Vec<extent::Extent> {
+        mem::replace(&mut *self.retired.lock().unwrap(), Vec::new())
+    }
+
+    /// Write a cluster, delaying its allocation.
+    ///
+    /// The cluster is kept in memory until the array is flushed, at which point it is allocated
+    /// along with the other dirty clusters of its run.
+    ///
+    /// This blocks while the cluster is being flushed or its extent switched (see `lock_range()`).
+    fn write_delayed(&self, index: u64, buf: Box<disk::SectorBuf>) {
+        let _guard = self.lock_range(index..index + 1);
+        self.dirty.lock().unwrap().write(index, buf);
+    }
+
+    /// Flush the dirty clusters.
+    ///
+    /// This allocates the dirty clusters, such that every run of consecutive dirty clusters is
+    /// allocated as physically contiguous as possible, and links the extents of the runs into the
+    /// mapping. The linked extents are returned with their file-relative indexes, wrapped in a
+    /// future.
+    ///
+    /// Dirty clusters in preallocated ranges are written into their reserved clusters instead. As
+    /// unwritten extents are written as a whole (see `fs::extent`), the clusters of the extent
+    /// which are not dirty are written as zeros. Likewise, as extents can't be split, dirty
+    /// clusters of written extents are copied-on-write along with the rest of their extent, and
+    /// the old extent is retired (see `take_retired()`).
+    ///
+    /// The dirty clusters stay in the buffer, where reads find them, until their extents are
+    /// linked. If the flush fails, they stay there, and the clusters allocated for them are
+    /// deallocated.
+    ///
+    /// Writes to the dirty range are blocked until the flush completes (see `lock_range()`).
+    fn flush(&self, fs: &fs::State) -> future!(Vec<(u64, extent::Extent)>) {
+        let span = self.dirty.lock().unwrap().span();
+        let guard = self.lock_range(span.clone());
+        let runs = self.dirty.lock().unwrap().runs(span.clone());
+        debug!(fs, "flushing dirty clusters"; "runs" => runs.len());
+
+        // Split the runs into the clusters needing allocation, and the clusters of unwritten and
+        // written extents (by the index of the extent).
+        let mut fresh: Vec<(u64, Vec<Box<disk::SectorBuf>>)> = Vec::new();
+        let mut unwritten = BTreeMap::new();
+        let mut rewritten = BTreeMap::new();
+        let extents = self.extents.read().unwrap();
+        for run in runs {
+            for (index, buf) in (run.start..).zip(run.clusters) {
+                match extents.get(index) {
+                    Some((extent, offset)) => {
+                        let clusters = if extent.unwritten {
+                            &mut unwritten
+                        } else {
+                            &mut rewritten
+                        };
+                        clusters.entry(index - offset as u64)
+                            .or_insert_with(|| (extent, (0..extent.len).map(|_| None).collect()))
+                            .1[offset as usize] = Some(buf);
+                    },
+                    None => match fresh.last_mut() {
+                        // The cluster extends the last run needing allocation.
+                        Some(&mut (start, ref mut bufs)) if start + bufs.len() as u64 == index => {
+                            bufs.push(buf);
+                        },
+                        _ => fresh.push((index, vec![buf])),
+                    },
+                }
+            }
+        }
+        drop(extents);
+
+        let written = future::join_all(unwritten.into_iter().map(|(index, (extent, bufs))| {
+            let bufs = bufs.into_iter().map(|buf: Option<Box<disk::SectorBuf>>| {
+                buf.unwrap_or_else(|| Box::new([0; disk::SECTOR_SIZE]))
+            }).collect();
+
+            fs.alloc.write_extent(extent, bufs).map(move |extent| (index, extent))
+        }).collect::<Vec<_>>());
+
+        // The unwritten extents are written first, as they need no deallocation if the
+        // allocations fail.
+        written.and_then(move |written| {
+            let fresh = fresh.into_iter().map(|(start, bufs)| {
+                future::Either::A(fs.alloc.alloc_extents(bufs).map(move |extents| (start, extents)))
+            });
+            let rewritten = rewritten.into_iter().map(|(index, (extent, bufs))| {
+                // Fill in the clean clusters of the extent from its old content.
+                future::Either::B(fs.alloc.read_extent(extent).and_then(move |content| {
+                    let bufs = bufs.into_iter().zip(content.chunks(disk::SECTOR_SIZE))
+                        .map(|(buf, chunk): (Option<Box<disk::SectorBuf>>, &[u8])| {
+                            buf.unwrap_or_else(|| {
+                                let mut buf = Box::new([0; disk::SECTOR_SIZE]);
+                                buf.copy_from_slice(chunk);
+                                buf
+                            })
+                        }).collect();
+
+                    fs.alloc.alloc_extents(bufs)
+                }).map(move |extents| (index, extents)))
+            });
+
+            join_allocations(fs, fresh.chain(rewritten).collect::<Vec<_>>())
+                .map(move |allocated| (written, allocated))
+        }).map(move |(mut linked, allocated)| {
+            {
+                let mut extents = self.extents.write().unwrap();
+                for &(index, extent) in &linked {
+                    // This replaces the unwritten extent.
+                    extents.insert(index, extent, extent.checksum);
+                }
+                for (mut index, new) in allocated {
+                    // Replace the rewritten extent, if any.
+                    if let Some((old, 0)) = extents.get(index) {
+                        extents.remove(index..index + old.len as u64);
+                        self.retired.lock().unwrap().push(old);
+                    }
+
+                    // The extents cover the run in order, so their indexes follow from their
+                    // lengths.
+                    for extent in new {
+                        extents.insert(index, extent, extent.checksum);
+                        linked.push((index, extent));
+                        index += extent.len as u64;
+                    }
+                }
+            }
+
+            // The clusters are linked, so they can leave the buffer, and the writes blocked on
+            // them can proceed.
+            self.dirty.lock().unwrap().clean(span);
+            drop(guard);
+
+            linked
+        })
+    }
+
+    /// Preallocate some range of the array.
+    ///
+    /// This reserves clusters for the indexes of `range` which are holes, without writing any
+    /// data, such that later writes to the range can't fail for lack of space, and are laid out
+    /// contiguously. The reserved clusters are described by unwritten extents (see `fs::extent`),
+    /// which read as zeros. Indexes which are already mapped (by extents or pages) are left as is.
+    ///
+    /// The clusters are reserved from the freelist like any allocation, so they count as used
+    /// space (physically, see `fs::usage`) as soon as they are reserved, whether they are written
+    /// or not.
+    ///
+    /// Whether the length of the array is extended to cover the range is determined by `mode`.
+    ///
+    /// Writes to the range are blocked until the preallocation completes (see `lock_range()`), so
+    /// the holes stay holes until their reserved extents are inserted. If a reservation fails, the
+    /// others are deallocated.
+    fn allocate(&self, fs: &fs::State, range: Range<u64>, mode: SizeMode) -> future!(()) {
+        debug!(fs, "preallocating"; "start" => range.start, "end" => range.end,
+               "mode" => format!("{:?}", mode));
+
+        let guard = self.lock_range(range.clone());
+
+        // Collect the indexes mapped by pages, as they aren't holes even though no extent covers
+        // them.
+        let pages = Arc::new(Mutex::new(BTreeSet::new()));
+        let pages_visit = pages.clone();
+        let mapped = cmp::min(range.end, self.len());
+        self.for_each(fs, range.start..cmp::max(range.start, mapped), move |index, _| {
+            pages_visit.lock().unwrap().insert(index as u64);
+        }).and_then(move |()| {
+            let pages = mem::replace(&mut *pages.lock().unwrap(), BTreeSet::new());
+
+            // Find the holes, and split them around the pages.
+            let mut holes = Vec::new();
+            for hole in self.extents.read().unwrap().holes(range.clone()) {
+                let mut start = hole.start;
+                for &page in pages.range(hole.clone()) {
+                    if start < page {
+                        holes.push(start..page);
+                    }
+                    start = page + 1;
+                }
+                if start < hole.end {
+                    holes.push(start..hole.end);
+                }
+            }
+
+            // Reserve the holes, each as contiguous as possible.
+            join_allocations(fs, holes.into_iter().map(|hole| {
+                fs.alloc.reserve_extents((hole.end - hole.start) as usize).map(move |extents| {
+                    (hole.start, extents)
+                })
+            }).collect::<Vec<_>>())
+        }).map(move |holes| {
+            {
+                let mut map = self.extents.write().unwrap();
+                for (mut index, extents) in holes {
+                    for extent in extents {
+                        map.insert(index, extent, extent.checksum);
+                        index += extent.len as u64;
+                    }
+                }
+            }
+
+            if mode == SizeMode::Extend {
+                let mut len = self.len.write().unwrap();
+                *len = cmp::max(*len, range.end);
+            }
+
+            // The holes are filled, so the writes blocked on them can proceed.
+            drop(guard);
+        })
+    }
+
+    /// Defragment the array.
+    ///
+    /// This rewrites every fragmented run of extents (logically contiguous extents which could be
+    /// described by fewer extents) into freshly allocated clusters, which are as contiguous as the
+    /// allocator can make them. The numbers of extents before and after are returned, wrapped in a
+    /// future. Unwritten extents and pages (which are not described by extents) are left as is.
+    ///
+    /// The runs are copied-on-write: Each run is read and written to its new clusters, and only
+    /// then is the mapping switched to the new extents. Until the switch, reads go to the old
+    /// extents, which stay intact, so the array stays readable throughout. Writes to a run are
+    /// blocked from the start of its copy until the switch (see `lock_range()`), as they would be
+    /// lost otherwise. If a copy turns out no less fragmented than the original (e.g. because free
+    /// space is fragmented too), the copy is discarded (and deallocated) instead.
+    ///
+    /// The switch only changes the in-memory mapping, so the old extents are retired rather than
+    /// deallocated (see `take_retired()`): Like any write, the switch must be committed before the
+    /// old clusters can be reused.
+    fn defragment(&self, fs: &fs::State) -> future!(Defragmentation) {
+        // Group the written extents into logically contiguous runs.
+        let mut runs: Vec<(u64, Vec<extent::Extent>)> = Vec::new();
+        let before = {
+            let extents = self.extents.read().unwrap();
+            for (index, extent) in extents.indexed_runs(0..self.len()) {
+                if extent.unwritten {
+                    continue;
+                }
+
+                match runs.last_mut() {
+                    Some(&mut (start, ref mut extents))
+                        if start + extents.iter().map(|x| x.len as u64).sum::<u64>() == index => {
+                        extents.push(extent);
+                    },
+                    _ => runs.push((index, vec![extent])),
+                }
+            }
+
+            extents.len()
+        };
+        debug!(fs, "defragmenting array"; "extents" => before);
+
+        // Keep the fragmented runs, i.e. those longer than the fewest extents covering them.
+        runs.retain(|&(_, ref extents)| {
+            let len = extents.iter().map(|x| x.len as u64).sum::<u64>();
+            let max = extent::MAX_EXTENT_LEN as u64;
+            extents.len() as u64 > (len + max - 1) / max
+        });
+
+        future::join_all(runs.into_iter().map(|(index, old)| {
+            let len = old.iter().map(|x| x.len as u64).sum::<u64>();
+            let guard = self.lock_range(index..index + len);
+
+            // Read the run, and write it to new clusters.
+            future::join_all(old.iter().map(|&extent| {
+                fs.alloc.read_extent(extent)
+            }).collect::<Vec<_>>()).and_then(move |contents| {
+                let bufs = contents.iter().flat_map(|content| {
+                    content.chunks(disk::SECTOR_SIZE).map(|chunk| {
+                        let mut buf = Box::new([0; disk::SECTOR_SIZE]);
+                        buf.copy_from_slice(chunk);
+                        buf
+                    })
+                }).collect();
+
+                fs.alloc.alloc_extents(bufs)
+            }).map(move |new| (index, len, old, new, guard))
+        }).collect::<Vec<_>>()).and_then(move |runs| {
+            let mut discarded = Vec::new();
+            let mut guards = Vec::new();
+            let after = {
+                let mut extents = self.extents.write().unwrap();
+                for (index, len, old, new, guard) in runs {
+                    // Switch to the copy, unless it is no better.
+                    if new.len() < old.len() {
+                        extents.remove(index..index + len);
+                        let mut index = index;
+                        for extent in &new {
+                            extents.insert(index, *extent, extent.checksum);
+                            index += extent.len as u64;
+                        }
+
+                        // Retire the old extents along with the switch, such that no commit sees
+                        // the one without the other.
+                        self.retired.lock().unwrap().extend(old);
+                    } else {
+                        discarded.extend(new);
+                    }
+
+                    guards.push(guard);
+                }
+
+                extents.len()
+            };
+            // The runs are switched, so the writes blocked on them can proceed.
+            drop(guards);
+            debug!(fs, "defragmented array"; "before" => before, "after" => after);
+
+            // Deallocate the discarded copies. They were never mapped, so nothing refers to them.
+            future::join_all(discarded.into_iter().flat_map(|extent| {
+                (0..extent.len).map(move |offset| extent.cluster(offset))
+            }).map(|cluster| fs.alloc.dealloc(cluster)).collect::<Vec<_>>()).map(move |_| {
+                Defragmentation {
+                    before: before,
+                    after: after,
+                }
+            })
+        })
+    }
+
+    /// Make the data of the array durable.
+    ///
+    /// This is the equivalent of `fdatasync`: The dirty clusters are flushed (see `flush()`), and
+    /// once they are written, the filesystem is synced (see `fs::State::sync()`). When the returned
+    /// future completes, every write to the array which had completed before this was called is
+    /// durable. The extents of the flushed runs are returned like `flush()` does.
+    ///
+    /// Arrays have no metadata beyond their mapping, so this is also the equivalent of `fsync`.
+    fn sync(&self, fs: &fs::State) -> future!(Vec<(u64, extent::Extent)>) {
+        debug!(fs, "syncing array");
+
+        self.flush(fs).and_then(move |extents| fs.sync().map(|()| extents))
+    }
+
+    /// Count the usage of the array.
+    ///
+    /// This walks the mapping of the array (but reads none of its data) and counts its pages and
+    /// extents (see `fs::usage`).
+    fn usage(&self, fs: &fs::State) -> future!(usage::Counter) {
+        let counter = Arc::new(Mutex::new(usage::Counter::default()));
+
+        // Count the extents.
+        for extent in self.extents.read().unwrap().runs(0..self.len()) {
+            counter.lock().unwrap().add_extent(&extent);
+        }
+
+        // Count the pages, which are not described by extents.
+        let counter_visit = counter.clone();
+        let extent_map = &self.extents;
+        self.for_each(fs, 0..self.len(), move |index, ptr| {
+            if extent_map.read().unwrap().get(index as u64).is_none() {
+                counter_visit.lock().unwrap().add_page(&ptr);
+            }
+        }).map(move |()| mem::replace(&mut *counter.lock().unwrap(), usage::Counter::default()))
+    }
+
+    /// Verify the integrity of the array.
+    ///
+    /// This reads every cluster of the array and checks it against its checksum, returning a
+    /// report of the clusters which failed (see `fs::verify`).
+    fn verify(&self, fs: &fs::State) -> future!(Report) {
+        // Check the extents.
+        let extents = self.extents.read().unwrap().indexed_runs(0..self.len()).into_iter().map(|(index, extent)| {
+            fs.alloc.read_extent(extent).then(move |res| {
+                Check::from_result(res, index, extent.len as u64)
+            })
+        }).collect::<Vec<_>>();
+
+        // Collect the pages, which are not described by extents.
+        let pages = Arc::new(Mutex::new(Vec::new()));
+        let pages_visit = pages.clone();
+        let extent_map = &self.extents;
+        let pages = self.for_each(fs, 0..self.len(), move |index, ptr| {
+            if extent_map.read().unwrap().get(index as u64).is_none() {
+                pages_visit.lock().unwrap().push((index as u64, ptr));
+            }
+        }).and_then(move |()| {
+            // Check the pages.
+            let pages = mem::replace(&mut *pages.lock().unwrap(), Vec::new());
+            future::join_all(pages.into_iter().map(|(index, ptr)| {
+                fs.alloc.read(ptr).then(move |res| Check::from_result(res, index, 1))
+            }).collect::<Vec<_>>())
+        });
+
+        future::join_all(extents).join(pages).map(|(mut checks, pages)| {
+            checks.extend(pages);
+            Report::from_checks(checks)
+        })
+    }
 }
 
 impl<T: fs::Object + From<page::Pointer>> fs::Object for Array<T> {
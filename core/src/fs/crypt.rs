@@ -0,0 +1,450 @@
+//! Per-subtree encryption.
+//!
+//! Rather than encrypting the whole disk with a single key (see the `Encryption` vdev), a
+//! directory can be marked as the root of an encrypted subtree. The object data and the entry
+//! names under it are then encrypted with keys derived from a master key of the subtree, which is
+//! unlocked at runtime (e.g. when the user logs in). Subtrees which are not encrypted go through
+//! none of this, and pay no overhead.
+//!
+//! # Keys
+//!
+//! Every encrypted subtree is identified by the object ID of its root directory. The keys of the
+//! subtree are derived from its master key and its ID by encrypting a domain-separated block with
+//! SPECK, such that knowing the keys of one subtree reveals nothing about other subtrees sharing
+//! the master key.
+//!
+//! # Data
+//!
+//! Every write of a cluster picks a random 128-bit nonce, from which (together with the data key)
+//! the key of the SPECK-CTR keystream is derived. Freed clusters are reused by the allocator, so
+//! the cluster pointer alone would make the same keystream encrypt different data.
+//!
+//! The ciphertext is then authenticated by a CMAC over the cluster pointer, the nonce and the
+//! ciphertext (encrypt-then-MAC), which detects tampered clusters, as well as clusters moved to
+//! another pointer. The nonce and the tag make up the `Seal` of the cluster, which is stored next
+//! to its pointer.
+//!
+//! # Names
+//!
+//! Names must be encrypted deterministically, such that an entry can be looked up by its
+//! encrypted name. This is done in the SIV construction: The nonce is a CMAC of the parent
+//! directory and the name, and is stored in front of the ciphertext, such that decryption can
+//! check it. This leaks only whether two entries of the same directory have the same name, which
+//! the directory reveals anyway.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use speck::{self, Key};
+use speck::rng::SpeckRng;
+use disk::cluster;
+use {integrity, rand, Error};
+
+/// The size (in bytes) of the nonce in front of an encrypted name.
+pub const NAME_NONCE_SIZE: usize = 8;
+
+/// The domain of the data key in key derivation.
+const DOMAIN_DATA: u128 = 1;
+/// The domain of the name authentication key in key derivation.
+const DOMAIN_NAME_MAC: u128 = 2;
+/// The domain of the name encryption key in key derivation.
+const DOMAIN_NAME: u128 = 3;
+/// The domain of the data authentication key in key derivation.
+const DOMAIN_DATA_MAC: u128 = 4;
+
+/// The protection of an object.
+///
+/// Objects inherit the protection of the directory they are created in, so an encrypted subtree
+/// stays encrypted as it grows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protection {
+    /// The object is stored in plaintext.
+    Plain,
+    /// The object belongs to the encrypted subtree with the given ID.
+    Encrypted(u64),
+}
+
+impl Default for Protection {
+    fn default() -> Protection {
+        Protection::Plain
+    }
+}
+
+/// The seal of an encrypted cluster.
+///
+/// This is produced by every write of a cluster, and must be stored next to its pointer, as it is
+/// needed to decrypt it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Seal {
+    /// The nonce of the write.
+    pub nonce: u128,
+    /// The MAC of the cluster pointer, the nonce and the ciphertext.
+    pub tag: u128,
+}
+
+/// The keys of an unlocked subtree.
+#[derive(Clone, Copy)]
+struct Keys {
+    /// The key deriving the keys of the cluster writes.
+    data: Key,
+    /// The key authenticating the clusters.
+    data_mac: Key,
+    /// The key authenticating names (and deriving their nonces).
+    name_mac: Key,
+    /// The key encrypting names.
+    name: u128,
+}
+
+impl Keys {
+    /// Derive the keys of some subtree from its master key.
+    fn derive(master: u128, subtree: u64) -> Keys {
+        let master = Key::new(master);
+        let derive = |domain: u128| master.encrypt_block(domain << 64 | subtree as u128);
+
+        Keys {
+            data: Key::new(derive(DOMAIN_DATA)),
+            data_mac: Key::new(derive(DOMAIN_DATA_MAC)),
+            name_mac: Key::new(derive(DOMAIN_NAME_MAC)),
+            name: derive(DOMAIN_NAME),
+        }
+    }
+}
+
+/// XOR a buffer with the keystream of some key and nonce.
+fn apply_keystream(key: u128, nonce: u64, buf: &mut [u8]) {
+    let mut keystream = vec![0; buf.len()];
+    SpeckRng::new(key, nonce).fill_bytes(&mut keystream);
+
+    for (byte, pad) in buf.iter_mut().zip(keystream) {
+        *byte ^= pad;
+    }
+}
+
+/// The keys of the unlocked subtrees.
+pub struct Keyring {
+    /// The keys by subtree ID.
+    subtrees: RwLock<HashMap<u64, Keys>>,
+    /// The reporter of integrity events.
+    ///
+    /// Names failing authentication are reported through this.
+    integrity: Arc<integrity::Reporter>,
+}
+
+impl Keyring {
+    /// Create a keyring reporting to some integrity reporter.
+    pub fn new(integrity: Arc<integrity::Reporter>) -> Keyring {
+        Keyring {
+            subtrees: RwLock::default(),
+            integrity: integrity,
+        }
+    }
+
+    /// Unlock some subtree.
+    ///
+    /// This derives the keys of subtree `subtree` from `master` (typically obtained through
+    /// `disk::crypto::derive_key()`) and keeps them until the subtree is locked again.
+    ///
+    /// The master key is not checked here. A wrong key shows up as failing name authentication.
+    pub fn unlock(&self, subtree: u64, master: u128) {
+        self.subtrees.write().unwrap().insert(subtree, Keys::derive(master, subtree));
+    }
+
+    /// Lock some subtree.
+    ///
+    /// This forgets the keys of `subtree`, making its data inaccessible until it is unlocked
+    /// again.
+    pub fn lock(&self, subtree: u64) {
+        self.subtrees.write().unwrap().remove(&subtree);
+    }
+
+    /// Is some object accessible?
+    ///
+    /// Plain objects are always accessible, and encrypted ones only when their subtree is unlocked.
+    pub fn is_unlocked(&self, protection: Protection) -> bool {
+        match protection {
+            Protection::Plain => true,
+            Protection::Encrypted(subtree) => self.subtrees.read().unwrap().contains_key(&subtree),
+        }
+    }
+
+    /// Get the keys of the subtree of some object.
+    ///
+    /// This returns `None` for plain objects, and fails if the subtree is locked.
+    fn keys(&self, protection: Protection) -> Result<Option<Keys>, Error> {
+        match protection {
+            Protection::Plain => Ok(None),
+            Protection::Encrypted(subtree) => self.subtrees.read().unwrap().get(&subtree).cloned()
+                .map(Some)
+                .ok_or(err!(Locked, "encrypted subtree {} is locked", subtree)),
+        }
+    }
+
+    /// Encrypt a cluster in place.
+    ///
+    /// `cluster` is the pointer of the cluster `buf` is written to. This returns the seal of the
+    /// cluster, which must be stored along with the pointer. Plain objects are left untouched, and
+    /// have no seal.
+    pub fn encrypt_cluster(&self, protection: Protection, cluster: u64, buf: &mut [u8])
+        -> Result<Option<Seal>, Error> {
+        let keys = match self.keys(protection)? {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+
+        // Pick a fresh nonce, such that the keystream is never reused, even if the cluster is.
+        let nonce = rand::random();
+        apply_keystream(keys.data.encrypt_block(nonce), cluster, buf);
+
+        Ok(Some(Seal {
+            nonce: nonce,
+            tag: cluster_mac(&keys, cluster, nonce, buf).finalize(),
+        }))
+    }
+
+    /// Decrypt a cluster in place.
+    ///
+    /// This is the inverse of `encrypt_cluster()`, and must be given the same cluster pointer and
+    /// the seal it returned. It fails with `Corruption` if the cluster doesn't authenticate, which
+    /// happens if it was tampered with, if it was read from another pointer, or if the subtree was
+    /// unlocked with a wrong key. Such failures are reported as integrity events of the cluster.
+    pub fn decrypt_cluster(&self, protection: Protection, cluster: u64, seal: Option<Seal>,
+                           buf: &mut [u8]) -> Result<(), Error> {
+        let keys = match self.keys(protection)? {
+            Some(keys) => keys,
+            None => return Ok(()),
+        };
+        let seal = match seal {
+            Some(seal) => seal,
+            None => return Err(err!(Corruption, "encrypted cluster {} has no seal", cluster)),
+        };
+
+        // Check the ciphertext before decrypting it.
+        if !cluster_mac(&keys, cluster, seal.nonce, buf).verify(seal.tag) {
+            self.integrity.report(integrity::Event {
+                kind: integrity::Kind::Authentication,
+                cluster: cluster::Pointer::new(cluster),
+                object: None,
+            });
+
+            return Err(err!(Corruption, "encrypted cluster {} failed authentication", cluster));
+        }
+
+        // CTR mode is its own inverse.
+        apply_keystream(keys.data.encrypt_block(seal.nonce), cluster, buf);
+
+        Ok(())
+    }
+
+    /// Encrypt the name of an entry.
+    ///
+    /// `parent` is the object ID of the directory containing the entry. The result is
+    /// deterministic, so it can be used as the key of the entry in the directory. Names of plain
+    /// objects are returned as is.
+    pub fn encrypt_name(&self, protection: Protection, parent: u64, name: &[u8])
+        -> Result<Vec<u8>, Error> {
+        let keys = match self.keys(protection)? {
+            Some(keys) => keys,
+            None => return Ok(name.to_vec()),
+        };
+
+        // Derive the nonce from the plaintext.
+        let nonce = name_nonce(&keys, parent, name);
+
+        let mut out = Vec::with_capacity(NAME_NONCE_SIZE + name.len());
+        out.extend((0..NAME_NONCE_SIZE).map(|i| (nonce >> (i * 8)) as u8));
+        out.extend_from_slice(name);
+        apply_keystream(keys.name, nonce, &mut out[NAME_NONCE_SIZE..]);
+
+        Ok(out)
+    }
+
+    /// Decrypt the name of an entry.
+    ///
+    /// This is the inverse of `encrypt_name()`. It fails with `Corruption` if the name doesn't
+    /// authenticate, which happens if it was tampered with, or if the subtree was unlocked with a
+    /// wrong key. Such failures are reported as integrity events of the directory.
+    pub fn decrypt_name(&self, protection: Protection, parent: u64, encrypted: &[u8])
+        -> Result<Vec<u8>, Error> {
+        let keys = match self.keys(protection)? {
+            Some(keys) => keys,
+            None => return Ok(encrypted.to_vec()),
+        };
+
+        if encrypted.len() < NAME_NONCE_SIZE {
+            return Err(err!(Corruption, "encrypted name too short ({} bytes)", encrypted.len()));
+        }
+
+        // Read the nonce and decrypt.
+        let nonce = encrypted[..NAME_NONCE_SIZE].iter().rev().fold(0, |x, &i| x << 8 | i as u64);
+        let mut name = encrypted[NAME_NONCE_SIZE..].to_vec();
+        apply_keystream(keys.name, nonce, &mut name);
+
+        // Check that the nonce matches the plaintext.
+        if name_nonce(&keys, parent, &name) != nonce {
+            self.integrity.report(integrity::Event {
+                kind: integrity::Kind::Authentication,
+                cluster: None,
+                object: Some(parent),
+            });
+
+            return Err(err!(Corruption, "encrypted name in directory {} failed authentication",
+                            parent));
+        }
+
+        Ok(name)
+    }
+}
+
+/// Feed an encrypted cluster to a CMAC state.
+fn cluster_mac(keys: &Keys, cluster: u64, nonce: u128, buf: &[u8]) -> speck::cmac::Cmac {
+    let mut mac = speck::cmac::Cmac::new(keys.data_mac);
+    mac.update(&(0..8).map(|i| (cluster >> (i * 8)) as u8).collect::<Vec<_>>());
+    mac.update(&(0..16).map(|i| (nonce >> (i * 8)) as u8).collect::<Vec<_>>());
+    mac.update(buf);
+
+    mac
+}
+
+/// Calculate the nonce of a name.
+fn name_nonce(keys: &Keys, parent: u64, name: &[u8]) -> u64 {
+    let mut mac = speck::cmac::Cmac::new(keys.name_mac);
+    mac.update(&(0..8).map(|i| (parent >> (i * 8)) as u8).collect::<Vec<_>>());
+    mac.update(name);
+
+    mac.finalize() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{self, AtomicUsize};
+
+    const SUBTREE: Protection = Protection::Encrypted(42);
+
+    #[test]
+    fn plain() {
+        let keyring = Keyring::new(Arc::default());
+        let mut buf = [1, 2, 3];
+
+        assert!(keyring.is_unlocked(Protection::Plain));
+        assert_eq!(keyring.encrypt_cluster(Protection::Plain, 7, &mut buf).ok(), Some(None));
+        assert_eq!(buf, [1, 2, 3]);
+        assert!(keyring.decrypt_cluster(Protection::Plain, 7, None, &mut buf).is_ok());
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(keyring.encrypt_name(Protection::Plain, 1, b"file").ok(),
+                   Some(b"file".to_vec()));
+    }
+
+    #[test]
+    fn cluster_round_trip() {
+        let keyring = Keyring::new(Arc::default());
+        keyring.unlock(42, 0xDEADBEEF);
+
+        let mut buf = [0xAB; 512];
+        let seal = keyring.encrypt_cluster(SUBTREE, 7, &mut buf).ok().unwrap();
+        assert!(buf.iter().any(|&x| x != 0xAB));
+
+        // Rewriting the cluster gives a different ciphertext.
+        let mut other = [0xAB; 512];
+        let other_seal = keyring.encrypt_cluster(SUBTREE, 7, &mut other).ok().unwrap();
+        assert!(buf[..] != other[..]);
+        assert!(seal != other_seal);
+
+        assert!(keyring.decrypt_cluster(SUBTREE, 7, seal, &mut buf).is_ok());
+        assert!(buf.iter().all(|&x| x == 0xAB));
+        assert!(keyring.decrypt_cluster(SUBTREE, 7, other_seal, &mut other).is_ok());
+        assert!(other.iter().all(|&x| x == 0xAB));
+    }
+
+    #[test]
+    fn cluster_authentication() {
+        let integrity = Arc::new(integrity::Reporter::default());
+        let events = Arc::new(AtomicUsize::new(0));
+        let events_callback = events.clone();
+        integrity.register(move |event| {
+            assert_eq!(event.kind, integrity::Kind::Authentication);
+            events_callback.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+
+        let keyring = Keyring::new(integrity);
+        keyring.unlock(42, 0xDEADBEEF);
+
+        let mut buf = [0xAB; 512];
+        let seal = keyring.encrypt_cluster(SUBTREE, 7, &mut buf).ok().unwrap();
+
+        // Tampered data.
+        let mut tampered = buf;
+        tampered[100] ^= 1;
+        let err = keyring.decrypt_cluster(SUBTREE, 7, seal, &mut tampered).err().unwrap();
+        assert!(err.kind == ::error::Kind::Corruption);
+        // Another pointer.
+        assert!(keyring.decrypt_cluster(SUBTREE, 8, seal, &mut buf.clone()).is_err());
+        // Another nonce.
+        let mut wrong_seal = seal.unwrap();
+        wrong_seal.nonce ^= 1;
+        assert!(keyring.decrypt_cluster(SUBTREE, 7, Some(wrong_seal), &mut buf.clone()).is_err());
+        // No seal.
+        assert!(keyring.decrypt_cluster(SUBTREE, 7, None, &mut buf.clone()).is_err());
+        assert_eq!(events.load(atomic::Ordering::Relaxed), 3);
+
+        // Failed authentication leaves the buffer as is.
+        assert_eq!(tampered[0], buf[0]);
+        assert!(keyring.decrypt_cluster(SUBTREE, 7, seal, &mut buf).is_ok());
+        assert!(buf.iter().all(|&x| x == 0xAB));
+    }
+
+    #[test]
+    fn locked() {
+        let keyring = Keyring::new(Arc::default());
+        let mut buf = [0; 16];
+
+        assert!(!keyring.is_unlocked(SUBTREE));
+        let err = keyring.encrypt_cluster(SUBTREE, 0, &mut buf).err().unwrap();
+        assert!(err.kind == ::error::Kind::Locked);
+
+        keyring.unlock(42, 1);
+        assert!(keyring.is_unlocked(SUBTREE));
+        // Other subtrees stay locked.
+        assert!(!keyring.is_unlocked(Protection::Encrypted(43)));
+
+        keyring.lock(42);
+        assert!(keyring.encrypt_name(SUBTREE, 0, b"x").is_err());
+    }
+
+    #[test]
+    fn name_round_trip() {
+        let keyring = Keyring::new(Arc::default());
+        keyring.unlock(42, 0xDEADBEEF);
+
+        let a = keyring.encrypt_name(SUBTREE, 1, b"secret.txt").ok().unwrap();
+        assert_eq!(a.len(), NAME_NONCE_SIZE + 10);
+        // Deterministic within a directory, but not across directories.
+        assert_eq!(keyring.encrypt_name(SUBTREE, 1, b"secret.txt").ok(), Some(a.clone()));
+        assert!(keyring.encrypt_name(SUBTREE, 2, b"secret.txt").ok() != Some(a.clone()));
+
+        assert_eq!(keyring.decrypt_name(SUBTREE, 1, &a).ok(), Some(b"secret.txt".to_vec()));
+        // The name is bound to its directory.
+        assert!(keyring.decrypt_name(SUBTREE, 2, &a).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let integrity = Arc::new(integrity::Reporter::default());
+        let events = Arc::new(AtomicUsize::new(0));
+        let events_callback = events.clone();
+        integrity.register(move |event| {
+            assert_eq!(event.kind, integrity::Kind::Authentication);
+            assert_eq!(event.object, Some(1));
+            events_callback.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+
+        let keyring = Keyring::new(integrity);
+        keyring.unlock(42, 1);
+        let name = keyring.encrypt_name(SUBTREE, 1, b"secret.txt").ok().unwrap();
+
+        keyring.unlock(42, 2);
+        assert!(keyring.decrypt_name(SUBTREE, 1, &name).is_err());
+        assert_eq!(events.load(atomic::Ordering::Relaxed), 1);
+    }
+}
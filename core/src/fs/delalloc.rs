@@ -0,0 +1,196 @@
+//! Delayed allocation.
+//!
+//! Allocating clusters as soon as data is written fragments files written in small increments
+//! (e.g. logs appended line by line), since every write grabs whichever cluster is free at that
+//! moment, and interleaved writers get interleaved clusters. It also churns the metadata, as the
+//! mapping tree is rewritten for every write.
+//!
+//! Instead, written clusters are kept in a buffer of dirty clusters, and only allocated when the
+//! buffer is flushed. At that point, the logically contiguous runs of dirty clusters are known, so
+//! each run can be allocated as physically contiguous clusters and described by a few extents
+//! (see `Allocator::alloc_extents()`), and the mapping is updated once per run.
+//!
+//! Until they are flushed, dirty clusters exist only in memory, so reads must check the buffer
+//! before the mapping.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use disk;
+use fs::extent::MAX_EXTENT_LEN;
+
+/// A logically contiguous run of dirty clusters.
+pub struct Run {
+    /// The file-relative index of the first cluster of the run.
+    pub start: u64,
+    /// The contents of the clusters of the run, in order.
+    ///
+    /// This is never empty, and never longer than `MAX_EXTENT_LEN`.
+    pub clusters: Vec<Box<disk::SectorBuf>>,
+}
+
+/// A buffer of dirty clusters, pending allocation.
+#[derive(Default)]
+pub struct Buffer {
+    /// The dirty clusters by their file-relative index.
+    clusters: BTreeMap<u64, Box<disk::SectorBuf>>,
+}
+
+impl Buffer {
+    /// Write a cluster.
+    ///
+    /// This replaces the previous content of the cluster at index `index`, if it is already dirty.
+    /// No allocation happens until the buffer is flushed.
+    pub fn write(&mut self, index: u64, buf: Box<disk::SectorBuf>) {
+        self.clusters.insert(index, buf);
+    }
+
+    /// Read a dirty cluster.
+    ///
+    /// This returns `None` if the cluster at index `index` isn't dirty, in which case it must be
+    /// read through the mapping.
+    pub fn read(&self, index: u64) -> Option<&disk::SectorBuf> {
+        self.clusters.get(&index).map(|buf| &**buf)
+    }
+
+    /// Get the number of dirty clusters.
+    pub fn len(&self) -> usize {
+        self.clusters.len()
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.clusters.is_empty()
+    }
+
+    /// Get the range spanned by the dirty clusters.
+    ///
+    /// This is the range from the first to the last dirty cluster, which is empty if the buffer
+    /// is.
+    pub fn span(&self) -> Range<u64> {
+        match (self.clusters.keys().next(), self.clusters.keys().next_back()) {
+            (Some(&first), Some(&last)) => first..last + 1,
+            _ => 0..0,
+        }
+    }
+
+    /// Get the dirty clusters of some range, grouped into runs.
+    ///
+    /// This copies the clusters of `range` and returns them as runs of consecutive indexes, in
+    /// order. Runs longer than `MAX_EXTENT_LEN` are split, such that every run can be allocated as
+    /// a single extent.
+    ///
+    /// The clusters stay in the buffer, so they can still be read while they are allocated. Once
+    /// they are linked into the mapping, they are removed through `clean()`.
+    pub fn runs(&self, range: Range<u64>) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+
+        for (&index, buf) in self.clusters.range(range) {
+            let buf = Box::new(**buf);
+
+            // Check if the cluster extends the last run.
+            if let Some(run) = runs.last_mut() {
+                if run.start + run.clusters.len() as u64 == index
+                    && run.clusters.len() < MAX_EXTENT_LEN as usize {
+                    run.clusters.push(buf);
+                    continue;
+                }
+            }
+
+            // It doesn't, so it starts a new run.
+            runs.push(Run {
+                start: index,
+                clusters: vec![buf],
+            });
+        }
+
+        runs
+    }
+
+    /// Remove the dirty clusters of some range.
+    ///
+    /// This is called when the clusters of `range` are flushed, i.e. allocated and linked into the
+    /// mapping.
+    pub fn clean(&mut self, range: Range<u64>) {
+        let rest = self.clusters.split_off(&range.end);
+        self.clusters.split_off(&range.start);
+        self.clusters.extend(rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(byte: u8) -> Box<disk::SectorBuf> {
+        let mut buf = Box::new(disk::SectorBuf::default());
+        buf[0] = byte;
+        buf
+    }
+
+    #[test]
+    fn read_write() {
+        let mut buffer = Buffer::default();
+        assert!(buffer.is_empty());
+
+        buffer.write(3, cluster(1));
+        buffer.write(3, cluster(2));
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.read(3).map(|buf| buf[0]), Some(2));
+        assert!(buffer.read(4).is_none());
+    }
+
+    #[test]
+    fn runs() {
+        let mut buffer = Buffer::default();
+        // Written out of order, in small increments.
+        for &index in &[5, 1, 2, 9, 0, 6, 10] {
+            buffer.write(index, cluster(index as u8));
+        }
+
+        assert_eq!(buffer.span(), 0..11);
+        let runs = buffer.runs(buffer.span());
+        // The clusters stay until they are cleaned.
+        assert_eq!(buffer.len(), 7);
+
+        let runs: Vec<_> = runs.iter().map(|run| {
+            (run.start, run.clusters.iter().map(|buf| buf[0]).collect::<Vec<_>>())
+        }).collect();
+        assert_eq!(runs, vec![(0, vec![0, 1, 2]), (5, vec![5, 6]), (9, vec![9, 10])]);
+
+        assert_eq!(buffer.runs(2..6).iter().map(|run| run.start).collect::<Vec<_>>(), vec![2, 5]);
+    }
+
+    #[test]
+    fn clean() {
+        let mut buffer = Buffer::default();
+        for &index in &[0, 1, 2, 5, 6, 9] {
+            buffer.write(index, cluster(index as u8));
+        }
+
+        buffer.clean(1..6);
+        assert_eq!(buffer.span(), 0..10);
+        assert_eq!(buffer.len(), 3);
+        assert!(buffer.read(0).is_some());
+        assert!(buffer.read(5).is_none());
+        assert!(buffer.read(6).is_some());
+
+        buffer.clean(buffer.span());
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.span(), 0..0);
+    }
+
+    #[test]
+    fn max_run_len() {
+        let mut buffer = Buffer::default();
+        for index in 0..MAX_EXTENT_LEN as u64 * 2 + 1 {
+            buffer.write(index, cluster(0));
+        }
+
+        let runs = buffer.runs(0..MAX_EXTENT_LEN as u64 * 2 + 1);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[1].start, MAX_EXTENT_LEN as u64);
+        assert_eq!(runs[1].clusters.len(), MAX_EXTENT_LEN as usize);
+        assert_eq!(runs[2].clusters.len(), 1);
+    }
+}
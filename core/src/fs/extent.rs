@@ -0,0 +1,447 @@
+//! Extents.
+//!
+//! An extent is a run of physically contiguous clusters, described by its first cluster and its
+//! length. When a file is allocated in contiguous runs (which is the common case for large files
+//! written sequentially), a handful of extent records can describe what would otherwise take one
+//! page pointer per cluster, and a sequential read of the file can be issued as a few large I/O
+//! operations, instead of being bound by walking the indirection tree cluster by cluster.
+//!
+//! Extents only ever describe uncompressed clusters: A compressed cluster holds several pages, so
+//! there is no way of addressing a page inside of it by a simple cluster offset.
+//!
+//! # Integrity
+//!
+//! Like page pointers, extent records carry the checksum of the data they point to. The checksum
+//! covers the whole extent (the concatenation of its clusters), which is why extents are bounded
+//! by `MAX_EXTENT_LEN`: Reading some part of an extent means reading (and verifying) all of it, so
+//! the extent must be small enough for that to be a cheap, single large I/O.
+//!
+//! # Unwritten extents
+//!
+//! Preallocated clusters (see `Array::allocate()`) are described by unwritten extents: Their
+//! clusters are reserved for the file, but hold no data yet. They read as zeros without any I/O,
+//! and their checksum is meaningless. When data is written into an unwritten extent, the whole
+//! extent is written (padding with zeros), turning it into a regular extent.
+
+use std::cmp;
+use std::ops::Range;
+
+use little_endian;
+use disk::cluster;
+
+/// The size (in bytes) of a serialized extent.
+///
+/// This coincides with the size of a page pointer, allowing extents to be stored in the same node
+/// slots.
+pub const EXTENT_SIZE: usize = 16;
+/// The maximal number of clusters in an extent.
+pub const MAX_EXTENT_LEN: u32 = 256;
+/// The flag of the serialized length marking an extent unwritten.
+const UNWRITTEN_FLAG: u32 = 1 << 31;
+
+/// An extent.
+///
+/// This describes `len` clusters starting at cluster `start`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Extent {
+    /// The first cluster of the extent.
+    pub start: cluster::Pointer,
+    /// The number of clusters in the extent.
+    ///
+    /// This is never zero, and never exceeds `MAX_EXTENT_LEN`.
+    pub len: u32,
+    /// The checksum of the extent.
+    ///
+    /// This is calculated over the concatenated content of the clusters of the extent, through the
+    /// algorithm specified in the disk header.
+    pub checksum: u32,
+    /// Is the extent unwritten?
+    ///
+    /// Unwritten extents are preallocated, and hold no data. They read as zeros, and their
+    /// checksum is unused.
+    pub unwritten: bool,
+}
+
+impl Extent {
+    /// Get the cluster at some offset into the extent.
+    ///
+    /// `offset` must be less than the length of the extent.
+    pub fn cluster(&self, offset: u32) -> cluster::Pointer {
+        debug_assert!(offset < self.len, "Offset {} out of bounds of extent {:?}.", offset, self);
+
+        self.start.offset(offset as u64)
+    }
+
+    /// Is `other` physically following this extent?
+    ///
+    /// This is true if the first cluster of `other` is the cluster right after the last cluster of
+    /// `self`, meaning that they can be read as a single I/O.
+    pub fn is_followed_by(&self, other: &Extent) -> bool {
+        self.start.offset(self.len as u64) == other.start
+    }
+}
+
+impl little_endian::Encode for Extent {
+    fn write_le(self, into: &mut [u8]) {
+        // The lowest bytes are dedicated to the cluster pointer of the first cluster.
+        little_endian::write(into, Some(self.start));
+        // Then the length of the extent (in clusters) follows. The length never uses the highest
+        // bit, so that bit is used to mark unwritten extents.
+        let flag = if self.unwritten { UNWRITTEN_FLAG } else { 0 };
+        little_endian::write(&mut into[cluster::POINTER_SIZE..], self.len | flag);
+        // Lastly, we write the checksum.
+        little_endian::write(&mut into[cluster::POINTER_SIZE + 4..], self.checksum);
+    }
+}
+
+impl little_endian::Decode for Option<Extent> {
+    fn read_le(from: &[u8]) -> Option<Extent> {
+        // A null start cluster represents the lack of an extent.
+        little_endian::read(from).map(|start| {
+            let len: u32 = little_endian::read(&from[cluster::POINTER_SIZE..]);
+
+            Extent {
+                start: start,
+                len: len & !UNWRITTEN_FLAG,
+                checksum: little_endian::read(&from[cluster::POINTER_SIZE + 4..]),
+                unwritten: len & UNWRITTEN_FLAG != 0,
+            }
+        })
+    }
+}
+
+/// A mapping of some file's clusters to extents.
+///
+/// This maps the (file-relative) cluster indexes to the extents containing them. Every entry
+/// covers the indexes from its start up to (but excluding) its start plus the length of the
+/// extent. Indexes not covered by any entry are holes (sparse or not described by extents).
+#[derive(Default)]
+pub struct Map {
+    /// The entries of the map.
+    ///
+    /// This is ordered by the file-relative index of the start of the entry, and the entries never
+    /// overlap.
+    entries: Vec<(u64, Extent)>,
+}
+
+impl Map {
+    /// Get the number of extents in the map.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Find the position of the entry containing some index, if any.
+    fn find(&self, index: u64) -> Result<usize, usize> {
+        match self.entries.binary_search_by_key(&index, |&(start, _)| start) {
+            // The index is the start of an entry.
+            Ok(n) => Ok(n),
+            // The index is before the first entry.
+            Err(0) => Err(0),
+            // The index is after the start of entry `n - 1`, so we check if it is covered by it.
+            Err(n) => {
+                let (start, extent) = self.entries[n - 1];
+                if index < start + extent.len as u64 {
+                    Ok(n - 1)
+                } else {
+                    Err(n)
+                }
+            },
+        }
+    }
+
+    /// Look up the extent containing some file-relative cluster index.
+    ///
+    /// This returns the extent and the offset of `index` into the extent, or `None` if `index` is
+    /// not covered by the map.
+    pub fn get(&self, index: u64) -> Option<(Extent, u32)> {
+        self.find(index).ok().map(|n| {
+            let (start, extent) = self.entries[n];
+            (extent, (index - start) as u32)
+        })
+    }
+
+    /// Insert an extent starting at some file-relative cluster index.
+    ///
+    /// The new extent is merged with the preceding entry, if it is both logically and physically
+    /// contiguous with it and the merged extent doesn't exceed `MAX_EXTENT_LEN`. `checksum` is the
+    /// checksum of the merged extent, which the caller must calculate (as we cannot do so without
+    /// the data). It is only used if a merge happens, and `extent.checksum` is used otherwise.
+    /// Unwritten extents are only merged with unwritten extents.
+    ///
+    /// If an unwritten entry with the same clusters starts at `index`, it is replaced by `extent`.
+    /// This is how preallocated extents are written.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the extent overlaps with an existing entry (other than the unwritten
+    /// entry it replaces).
+    pub fn insert(&mut self, index: u64, extent: Extent, merged_checksum: u32) {
+        let pos = match self.find(index) {
+            Ok(n) => {
+                let (start, ref mut old) = self.entries[n];
+                assert!(start == index && old.unwritten && old.start == extent.start
+                        && old.len == extent.len, "Extent inserted at {} overlaps with an existing \
+                        extent.", index);
+
+                // Write the unwritten extent.
+                *old = extent;
+                return;
+            },
+            Err(pos) => pos,
+        };
+
+        // Make sure it doesn't overlap with the following extent either.
+        if let Some(&(next, _)) = self.entries.get(pos) {
+            assert!(index + extent.len as u64 <= next, "Extent inserted at {} overlaps with an \
+                    existing extent.", index);
+        }
+
+        // Check if we can merge it into the preceding extent.
+        if pos > 0 {
+            let (start, ref mut prev) = self.entries[pos - 1];
+            if start + prev.len as u64 == index
+                && prev.unwritten == extent.unwritten
+                && prev.is_followed_by(&extent)
+                && prev.len + extent.len <= MAX_EXTENT_LEN {
+                // The extents are contiguous, so we extend the old one.
+                prev.len += extent.len;
+                prev.checksum = merged_checksum;
+
+                return;
+            }
+        }
+
+        // We could not merge, so we add a new entry.
+        self.entries.insert(pos, (index, extent));
+    }
+
+    /// Remove the entries of a range of file-relative cluster indexes.
+    ///
+    /// This removes the entries starting in `range`, and returns their extents in order.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if an entry overlaps with the bounds of `range`, as extents cannot be split.
+    pub fn remove(&mut self, range: Range<u64>) -> Vec<Extent> {
+        let first = match self.find(range.start) {
+            Ok(n) => {
+                assert!(self.entries[n].0 == range.start, "Removing range starting inside an \
+                        extent.");
+                n
+            },
+            Err(n) => n,
+        };
+        let end = first + self.entries[first..].iter()
+            .take_while(|&&(start, _)| start < range.end)
+            .count();
+
+        if end > first {
+            let (start, extent) = self.entries[end - 1];
+            assert!(start + extent.len as u64 <= range.end, "Removing range ending inside an \
+                    extent.");
+        }
+
+        self.entries.drain(first..end).map(|(_, extent)| extent).collect()
+    }
+
+    /// Get the holes of a range of file-relative cluster indexes.
+    ///
+    /// This returns the subranges of `range` which are not covered by any entry, in order.
+    pub fn holes(&self, range: Range<u64>) -> Vec<Range<u64>> {
+        let mut holes = Vec::new();
+        let mut index = range.start;
+
+        for (start, extent) in self.indexed_runs(range.clone()) {
+            if index < start {
+                holes.push(index..start);
+            }
+            index = cmp::max(index, start + extent.len as u64);
+        }
+
+        if index < range.end {
+            holes.push(index..range.end);
+        }
+
+        holes
+    }
+
+    /// Get the I/O runs needed to read a range of file-relative cluster indexes.
+    ///
+    /// This returns the extents which must be read, in order, to cover all the indexes of `range`
+    /// which are described by the map. Since extents are verified as a whole, the complete extents
+    /// are returned, even if the range only covers a part of them.
+    pub fn runs(&self, range: Range<u64>) -> Vec<Extent> {
+        self.indexed_runs(range).into_iter().map(|(_, extent)| extent).collect()
+    }
+
+    /// Get the I/O runs needed to read a range of file-relative cluster indexes, along with their
+    /// indexes.
+    ///
+    /// This acts like `runs()`, but pairs every extent with the file-relative index of its first
+    /// cluster.
+    pub fn indexed_runs(&self, range: Range<u64>) -> Vec<(u64, Extent)> {
+        // Find the first entry of interest.
+        let first = match self.find(range.start) {
+            Ok(n) | Err(n) => n,
+        };
+
+        self.entries[first..].iter()
+            .take_while(|&&(start, _)| start < range.end)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(start: u64, len: u32) -> Extent {
+        Extent {
+            start: cluster::Pointer::new(start).unwrap(),
+            len: len,
+            checksum: 0,
+            unwritten: false,
+        }
+    }
+
+    fn unwritten(start: u64, len: u32) -> Extent {
+        Extent {
+            unwritten: true,
+            .. extent(start, len)
+        }
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let mut buf = [0; EXTENT_SIZE];
+        let ext = Extent {
+            start: cluster::Pointer::new(0x0101010101010101).unwrap(),
+            len: 200,
+            checksum: 0xCCCCCCCC,
+            unwritten: false,
+        };
+        little_endian::write(&mut buf, ext);
+        assert_eq!(little_endian::read(&buf), Some(ext));
+
+        let ext = Extent {
+            unwritten: true,
+            .. ext
+        };
+        little_endian::write(&mut buf, ext);
+        assert_eq!(little_endian::read(&buf), Some(ext));
+    }
+
+    #[test]
+    fn null_extent() {
+        assert!(little_endian::read::<Option<Extent>>(&[0; EXTENT_SIZE]).is_none());
+    }
+
+    #[test]
+    fn lookup() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.insert(10, extent(50, 2), 0);
+
+        assert_eq!(map.get(0), Some((extent(100, 4), 0)));
+        assert_eq!(map.get(3), Some((extent(100, 4), 3)));
+        assert_eq!(map.get(4), None);
+        assert_eq!(map.get(11), Some((extent(50, 2), 1)));
+        assert_eq!(map.get(12), None);
+    }
+
+    #[test]
+    fn merge_contiguous() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.insert(4, extent(104, 4), 7);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(7), Some((Extent { checksum: 7, .. extent(100, 8) }, 7)));
+
+        // Logically, but not physically contiguous.
+        map.insert(8, extent(200, 1), 0);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn max_len() {
+        let mut map = Map::default();
+        map.insert(0, extent(1, MAX_EXTENT_LEN), 0);
+        map.insert(MAX_EXTENT_LEN as u64, extent(MAX_EXTENT_LEN as u64 + 1, 1), 0);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn runs() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.insert(10, extent(50, 2), 0);
+        map.insert(20, extent(10, 2), 0);
+
+        assert_eq!(map.runs(2..11), vec![extent(100, 4), extent(50, 2)]);
+        assert_eq!(map.runs(5..9), vec![]);
+        assert_eq!(map.runs(11..100), vec![extent(50, 2), extent(10, 2)]);
+        assert_eq!(map.indexed_runs(11..100), vec![(10, extent(50, 2)), (20, extent(10, 2))]);
+    }
+
+    #[test]
+    fn holes() {
+        let mut map = Map::default();
+        map.insert(2, extent(100, 4), 0);
+        map.insert(10, extent(50, 2), 0);
+
+        assert_eq!(map.holes(0..20), vec![0..2, 6..10, 12..20]);
+        assert_eq!(map.holes(3..11), vec![6..10]);
+        assert_eq!(map.holes(2..6), vec![]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.insert(4, extent(50, 2), 0);
+        map.insert(10, extent(10, 2), 0);
+
+        assert_eq!(map.remove(4..12), vec![extent(50, 2), extent(10, 2)]);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.remove(4..12), vec![]);
+        assert_eq!(map.get(3), Some((extent(100, 4), 3)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn remove_partial() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.remove(0..2);
+    }
+
+    #[test]
+    fn write_unwritten() {
+        let mut map = Map::default();
+        map.insert(0, unwritten(100, 4), 0);
+        // Written and unwritten extents are not merged.
+        map.insert(4, extent(104, 4), 0);
+        assert_eq!(map.len(), 2);
+
+        map.insert(0, Extent { checksum: 7, .. extent(100, 4) }, 0);
+        assert_eq!(map.get(1), Some((Extent { checksum: 7, .. extent(100, 4) }, 1)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlap_unwritten() {
+        let mut map = Map::default();
+        map.insert(0, unwritten(100, 4), 0);
+        // Writing must cover the same clusters.
+        map.insert(0, extent(100, 2), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn overlap() {
+        let mut map = Map::default();
+        map.insert(0, extent(100, 4), 0);
+        map.insert(2, extent(10, 1), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Importing host directory trees.
+//!
+//! Building an image (e.g. for tests or distribution) from an existing directory tree shouldn't
+//! require mounting the image and copying the files through the mount. Instead, the tree is
+//! scanned on the host, and the entries are created in the image directly, in an order such that
+//! every directory is created before its contents.
+//!
+//! The entries are sorted by name within each directory, such that importing the same tree twice
+//! gives identical images.
+//!
+//! File contents are read into the buffer of dirty clusters of the new file (see `fs::delalloc`),
+//! such that each file is allocated contiguously when flushed. Clusters consisting entirely of
+//! zeros are skipped, preserving sparse files as holes.
+
+use std::fs as host;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use disk;
+use fs::delalloc;
+
+/// The kind of an imported entry.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// A directory.
+    Directory,
+    /// A regular file of some length in bytes.
+    File(u64),
+    /// A symbolic link to some target.
+    Symlink(PathBuf),
+}
+
+/// An entry of the imported tree.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    /// The path of the entry, relative to the root of the tree.
+    pub path: PathBuf,
+    /// The kind of the entry.
+    pub kind: Kind,
+    /// The permission bits of the entry.
+    ///
+    /// On hosts without Unix permissions, this is `0o755` for directories and `0o644` otherwise
+    /// (`0o444` for read-only files).
+    pub mode: u32,
+    /// The last modification time of the entry, if supported by the host.
+    pub modified: Option<SystemTime>,
+}
+
+/// Get the permission bits of some entry.
+#[cfg(unix)]
+fn mode(metadata: &host::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Get the permission bits of some entry.
+#[cfg(not(unix))]
+fn mode(metadata: &host::Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o755
+    } else if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Scan a host directory tree.
+///
+/// This returns the entries of the tree rooted at `root` (excluding the root itself), such that
+/// every directory comes before its contents. Symbolic links are not followed.
+pub fn scan<P: AsRef<Path>>(root: P) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    scan_dir(root.as_ref(), Path::new(""), &mut entries)?;
+
+    Ok(entries)
+}
+
+/// Scan a directory, pushing its entries (recursively) to `entries`.
+///
+/// `dir` is the host path of the directory, and `relative` is its path relative to the root.
+fn scan_dir(dir: &Path, relative: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+    // Sort the directory for reproducibility.
+    let mut names = host::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+
+    for name in names {
+        let path = dir.join(&name);
+        let metadata = host::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+
+        let kind = if file_type.is_dir() {
+            Kind::Directory
+        } else if file_type.is_symlink() {
+            Kind::Symlink(host::read_link(&path)?)
+        } else if file_type.is_file() {
+            Kind::File(metadata.len())
+        } else {
+            // Devices, sockets and pipes have no content to import, and cannot be represented.
+            continue;
+        };
+
+        entries.push(Entry {
+            path: relative.join(&name),
+            kind: kind.clone(),
+            mode: mode(&metadata),
+            modified: metadata.modified().ok(),
+        });
+
+        if kind == Kind::Directory {
+            scan_dir(&path, &relative.join(&name), entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a host file into a buffer of dirty clusters.
+///
+/// This reads `file` cluster by cluster into `buffer`, skipping clusters consisting entirely of
+/// zeros (which are left as holes). The last cluster is padded with zeros. The number of bytes
+/// read is returned.
+pub fn read_file<R: Read>(mut file: R, buffer: &mut delalloc::Buffer) -> io::Result<u64> {
+    let mut len = 0;
+
+    for index in 0.. {
+        // Fill a cluster. `read` may return less than asked for, so we loop until the cluster is
+        // full or the file is over.
+        let mut buf = Box::new([0; disk::SECTOR_SIZE]);
+        let mut filled = 0;
+        while filled < disk::SECTOR_SIZE {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if filled == 0 {
+            break;
+        }
+        len += filled as u64;
+
+        if buf.iter().any(|&byte| byte != 0) {
+            buffer.write(index, buf);
+        }
+
+        if filled < disk::SECTOR_SIZE {
+            break;
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Write;
+
+    #[test]
+    fn scan_tree() {
+        let root = env::temp_dir().join(format!("tfs-import-{}", ::std::process::id()));
+        host::create_dir_all(root.join("b/c")).unwrap();
+        host::File::create(root.join("b/c/file")).unwrap().write_all(b"hello").unwrap();
+        host::File::create(root.join("a")).unwrap();
+        host::create_dir(root.join("d")).unwrap();
+
+        let entries = scan(&root).unwrap();
+        let _ = host::remove_dir_all(&root);
+
+        let entries: Vec<_> = entries.into_iter().map(|entry| (entry.path, entry.kind)).collect();
+        assert_eq!(entries, vec![
+            (PathBuf::from("a"), Kind::File(0)),
+            (PathBuf::from("b"), Kind::Directory),
+            (PathBuf::from("b/c"), Kind::Directory),
+            (PathBuf::from("b/c/file"), Kind::File(5)),
+            (PathBuf::from("d"), Kind::Directory),
+        ]);
+    }
+
+    #[test]
+    fn sparse_file() {
+        let mut data = vec![0; disk::SECTOR_SIZE * 3 + 10];
+        data[1] = 1;
+        data[disk::SECTOR_SIZE * 3] = 2;
+
+        let mut buffer = delalloc::Buffer::default();
+        assert_eq!(read_file(&data[..], &mut buffer).unwrap(), data.len() as u64);
+
+        // The zero clusters are holes.
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.read(0).map(|buf| buf[1]), Some(1));
+        assert!(buffer.read(1).is_none());
+        assert_eq!(buffer.read(3).map(|buf| buf[0]), Some(2));
+    }
+}
@@ -1,19 +1,52 @@
 mod array;
+mod crypt;
+mod delalloc;
+mod extent;
+mod import;
 mod object;
+mod range_lock;
+mod tier;
+mod usage;
+mod verify;
+mod watch;
 
 pub use self::object::Object;
 
 use {type_name, cbloom, alloc, Error};
 use alloc::page;
 use futures::Future;
+use std::sync::mpsc;
 use disk::{self, Disk};
 
 struct State<D> {
     alloc: alloc::Allocator<D>,
     reachable: cbloom::Filter,
+    /// The access tracker for tiering.
+    tiering: tier::Tracker,
+    /// The change-notification subscriptions.
+    watchers: watch::Registry,
+    /// The keys of the unlocked encrypted subtrees.
+    ///
+    /// This reports to the integrity reporter of the allocator (see `Allocator::integrity()`).
+    keyring: crypt::Keyring,
 }
 
 impl<D: Disk> State<D> {
+    /// Create the state of a filesystem on top of some allocator.
+    ///
+    /// `reachable` is the (empty) filter of reachable pages, and `tiering` the tiering policy
+    /// (see `tier::Policy::from_mount_options()`).
+    pub fn new(alloc: alloc::Allocator<D>, reachable: cbloom::Filter, tiering: tier::Policy)
+        -> State<D> {
+        State {
+            keyring: crypt::Keyring::new(alloc.integrity().clone()),
+            alloc: alloc,
+            reachable: reachable,
+            tiering: tier::Tracker::new(tiering),
+            watchers: watch::Registry::default(),
+        }
+    }
+
     pub fn alloc(
         &self,
         buf: disk::SectorBuf,
@@ -26,6 +59,28 @@ impl<D: Disk> State<D> {
         Ok(self.alloc.alloc(buf).map(|ptr| self.visit(ptr)))
     }
 
+    /// Watch some path for changes.
+    ///
+    /// This returns a receiver of the events of the kinds in `mask` on the object at `path` and
+    /// (if it is a directory) its entries. See `watch::Registry::watch()`.
+    pub fn watch(&self, path: &str, mask: watch::Mask) -> mpsc::Receiver<watch::Event> {
+        debug!(self, "watching path"; "path" => path);
+
+        self.watchers.watch(path, mask)
+    }
+
+    /// Make every completed operation on the filesystem durable.
+    ///
+    /// This is the equivalent of `syncfs`: When the returned future completes, every write to
+    /// the filesystem which had completed before this was called survives a crash. Data still
+    /// buffered in the objects (see `fs::delalloc`) is not covered; that is what the per-object
+    /// `sync()` is for.
+    pub fn sync(&self) -> future!(()) {
+        debug!(self, "syncing the filesystem");
+
+        self.alloc.sync()
+    }
+
     pub fn set_reachable(&self, ptr: page::Pointer) {
         self.reachable.insert(ptr);
     }
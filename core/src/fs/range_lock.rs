@@ -0,0 +1,220 @@
+//! Range write serialization.
+//!
+//! Writes to a file must be serialized when they overlap (otherwise the result could be a mix of
+//! both), but locking the whole file would serialize writers of disjoint ranges of large files
+//! (e.g. database files or VM images) for no reason. Instead, writers lock the range they write,
+//! and only wait for the writers of overlapping ranges.
+//!
+//! The lock doesn't care about the unit of the ranges. Arrays lock ranges of cluster indexes (see
+//! `Array::lock_range()`), so byte ranges must be rounded outwards to whole clusters first, and
+//! writers of distinct bytes of the same cluster are serialized.
+//!
+//! Overlapping writers are ordered by their arrival: A writer waits for every earlier writer of an
+//! overlapping range, including those which are still waiting themselves. Hence writers are never
+//! starved, and the writes are applied in the order they were issued.
+//!
+//! This is internal to the library, and independent of POSIX advisory locks.
+
+use std::ops::Range;
+use std::sync::{Condvar, Mutex};
+
+/// Do two ranges overlap?
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// A writer, either waiting for or holding its range.
+struct Writer {
+    /// The arrival ticket of the writer.
+    ticket: u64,
+    /// The locked range.
+    range: Range<u64>,
+}
+
+/// The state of a range lock.
+#[derive(Default)]
+struct State {
+    /// The ticket of the next writer.
+    next_ticket: u64,
+    /// The writers, in order of arrival.
+    writers: Vec<Writer>,
+}
+
+/// A range lock.
+#[derive(Default)]
+pub struct RangeLock {
+    /// The state of the lock.
+    state: Mutex<State>,
+    /// The condition variable notified whenever a range is unlocked.
+    unlocked: Condvar,
+}
+
+impl RangeLock {
+    /// Lock some range.
+    ///
+    /// This blocks until every earlier writer of an overlapping range has unlocked. The range is
+    /// unlocked when the returned guard is dropped. Empty ranges never wait.
+    pub fn lock(&self, range: Range<u64>) -> Guard {
+        let mut state = self.state.lock().unwrap();
+
+        // Take a ticket and queue up.
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.writers.push(Writer {
+            ticket: ticket,
+            range: range.clone(),
+        });
+
+        // Wait until no earlier writer overlaps. The writers are in order of arrival, so these are
+        // the ones before ours.
+        while state.writers.iter()
+            .take_while(|writer| writer.ticket != ticket)
+            .any(|writer| overlaps(&writer.range, &range)) {
+            state = self.unlocked.wait(state).unwrap();
+        }
+
+        Guard {
+            lock: self,
+            ticket: ticket,
+        }
+    }
+
+    /// Try to lock some range without blocking.
+    ///
+    /// This returns `None` if any writer (holding or waiting) has an overlapping range.
+    pub fn try_lock(&self, range: Range<u64>) -> Option<Guard> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.writers.iter().any(|writer| overlaps(&writer.range, &range)) {
+            return None;
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.writers.push(Writer {
+            ticket: ticket,
+            range: range,
+        });
+
+        Some(Guard {
+            lock: self,
+            ticket: ticket,
+        })
+    }
+
+    /// Unlock the range of some writer.
+    fn unlock(&self, ticket: u64) {
+        self.state.lock().unwrap().writers.retain(|writer| writer.ticket != ticket);
+        // Wake up the waiting writers to let them check if they can proceed.
+        self.unlocked.notify_all();
+    }
+}
+
+/// A guard of a locked range.
+///
+/// The range is unlocked when this is dropped.
+pub struct Guard<'a> {
+    /// The lock the range belongs to.
+    lock: &'a RangeLock,
+    /// The ticket of the writer holding the range.
+    ticket: u64,
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        self.lock.unlock(self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Wait until some number of writers are queued up (holding or waiting).
+    fn wait_for_writers(lock: &RangeLock, writers: usize) {
+        while lock.state.lock().unwrap().writers.len() < writers {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn disjoint() {
+        let lock = RangeLock::default();
+        let _a = lock.lock(0..10);
+        let _b = lock.lock(10..20);
+
+        assert!(lock.try_lock(20..30).is_some());
+        assert!(lock.try_lock(5..15).is_none());
+        assert!(lock.try_lock(0..0).is_some());
+    }
+
+    #[test]
+    fn unlock() {
+        let lock = RangeLock::default();
+        drop(lock.lock(0..10));
+
+        assert!(lock.try_lock(0..10).is_some());
+    }
+
+    #[test]
+    fn overlapping_waits() {
+        let lock = Arc::new(RangeLock::default());
+        let (done_tx, done_rx) = mpsc::channel();
+        let guard = lock.lock(0..100);
+
+        let thread = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock(50..150);
+                done_tx.send(()).unwrap();
+            })
+        };
+
+        // The writer is queued up, but can't proceed while we hold the range.
+        wait_for_writers(&lock, 2);
+        assert!(done_rx.try_recv().is_err());
+
+        drop(guard);
+        done_rx.recv().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn arrival_order() {
+        let lock = Arc::new(RangeLock::default());
+        let (order_tx, order_rx) = mpsc::channel();
+        let guard = lock.lock(0..10);
+
+        // Queue up a writer overlapping both the held range and the next writer.
+        let first = {
+            let lock = lock.clone();
+            let order_tx = order_tx.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock(5..20);
+                order_tx.send(1).unwrap();
+            })
+        };
+        wait_for_writers(&lock, 2);
+
+        // This doesn't overlap the held range, but must wait for the earlier writer.
+        assert!(lock.try_lock(15..25).is_none());
+        let second = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock(15..25);
+                order_tx.send(2).unwrap();
+            })
+        };
+        wait_for_writers(&lock, 3);
+        assert!(order_rx.try_recv().is_err());
+
+        drop(guard);
+        first.join().unwrap();
+        second.join().unwrap();
+        assert_eq!(order_rx.iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Hot/cold data tiering.
+//!
+//! Pools consisting of devices of different speed (e.g. an SSD and an HDD) can have the data
+//! which is accessed frequently (hot data) placed on the fast device, and the rest (cold data) on
+//! the slow device. This module implements the policy: It tracks the access frequency of every
+//! extent, and plans migrations of extents residing on the wrong tier. The migrations are
+//! executed by a background task.
+//!
+//! The access frequencies decay over time (halving every `Policy::decay_period` accesses), such
+//! that data which was hot long ago eventually cools down.
+//!
+//! Tiering is optional, and disabled by default.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use disk::cluster;
+use fs::extent::Extent;
+use Error;
+
+/// A storage tier.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tier {
+    /// The fast device(s) of the pool.
+    Fast,
+    /// The slow device(s) of the pool.
+    Slow,
+}
+
+/// A tiering policy.
+///
+/// The thresholds are set through the mount options (see `Policy::from_mount_options()`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Policy {
+    /// Is tiering enabled?
+    pub enabled: bool,
+    /// The number of accesses making an extent hot.
+    ///
+    /// Hot extents on the slow tier are migrated to the fast tier.
+    pub hot_threshold: u32,
+    /// The number of accesses under which an extent is cold.
+    ///
+    /// Cold extents on the fast tier are migrated to the slow tier. This is always less than
+    /// `hot_threshold`, such that extents don't bounce back and forth between the tiers.
+    pub cold_threshold: u32,
+    /// The number of accesses between every halving of the access counts.
+    pub decay_period: u64,
+    /// The maximal number of migrations planned at once.
+    pub max_migrations: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            enabled: false,
+            hot_threshold: 8,
+            cold_threshold: 2,
+            decay_period: 1 << 16,
+            max_migrations: 64,
+        }
+    }
+}
+
+impl Policy {
+    /// Parse the tiering policy from the mount options.
+    ///
+    /// `options` is a comma-separated list of mount options. The following options are
+    /// recognized:
+    ///
+    /// - `tiering` enables tiering.
+    /// - `tier_hot=N` sets the hot threshold.
+    /// - `tier_cold=N` sets the cold threshold.
+    /// - `tier_decay=N` sets the decay period.
+    /// - `tier_max_migrations=N` sets the maximal number of migrations planned at once.
+    ///
+    /// Other options are ignored, and unspecified parameters keep their default value.
+    pub fn from_mount_options(options: &str) -> Result<Policy, Error> {
+        let mut policy = Policy::default();
+
+        for option in options.split(',') {
+            let mut split = option.splitn(2, '=');
+            let key = split.next().unwrap().trim();
+            // Parse the value of the option as a number.
+            let num = || -> Result<u64, Error> {
+                split.clone().next()
+                    .and_then(|x| x.trim().parse().ok())
+                    .ok_or(err!(InvalidOption, "invalid value of mount option {}", key))
+            };
+
+            match key {
+                "tiering" => policy.enabled = true,
+                "tier_hot" => policy.hot_threshold = num()? as u32,
+                "tier_cold" => policy.cold_threshold = num()? as u32,
+                "tier_decay" => policy.decay_period = num()?,
+                "tier_max_migrations" => policy.max_migrations = num()? as usize,
+                // Not ours.
+                _ => (),
+            }
+        }
+
+        if policy.cold_threshold >= policy.hot_threshold {
+            return Err(err!(InvalidOption, "the cold threshold ({}) must be less than the hot \
+                            threshold ({})", policy.cold_threshold, policy.hot_threshold));
+        }
+        if policy.decay_period == 0 {
+            return Err(err!(InvalidOption, "the decay period must be non-zero"));
+        }
+
+        Ok(policy)
+    }
+}
+
+/// A planned migration of an extent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Migration {
+    /// The extent to migrate.
+    pub extent: Extent,
+    /// The tier to migrate it to.
+    pub to: Tier,
+}
+
+/// The state of an access tracker.
+#[derive(Default)]
+struct TrackerState {
+    /// The access count of every extent, indexed by the first cluster of the extent.
+    ///
+    /// Extents with no accesses are not in the map.
+    counts: HashMap<cluster::Pointer, u32>,
+    /// The number of accesses since the last decay.
+    since_decay: u64,
+}
+
+/// An access tracker.
+///
+/// This tracks the access frequency of extents.
+pub struct Tracker {
+    /// The tiering policy.
+    policy: Policy,
+    /// The state of the tracker.
+    state: Mutex<TrackerState>,
+}
+
+impl Tracker {
+    /// Create a new tracker with some policy.
+    pub fn new(policy: Policy) -> Tracker {
+        Tracker {
+            policy: policy,
+            state: Mutex::new(TrackerState::default()),
+        }
+    }
+
+    /// Record an access to some extent.
+    pub fn record(&self, extent: &Extent) {
+        if !self.policy.enabled {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        {
+            let count = state.counts.entry(extent.start).or_insert(0);
+            *count = count.saturating_add(1);
+        }
+
+        // Decay the counts, if the period is over.
+        state.since_decay += 1;
+        if state.since_decay >= self.policy.decay_period {
+            state.since_decay = 0;
+            state.counts.retain(|_, count| {
+                *count /= 2;
+                // Drop the extents which cooled down entirely.
+                *count != 0
+            });
+        }
+    }
+
+    /// Get the access count of some extent.
+    pub fn temperature(&self, extent: &Extent) -> u32 {
+        self.state.lock().unwrap().counts.get(&extent.start).cloned().unwrap_or(0)
+    }
+
+    /// Forget some extent.
+    ///
+    /// This should be called when the extent is freed or migrated (as it changes location).
+    pub fn forget(&self, extent: &Extent) {
+        self.state.lock().unwrap().counts.remove(&extent.start);
+    }
+
+    /// Plan the migrations of some extents.
+    ///
+    /// `extents` yields the extents along with the tier they currently reside on. This returns the
+    /// extents on the wrong tier (hot extents on the slow tier and cold extents on the fast
+    /// tier), at most `Policy::max_migrations` of them.
+    pub fn plan<I>(&self, extents: I) -> Vec<Migration>
+    where I: IntoIterator<Item = (Extent, Tier)> {
+        if !self.policy.enabled {
+            return Vec::new();
+        }
+
+        let state = self.state.lock().unwrap();
+        extents.into_iter().filter_map(|(extent, tier)| {
+            let temperature = state.counts.get(&extent.start).cloned().unwrap_or(0);
+
+            match tier {
+                Tier::Slow if temperature >= self.policy.hot_threshold => Some(Migration {
+                    extent: extent,
+                    to: Tier::Fast,
+                }),
+                Tier::Fast if temperature < self.policy.cold_threshold => Some(Migration {
+                    extent: extent,
+                    to: Tier::Slow,
+                }),
+                _ => None,
+            }
+        }).take(self.policy.max_migrations).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(start: u64) -> Extent {
+        Extent {
+            start: cluster::Pointer::new(start).unwrap(),
+            len: 1,
+            checksum: 0,
+            unwritten: false,
+        }
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            enabled: true,
+            hot_threshold: 4,
+            cold_threshold: 1,
+            decay_period: 1000,
+            max_migrations: 10,
+        }
+    }
+
+    #[test]
+    fn mount_options() {
+        assert_eq!(Policy::from_mount_options("ro,noatime").unwrap(), Policy::default());
+        assert_eq!(Policy::from_mount_options("tiering,tier_hot=4,tier_cold=1,tier_decay=1000,\
+                                               tier_max_migrations=10").unwrap(), policy());
+
+        assert!(Policy::from_mount_options("tier_hot=x").is_err());
+        assert!(Policy::from_mount_options("tier_hot=2,tier_cold=2").is_err());
+        assert!(Policy::from_mount_options("tier_decay=0").is_err());
+    }
+
+    #[test]
+    fn plan() {
+        let tracker = Tracker::new(policy());
+        for _ in 0..4 {
+            tracker.record(&extent(1));
+            tracker.record(&extent(2));
+        }
+        tracker.record(&extent(3));
+
+        assert_eq!(tracker.plan(vec![(extent(1), Tier::Slow), (extent(2), Tier::Fast),
+                                     (extent(3), Tier::Fast), (extent(4), Tier::Fast),
+                                     (extent(5), Tier::Slow)]),
+                   vec![Migration { extent: extent(1), to: Tier::Fast },
+                        Migration { extent: extent(4), to: Tier::Slow }]);
+    }
+
+    #[test]
+    fn decay() {
+        let tracker = Tracker::new(Policy { decay_period: 4, .. policy() });
+        tracker.record(&extent(1));
+        tracker.record(&extent(1));
+        tracker.record(&extent(1));
+        tracker.record(&extent(2));
+
+        // The period is over, so the counts were halved.
+        assert_eq!(tracker.temperature(&extent(1)), 1);
+        assert_eq!(tracker.temperature(&extent(2)), 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let tracker = Tracker::new(Policy::default());
+        tracker.record(&extent(1));
+
+        assert_eq!(tracker.temperature(&extent(1)), 0);
+        assert!(tracker.plan(vec![(extent(1), Tier::Fast)]).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+//! Disk usage accounting.
+//!
+//! Compression and deduplication make the space a file occupies on the disk (its physical size)
+//! differ from its length (its logical size): Several pages can share a cluster when they compress
+//! well, and duplicate pages share the same page pointer altogether.
+//!
+//! Both sizes can be computed from metadata alone: Every page (or cluster of an extent) accounts
+//! for a sector of logical size, while the physical size is the number of distinct clusters
+//! referenced. Hence, computing the usage walks the mapping trees, but never reads or decompresses
+//! any data.
+//!
+//! Since clusters are shared between files (and subtrees), the physical sizes are not additive:
+//! The physical size of a directory is that of the union of the clusters of its entries, which
+//! is why the accumulated clusters are kept (rather than just their number) until the breakdown
+//! is computed.
+
+use std::collections::BTreeSet;
+
+use disk::{self, cluster};
+use alloc::page;
+use fs::extent::Extent;
+
+/// The usage of some object or subtree.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Usage {
+    /// The logical size in bytes.
+    ///
+    /// This is the size of the data before compression and deduplication.
+    pub logical: u64,
+    /// The physical size in bytes.
+    ///
+    /// This is the size of the clusters holding the data.
+    pub physical: u64,
+}
+
+impl Usage {
+    /// Get the number of bytes saved by compression and deduplication.
+    pub fn saved(&self) -> u64 {
+        self.logical.saturating_sub(self.physical)
+    }
+}
+
+/// A usage counter.
+///
+/// This accumulates the pages and extents of some objects.
+#[derive(Clone, Default)]
+pub struct Counter {
+    /// The logical size in bytes.
+    logical: u64,
+    /// The distinct clusters referenced.
+    clusters: BTreeSet<cluster::Pointer>,
+}
+
+impl Counter {
+    /// Count a page.
+    pub fn add_page(&mut self, ptr: &page::Pointer) {
+        self.logical += disk::SECTOR_SIZE as u64;
+        self.clusters.insert(ptr.cluster());
+    }
+
+    /// Count an extent.
+    ///
+    /// Unwritten extents hold no data, so they only count physically.
+    pub fn add_extent(&mut self, extent: &Extent) {
+        if !extent.unwritten {
+            self.logical += extent.len as u64 * disk::SECTOR_SIZE as u64;
+        }
+        self.clusters.extend((0..extent.len).map(|offset| extent.cluster(offset)));
+    }
+
+    /// Add the counts of another counter.
+    ///
+    /// Clusters counted by both are only counted once physically.
+    pub fn merge(&mut self, other: &Counter) {
+        self.logical += other.logical;
+        self.clusters.extend(other.clusters.iter().cloned());
+    }
+
+    /// Get the usage counted so far.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            logical: self.logical,
+            physical: self.clusters.len() as u64 * disk::SECTOR_SIZE as u64,
+        }
+    }
+}
+
+/// A node of a tree of objects to compute the usage breakdown of.
+///
+/// This is built by the directory traversal, with the counter of each object's own pages.
+pub struct Node {
+    /// The name of the object.
+    pub name: String,
+    /// The usage of the object itself (excluding its children).
+    pub counter: Counter,
+    /// The children of the object (if it is a directory).
+    pub children: Vec<Node>,
+}
+
+/// A usage breakdown of a subtree.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Breakdown {
+    /// The name of the root of the subtree.
+    pub name: String,
+    /// The total usage of the subtree.
+    pub usage: Usage,
+    /// The breakdowns of the subtrees of the children.
+    ///
+    /// This is sorted by descending physical size, such that the subtrees taking up the most space
+    /// come first.
+    pub children: Vec<Breakdown>,
+}
+
+impl Node {
+    /// Compute the usage breakdown of the subtree.
+    pub fn breakdown(&self) -> Breakdown {
+        self.breakdown_with_counter().0
+    }
+
+    /// Compute the usage breakdown of the subtree along with its total counter.
+    fn breakdown_with_counter(&self) -> (Breakdown, Counter) {
+        let mut counter = self.counter.clone();
+        let mut children = Vec::with_capacity(self.children.len());
+
+        for child in &self.children {
+            let (breakdown, child_counter) = child.breakdown_with_counter();
+            counter.merge(&child_counter);
+            children.push(breakdown);
+        }
+
+        children.sort_by(|a, b| b.usage.physical.cmp(&a.usage.physical));
+
+        (Breakdown {
+            name: self.name.clone(),
+            usage: counter.usage(),
+            children: children,
+        }, counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = disk::SECTOR_SIZE as u64;
+
+    fn extent(start: u64, len: u32) -> Extent {
+        Extent {
+            start: cluster::Pointer::new(start).unwrap(),
+            len: len,
+            checksum: 0,
+            unwritten: false,
+        }
+    }
+
+    fn node(name: &str, extents: &[Extent], children: Vec<Node>) -> Node {
+        let mut counter = Counter::default();
+        for extent in extents {
+            counter.add_extent(extent);
+        }
+
+        Node {
+            name: name.to_owned(),
+            counter: counter,
+            children: children,
+        }
+    }
+
+    #[test]
+    fn extents() {
+        let mut counter = Counter::default();
+        counter.add_extent(&extent(10, 4));
+        counter.add_extent(&extent(20, 2));
+
+        assert_eq!(counter.usage(), Usage {
+            logical: 6 * SECTOR,
+            physical: 6 * SECTOR,
+        });
+        assert_eq!(counter.usage().saved(), 0);
+    }
+
+    #[test]
+    fn deduplicated() {
+        let mut counter = Counter::default();
+        counter.add_extent(&extent(10, 4));
+        // The same clusters referenced again (e.g. a deduplicated or reflinked file).
+        counter.add_extent(&extent(12, 2));
+
+        assert_eq!(counter.usage(), Usage {
+            logical: 6 * SECTOR,
+            physical: 4 * SECTOR,
+        });
+        assert_eq!(counter.usage().saved(), 2 * SECTOR);
+    }
+
+    #[test]
+    fn breakdown() {
+        let tree = node("/", &[extent(1, 1)], vec![
+            node("small", &[extent(10, 1)], Vec::new()),
+            node("big", &[extent(20, 8)], vec![
+                // Shares clusters with its sibling subtree.
+                node("copy", &[extent(10, 1)], Vec::new()),
+            ]),
+        ]);
+
+        let breakdown = tree.breakdown();
+        assert_eq!(breakdown.usage, Usage {
+            logical: 11 * SECTOR,
+            physical: 10 * SECTOR,
+        });
+
+        // Sorted by physical size.
+        assert_eq!(breakdown.children[0].name, "big");
+        assert_eq!(breakdown.children[0].usage, Usage {
+            logical: 9 * SECTOR,
+            physical: 9 * SECTOR,
+        });
+        assert_eq!(breakdown.children[1].name, "small");
+        assert_eq!(breakdown.children[0].children[0].usage.physical, SECTOR);
+    }
+}
@@ -0,0 +1,133 @@
+//! On-demand integrity verification.
+//!
+//! Scrubbing verifies the whole pool, which takes time proportional to the size of the pool. After
+//! suspected corruption (e.g. an unclean shutdown or a flaky cable), applications rather want to
+//! verify some particular file, which is what this is for: Every cluster of the file is read and
+//! checked against its checksum, and the bad clusters are collected into a report.
+//!
+//! Verification only detects and reports corruption: Data is read through the usual read path,
+//! which doesn't heal corrupt clusters from the redundancy of the vdev stack (yet), so every bad
+//! cluster ends up in the report, and is left as is.
+//!
+//! There is no path resolution yet (the directory layer doesn't exist), so the entry point is
+//! `Array::verify()`, which verifies the clusters of a single file. Once paths can be resolved,
+//! `fs::verify(path)` simply looks up the array of the file and verifies it.
+
+use std::ops::Range;
+
+use error::Kind;
+use Error;
+
+/// The outcome of checking some run of clusters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Check {
+    /// The file-relative index of the first cluster of the run.
+    pub start: u64,
+    /// The number of clusters in the run.
+    pub len: u64,
+    /// Did the clusters match their checksum?
+    pub ok: bool,
+}
+
+impl Check {
+    /// Create a check from the result of reading some run of clusters.
+    ///
+    /// Corruption is recorded as a failed check, whereas other errors (e.g. I/O failure) are
+    /// returned, as they say nothing about the integrity of the data.
+    pub fn from_result<T>(res: Result<T, Error>, start: u64, len: u64) -> Result<Check, Error> {
+        match res {
+            Ok(_) => Ok(Check { start: start, len: len, ok: true }),
+            Err(ref err) if err.kind == Kind::Corruption => Ok(Check {
+                start: start,
+                len: len,
+                ok: false,
+            }),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A verification report.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Report {
+    /// The number of clusters checked.
+    pub checked: u64,
+    /// The ranges of bad clusters.
+    ///
+    /// These are file-relative cluster indexes, in ascending order. Adjacent bad clusters are
+    /// merged into a single range.
+    pub bad: Vec<Range<u64>>,
+}
+
+impl Report {
+    /// Create a report from a number of checks.
+    ///
+    /// The checks can be in any order, but must not overlap.
+    pub fn from_checks(mut checks: Vec<Check>) -> Report {
+        // The checks complete in arbitrary order, so we sort them to be able to merge the ranges.
+        checks.sort_by_key(|check| check.start);
+
+        let mut report = Report::default();
+        for check in checks {
+            report.checked += check.len;
+
+            if !check.ok {
+                let end = check.start + check.len;
+                match report.bad.last_mut() {
+                    // The run follows the last bad range, so we extend it.
+                    Some(last) if last.end == check.start => last.end = end,
+                    _ => report.bad.push(check.start..end),
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Did every cluster pass verification?
+    pub fn is_ok(&self) -> bool {
+        self.bad.is_empty()
+    }
+
+    /// Get the number of bad clusters.
+    pub fn bad_clusters(&self) -> u64 {
+        self.bad.iter().map(|range| range.end - range.start).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(start: u64, len: u64, ok: bool) -> Check {
+        Check { start: start, len: len, ok: ok }
+    }
+
+    #[test]
+    fn merge() {
+        let report = Report::from_checks(vec![
+            check(10, 4, false),
+            check(0, 1, true),
+            check(1, 2, false),
+            check(3, 1, false),
+            check(4, 6, true),
+            check(14, 1, false),
+        ]);
+
+        assert_eq!(report.checked, 15);
+        assert_eq!(report.bad, vec![1..4, 10..15]);
+        assert_eq!(report.bad_clusters(), 8);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn from_result() {
+        let corrupt: Result<(), Error> = Err(err!(Corruption, "bad"));
+        let failed: Result<(), Error> = Err(err!(Implementation, "oops"));
+
+        assert_eq!(Check::from_result(Ok(()), 2, 1).ok(), Some(check(2, 1, true)));
+        assert_eq!(Check::from_result(corrupt, 2, 1).ok(), Some(check(2, 1, false)));
+        assert!(Check::from_result(failed, 2, 1).is_err());
+        assert!(Report::from_checks(vec![check(0, 3, true)]).is_ok());
+    }
+}
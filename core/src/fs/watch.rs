@@ -0,0 +1,211 @@
+//! Change notification.
+//!
+//! Applications built on TFS (sync daemons, indexing services) need to know when the tree changes,
+//! and polling directory trees scales badly. Instead, they can subscribe to the changes of some
+//! path (see `Registry::watch()`), and receive the events generated by the operation layer through
+//! a channel.
+//!
+//! Watching a path covers the object at the path itself and (if it is a directory) its immediate
+//! entries, like inotify. Subtrees must be watched directory by directory.
+
+use std::sync::{mpsc, Mutex};
+
+/// A mask of event kinds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mask(u8);
+
+impl Mask {
+    /// Objects created.
+    pub const CREATE: Mask = Mask(1);
+    /// Objects modified.
+    pub const MODIFY: Mask = Mask(1 << 1);
+    /// Objects deleted.
+    pub const DELETE: Mask = Mask(1 << 2);
+    /// Objects renamed.
+    pub const RENAME: Mask = Mask(1 << 3);
+    /// Every kind of event.
+    pub const ALL: Mask = Mask(0b1111);
+
+    /// Combine two masks.
+    pub fn with(self, other: Mask) -> Mask {
+        Mask(self.0 | other.0)
+    }
+
+    /// Does this mask contain every kind of `other`?
+    pub fn contains(self, other: Mask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// A change event.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// An object was created at some path.
+    Create(String),
+    /// The object at some path was modified.
+    Modify(String),
+    /// The object at some path was deleted.
+    Delete(String),
+    /// An object was renamed.
+    Rename {
+        /// The old path of the object.
+        from: String,
+        /// The new path of the object.
+        to: String,
+    },
+}
+
+impl Event {
+    /// Get the mask of the kind of this event.
+    pub fn mask(&self) -> Mask {
+        match *self {
+            Event::Create(_) => Mask::CREATE,
+            Event::Modify(_) => Mask::MODIFY,
+            Event::Delete(_) => Mask::DELETE,
+            Event::Rename { .. } => Mask::RENAME,
+        }
+    }
+
+    /// Does a watch of `path` cover this event?
+    ///
+    /// Renames are covered by the watches of both the old and the new path.
+    fn is_covered_by(&self, path: &str) -> bool {
+        match *self {
+            Event::Create(ref x) | Event::Modify(ref x) | Event::Delete(ref x) => covers(path, x),
+            Event::Rename { ref from, ref to } => covers(path, from) || covers(path, to),
+        }
+    }
+}
+
+/// Does a watch of `watched` cover `path`?
+///
+/// This is the case if the paths are equal, or `watched` is the parent directory of `path`.
+fn covers(watched: &str, path: &str) -> bool {
+    let watched = watched.trim_right_matches('/');
+    let path = path.trim_right_matches('/');
+
+    if path == watched {
+        return true;
+    }
+
+    match path.rfind('/') {
+        // Compare the parent of `path` with the watched path.
+        Some(n) => &path[..n] == watched,
+        None => false,
+    }
+}
+
+/// A subscription.
+struct Subscription {
+    /// The watched path.
+    path: String,
+    /// The kinds of events the subscriber is interested in.
+    mask: Mask,
+    /// The sending end of the subscriber's channel.
+    sender: mpsc::Sender<Event>,
+}
+
+/// A registry of subscriptions.
+#[derive(Default)]
+pub struct Registry {
+    /// The active subscriptions.
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Registry {
+    /// Watch some path.
+    ///
+    /// This returns a receiver of the events of the kinds in `mask` covering `path`. Dropping the
+    /// receiver ends the subscription.
+    pub fn watch(&self, path: &str, mask: Mask) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+
+        self.subscriptions.lock().unwrap().push(Subscription {
+            path: path.to_owned(),
+            mask: mask,
+            sender: sender,
+        });
+
+        receiver
+    }
+
+    /// Notify the subscribers of some event.
+    ///
+    /// This is called by the operation layer after the change is committed.
+    pub fn notify(&self, event: Event) {
+        let mask = event.mask();
+
+        self.subscriptions.lock().unwrap().retain(|sub| {
+            if sub.mask.contains(mask) && event.is_covered_by(&sub.path) {
+                // Sending only fails when the receiver is dropped, in which case the subscription
+                // is over.
+                sub.sender.send(event.clone()).is_ok()
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Get the number of active subscriptions.
+    ///
+    /// Subscriptions whose receiver was dropped are only removed when the next event for them is
+    /// sent, so this is an upper bound.
+    pub fn len(&self) -> usize {
+        self.subscriptions.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cover() {
+        assert!(covers("/a", "/a"));
+        assert!(covers("/a", "/a/b"));
+        assert!(covers("/a/", "/a/b"));
+        assert!(!covers("/a", "/a/b/c"));
+        assert!(!covers("/a", "/ab"));
+        assert!(!covers("/a", "/"));
+    }
+
+    #[test]
+    fn mask() {
+        let registry = Registry::default();
+        let created = registry.watch("/dir", Mask::CREATE.with(Mask::DELETE));
+
+        registry.notify(Event::Create("/dir/x".to_owned()));
+        registry.notify(Event::Modify("/dir/x".to_owned()));
+        registry.notify(Event::Delete("/dir/x".to_owned()));
+        registry.notify(Event::Create("/other/x".to_owned()));
+
+        assert_eq!(created.try_iter().collect::<Vec<_>>(), vec![
+            Event::Create("/dir/x".to_owned()),
+            Event::Delete("/dir/x".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn rename() {
+        let registry = Registry::default();
+        let from = registry.watch("/a", Mask::ALL);
+        let to = registry.watch("/b", Mask::RENAME);
+        let event = Event::Rename { from: "/a/x".to_owned(), to: "/b/y".to_owned() };
+
+        registry.notify(event.clone());
+
+        assert_eq!(from.try_recv(), Ok(event.clone()));
+        assert_eq!(to.try_recv(), Ok(event));
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let registry = Registry::default();
+        let receiver = registry.watch("/a", Mask::ALL);
+        assert_eq!(registry.len(), 1);
+
+        drop(receiver);
+        registry.notify(Event::Modify("/a".to_owned()));
+        assert_eq!(registry.len(), 0);
+    }
+}
@@ -0,0 +1,173 @@
+//! Integrity event reporting.
+//!
+//! Checksum mismatches, failed authentication and unreadable clusters are turned into errors deep
+//! down the stack, where they are easily lost in some retry logic. The operator only learns about
+//! them through the debug logs, if at all, and latent failures (e.g. a dying disk) go unnoticed
+//! until the data is lost.
+//!
+//! Instead, every integrity failure is reported as a structured event to the callbacks
+//! registered in the `Reporter` of the filesystem (see `Allocator::integrity()`), such that they
+//! can be counted, logged, or alerted on.
+
+use std::sync::RwLock;
+use std::sync::atomic::{self, AtomicUsize};
+
+use disk::cluster;
+use error;
+use Error;
+
+/// The kind of an integrity failure.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// Data didn't match its checksum.
+    ChecksumMismatch,
+    /// Encrypted data failed authentication.
+    ///
+    /// This happens if it was tampered with, or if it was decrypted with a wrong key.
+    Authentication,
+    /// Data couldn't be read from the device at all.
+    Unreadable,
+}
+
+/// An integrity event.
+///
+/// Failures are not healed, so the operation which detected the failure always fails as well.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Event {
+    /// The kind of the failure.
+    pub kind: Kind,
+    /// The cluster affected, if known.
+    pub cluster: Option<cluster::Pointer>,
+    /// The object affected, if known.
+    pub object: Option<u64>,
+}
+
+/// The handle of a registered callback.
+///
+/// This is used to unregister the callback (see `Reporter::unregister()`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Registration(usize);
+
+/// A callback receiving integrity events.
+type Callback = Box<Fn(&Event) + Send + Sync>;
+
+/// A reporter of integrity events.
+///
+/// This holds the registered callbacks, and is shared by the layers of the filesystem.
+#[derive(Default)]
+pub struct Reporter {
+    /// The registered callbacks.
+    callbacks: RwLock<Vec<(Registration, Callback)>>,
+    /// The ID of the next registration.
+    next_id: AtomicUsize,
+}
+
+impl Reporter {
+    /// Register a callback.
+    ///
+    /// `callback` is called with every integrity event from now on, until it is unregistered.
+    ///
+    /// The callbacks are called synchronously from the operation which detected the failure, so
+    /// they should be quick (e.g. count the event or send it through a channel), and must not
+    /// call into the filesystem.
+    pub fn register<F>(&self, callback: F) -> Registration
+    where F: Fn(&Event) + Send + Sync + 'static {
+        let registration = Registration(self.next_id.fetch_add(1, atomic::Ordering::Relaxed));
+        self.callbacks.write().unwrap().push((registration, Box::new(callback)));
+
+        registration
+    }
+
+    /// Unregister a callback.
+    ///
+    /// This returns `false` if the callback was already unregistered.
+    pub fn unregister(&self, registration: Registration) -> bool {
+        let mut callbacks = self.callbacks.write().unwrap();
+        let len = callbacks.len();
+        callbacks.retain(|&(x, _)| x != registration);
+
+        callbacks.len() != len
+    }
+
+    /// Report an event to the registered callbacks.
+    pub fn report(&self, event: Event) {
+        for &(_, ref callback) in &*self.callbacks.read().unwrap() {
+            callback(&event);
+        }
+    }
+
+    /// Report the failure of reading some cluster, if any.
+    ///
+    /// This passes `res` through, reporting an event if it is an error: Corruption is reported as a
+    /// checksum mismatch, and any other error as the cluster being unreadable. The error is
+    /// returned to the caller.
+    pub fn check_read<T>(&self, res: Result<T, Error>, cluster: cluster::Pointer)
+        -> Result<T, Error> {
+        if let Err(ref err) = res {
+            self.report(Event {
+                kind: if err.kind == error::Kind::Corruption {
+                    Kind::ChecksumMismatch
+                } else {
+                    Kind::Unreadable
+                },
+                cluster: Some(cluster),
+                object: None,
+            });
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    fn cluster() -> cluster::Pointer {
+        cluster::Pointer::new(7).unwrap()
+    }
+
+    #[test]
+    fn callbacks() {
+        let reporter = Reporter::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let events_callback = events.clone();
+        let registration = reporter.register(move |event| {
+            events_callback.lock().unwrap().push(*event);
+        });
+
+        let event = Event {
+            kind: Kind::Authentication,
+            cluster: None,
+            object: Some(42),
+        };
+        reporter.report(event);
+        assert_eq!(*events.lock().unwrap(), vec![event]);
+
+        assert!(reporter.unregister(registration));
+        assert!(!reporter.unregister(registration));
+        reporter.report(event);
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn check_read() {
+        let reporter = Reporter::default();
+        let kinds = Arc::new(Mutex::new(Vec::new()));
+
+        let kinds_callback = kinds.clone();
+        reporter.register(move |event| {
+            assert_eq!(event.cluster, Some(cluster()));
+            kinds_callback.lock().unwrap().push(event.kind);
+        });
+
+        assert!(reporter.check_read(Ok(()), cluster()).is_ok());
+        assert!(reporter.check_read::<()>(Err(err!(Corruption, "bad")), cluster()).is_err());
+        assert!(reporter.check_read::<()>(Err(err!(Implementation, "eio")), cluster()).is_err());
+
+        assert_eq!(*kinds.lock().unwrap(), vec![Kind::ChecksumMismatch, Kind::Unreadable]);
+    }
+}
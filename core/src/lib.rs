@@ -28,7 +28,9 @@ mod error;
 mod macros;
 
 mod alloc;
+mod budget;
 mod disk;
 mod fs;
+mod integrity;
 
 pub use error::Error;
@@ -1,6 +1,7 @@
 //! A highly optimized version of SeaHash.
 
-use core::slice;
+use core::{cmp, slice};
+use core::hash::Hasher;
 
 use helper;
 
@@ -172,6 +173,20 @@ impl State {
         }
     }
 
+    /// Mix some number of whole blocks into the state.
+    ///
+    /// The length of `buf` must be divisible by 32, as the lanes would otherwise be finalized.
+    fn blocks(&mut self, buf: &[u8]) {
+        debug_assert!(buf.len() & 0x1F == 0, "Buffer is not a sequence of whole blocks.");
+
+        let State { a, b, c, d, written } = State::hash(buf, (self.a, self.b, self.c, self.d));
+        self.a = a;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.written += written;
+    }
+
     /// Write another 64-bit integer into the state.
     pub fn push(&mut self, x: u64) {
         let mut a = self.a;
@@ -277,6 +292,89 @@ pub fn hash_seeded(buf: &[u8], a: u64, b: u64, c: u64, d: u64) -> u64 {
     State::hash(buf, (a, b, c, d)).finalize()
 }
 
+/// Hash the excessive bytes on top of some lanes, and finalize.
+///
+/// `tail` must be shorter than a block, and `written` is the number of bytes already mixed into
+/// the lanes.
+pub fn hash_tail(tail: &[u8], lanes: (u64, u64, u64, u64), written: u64) -> u64 {
+    let mut state = State::hash(tail, lanes);
+    state.written += written;
+
+    state.finalize()
+}
+
+/// An incremental version of `hash_seeded`.
+///
+/// No matter how the input is split into writes, writing some bytes gives the same value as
+/// hashing them with `hash_seeded()` (or `hash()` if the default seeds are used). `SeaHasher`
+/// carries its runs of bytes in this, which is what makes it independent of the chunking.
+///
+/// To do so, we buffer the bytes until we have a whole block (32 bytes), which is then mixed into
+/// the state.
+#[derive(Clone)]
+pub struct BufferHasher {
+    /// The state of the whole blocks written so far.
+    state: State,
+    /// The bytes of the incomplete block.
+    buf: [u8; 32],
+    /// The number of bytes in `buf`.
+    len: usize,
+}
+
+impl Default for BufferHasher {
+    fn default() -> BufferHasher {
+        BufferHasher::with_seeds(0x16f11fe89b0d677c, 0xb480a793d8e6c86c, 0x6fe2e5aaf078ebc9, 0x14f994a4c5259381)
+    }
+}
+
+impl BufferHasher {
+    /// Create a new `BufferHasher` with the same seeds as `hash_seeded(_, a, b, c, d)`.
+    pub fn with_seeds(a: u64, b: u64, c: u64, d: u64) -> BufferHasher {
+        BufferHasher {
+            state: State::new(a, b, c, d),
+            buf: [0; 32],
+            len: 0,
+        }
+    }
+}
+
+impl Hasher for BufferHasher {
+    fn finish(&self) -> u64 {
+        // Hash the incomplete block on top of the whole blocks. This finalizes the lanes the same
+        // way as the excessive bytes of a single buffer.
+        hash_tail(&self.buf[..self.len], (self.state.a, self.state.b, self.state.c, self.state.d),
+                  self.state.written)
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        // Start by completing the buffered block, if any.
+        if self.len != 0 {
+            let n = cmp::min(32 - self.len, bytes.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+
+            if self.len < 32 {
+                // The block is still incomplete, so there is nothing more to do.
+                return;
+            }
+
+            let buf = self.buf;
+            self.state.blocks(&buf);
+            self.len = 0;
+        }
+
+        // Mix in the whole blocks directly, without copying them.
+        let split = bytes.len() & !0x1F;
+        self.state.blocks(&bytes[..split]);
+
+        // Buffer the rest.
+        let rest = &bytes[split..];
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.len = rest.len();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -354,6 +452,30 @@ mod tests {
         assert_ne!(hash(b"ab"), hash(b"bb"));
     }
 
+    #[test]
+    fn buffer_hasher() {
+        let mut buf = [0; 1000];
+        for i in 0..1000 {
+            buf[i] = i as u8;
+        }
+
+        for &chunk in &[1, 3, 8, 31, 32, 33, 100, 1000] {
+            for &len in &[0, 1, 31, 32, 33, 64, 65, 999, 1000] {
+                let mut hasher = BufferHasher::default();
+                for i in buf[..len].chunks(chunk) {
+                    hasher.write(i);
+                }
+                assert_eq!(hasher.finish(), hash(&buf[..len]));
+
+                let mut hasher = BufferHasher::with_seeds(1, 2, 3, 4);
+                for i in buf[..len].chunks(chunk) {
+                    hasher.write(i);
+                }
+                assert_eq!(hasher.finish(), hash_seeded(&buf[..len], 1, 2, 3, 4));
+            }
+        }
+    }
+
     #[test]
     fn push() {
         let mut state = State::new(1, 2, 3, 4);
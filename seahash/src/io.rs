@@ -0,0 +1,65 @@
+//! Conveniences for hashing through `std::io`.
+//!
+//! These are only available with the `std` feature (enabled by default).
+
+use std::io::{self, Read, Write};
+use core::hash::Hasher;
+
+use buffer::BufferHasher;
+use stream::SeaHasher;
+
+impl Write for SeaHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Hasher::write(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash everything read from some reader.
+///
+/// This reads `reader` to the end, and gives the same value as `hash()` of the read bytes.
+pub fn hash_reader<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut hasher = BufferHasher::default();
+    let mut buf = [0; 4096];
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(n) => hasher.write(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use hash;
+
+    #[test]
+    fn reader() {
+        let buf: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+
+        assert_eq!(hash_reader(&buf[..]).unwrap(), hash(&buf));
+        assert_eq!(hash_reader(&buf[..77]).unwrap(), hash(&buf[..77]));
+        assert_eq!(hash_reader(io::empty()).unwrap(), hash(&[]));
+    }
+
+    #[test]
+    fn writer() {
+        let mut a = SeaHasher::new();
+        a.write_all(b"to be or not to be").unwrap();
+        let mut b = SeaHasher::new();
+        Hasher::write(&mut b, b"to be or not to be");
+
+        assert_eq!(a.finish(), b.finish());
+    }
+}
@@ -141,6 +141,18 @@
 //! SeaHash is specifically designed such that it can be efficiently implemented in the form of
 //! ASIC while only using very few transistors.
 //!
+//! # `no_std`
+//!
+//! The hash function itself only depends on `core`, so it can be used in kernels, bootloaders,
+//! and other freestanding environments, giving exactly the same output as everywhere else.
+//! Disabling the default `std` feature removes the dependency on `std`, leaving out only the
+//! `std::io` conveniences (`hash_reader()`, and `Write` for `SeaHasher`).
+//!
+//! # SIMD
+//!
+//! On nightly compilers, the `simd` feature enables the `simd` module, a vectorized version using
+//! portable SIMD, which works on any target supported by `core::simd`.
+//!
 //! # Specification
 //!
 //! See the [`reference`](./reference) module.
@@ -152,12 +164,22 @@
 //! created by Melissa E. O'Neill. Sokolov Yura spotted multiple bugs in SeaHash.
 
 #![no_std]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
 #![warn(missing_docs)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 pub use buffer::{hash, hash_seeded, State};
 pub use stream::SeaHasher;
+#[cfg(feature = "std")]
+pub use io::hash_reader;
 
 pub mod reference;
 mod buffer;
 mod helper;
+#[cfg(feature = "std")]
+mod io;
+#[cfg(feature = "simd")]
+pub mod simd;
 mod stream;
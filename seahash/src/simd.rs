@@ -0,0 +1,95 @@
+//! A portable SIMD version of SeaHash.
+//!
+//! The four lanes of the state are independent until finalization, so they map neatly onto a
+//! vector of four 64-bit integers. Rather than writing intrinsics for every architecture, we use
+//! `core::simd`, leaving it to the compiler to emit the right instructions for the target (AVX2,
+//! NEON, RISC-V vectors, wasm SIMD, etc.), or fall back to scalar code, if there are none.
+//!
+//! This requires a nightly compiler, and is enabled through the `simd` feature. The output is
+//! exactly the same as the scalar version's.
+
+use core::simd::{u64x4, u8x32, ToBytes};
+
+use buffer;
+
+/// Diffuse every lane of a vector.
+///
+/// This is the vector version of `helper::diffuse()`.
+#[inline(always)]
+fn diffuse(mut x: u64x4) -> u64x4 {
+    // Wrapping multiplication is the default for SIMD vectors.
+    let k = u64x4::splat(0x6eed0e9da4d94a4f);
+
+    x *= k;
+    let a = x >> u64x4::splat(32);
+    let b = x >> u64x4::splat(60);
+    // The shift is dynamic, and hence differs from lane to lane.
+    x ^= a >> b;
+    x *= k;
+
+    x
+}
+
+/// Hash some buffer according to a chosen seed, using SIMD.
+///
+/// This gives the same value as `hash_seeded()`.
+pub fn hash_seeded(buf: &[u8], a: u64, b: u64, c: u64, d: u64) -> u64 {
+    let mut lanes = u64x4::from_array([a, b, c, d]);
+
+    // The "main segment", i.e. the biggest prefix s.t. the length is divisible by 32.
+    let split = buf.len() & !0x1F;
+    for block in buf[..split].chunks(32) {
+        // The integers of the block are little-endian.
+        lanes ^= u64x4::from_le_bytes(u8x32::from_slice(block));
+        lanes = diffuse(lanes);
+    }
+
+    // The excessive bytes are few, so we handle them (and the finalization) by the scalar code.
+    let [a, b, c, d] = lanes.to_array();
+    buffer::hash_tail(&buf[split..], (a, b, c, d), split as u64)
+}
+
+/// Hash some buffer, using SIMD.
+///
+/// This gives the same value as `hash()`.
+pub fn hash(buf: &[u8]) -> u64 {
+    hash_seeded(buf, 0x16f11fe89b0d677c, 0xb480a793d8e6c86c, 0x6fe2e5aaf078ebc9, 0x14f994a4c5259381)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_match(a: &[u8]) {
+        // The scalar version is the oracle.
+        assert_eq!(hash(a), ::hash(a));
+        assert_eq!(hash_seeded(a, 1, 1, 1, 1), ::hash_seeded(a, 1, 1, 1, 1));
+        assert_eq!(hash_seeded(a, 500, 2873, 2389, 9283), ::hash_seeded(a, 500, 2873, 2389, 9283));
+        assert_eq!(hash_seeded(a, !0, !0, !0, !0), ::hash_seeded(a, !0, !0, !0, !0));
+        assert_eq!(hash_seeded(a, 0, 0, 0, 0), ::hash_seeded(a, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn seq() {
+        let mut buf = [0; 4096];
+        for i in 0..4096 {
+            buf[i] = i as u8;
+        }
+
+        for n in 0..4096 {
+            hash_match(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn unaligned() {
+        let mut buf = [0; 1024];
+        for i in 0..1024 {
+            buf[i] = (i * 7) as u8;
+        }
+
+        for start in 0..32 {
+            hash_match(&buf[start..]);
+        }
+    }
+}
@@ -1,11 +1,24 @@
 use core::hash::Hasher;
 
-use {hash_seeded, helper};
+use buffer::BufferHasher;
+use helper;
 
 /// The streaming version of the algorithm.
+///
+/// Consecutive byte writes are treated as a single run of bytes: They are carried in a buffer
+/// until an integer is written, or the hasher is finished, so splitting some bytes into several
+/// writes gives the same value as writing them at once. This makes it usable for checksumming
+/// streams, which arrive in arbitrarily sized chunks.
+#[derive(Clone)]
 pub struct SeaHasher {
     /// The state of the hasher.
     state: u64,
+    /// The hasher of the current run of bytes.
+    bytes: BufferHasher,
+    /// Is a run of bytes pending (i.e. written, but not yet mixed into the state)?
+    ///
+    /// This is set by any byte write, including empty ones, as they contribute to the value.
+    pending: bool,
     /// The first key.
     k1: u64,
     /// The second key.
@@ -34,6 +47,8 @@ impl SeaHasher {
     pub fn with_seeds(k1: u64, k2: u64, k3: u64, k4: u64) -> SeaHasher {
         SeaHasher {
             state: k1 ^ k3,
+            bytes: BufferHasher::with_seeds(k1, k2, k3, k4),
+            pending: false,
             k1: k1,
             k2: k2,
             k3: k3,
@@ -41,10 +56,25 @@ impl SeaHasher {
         }
     }
 
+    /// Mix the pending run of bytes (if any) into the state.
+    fn flush(&mut self) {
+        if self.pending {
+            self.state ^= self.bytes.finish();
+            self.state = helper::diffuse(self.state);
+
+            // Start a new run.
+            self.bytes = BufferHasher::with_seeds(self.k1, self.k2, self.k3, self.k4);
+            self.pending = false;
+        }
+    }
+
     /// Write some integer in.
     ///
     /// This applies XEX key whitening with the keys given as argument.
-    fn write(&mut self, n: u64, k1: u64, k2: u64) {
+    fn write_int(&mut self, n: u64, k1: u64, k2: u64) {
+        // The run of bytes ends here.
+        self.flush();
+
         self.state ^= n ^ k1;
         self.state = helper::diffuse(self.state) ^ k2;
     }
@@ -52,71 +82,157 @@ impl SeaHasher {
 
 impl Hasher for SeaHasher {
     fn finish(&self) -> u64 {
-        helper::diffuse(self.state ^ self.k3) ^ self.k4
+        // Mix in the pending run of bytes without ending it, as more bytes can still be written.
+        let state = if self.pending {
+            helper::diffuse(self.state ^ self.bytes.finish())
+        } else {
+            self.state
+        };
+
+        helper::diffuse(state ^ self.k3) ^ self.k4
     }
 
     fn write(&mut self, bytes: &[u8]) {
-        self.state ^= hash_seeded(bytes, self.k1, self.k2, self.k3, self.k4);
-        self.state = helper::diffuse(self.state);
+        // Carry the bytes over to the run. This gives the same value as `hash_seeded()` over the
+        // whole run, no matter how it is split.
+        self.bytes.write(bytes);
+        self.pending = true;
     }
 
     fn write_u64(&mut self, n: u64) {
         let k1 = self.k1;
         let k2 = self.k2;
-        self.write(n, k1, k2)
+        self.write_int(n, k1, k2)
     }
 
     fn write_u8(&mut self, n: u8) {
         let k1 = self.k1;
         let k3 = self.k3;
-        self.write(n as u64, k1, k3)
+        self.write_int(n as u64, k1, k3)
     }
 
     fn write_u16(&mut self, n: u16) {
         let k1 = self.k1;
         let k2 = self.k2;
-        self.write(n as u64, k2, k1)
+        self.write_int(n as u64, k2, k1)
     }
 
     fn write_u32(&mut self, n: u32) {
         let k2 = self.k2;
         let k3 = self.k3;
-        self.write(n as u64, k2, k3)
+        self.write_int(n as u64, k2, k3)
     }
 
     fn write_usize(&mut self, n: usize) {
         let k2 = self.k2;
         let k3 = self.k3;
-        self.write(n as u64, k3, k2)
+        self.write_int(n as u64, k3, k2)
     }
 
     fn write_i64(&mut self, n: i64) {
         let k1 = self.k1;
         let k2 = self.k2;
-        self.write(n as u64, !k1, !k2)
+        self.write_int(n as u64, !k1, !k2)
     }
 
     fn write_i8(&mut self, n: i8) {
         let k1 = self.k1;
         let k3 = self.k3;
-        self.write(n as u64, !k1, !k3)
+        self.write_int(n as u64, !k1, !k3)
     }
 
     fn write_i16(&mut self, n: i16) {
         let k1 = self.k1;
         let k2 = self.k2;
-        self.write(n as u64, !k2, !k1)
+        self.write_int(n as u64, !k2, !k1)
     }
 
     fn write_i32(&mut self, n: i32) {
         let k2 = self.k2;
         let k3 = self.k3;
-        self.write(n as u64, !k2, !k3)
+        self.write_int(n as u64, !k2, !k3)
     }
 
     fn write_isize(&mut self, n: isize) {
         let k2 = self.k2;
         let k3 = self.k3;
-        self.write(n as u64, !k3, !k2)
+        self.write_int(n as u64, !k3, !k2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hash_seeded;
+
+    /// Hash some bytes in a single write, the way `SeaHasher` did before carrying runs of bytes.
+    fn one_shot(bytes: &[u8]) -> u64 {
+        let (k1, k2, k3, k4) = (0xe7b0c93ca8525013, 0x011d02b854ae8182, 0x7bcc5cf9c39cec76,
+                                0xfa336285d102d083);
+        let state = helper::diffuse(k1 ^ k3 ^ hash_seeded(bytes, k1, k2, k3, k4));
+
+        helper::diffuse(state ^ k3) ^ k4
+    }
+
+    #[test]
+    fn split_points() {
+        let mut buf = [0; 80];
+        for i in 0..80 {
+            buf[i] = (i * 7) as u8;
+        }
+
+        // Try every way of splitting the buffer into three writes.
+        for i in 0..buf.len() + 1 {
+            for j in i..buf.len() + 1 {
+                let mut hasher = SeaHasher::new();
+                hasher.write(&buf[..i]);
+                hasher.write(&buf[i..j]);
+                hasher.write(&buf[j..]);
+
+                assert_eq!(hasher.finish(), one_shot(&buf));
+            }
+        }
+    }
+
+    #[test]
+    fn integers_end_runs() {
+        let mut a = SeaHasher::new();
+        a.write(b"hello ");
+        a.write(b"world");
+        a.write_u64(42);
+        a.write(b"!");
+
+        let mut b = SeaHasher::new();
+        b.write(b"hello world");
+        b.write_u64(42);
+        b.write(b"!");
+
+        let mut c = SeaHasher::new();
+        c.write(b"hello world!");
+        c.write_u64(42);
+
+        assert_eq!(a.finish(), b.finish());
+        assert_ne!(a.finish(), c.finish());
+    }
+
+    #[test]
+    fn finish_is_idempotent() {
+        let mut hasher = SeaHasher::new();
+        hasher.write(b"abc");
+        assert_eq!(hasher.finish(), hasher.finish());
+        assert_eq!(hasher.finish(), one_shot(b"abc"));
+
+        hasher.write(b"def");
+        assert_eq!(hasher.finish(), one_shot(b"abcdef"));
+    }
+
+    #[test]
+    fn empty_write() {
+        let mut a = SeaHasher::new();
+        a.write(&[]);
+
+        assert_eq!(a.finish(), one_shot(&[]));
+        assert_ne!(a.finish(), SeaHasher::new().finish());
     }
 }
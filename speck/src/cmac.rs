@@ -0,0 +1,192 @@
+//! CMAC (OMAC1) over SPECK.
+//!
+//! CMAC turns a block cipher into a message authentication code. It is essentially CBC-MAC, but
+//! with the last block whitened by one of two subkeys derived from the cipher key, which makes it
+//! secure for messages of arbitrary (and varying) length.
+//!
+//! Messages are split into 128-bit blocks, which are read as little-endian integers (matching the
+//! byte order of the test vectors in the SPECK paper). The subkeys are derived by doubling in
+//! GF(2^128) with the reduction polynomial x^128 + x^7 + x^2 + x + 1, as in RFC 4493.
+
+use Key;
+
+/// The size of a block in bytes.
+const BLOCK_SIZE: usize = 16;
+/// The constant reducing the doubled subkeys.
+const RB: u128 = 0x87;
+
+/// Double an element of GF(2^128).
+fn dbl(x: u128) -> u128 {
+    // Shift left, and reduce if the top bit was shifted out.
+    (x << 1) ^ if x >> 127 == 1 { RB } else { 0 }
+}
+
+/// A CMAC state.
+///
+/// This computes the MAC of a message incrementally, i.e. the message can be given in pieces
+/// through `update()`, and the tag is obtained in the end through `finalize()`.
+#[derive(Clone)]
+pub struct Cmac {
+    /// The cipher key.
+    key: Key,
+    /// The subkey whitening a complete last block.
+    k1: u128,
+    /// The subkey whitening an incomplete (padded) last block.
+    k2: u128,
+    /// The CBC chaining value of the blocks processed so far.
+    state: u128,
+    /// The bytes of the current block.
+    ///
+    /// This is never processed before more data arrives, as the last block must be whitened.
+    buf: [u8; BLOCK_SIZE],
+    /// The number of bytes in `buf`.
+    len: usize,
+}
+
+impl Cmac {
+    /// Create a new CMAC state with some key.
+    pub fn new(key: Key) -> Cmac {
+        // Derive the subkeys from the encrypted zero block.
+        let l = key.encrypt_block(0);
+        let k1 = dbl(l);
+
+        Cmac {
+            key: key,
+            k1: k1,
+            k2: dbl(k1),
+            state: 0,
+            buf: [0; BLOCK_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Feed some bytes of the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // If the buffered block is complete, and more data follows, it is not the last block,
+            // so we can process it.
+            if self.len == BLOCK_SIZE {
+                self.state = self.key.encrypt_block(self.state ^ read_block(&self.buf));
+                self.len = 0;
+            }
+
+            // Fill the buffer.
+            let n = (BLOCK_SIZE - self.len).min(data.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
+    }
+
+    /// Finalize the state, producing the tag of the message.
+    pub fn finalize(mut self) -> u128 {
+        let last = if self.len == BLOCK_SIZE {
+            // The last block is complete, so we whiten it with the first subkey.
+            read_block(&self.buf) ^ self.k1
+        } else {
+            // The last block is incomplete (or the message is empty), so we pad it with a single
+            // one bit followed by zeros, and whiten it with the second subkey.
+            self.buf[self.len] = 0x80;
+            for i in &mut self.buf[self.len + 1..] {
+                *i = 0;
+            }
+
+            read_block(&self.buf) ^ self.k2
+        };
+
+        self.key.encrypt_block(self.state ^ last)
+    }
+
+    /// Finalize the state, and check it against some tag.
+    ///
+    /// This runs in constant time with respect to the tags, so it doesn't leak how much of the
+    /// tag was correct.
+    pub fn verify(self, tag: u128) -> bool {
+        // Compare through the bits of the difference, such that we never short-circuit.
+        let diff = self.finalize() ^ tag;
+        (diff as u64 | (diff >> 64) as u64) == 0
+    }
+}
+
+/// Read a block as a little-endian integer.
+fn read_block(buf: &[u8; BLOCK_SIZE]) -> u128 {
+    let mut x = 0;
+    for &i in buf.iter().rev() {
+        x = x << 8 | i as u128;
+    }
+
+    x
+}
+
+/// Calculate the CMAC tag of some message.
+pub fn cmac(key: &Key, msg: &[u8]) -> u128 {
+    let mut cmac = Cmac::new(*key);
+    cmac.update(msg);
+    cmac.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key of the test vectors in the SPECK paper.
+    const KEY: u128 = 0x0f0e0d0c0b0a09080706050403020100;
+
+    fn msg() -> [u8; 64] {
+        let mut buf = [0; 64];
+        for i in 0..64 {
+            buf[i] = i as u8;
+        }
+
+        buf
+    }
+
+    #[test]
+    fn test_vectors() {
+        // These were cross-checked against an independent implementation of RFC 4493 with SPECK
+        // substituted for AES.
+        let key = Key::new(KEY);
+        let msg = msg();
+
+        assert_eq!(cmac(&key, &msg[..0]), 0x3bdc19dffcc6423f480f90cd66c0fea2);
+        assert_eq!(cmac(&key, &msg[..1]), 0x8e4f256f0c3ad0e1621d4d87bfa2d83d);
+        assert_eq!(cmac(&key, &msg[..15]), 0xa2228d15fe61c1b412203c00cd1664d5);
+        assert_eq!(cmac(&key, &msg[..16]), 0x3d04f9d071eec64d0a8daf125fcb9187);
+        assert_eq!(cmac(&key, &msg[..17]), 0xca6937d30ab3a29599e567246c0df677);
+        assert_eq!(cmac(&key, &msg[..32]), 0x25e7106ca7587c71a624b9f8bf7c1886);
+        assert_eq!(cmac(&key, &msg[..40]), 0xe3517ca6bbc873c95196794e94e22a76);
+        assert_eq!(cmac(&key, &msg[..64]), 0xe0df5d2e484ce86142e0a13e3eeb815b);
+    }
+
+    #[test]
+    fn incremental() {
+        let key = Key::new(KEY);
+        let msg = msg();
+
+        for len in 0..64 {
+            for chunk in 1..20 {
+                let mut state = Cmac::new(key);
+                for i in msg[..len].chunks(chunk) {
+                    state.update(i);
+                }
+
+                assert_eq!(state.finalize(), cmac(&key, &msg[..len]));
+            }
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let key = Key::new(KEY);
+        let tag = cmac(&key, b"to be or not to be");
+
+        let mut state = Cmac::new(key);
+        state.update(b"to be or not to be");
+        assert!(state.clone().verify(tag));
+        assert!(!state.clone().verify(tag ^ 1));
+        assert!(!state.verify(tag ^ 1 << 127));
+
+        // Different keys give different tags.
+        assert_ne!(cmac(&Key::new(!KEY), b"to be or not to be"), tag);
+    }
+}
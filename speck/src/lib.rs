@@ -3,12 +3,24 @@
 //! SPECK is a really simple block cipher designed by the NSA. It is famous for its simple
 //! structure and code size, which can fit in just a couple of lines, while still preserving
 //! security.
+//!
+//! Besides the raw block cipher, the `cmac` module provides a message authentication code built on
+//! top of it, the `rng` module a deterministic random number generator, and (with the `std`
+//! feature) the `stream` module encrypting `Read`ers and `Write`rs.
 #![feature(i128_type)]
 #![no_std]
 #![forbid(unsafe_code)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 use core::fmt;
 
+pub mod cmac;
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod stream;
+
 /// The number of rounds.
 const ROUNDS: u64 = 32;
 
@@ -0,0 +1,191 @@
+//! A deterministic CSPRNG based on SPECK in counter mode.
+//!
+//! The output is the keystream of SPECK-CTR: The 128-bit counter blocks (the nonce in the upper
+//! half, the block counter in the lower half) are encrypted under the key, and the ciphertext
+//! blocks are emitted as little-endian bytes. Hence, the same key and nonce always give the same
+//! stream, which makes it suited for reproducible randomized tests and decisions.
+//!
+//! The block counter is 64 bits, so the stream repeats after 2^68 bytes.
+//!
+//! With feature `rand_core`, it implements `RngCore`, `SeedableRng` and `CryptoRng`.
+
+use Key;
+
+/// The size of a keystream block in bytes.
+const BLOCK_SIZE: usize = 16;
+
+/// A SPECK-CTR random number generator.
+#[derive(Clone)]
+pub struct SpeckRng {
+    /// The key.
+    key: Key,
+    /// The nonce.
+    nonce: u64,
+    /// The counter of the next block.
+    counter: u64,
+    /// The current block of the keystream.
+    block: [u8; BLOCK_SIZE],
+    /// The number of bytes of `block` which are used up.
+    used: usize,
+}
+
+impl SpeckRng {
+    /// Create a new generator from some key and nonce.
+    pub fn new(key: u128, nonce: u64) -> SpeckRng {
+        SpeckRng {
+            key: Key::new(key),
+            nonce: nonce,
+            counter: 0,
+            block: [0; BLOCK_SIZE],
+            // Start with the block used up, such that the first block is generated on demand.
+            used: BLOCK_SIZE,
+        }
+    }
+
+    /// Generate the next block of the keystream.
+    fn refill(&mut self) {
+        let mut x = self.key.encrypt_block((self.nonce as u128) << 64 | self.counter as u128);
+        self.counter = self.counter.wrapping_add(1);
+
+        // Write the block in little-endian.
+        for i in &mut self.block {
+            *i = x as u8;
+            x >>= 8;
+        }
+        self.used = 0;
+    }
+
+    /// Fill a buffer with random bytes.
+    pub fn fill_bytes(&mut self, mut dest: &mut [u8]) {
+        while !dest.is_empty() {
+            if self.used == BLOCK_SIZE {
+                self.refill();
+            }
+
+            // Copy as much of the current block as possible.
+            let n = (BLOCK_SIZE - self.used).min(dest.len());
+            dest[..n].copy_from_slice(&self.block[self.used..self.used + n]);
+            self.used += n;
+
+            let rest = dest;
+            dest = &mut rest[n..];
+        }
+    }
+
+    /// Generate a random `u32`.
+    ///
+    /// This is read from the next 4 bytes of the keystream in little-endian.
+    pub fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+
+        buf.iter().rev().fold(0, |x, &i| x << 8 | i as u32)
+    }
+
+    /// Generate a random `u64`.
+    ///
+    /// This is read from the next 8 bytes of the keystream in little-endian.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+
+        buf.iter().rev().fold(0, |x, &i| x << 8 | i as u64)
+    }
+}
+
+#[cfg(feature = "rand_core")]
+mod rand_impls {
+    extern crate rand_core;
+
+    use self::rand_core::{CryptoRng, Error, RngCore, SeedableRng};
+    use super::SpeckRng;
+
+    impl RngCore for SpeckRng {
+        fn next_u32(&mut self) -> u32 {
+            SpeckRng::next_u32(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            SpeckRng::next_u64(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            SpeckRng::fill_bytes(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+            SpeckRng::fill_bytes(self, dest);
+            Ok(())
+        }
+    }
+
+    impl SeedableRng for SpeckRng {
+        /// The key (the first 16 bytes) followed by the nonce (the last 8 bytes), in
+        /// little-endian.
+        type Seed = [u8; 24];
+
+        fn from_seed(seed: [u8; 24]) -> SpeckRng {
+            let key = seed[..16].iter().rev().fold(0, |x, &i| x << 8 | i as u128);
+            let nonce = seed[16..].iter().rev().fold(0, |x, &i| x << 8 | i as u64);
+
+            SpeckRng::new(key, nonce)
+        }
+    }
+
+    impl CryptoRng for SpeckRng {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: u128 = 0x0f0e0d0c0b0a09080706050403020100;
+
+    #[test]
+    fn keystream() {
+        let key = Key::new(KEY);
+        let mut rng = SpeckRng::new(KEY, 7);
+
+        let a = rng.next_u64() as u128 | (rng.next_u64() as u128) << 64;
+        let b = rng.next_u64() as u128 | (rng.next_u64() as u128) << 64;
+
+        assert_eq!(a, key.encrypt_block(7 << 64));
+        assert_eq!(b, key.encrypt_block(7 << 64 | 1));
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut a = SpeckRng::new(KEY, 1);
+        let mut b = SpeckRng::new(KEY, 1);
+        let mut c = SpeckRng::new(KEY, 2);
+        let mut d = SpeckRng::new(!KEY, 1);
+
+        for _ in 0..100 {
+            let x = a.next_u64();
+            assert_eq!(x, b.next_u64());
+            assert_ne!(x, c.next_u64());
+            assert_ne!(x, d.next_u64());
+        }
+    }
+
+    #[test]
+    fn chunking() {
+        let mut whole = [0; 100];
+        SpeckRng::new(KEY, 0).fill_bytes(&mut whole);
+
+        for chunk in 1..40 {
+            let mut rng = SpeckRng::new(KEY, 0);
+            let mut buf = [0; 100];
+            for i in buf.chunks_mut(chunk) {
+                rng.fill_bytes(i);
+            }
+
+            assert_eq!(&buf[..], &whole[..]);
+        }
+
+        // Integers are read from the same stream.
+        let mut rng = SpeckRng::new(KEY, 0);
+        assert_eq!(rng.next_u32(), whole[..4].iter().rev().fold(0, |x, &i| x << 8 | i as u32));
+        assert_eq!(rng.next_u64(), whole[4..12].iter().rev().fold(0, |x, &i| x << 8 | i as u64));
+    }
+}
@@ -0,0 +1,238 @@
+//! Streaming encryption.
+//!
+//! `EncryptWriter` and `DecryptReader` wrap `Write`rs and `Read`ers, encrypting and decrypting
+//! the bytes passing through them with SPECK in CTR mode (the keystream of `SpeckRng`). Hence,
+//! streams of any length can be encrypted in constant memory.
+//!
+//! The encrypted stream starts with a header holding the nonce (8 bytes, little-endian), followed
+//! by the ciphertext, which has the same length as the plaintext. The nonce must never be reused
+//! with the same key, as the keystreams would be equal.
+//!
+//! CTR mode provides no integrity: Flipping a bit of the ciphertext flips the same bit of the
+//! plaintext. Streams which can be tampered with should be authenticated separately (e.g. with a
+//! CMAC of the ciphertext, see `cmac`).
+//!
+//! This module requires the `std` feature.
+
+use std::io::{self, Read, Write};
+
+use rng::SpeckRng;
+
+/// The size of the header in bytes.
+pub const HEADER_SIZE: usize = 8;
+/// The size of the buffer used for encrypting.
+const BUFFER_SIZE: usize = 4096;
+
+/// A writer encrypting the data written to it.
+pub struct EncryptWriter<W> {
+    /// The inner writer.
+    inner: W,
+    /// The keystream.
+    keystream: SpeckRng,
+    /// The header, if it is yet to be written.
+    header: Option<[u8; HEADER_SIZE]>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Create an encrypting writer.
+    ///
+    /// The data is encrypted with `key` and `nonce`, and written to `inner`. The header is written
+    /// along with the first data (or when flushed).
+    pub fn new(inner: W, key: u128, nonce: u64) -> EncryptWriter<W> {
+        let mut header = [0; HEADER_SIZE];
+        for (i, byte) in header.iter_mut().enumerate() {
+            *byte = (nonce >> (i * 8)) as u8;
+        }
+
+        EncryptWriter {
+            inner: inner,
+            keystream: SpeckRng::new(key, nonce),
+            header: Some(header),
+        }
+    }
+
+    /// Write the header, if it isn't written already.
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(header) = self.header {
+            self.inner.write_all(&header)?;
+            self.header = None;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the stream and get the inner writer.
+    ///
+    /// This writes the header, if nothing was written, such that even the empty stream can be
+    /// decrypted.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_header()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+
+        // Encrypt a chunk of the data. The chunk must be written in full, as the keystream is
+        // used up, so partial writes are hidden from the caller.
+        let len = buf.len().min(BUFFER_SIZE);
+        let mut encrypted = [0; BUFFER_SIZE];
+        self.keystream.fill_bytes(&mut encrypted[..len]);
+        for (byte, &plain) in encrypted.iter_mut().zip(&buf[..len]) {
+            *byte ^= plain;
+        }
+
+        self.inner.write_all(&encrypted[..len])?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.inner.flush()
+    }
+}
+
+/// A reader decrypting the data read from it.
+pub struct DecryptReader<R> {
+    /// The inner reader.
+    inner: R,
+    /// The key.
+    key: u128,
+    /// The keystream.
+    ///
+    /// This is `None` until the header is read.
+    keystream: Option<SpeckRng>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Create a decrypting reader.
+    ///
+    /// The stream read from `inner` is decrypted with `key`. The header is read along with the
+    /// first data.
+    pub fn new(inner: R, key: u128) -> DecryptReader<R> {
+        DecryptReader {
+            inner: inner,
+            key: key,
+            keystream: None,
+        }
+    }
+
+    /// Get the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.keystream.is_none() {
+            // Read the header. A truncated header is reported as `UnexpectedEof`.
+            let mut header = [0; HEADER_SIZE];
+            self.inner.read_exact(&mut header)?;
+            let nonce = header.iter().rev().fold(0, |x, &i| x << 8 | i as u64);
+
+            self.keystream = Some(SpeckRng::new(self.key, nonce));
+        }
+
+        let len = self.inner.read(buf)?;
+
+        // Decrypt what we read. Only the bytes actually read use up the keystream.
+        let mut keystream = [0; BUFFER_SIZE];
+        for chunk in buf[..len].chunks_mut(BUFFER_SIZE) {
+            self.keystream.as_mut().unwrap().fill_bytes(&mut keystream[..chunk.len()]);
+            for (byte, &pad) in chunk.iter_mut().zip(&keystream[..]) {
+                *byte ^= pad;
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    const KEY: u128 = 0x0f0e0d0c0b0a09080706050403020100;
+
+    fn encrypt(data: &[u8], nonce: u64) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), KEY, nonce);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+        let encrypted = encrypt(&data, 42);
+
+        assert_eq!(encrypted.len(), HEADER_SIZE + data.len());
+        assert_eq!(&encrypted[..HEADER_SIZE], &[42, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(encrypted[HEADER_SIZE..] != data[..]);
+
+        let mut decrypted = Vec::new();
+        DecryptReader::new(&encrypted[..], KEY).read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn keystream() {
+        // The ciphertext is the plaintext XOR the keystream of the nonce.
+        let encrypted = encrypt(&[0; 100], 7);
+
+        let mut keystream = [0; 100];
+        SpeckRng::new(KEY, 7).fill_bytes(&mut keystream);
+        assert_eq!(&encrypted[HEADER_SIZE..], &keystream[..]);
+
+        assert!(encrypt(&[0; 100], 8)[HEADER_SIZE..] != encrypted[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn chunking() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let whole = encrypt(&data, 1);
+
+        for chunk in 1..40 {
+            let mut writer = EncryptWriter::new(Vec::new(), KEY, 1);
+            for i in data.chunks(chunk) {
+                writer.write_all(i).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), whole);
+
+            let mut reader = DecryptReader::new(&whole[..], KEY);
+            let mut decrypted = Vec::new();
+            let mut buf = [0; 40];
+            loop {
+                let n = reader.read(&mut buf[..chunk]).unwrap();
+                if n == 0 {
+                    break;
+                }
+                decrypted.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let encrypted = encrypt(&[], 3);
+        assert_eq!(encrypted.len(), HEADER_SIZE);
+
+        let mut decrypted = Vec::new();
+        DecryptReader::new(&encrypted[..], KEY).read_to_end(&mut decrypted).unwrap();
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut buf = [0; 4];
+        let err = DecryptReader::new(&[1, 2, 3][..], KEY).read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
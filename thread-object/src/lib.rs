@@ -11,10 +11,15 @@
 //! pointers to the object.
 //!
 //! Performance wise, this is suboptimal, but it is portable contrary to most other approaches.
+//!
+//! The instances live until the thread exits, unless torn down earlier through
+//! `Object::remove_current()`. A `Weak` handle can be used to access an instance without creating
+//! it, e.g. to release the resources of the instance if (and only if) the thread has one.
 
 #![feature(const_fn)]
 
 use std::any::Any;
+use std::marker::PhantomData;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::mem;
@@ -58,6 +63,16 @@ impl<T> Object<T> {
             id: ID_COUNTER.fetch_add(1, atomic::Ordering::Relaxed),
         }
     }
+
+    /// Get a weak handle to the object.
+    ///
+    /// The handle refers to the same per-thread instances, but never initializes them.
+    pub fn downgrade(&self) -> Weak<T> {
+        Weak {
+            id: self.id,
+            _phantom: PhantomData,
+        }
+    }
 }
 
 impl<T: Clone + Any> Object<T> {
@@ -94,6 +109,59 @@ impl<T: Clone + Any> Object<T> {
     where T: Copy {
         self.with(|x| *x)
     }
+
+    /// Tear down the instance of the current thread.
+    ///
+    /// This removes the value associated with this thread and returns it (or `None` if the thread
+    /// has no instance), such that its resources can be released without waiting for the thread to
+    /// exit. If the object is read again, the instance is recreated from the initial value.
+    pub fn remove_current(&self) -> Option<T> {
+        self.downgrade().remove_current()
+    }
+}
+
+/// A weak handle to a thread object.
+///
+/// This is obtained through `Object::downgrade()`. Contrary to `Object`, it doesn't hold the
+/// initial value, and never initializes the instance of the current thread: If the thread has no
+/// instance (because it never read the object, or the instance was removed), the handle does
+/// nothing.
+pub struct Weak<T> {
+    /// The ID of the object.
+    id: usize,
+    /// Marker for the type of the instances.
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Weak<T> {}
+
+impl<T: Any> Weak<T> {
+    /// Read and/or modify the value associated with this thread, if any.
+    ///
+    /// This acts like `Object::with()`, except that it returns `None` without running `f` if the
+    /// current thread has no instance of the object.
+    pub fn with<F, R>(&self, f: F) -> Option<R>
+    where F: FnOnce(&mut T) -> R {
+        THREAD_OBJECTS.with(|map| {
+            map.borrow_mut().get_mut(&self.id).map(|ptr| f(ptr.downcast_mut().unwrap()))
+        })
+    }
+
+    /// Tear down the instance of the current thread.
+    ///
+    /// See `Object::remove_current()`.
+    pub fn remove_current(&self) -> Option<T> {
+        // Note that the value is returned rather than dropped here, so its destructor runs after
+        // the map is released (allowing it to access other thread objects).
+        THREAD_OBJECTS.with(|map| map.borrow_mut().remove(&self.id))
+            .map(|ptr| *ptr.downcast().unwrap())
+    }
 }
 
 impl<T: Default> Default for Object<T> {
@@ -178,6 +246,39 @@ mod tests {
         assert_eq!(Object::<usize>::default().get(), 0);
     }
 
+    #[test]
+    fn remove_current() {
+        let obj = Object::new(1);
+        assert_eq!(obj.remove_current(), None);
+
+        obj.with(|x| *x = 2);
+        assert_eq!(obj.remove_current(), Some(2));
+        assert_eq!(obj.remove_current(), None);
+
+        // The instance is recreated from the initial value.
+        assert_eq!(obj.get(), 1);
+    }
+
+    #[test]
+    fn weak() {
+        let obj = Object::new(String::new());
+        let weak = obj.downgrade();
+
+        // The weak handle doesn't create the instance.
+        assert_eq!(weak.with(|x| x.push('a')), None);
+        obj.with(|x| assert!(x.is_empty()));
+
+        assert_eq!(weak.with(|x| { x.push('a'); x.len() }), Some(1));
+        assert_eq!(obj.with(|x| x.clone()), "a");
+
+        thread::spawn(move || {
+            assert_eq!(weak.with(|x| x.clone()), None);
+        }).join().unwrap();
+
+        assert_eq!(weak.remove_current(), Some("a".to_owned()));
+        assert_eq!(weak.with(|x| x.clone()), None);
+    }
+
     #[derive(Clone)]
     struct Dropper {
         is_dropped: Arc<Mutex<bool>>,
@@ -205,4 +306,18 @@ mod tests {
 
         assert!(*is_dropped.lock().unwrap());
     }
+
+    #[test]
+    fn drop_on_remove() {
+        let is_dropped = Arc::new(Mutex::new(false));
+        let obj = Object::new(Dropper {
+            is_dropped: is_dropped.clone(),
+        });
+
+        obj.with(|_| {});
+        assert!(!*is_dropped.lock().unwrap());
+
+        mem::drop(obj.remove_current());
+        assert!(*is_dropped.lock().unwrap());
+    }
 }
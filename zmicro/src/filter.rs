@@ -0,0 +1,297 @@
+//! Reversible pre-filters.
+//!
+//! Filters transform the input before it is fed to the model, in order to expose redundancy which
+//! the model cannot pick up by itself. In particular, filesystem metadata pages are mostly arrays
+//! of ascending 64-bit integers (e.g. pointers to consecutive clusters) padded with zeros, which
+//! compress far better when replaced by their differences, and with the zero runs shortened.
+//!
+//! Every filter is exactly reversible. The filters applied to a frame are recorded in its header
+//! as a single byte (see `frame`), such that the decoder knows which to undo.
+
+/// The size of a word of the delta filter in bytes.
+const WORD_SIZE: usize = 8;
+/// The maximal size of a frame in bytes.
+///
+/// Filtered data decoding to more than this is malformed, which keeps a corrupt run length from
+/// making the decoder allocate arbitrarily much.
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// A set of filters.
+///
+/// When encoding, the filters are applied in a fixed order (delta coding, then zero run-length
+/// coding), and when decoding they are undone in the reverse order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Filters {
+    /// The bit flags of the enabled filters.
+    bits: u8,
+}
+
+impl Filters {
+    /// No filters.
+    pub const NONE: Filters = Filters { bits: 0 };
+    /// Delta coding of little-endian 64-bit words.
+    ///
+    /// Each word is replaced by its (wrapping) difference to the previous word. The trailing bytes
+    /// which don't make up a whole word are left untouched. This turns monotone sequences into
+    /// sequences of small numbers.
+    pub const DELTA: Filters = Filters { bits: 1 };
+    /// Run-length coding of zero bytes.
+    ///
+    /// Each run of zeros is replaced by a single zero followed by the length of the run as a
+    /// LEB128 varint. Other bytes are left untouched.
+    pub const ZERO_RLE: Filters = Filters { bits: 2 };
+
+    /// Combine two sets of filters.
+    pub fn with(self, other: Filters) -> Filters {
+        Filters { bits: self.bits | other.bits }
+    }
+
+    /// Does this set contain every filter of another set?
+    pub fn contains(self, other: Filters) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Get the header byte representing this set.
+    pub fn to_byte(self) -> u8 {
+        self.bits
+    }
+
+    /// Read the set from a header byte.
+    ///
+    /// This returns `None` if the byte contains unknown filters.
+    pub fn from_byte(byte: u8) -> Option<Filters> {
+        if byte & !(Filters::DELTA.bits | Filters::ZERO_RLE.bits) == 0 {
+            Some(Filters { bits: byte })
+        } else {
+            None
+        }
+    }
+
+    /// Apply the filters to some input.
+    ///
+    /// The input must not exceed the maximal frame size.
+    pub fn encode(self, mut buf: Vec<u8>) -> Vec<u8> {
+        debug_assert!(buf.len() <= MAX_FRAME_SIZE, "Frame exceeds the maximal size.");
+
+        if self.contains(Filters::DELTA) {
+            delta_encode(&mut buf);
+        }
+        if self.contains(Filters::ZERO_RLE) {
+            buf = rle_encode(&buf);
+        }
+
+        buf
+    }
+
+    /// Undo the filters on some filtered data.
+    ///
+    /// This returns `None` if the data is malformed (i.e. it wasn't the output of `encode()`).
+    pub fn decode(self, mut buf: Vec<u8>) -> Option<Vec<u8>> {
+        if self.contains(Filters::ZERO_RLE) {
+            buf = rle_decode(&buf)?;
+        }
+        if self.contains(Filters::DELTA) {
+            delta_decode(&mut buf);
+        }
+
+        Some(buf)
+    }
+}
+
+/// Read a little-endian word.
+fn read_word(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |x, &i| x << 8 | i as u64)
+}
+
+/// Write a little-endian word.
+fn write_word(buf: &mut [u8], mut x: u64) {
+    for i in buf {
+        *i = x as u8;
+        x >>= 8;
+    }
+}
+
+/// Delta code the words of a buffer in place.
+fn delta_encode(buf: &mut [u8]) {
+    let mut prev = 0;
+    for word in buf.chunks_mut(WORD_SIZE).filter(|word| word.len() == WORD_SIZE) {
+        let x = read_word(word);
+        write_word(word, x.wrapping_sub(prev));
+        prev = x;
+    }
+}
+
+/// Undo the delta coding of a buffer in place.
+fn delta_decode(buf: &mut [u8]) {
+    let mut prev = 0u64;
+    for word in buf.chunks_mut(WORD_SIZE).filter(|word| word.len() == WORD_SIZE) {
+        prev = prev.wrapping_add(read_word(word));
+        write_word(word, prev);
+    }
+}
+
+/// Run-length code the zeros of a buffer.
+fn rle_encode(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut i = 0;
+
+    while i < buf.len() {
+        if buf[i] != 0 {
+            out.push(buf[i]);
+            i += 1;
+            continue;
+        }
+
+        // Measure the run.
+        let start = i;
+        while i < buf.len() && buf[i] == 0 {
+            i += 1;
+        }
+
+        // Write the marker followed by the varint length.
+        out.push(0);
+        let mut len = i - start;
+        while len >= 0x80 {
+            out.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        out.push(len as u8);
+    }
+
+    out
+}
+
+/// Undo the run-length coding of a buffer.
+fn rle_decode(buf: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte != 0 {
+            out.push(byte);
+            continue;
+        }
+
+        // Read the varint length of the run.
+        let mut len = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.next()?;
+            // A varint of more than 10 bytes doesn't fit in 64 bits.
+            if shift >= 64 {
+                return None;
+            }
+            let bits = (byte & 0x7F) as u64;
+            // Neither must the last byte overflow.
+            if (bits << shift) >> shift != bits {
+                return None;
+            }
+            len |= bits << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        // Runs are never empty, and never exceed the frame.
+        if len == 0 || len > MAX_FRAME_SIZE as u64 {
+            return None;
+        }
+        let new_len = out.len().checked_add(len as usize)?;
+        if new_len > MAX_FRAME_SIZE {
+            return None;
+        }
+        out.resize(new_len, 0);
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(filters: Filters, buf: &[u8]) -> Vec<u8> {
+        let encoded = filters.encode(buf.to_vec());
+        assert_eq!(filters.decode(encoded.clone()).unwrap(), buf);
+        encoded
+    }
+
+    #[test]
+    fn header_byte() {
+        let all = Filters::DELTA.with(Filters::ZERO_RLE);
+
+        assert_eq!(Filters::from_byte(Filters::NONE.to_byte()), Some(Filters::NONE));
+        assert_eq!(Filters::from_byte(all.to_byte()), Some(all));
+        assert_eq!(Filters::from_byte(0x80), None);
+    }
+
+    #[test]
+    fn delta() {
+        let mut buf = Vec::new();
+        for i in 0..16u64 {
+            let mut word = [0; WORD_SIZE];
+            write_word(&mut word, 1000 + i * 3);
+            buf.extend_from_slice(&word);
+        }
+        // A trailing partial word.
+        buf.extend_from_slice(&[1, 2, 3]);
+
+        let encoded = round_trip(Filters::DELTA, &buf);
+        assert_eq!(read_word(&encoded[..8]), 1000);
+        assert_eq!(read_word(&encoded[8..16]), 3);
+        assert_eq!(&encoded[encoded.len() - 3..], &[1, 2, 3]);
+
+        // Wrapping differences.
+        round_trip(Filters::DELTA, &[0xFF; 8 * 3]);
+        round_trip(Filters::DELTA, &[0xFF, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn zero_rle() {
+        assert_eq!(round_trip(Filters::ZERO_RLE, &[]), []);
+        assert_eq!(round_trip(Filters::ZERO_RLE, &[1, 2, 3]), [1, 2, 3]);
+        assert_eq!(round_trip(Filters::ZERO_RLE, &[0]), [0, 1]);
+        assert_eq!(round_trip(Filters::ZERO_RLE, &[5, 0, 0, 0, 6]), [5, 0, 3, 6]);
+        assert_eq!(round_trip(Filters::ZERO_RLE, &[0; 4096]), [0, 0x80, 0x20]);
+
+        for len in 120..140 {
+            round_trip(Filters::ZERO_RLE, &vec![0; len]);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        // Truncated varint.
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0]), None);
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0x80]), None);
+        // Empty run.
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0]), None);
+        // Overlong and overflowing varints.
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                                                 0x7F]), None);
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                                                 0xFF, 0x7F]), None);
+        assert_eq!(Filters::ZERO_RLE.decode([0, 0x80].iter().cycle().take(100).cloned()
+                                            .chain(Some(1)).collect()), None);
+        // Runs exceeding the frame.
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0x81, 0x80, 0x80, 0x01]), None);
+        assert_eq!(Filters::ZERO_RLE.decode(vec![0, 0x80, 0x80, 0x40, 0, 0x80, 0x80, 0x40]),
+                   None);
+    }
+
+    #[test]
+    fn metadata_page() {
+        // An array of consecutive cluster pointers, padded with zeros.
+        let mut page = vec![0; 4096];
+        for (n, word) in page.chunks_mut(WORD_SIZE).take(100).enumerate() {
+            write_word(word, 0x1234_5678 + n as u64);
+        }
+
+        let all = Filters::DELTA.with(Filters::ZERO_RLE);
+        let encoded = round_trip(all, &page);
+        assert!(encoded.len() < 400);
+        assert!(encoded.len() < round_trip(Filters::ZERO_RLE, &page).len());
+    }
+}
@@ -0,0 +1,64 @@
+//! Frames.
+//!
+//! A frame is the unit of (de)compression. It consists of a header followed by the payload:
+//!
+//! 1. The filters applied to the payload, as a single byte (see `Filters::to_byte()`).
+//! 2. The payload, i.e. the filtered data.
+//!
+//! The header tells the decoder which filters to undo, so frames filtered differently (e.g.
+//! metadata pages with delta coding, and file data without) are decoded alike. The payload is to be
+//! entropy coded (see `range`) once the model exists.
+
+use filter::Filters;
+
+/// The size of the frame header in bytes.
+pub const HEADER_SIZE: usize = 1;
+
+/// Encode a frame.
+///
+/// This applies `filters` to `data`, and prepends the header recording them.
+pub fn encode(filters: Filters, data: Vec<u8>) -> Vec<u8> {
+    let payload = filters.encode(data);
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.push(filters.to_byte());
+    frame.extend_from_slice(&payload);
+
+    frame
+}
+
+/// Decode a frame.
+///
+/// This reads the filters from the header, and undoes them on the payload. `None` is returned if
+/// the frame is malformed (i.e. it wasn't the output of `encode()`).
+pub fn decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let (&header, payload) = frame.split_first()?;
+
+    Filters::from_byte(header)?.decode(payload.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3];
+
+        for &filters in &[Filters::NONE, Filters::DELTA, Filters::ZERO_RLE,
+                          Filters::DELTA.with(Filters::ZERO_RLE)] {
+            let frame = encode(filters, data.clone());
+            assert_eq!(frame[0], filters.to_byte());
+            assert_eq!(decode(&frame).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(decode(&[]), None);
+        // Unknown filters.
+        assert_eq!(decode(&[0x80, 1, 2, 3]), None);
+        // A zero run without a length.
+        assert_eq!(decode(&[Filters::ZERO_RLE.to_byte(), 0]), None);
+    }
+}
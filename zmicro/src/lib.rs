@@ -1 +1,3 @@
+pub mod filter;
+pub mod frame;
 mod range;